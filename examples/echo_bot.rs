@@ -0,0 +1,177 @@
+//! A bot which joins a channel and answers a few commands.
+//!
+//!     cargo run --example echo_bot -- irc.example.net:6667 echobot '#test'
+//!
+//! Say `!echo <text>` to have it repeated, `!users` for a count of the
+//! channel's members, or `!part` to make it leave.  It is built only on
+//! the public API: `IrcSession` does the line handling, `RegisterReq`
+//! the nick negotiation and `State` the channel tracking, while the
+//! socket is a plain blocking `TcpStream`.
+
+extern crate irc;
+
+use std::env;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::process;
+use std::time::Instant;
+
+use irc::prelude::*;
+use irc::client::{JoinBuf, NoticeBuf, PartBuf, PrivmsgBuf};
+use irc::legacy::{BundlerManager, RegisterReq, RegisterReqBuilder, RegisterStatus, SessionIdentity};
+
+struct Bot {
+    session: IrcSession,
+    state: State,
+    register: RegisterReq,
+    /// Shared with the join bundler, so it can tell our JOINs apart
+    identity: SessionIdentity,
+    channel: String,
+}
+
+impl Bot {
+    fn new(nick: &str, channel: &str) -> Bot {
+        let register = RegisterReqBuilder::new(nick, nick)
+            .realname("rust-irc echo bot")
+            .build()
+            .expect("nick can't be sent");
+        let identity = SessionIdentity::new(nick.as_bytes());
+        let mut session = IrcSession::new(BundlerManager::with_identity(identity.clone()));
+        for msg in register.start() {
+            session.send(&msg);
+        }
+        Bot {
+            session: session,
+            state: State::new(),
+            register: register,
+            identity: identity,
+            channel: channel.to_string(),
+        }
+    }
+
+    fn flush(&mut self, stream: &mut TcpStream) -> io::Result<()> {
+        while self.session.has_pending_output() {
+            let written = try!(stream.write(self.session.pending_output()));
+            self.session.consume_output(written);
+        }
+        Ok(())
+    }
+
+    /// Handle everything the session has parsed so far.  Returns false
+    /// once the bot should disconnect.
+    fn process_events(&mut self) -> Result<bool, MessageError> {
+        while let Some(event) = self.session.poll_event() {
+            self.state.on_event(&event);
+            if let IrcEvent::IrcMsg(ref legacy) = event {
+                match self.register.on_irc_msg(legacy) {
+                    RegisterStatus::Pending => (),
+                    RegisterStatus::Retry(ref nick) => self.session.send(nick),
+                    RegisterStatus::Registered(ref nick) => {
+                        println!("registered as {}", nick);
+                        self.identity.set_nick(nick.as_bytes());
+                        let join = try!(JoinBuf::new(&self.channel));
+                        self.session.send_msg(&join);
+                    },
+                    RegisterStatus::Failed(ref err) => {
+                        println!("registration failed: {:?}", err.errtype);
+                        return Ok(false);
+                    },
+                }
+
+                let msg = IrcMsg::from_legacy(legacy);
+                if let Ok(privmsg) = msg.as_tymsg::<&server::Privmsg>() {
+                    if !try!(self.on_privmsg(privmsg)) {
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    fn on_privmsg(&mut self, privmsg: &server::Privmsg) -> Result<bool, MessageError> {
+        let body = privmsg.get_body_raw();
+        let (command, rest) = match body.iter().position(|&b| b == b' ') {
+            Some(idx) => (&body[..idx], &body[idx + 1..]),
+            None => (body, &b""[..]),
+        };
+
+        // Reply where the command was said: in the channel, or to the
+        // sender if it was said to us.
+        let nick = privmsg.source_nick().to_string();
+        let target = privmsg.get_target().to_vec();
+        let reply_to = if target.eq_ignore_ascii_case(self.state.get_self_nick().as_bytes()) {
+            nick.clone().into_bytes()
+        } else {
+            target.clone()
+        };
+
+        match command {
+            b"!echo" if !rest.is_empty() => {
+                let reply = try!(PrivmsgBuf::new(&reply_to, rest));
+                self.session.send_msg(&reply);
+            },
+            b"!users" => {
+                let target = String::from_utf8_lossy(&target).into_owned();
+                let count = self.state.identify_channel(&target)
+                    .and_then(|id| self.state.resolve_channel(id))
+                    .map(|channel| channel.len());
+                let text = match count {
+                    Some(count) => format!("{} has {} known users", target, count),
+                    None => "only works in a channel".to_string(),
+                };
+                let reply = try!(NoticeBuf::new(&nick, text));
+                self.session.send_msg(&reply);
+            },
+            b"!part" => {
+                let reason = format!("asked to leave by {}", nick);
+                let part = try!(PartBuf::new(&self.channel, Some(reason.as_bytes())));
+                self.session.send_msg(&part);
+                let quit = try!(client::QuitBuf::new("bye"));
+                self.session.send_msg(&quit);
+                return Ok(false);
+            },
+            _ => (),
+        }
+        Ok(true)
+    }
+}
+
+fn run(addr: &str, nick: &str, channel: &str) -> io::Result<()> {
+    let mut stream = try!(TcpStream::connect(addr));
+    let mut bot = Bot::new(nick, channel);
+    try!(bot.flush(&mut stream));
+
+    let mut buf = [0; 4096];
+    loop {
+        let read = try!(stream.read(&mut buf));
+        if read == 0 {
+            println!("server closed the connection");
+            return Ok(());
+        }
+        bot.session.feed(&buf[..read], Instant::now());
+        let keep_going = match bot.process_events() {
+            Ok(keep_going) => keep_going,
+            Err(err) => {
+                println!("couldn't build a reply: {}", err);
+                true
+            }
+        };
+        try!(bot.flush(&mut stream));
+        if !keep_going {
+            return Ok(());
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 4 {
+        println!("usage: {} HOST:PORT NICK CHANNEL", args[0]);
+        process::exit(2);
+    }
+    if let Err(err) = run(&args[1], &args[2], &args[3]) {
+        println!("connection error: {}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,85 @@
+//! Turn a raw IRC log into JSON, one object per line.
+//!
+//!     cargo run --example json_log < session.log > session.jsonl
+//!
+//! Each line of standard input is parsed with `IrcMsg::new`; tag values
+//! are unescaped, and anything which isn't UTF-8 is replaced lossily.
+//! Lines which don't parse are reported on standard error and skipped.
+
+extern crate irc;
+
+use std::io::{self, BufRead, Write};
+
+use irc::IrcMsg;
+use irc::tags;
+
+fn write_json_str<W: Write>(out: &mut W, bytes: &[u8]) -> io::Result<()> {
+    try!(out.write_all(b"\""));
+    for ch in String::from_utf8_lossy(bytes).chars() {
+        match ch {
+            '"' => try!(out.write_all(b"\\\"")),
+            '\\' => try!(out.write_all(b"\\\\")),
+            '\n' => try!(out.write_all(b"\\n")),
+            '\r' => try!(out.write_all(b"\\r")),
+            '\t' => try!(out.write_all(b"\\t")),
+            ch if (ch as u32) < 0x20 => try!(write!(out, "\\u{:04x}", ch as u32)),
+            ch => try!(write!(out, "{}", ch)),
+        }
+    }
+    out.write_all(b"\"")
+}
+
+fn write_msg<W: Write>(out: &mut W, msg: &IrcMsg) -> io::Result<()> {
+    try!(out.write_all(b"{\"tags\":{"));
+    for (idx, (key, value)) in msg.tags().enumerate() {
+        if idx > 0 {
+            try!(out.write_all(b","));
+        }
+        try!(write_json_str(out, key));
+        try!(out.write_all(b":"));
+        match value {
+            Some(value) => try!(write_json_str(out, &tags::unescape_value(value))),
+            None => try!(out.write_all(b"true")),
+        }
+    }
+
+    try!(out.write_all(b"},\"prefix\":"));
+    match msg.get_prefix() {
+        Some(prefix) => try!(write_json_str(out, prefix.as_bytes())),
+        None => try!(out.write_all(b"null")),
+    }
+
+    try!(out.write_all(b",\"command\":"));
+    try!(write_json_str(out, msg.get_command().as_bytes()));
+
+    try!(out.write_all(b",\"args\":["));
+    for (idx, arg) in msg.args().enumerate() {
+        if idx > 0 {
+            try!(out.write_all(b","));
+        }
+        try!(write_json_str(out, arg));
+    }
+    out.write_all(b"]}\n")
+}
+
+fn main() {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    for (lineno, line) in stdin.lock().split(b'\n').enumerate() {
+        let mut line = line.expect("failed to read standard input");
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        if line.is_empty() {
+            continue;
+        }
+        match IrcMsg::new(&line) {
+            Ok(msg) => write_msg(&mut out, msg).expect("failed to write standard output"),
+            Err(err) => {
+                let _ = writeln!(io::stderr(), "line {}: {}", lineno + 1, err);
+            },
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! Grouping messages into IRCv3 batches.
+//!
+//! With the `batch` capability, the server brackets related messages in
+//! `BATCH +ref type` and `BATCH -ref`, tagging each message in between
+//! with `batch=ref`.  Batches may nest: a `chathistory` batch can hold
+//! `netsplit` batches, whose own BATCH lines are tagged with the outer
+//! reference.
+
+use std::borrow::ToOwned;
+use std::collections::HashMap;
+
+use ::{IrcMsg, IrcMsgBuf};
+use ::server::{Batch, BatchType};
+
+/// A closed batch with everything it contained.
+#[derive(Clone, Debug)]
+pub struct BatchEvent {
+    pub reference: String,
+    pub batch_type: BatchType,
+    /// The type-specific parameters from the opening BATCH
+    pub params: Vec<Vec<u8>>,
+    /// The messages in the batch, in order, excluding nested batches
+    pub messages: Vec<IrcMsgBuf>,
+    /// Batches nested in this one, in the order they closed
+    pub children: Vec<BatchEvent>,
+}
+
+impl BatchEvent {
+    fn open(batch: &Batch) -> BatchEvent {
+        BatchEvent {
+            reference: String::from_utf8_lossy(batch.get_reference()).into_owned(),
+            batch_type: batch.get_batch_type().unwrap(),
+            params: batch.get_params().into_iter().map(|p| p.to_vec()).collect(),
+            messages: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+}
+
+/// What a `BatchTracker` did with a message.
+#[derive(Clone, Debug)]
+pub enum BatchStatus {
+    /// The message isn't part of a batch and should be handled as usual
+    Unbatched,
+    /// The message was kept in an open batch
+    Held,
+    /// The message closed an outermost batch
+    Closed(BatchEvent),
+}
+
+struct OpenBatch {
+    event: BatchEvent,
+    /// The reference of the batch this one is nested in
+    parent: Option<String>,
+}
+
+/// Holds the messages of open batches until they close.
+pub struct BatchTracker {
+    open: HashMap<String, OpenBatch>,
+}
+
+impl BatchTracker {
+    pub fn new() -> BatchTracker {
+        BatchTracker { open: HashMap::new() }
+    }
+
+    pub fn is_open(&self, reference: &str) -> bool {
+        self.open.contains_key(reference)
+    }
+
+    /// The number of batches currently open, including nested ones.
+    pub fn open_count(&self) -> usize {
+        self.open.len()
+    }
+
+    pub fn on_irc_msg(&mut self, msg: &IrcMsg) -> BatchStatus {
+        let parent = msg.batch().map(|reference| reference.into_owned());
+        if let Some(ref parent) = parent {
+            if !self.is_open(parent) {
+                warn!("message in unknown batch {:?}", parent);
+                return BatchStatus::Unbatched;
+            }
+        }
+
+        if let Ok(batch) = msg.as_tymsg::<&Batch>() {
+            let reference = String::from_utf8_lossy(batch.get_reference()).into_owned();
+            if batch.is_start() {
+                self.open.insert(reference, OpenBatch {
+                    event: BatchEvent::open(batch),
+                    parent: parent,
+                });
+                return BatchStatus::Held;
+            }
+            return match self.open.remove(&reference) {
+                Some(closed) => self.close(closed),
+                None => {
+                    warn!("end of unknown batch {:?}", reference);
+                    BatchStatus::Unbatched
+                }
+            };
+        }
+
+        match parent {
+            Some(parent) => {
+                self.open.get_mut(&parent).unwrap().event.messages.push(msg.to_owned());
+                BatchStatus::Held
+            },
+            None => BatchStatus::Unbatched,
+        }
+    }
+
+    fn close(&mut self, closed: OpenBatch) -> BatchStatus {
+        let parent = closed.parent.and_then(|parent| self.open.get_mut(&parent));
+        match parent {
+            Some(parent) => {
+                parent.event.children.push(closed.event);
+                BatchStatus::Held
+            },
+            None => BatchStatus::Closed(closed.event),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::IrcMsg;
+    use ::server::BatchType;
+    use super::{BatchStatus, BatchTracker};
+
+    fn feed(tracker: &mut BatchTracker, line: &[u8]) -> BatchStatus {
+        tracker.on_irc_msg(IrcMsg::new(line).unwrap())
+    }
+
+    #[test]
+    fn nested_batches() {
+        let mut tracker = BatchTracker::new();
+        let lines: &[&[u8]] = &[
+            b":irc.host BATCH +outer chathistory #chan",
+            b"@batch=outer :nick!u@h PRIVMSG #chan :hello",
+            b"@batch=outer :irc.host BATCH +inner netsplit irc.hub other.host",
+            b"@batch=inner :alice!u@h QUIT :irc.hub other.host",
+            b"@batch=outer :irc.host BATCH -inner",
+        ];
+        for line in lines.iter() {
+            match feed(&mut tracker, line) {
+                BatchStatus::Held => (),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert_eq!(tracker.open_count(), 1);
+        match feed(&mut tracker, b":nick!u@h PRIVMSG #chan :live") {
+            BatchStatus::Unbatched => (),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let event = match feed(&mut tracker, b":irc.host BATCH -outer") {
+            BatchStatus::Closed(event) => event,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(tracker.open_count(), 0);
+        assert_eq!(event.reference, "outer");
+        assert_eq!(event.batch_type, BatchType::Chathistory);
+        assert_eq!(event.params, vec![b"#chan".to_vec()]);
+        assert_eq!(event.messages.len(), 1);
+        assert_eq!(event.children.len(), 1);
+        let inner = &event.children[0];
+        assert_eq!(inner.batch_type, BatchType::Netsplit);
+        assert_eq!(inner.messages[0].get_command(), "QUIT");
+
+        match feed(&mut tracker, b"@batch=gone :nick!u@h PRIVMSG #chan :late") {
+            BatchStatus::Unbatched => (),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -1,11 +1,31 @@
-use std::{slice, fmt, str};
-use std::any::{Any, TypeId};
+use std::{fmt, mem, str};
+use std::any::Any;
 use std::collections::{HashMap, hash_map};
-use std::borrow::{Cow, ToOwned};
+use std::borrow::Cow;
 
 use unicase::UniCase;
 
+use ::IrcMsg;
+use ::server::CapLs;
+
+mod batch;
+pub use self::batch::{BatchEvent, BatchStatus, BatchTracker};
+
+mod negotiate;
+pub use self::negotiate::CapNegotiator;
+
+mod sasl;
+pub use self::sasl::{
+    SaslError, SaslMechanism, SaslAuthenticator,
+    Plain, External,
+    chunk_payload, is_mechanism_offered,
+};
+
+mod scram;
+pub use self::scram::ScramSha256;
+
 /// The available IRCv3 capability negotiation versions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum NegotiationVersion {
     /// [IRCv3.1](http://ircv3.net/specs/core/capability-negotiation-3.1.html)
     V301,
@@ -15,6 +35,69 @@ pub enum NegotiationVersion {
 
 type CapabilityName = UniCase<Cow<'static, str>>;
 
+/// One entry of a `CAP REQ`, `ACK` or `NAK` list.  A leading `-` asks for,
+/// or acknowledges, disabling the capability.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CapabilityChange<'a> {
+    pub name: &'a str,
+    pub enable: bool,
+}
+
+impl<'a> CapabilityChange<'a> {
+    pub fn parse(token: &'a str) -> CapabilityChange<'a> {
+        if token.starts_with('-') {
+            CapabilityChange { name: &token[1..], enable: false }
+        } else {
+            CapabilityChange { name: token, enable: true }
+        }
+    }
+}
+
+impl<'a> fmt::Display for CapabilityChange<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.enable {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "-{}", self.name)
+        }
+    }
+}
+
+/// Iterates over a space-separated capability list.
+pub struct CapabilityChangeIter<'a> {
+    inner: str::Split<'a, char>,
+}
+
+impl<'a> CapabilityChangeIter<'a> {
+    pub fn new(list: &'a str) -> CapabilityChangeIter<'a> {
+        CapabilityChangeIter { inner: list.split(' ') }
+    }
+}
+
+impl<'a> Iterator for CapabilityChangeIter<'a> {
+    type Item = CapabilityChange<'a>;
+
+    fn next(&mut self) -> Option<CapabilityChange<'a>> {
+        loop {
+            match self.inner.next() {
+                Some("") => continue,
+                Some(token) => return Some(CapabilityChange::parse(token)),
+                None => return None,
+            }
+        }
+    }
+}
+
+/// Whether `cap` may appear in a capability list we send: non-empty, and
+/// free of spaces and of bytes which can't appear in a message.
+pub fn is_valid_list_entry(cap: &str) -> bool {
+    let name = if cap.starts_with('-') { &cap[1..] } else { cap };
+    name.len() > 0 && !name.starts_with(':') && name.bytes().all(|b| {
+        b != b' ' && b != b'\0' && b != b'\r' && b != b'\n'
+    })
+}
+
+#[derive(Clone, Debug)]
 pub struct Capabilities {
     items: HashMap<CapabilityName, String>,
 }
@@ -40,11 +123,77 @@ impl Capabilities {
         })
     }
 
+    /// Add a capability as written in `CAP LS`, e.g. `sasl=PLAIN`.
+    pub fn insert_raw(&mut self, cap: &str) {
+        let name = cap.splitn(2, '=').next().unwrap();
+        self.items.insert(UniCase(Cow::Owned(name.to_string())), cap.to_string());
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.items.contains_key(&UniCase(Cow::Owned(name.to_string())))
+    }
+
+    /// The value advertised for `name`, as in `PLAIN` for `sasl=PLAIN`.
+    /// Empty if the capability has no value.
+    pub fn get_value(&self, name: &str) -> Option<&str> {
+        self.items.get(&UniCase(Cow::Owned(name.to_string()))).map(|raw| {
+            match raw.find('=') {
+                Some(idx) => &raw[idx+1..],
+                None => "",
+            }
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Add every capability of `other`, replacing values already present.
+    pub fn extend(&mut self, other: Capabilities) {
+        self.items.extend(other.items);
+    }
+
     pub fn iter_raw(&self) -> CapabilitiesRawIter {
         CapabilitiesRawIter { piter: self.items.values() }
     }
 }
 
+/// Collects the lines of a multi-line `CAP LS` reply.  Servers using
+/// 302-style negotiation mark every line but the last with `*`.
+#[derive(Debug)]
+pub struct CapLsAggregator {
+    pending: Capabilities,
+}
+
+impl CapLsAggregator {
+    pub fn new() -> CapLsAggregator {
+        CapLsAggregator { pending: Capabilities::new() }
+    }
+
+    /// Add one line.  On the final line the complete set is returned and
+    /// the aggregator is ready for the next reply.
+    pub fn push(&mut self, line: &CapLs) -> Option<Capabilities> {
+        self.pending.extend(line.capabilities());
+        if line.is_final() {
+            Some(mem::replace(&mut self.pending, Capabilities::new()))
+        } else {
+            None
+        }
+    }
+
+    /// Like `push`, ignoring anything which is not a `CAP LS` reply.
+    pub fn on_irc_msg(&mut self, msg: &IrcMsg) -> Option<Capabilities> {
+        match msg.as_tymsg::<&CapLs>() {
+            Ok(line) => self.push(line),
+            Err(_) => None,
+        }
+    }
+}
+
 pub struct CapabilitiesRawIter<'a> {
     piter: hash_map::Values<'a, CapabilityName, String>,
 }
@@ -412,6 +561,69 @@ impl<'a> Iterator for SaslProtocolIter<'a> {
     }
 }
 
+#[test]
+fn capability_change_iter() {
+    let changes: Vec<_> = CapabilityChangeIter::new("multi-prefix  -sasl ").collect();
+    assert_eq!(changes, vec![
+        CapabilityChange { name: "multi-prefix", enable: true },
+        CapabilityChange { name: "sasl", enable: false },
+    ]);
+    assert_eq!(changes[1].to_string(), "-sasl");
+    assert!(is_valid_list_entry("-sasl"));
+    assert!(!is_valid_list_entry("-"));
+    assert!(!is_valid_list_entry("a b"));
+}
+
+#[test]
+fn cap_ls_aggregation() {
+    let lines: &[&[u8]] = &[
+        b":irc.example.com CAP * LS * :multi-prefix extended-join account-notify",
+        b":irc.example.com CAP * NAK :nothing",
+        b":irc.example.com CAP * LS * :server-time example.org/dummy-cap=dummyvalue",
+        b":irc.example.com CAP * LS :userhost-in-names sasl=EXTERNAL,PLAIN",
+    ];
+
+    let mut aggregator = CapLsAggregator::new();
+    let mut results = Vec::new();
+    for &line in lines.iter() {
+        results.push(aggregator.on_irc_msg(&IrcMsg::new(line).unwrap()));
+    }
+    assert!(results[..3].iter().all(Option::is_none));
+
+    let caps = results.pop().unwrap().unwrap();
+    assert_eq!(caps.len(), 7);
+    assert!(caps.contains("Multi-Prefix"));
+    assert_eq!(caps.get_value("example.org/dummy-cap"), Some("dummyvalue"));
+    assert_eq!(caps.get_value("server-time"), Some(""));
+    let sasl: Sasl = caps.get().unwrap();
+    assert_eq!(sasl.protocols().collect::<Vec<_>>(), vec!["EXTERNAL", "PLAIN"]);
+
+    let single = IrcMsg::new(b":irc.example.com CAP * LS :tls").unwrap();
+    assert_eq!(aggregator.on_irc_msg(&single).unwrap().len(), 1);
+}
+
+#[test]
+fn cap_ls_group_round_trip() {
+    use ::server::CapLsGroupBuilder;
+
+    let mut caps = Capabilities::new();
+    for idx in 0..40 {
+        caps.insert_raw(&format!("example.org/cap-{}", idx));
+    }
+    let group = CapLsGroupBuilder::new(b"irc.example.com", &caps).build().unwrap();
+
+    let mut aggregator = CapLsAggregator::new();
+    let mut result = None;
+    let lines: Vec<&[u8]> = group.as_bytes().split(|&b| b == b'\n')
+        .filter(|l| l.len() > 0).collect();
+    assert!(lines.len() > 1);
+    for line in lines.iter() {
+        assert!(result.is_none());
+        result = aggregator.on_irc_msg(&IrcMsg::new(line).unwrap());
+    }
+    assert_eq!(result.unwrap().len(), 40);
+}
+
 #[test]
 fn swag() {
     let mut caps = Capabilities::new();
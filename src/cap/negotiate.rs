@@ -0,0 +1,264 @@
+//! Driving capability negotiation from `CAP LS` to `CAP END`.
+
+use std::collections::VecDeque;
+
+use ::{IrcMsg, IrcMsgBuf};
+use ::client::{CapLsBuf, CapReqBuf, CapEndBuf};
+use ::server::{CapAck, CapNak};
+
+use super::{Capabilities, CapLsAggregator, NegotiationVersion};
+
+/// Requests are batched so each `CAP REQ` stays well inside the message
+/// length limit.
+const MAX_REQ_LEN: usize = 400;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Phase {
+    Listing,
+    Requesting,
+    Finished,
+}
+
+/// Negotiates a wanted set of capabilities during registration.
+///
+/// Send the messages from `start` along with NICK and USER, then feed
+/// every incoming message to `on_irc_msg` and send what it returns until
+/// `is_finished`.  Wanted capabilities the server does not offer are
+/// skipped.  If the server refuses a request for several capabilities,
+/// each is requested again on its own, so one refusal doesn't cost the
+/// rest.
+///
+/// Anything which must happen before `CAP END`, such as SASL, can hold
+/// it back with `hold_end`.
+#[derive(Debug)]
+pub struct CapNegotiator {
+    version: NegotiationVersion,
+    wanted: Vec<String>,
+    phase: Phase,
+    listing: CapLsAggregator,
+    available: Capabilities,
+    /// Requests sent and not yet answered, oldest first.  Servers answer
+    /// in order.
+    in_flight: VecDeque<Vec<String>>,
+    enabled: Vec<String>,
+    refused: Vec<String>,
+    end_held: bool,
+}
+
+impl CapNegotiator {
+    pub fn new(version: NegotiationVersion, wanted: &[&str]) -> CapNegotiator {
+        CapNegotiator {
+            version: version,
+            wanted: wanted.iter().map(|cap| cap.to_string()).collect(),
+            phase: Phase::Listing,
+            listing: CapLsAggregator::new(),
+            available: Capabilities::new(),
+            in_flight: VecDeque::new(),
+            enabled: Vec::new(),
+            refused: Vec::new(),
+            end_held: false,
+        }
+    }
+
+    /// The messages which begin negotiation.
+    pub fn start(&mut self) -> Vec<IrcMsgBuf> {
+        vec![CapLsBuf::new(self.version).into_inner()]
+    }
+
+    pub fn on_irc_msg(&mut self, msg: &IrcMsg) -> Vec<IrcMsgBuf> {
+        match self.phase {
+            Phase::Listing => {
+                if let Some(caps) = self.listing.on_irc_msg(msg) {
+                    self.available = caps;
+                    self.phase = Phase::Requesting;
+                    return self.request_wanted();
+                }
+                if is_registered_without_cap(msg) {
+                    self.phase = Phase::Finished;
+                }
+                Vec::new()
+            },
+            Phase::Requesting => {
+                if let Ok(ack) = msg.as_tymsg::<&CapAck>() {
+                    self.in_flight.pop_front();
+                    for change in ack.capabilities() {
+                        self.enabled.retain(|cap| !cap.eq_ignore_ascii_case(change.name));
+                        if change.enable {
+                            self.enabled.push(change.name.to_string());
+                        }
+                    }
+                    return self.finish_if_done();
+                }
+                if msg.as_tymsg::<&CapNak>().is_ok() {
+                    let mut out = Vec::new();
+                    match self.in_flight.pop_front() {
+                        Some(ref batch) if batch.len() > 1 => {
+                            for cap in batch.iter() {
+                                out.extend(self.request(vec![cap.clone()]));
+                            }
+                        },
+                        Some(batch) => self.refused.extend(batch),
+                        None => (),
+                    }
+                    out.extend(self.finish_if_done());
+                    return out;
+                }
+                Vec::new()
+            },
+            Phase::Finished => Vec::new(),
+        }
+    }
+
+    fn request_wanted(&mut self) -> Vec<IrcMsgBuf> {
+        let mut batches: Vec<Vec<String>> = Vec::new();
+        let mut batch_len = 0;
+        for cap in self.wanted.iter() {
+            if !self.available.contains(cap) {
+                continue;
+            }
+            if batches.is_empty() || MAX_REQ_LEN < batch_len + cap.len() + 1 {
+                batches.push(Vec::new());
+                batch_len = 0;
+            }
+            batch_len += cap.len() + 1;
+            batches.last_mut().unwrap().push(cap.clone());
+        }
+
+        let mut out = Vec::new();
+        for batch in batches.into_iter() {
+            out.extend(self.request(batch));
+        }
+        out.extend(self.finish_if_done());
+        out
+    }
+
+    fn request(&mut self, batch: Vec<String>) -> Option<IrcMsgBuf> {
+        let req = {
+            let names: Vec<&str> = batch.iter().map(|cap| &cap[..]).collect();
+            CapReqBuf::new(&names)
+        };
+        match req {
+            Ok(req) => {
+                self.in_flight.push_back(batch);
+                Some(req.into_inner())
+            },
+            Err(_) => {
+                warn!("unsendable capability request {:?}", batch);
+                self.refused.extend(batch);
+                None
+            },
+        }
+    }
+
+    /// Don't send `CAP END` until `release_end` is called.
+    pub fn hold_end(&mut self) {
+        self.end_held = true;
+    }
+
+    /// Allow `CAP END`, returning it if every request has been answered.
+    pub fn release_end(&mut self) -> Vec<IrcMsgBuf> {
+        self.end_held = false;
+        self.finish_if_done()
+    }
+
+    fn finish_if_done(&mut self) -> Vec<IrcMsgBuf> {
+        if self.end_held || !self.is_settled() || self.phase == Phase::Finished {
+            return Vec::new();
+        }
+        self.phase = Phase::Finished;
+        vec![CapEndBuf::new().into_inner()]
+    }
+
+    /// True once every request has been answered, whether or not
+    /// `CAP END` has been sent.
+    pub fn is_settled(&self) -> bool {
+        self.phase != Phase::Listing && self.in_flight.is_empty()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.phase == Phase::Finished
+    }
+
+    /// Everything the server offered.  Empty until `CAP LS` completes.
+    pub fn available(&self) -> &Capabilities {
+        &self.available
+    }
+
+    /// The capabilities the server has enabled for us.
+    pub fn enabled(&self) -> &[String] {
+        &self.enabled
+    }
+
+    /// Wanted capabilities the server refused.
+    pub fn refused(&self) -> &[String] {
+        &self.refused
+    }
+
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.enabled.iter().any(|cap| cap.eq_ignore_ascii_case(name))
+    }
+}
+
+/// Servers without CAP support answer `CAP LS` with ERR_UNKNOWNCOMMAND,
+/// or just carry on registering.
+fn is_registered_without_cap(msg: &IrcMsg) -> bool {
+    match msg.get_command() {
+        "001" => true,
+        "421" => msg.args().nth(1).map(|cmd| cmd.eq_ignore_ascii_case(b"CAP")).unwrap_or(false),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::{IrcMsg, IrcMsgBuf};
+    use super::super::NegotiationVersion;
+    use super::CapNegotiator;
+
+    fn feed(negotiator: &mut CapNegotiator, line: &[u8]) -> Vec<Vec<u8>> {
+        let msg = IrcMsg::new(line).unwrap();
+        negotiator.on_irc_msg(msg).into_iter().map(IrcMsgBuf::into_inner).collect()
+    }
+
+    #[test]
+    fn negotiate_with_nak_fallback() {
+        let mut negotiator = CapNegotiator::new(NegotiationVersion::V302,
+            &["multi-prefix", "away-notify", "server-time", "not-offered"]);
+        let start: Vec<_> = negotiator.start().into_iter().map(IrcMsgBuf::into_inner).collect();
+        assert_eq!(start, vec![b"CAP LS 302".to_vec()]);
+
+        assert!(feed(&mut negotiator, b":srv CAP * LS * :multi-prefix sasl").is_empty());
+        assert_eq!(feed(&mut negotiator, b":srv CAP * LS :away-notify server-time"),
+            vec![b"CAP REQ :multi-prefix away-notify server-time".to_vec()]);
+
+        assert_eq!(feed(&mut negotiator, b":srv CAP * NAK :multi-prefix away-notify server-time"), vec![
+            b"CAP REQ :multi-prefix".to_vec(),
+            b"CAP REQ :away-notify".to_vec(),
+            b"CAP REQ :server-time".to_vec(),
+        ]);
+        assert!(feed(&mut negotiator, b":srv CAP * ACK :multi-prefix").is_empty());
+        assert!(feed(&mut negotiator, b":srv CAP * NAK :away-notify").is_empty());
+        assert!(!negotiator.is_finished());
+        assert_eq!(feed(&mut negotiator, b":srv CAP * ACK :server-time"),
+            vec![b"CAP END".to_vec()]);
+
+        assert!(negotiator.is_finished());
+        assert!(negotiator.is_enabled("Multi-Prefix"));
+        assert_eq!(negotiator.enabled(), &["multi-prefix".to_string(), "server-time".to_string()]);
+        assert_eq!(negotiator.refused(), &["away-notify".to_string()]);
+        assert!(negotiator.available().contains("sasl"));
+    }
+
+    #[test]
+    fn nothing_wanted_or_no_cap_support() {
+        let mut negotiator = CapNegotiator::new(NegotiationVersion::V302, &["batch"]);
+        assert_eq!(feed(&mut negotiator, b":srv CAP * LS :multi-prefix"),
+            vec![b"CAP END".to_vec()]);
+        assert!(negotiator.is_finished());
+
+        let mut negotiator = CapNegotiator::new(NegotiationVersion::V302, &["batch"]);
+        assert!(feed(&mut negotiator, b":srv 421 * CAP :Unknown command").is_empty());
+        assert!(negotiator.is_finished());
+        assert!(negotiator.enabled().is_empty());
+    }
+}
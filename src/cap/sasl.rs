@@ -0,0 +1,496 @@
+//! SASL authentication over `AUTHENTICATE`.
+//!
+//! Authentication happens during capability negotiation, after `sasl`
+//! has been acknowledged and before `CAP END`; see
+//! `CapNegotiator::hold_end`.
+
+use std::fmt;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
+
+use ::{IrcMsg, IrcMsgBuf};
+use ::client::{AuthenticateBuf, AUTHENTICATE_CHUNK_LEN};
+use ::server::Authenticate;
+
+use super::{Capabilities, Sasl};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SaslError {
+    /// ERR_NICKLOCKED: the account is unavailable under this nick
+    NickLocked,
+    /// ERR_SASLFAIL: bad credentials, or an unsupported mechanism
+    Failed,
+    /// ERR_SASLTOOLONG
+    TooLong,
+    /// ERR_SASLABORTED
+    Aborted,
+    /// ERR_SASLALREADY
+    AlreadyAuthenticated,
+    /// The server's challenge was malformed, or failed verification
+    InvalidChallenge,
+    /// The server reported success before the mechanism could verify it
+    Unverified,
+}
+
+impl SaslError {
+    fn from_numeric(command: &str) -> Option<SaslError> {
+        match command {
+            "902" => Some(SaslError::NickLocked),
+            "904" => Some(SaslError::Failed),
+            "905" => Some(SaslError::TooLong),
+            "906" => Some(SaslError::Aborted),
+            "907" => Some(SaslError::AlreadyAuthenticated),
+            _ => None,
+        }
+    }
+}
+
+/// Split an unencoded payload into the AUTHENTICATE messages carrying it:
+/// base64 in chunks of 400 bytes, with a final `+` when the last chunk
+/// is full or the payload is empty.
+pub fn chunk_payload(payload: &[u8]) -> Vec<AuthenticateBuf> {
+    let encoded = base64_encode(payload);
+    let mut out: Vec<AuthenticateBuf> = encoded.as_bytes()
+        .chunks(AUTHENTICATE_CHUNK_LEN)
+        .map(|chunk| AuthenticateBuf::new(chunk).unwrap())
+        .collect();
+    if encoded.len() % AUTHENTICATE_CHUNK_LEN == 0 {
+        out.push(AuthenticateBuf::new("+").unwrap());
+    }
+    out
+}
+
+/// Whether the server offers SASL with the named mechanism.  Servers
+/// which don't list their mechanisms are assumed to support it.
+pub fn is_mechanism_offered(caps: &Capabilities, mechanism: &str) -> bool {
+    if !caps.contains("sasl") {
+        return false;
+    }
+    match caps.get_value("sasl") {
+        Some(value) if !value.is_empty() => {
+            Sasl::new(value).protocols().any(|mech| mech.eq_ignore_ascii_case(mechanism))
+        },
+        _ => true,
+    }
+}
+
+/// A SASL mechanism.  The authenticator takes care of the AUTHENTICATE
+/// framing: base64, chunking and reassembly.
+pub trait SaslMechanism {
+    /// The name sent in the opening AUTHENTICATE, such as `PLAIN`.
+    fn name(&self) -> &str;
+
+    /// Answer a decoded challenge.  The first challenge is usually empty.
+    fn respond(&mut self, challenge: &[u8]) -> Result<Vec<u8>, SaslError>;
+
+    /// Whether the exchange has gone far enough for the server's
+    /// RPL_SASLSUCCESS to be believed.  Mechanisms which authenticate
+    /// the server, such as SCRAM, must have checked its proof by then.
+    fn is_complete(&self) -> bool {
+        true
+    }
+}
+
+/// The PLAIN mechanism: an account name and password, sent in the clear.
+pub struct Plain {
+    authzid: String,
+    authcid: String,
+    password: String,
+}
+
+impl fmt::Debug for Plain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Plain")
+            .field("authzid", &self.authzid)
+            .field("authcid", &self.authcid)
+            .finish()
+    }
+}
+
+impl Plain {
+    pub fn new(account: &str, password: &str) -> Plain {
+        Plain {
+            authzid: String::new(),
+            authcid: account.to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    /// Act as `authzid` rather than the authenticating account.  Few
+    /// services support this.
+    pub fn with_authzid(mut self, authzid: &str) -> Plain {
+        self.authzid = authzid.to_string();
+        self
+    }
+}
+
+impl SaslMechanism for Plain {
+    fn name(&self) -> &str {
+        "PLAIN"
+    }
+
+    fn respond(&mut self, challenge: &[u8]) -> Result<Vec<u8>, SaslError> {
+        if !challenge.is_empty() {
+            return Err(SaslError::InvalidChallenge);
+        }
+        let mut out = Vec::with_capacity(
+            self.authzid.len() + self.authcid.len() + self.password.len() + 2);
+        out.extend(self.authzid.bytes());
+        out.push(0);
+        out.extend(self.authcid.bytes());
+        out.push(0);
+        out.extend(self.password.bytes());
+        Ok(out)
+    }
+}
+
+/// The EXTERNAL mechanism, where the server identifies us by other
+/// means, usually a TLS client certificate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct External {
+    authzid: String,
+}
+
+impl External {
+    pub fn new() -> External {
+        External { authzid: String::new() }
+    }
+
+    pub fn with_authzid(authzid: &str) -> External {
+        External { authzid: authzid.to_string() }
+    }
+}
+
+impl SaslMechanism for External {
+    fn name(&self) -> &str {
+        "EXTERNAL"
+    }
+
+    fn respond(&mut self, challenge: &[u8]) -> Result<Vec<u8>, SaslError> {
+        if !challenge.is_empty() {
+            return Err(SaslError::InvalidChallenge);
+        }
+        Ok(self.authzid.as_bytes().to_vec())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Phase {
+    Idle,
+    Exchanging,
+    Finished,
+}
+
+/// Authenticates with a `SaslMechanism`.
+///
+/// Send the messages from `start` once `sasl` has been acknowledged,
+/// then feed every incoming message to `on_irc_msg` and send what it
+/// returns until `is_finished`.
+pub struct SaslAuthenticator {
+    mechanism: Box<SaslMechanism+Send>,
+    phase: Phase,
+    /// Base64 challenge chunks received so far.
+    challenge: String,
+    result: Option<Result<(), SaslError>>,
+    account: Option<String>,
+    server_mechanisms: Vec<String>,
+}
+
+impl fmt::Debug for SaslAuthenticator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SaslAuthenticator")
+            .field("mechanism", &self.mechanism.name())
+            .field("phase", &self.phase)
+            .field("result", &self.result)
+            .field("account", &self.account)
+            .finish()
+    }
+}
+
+impl SaslAuthenticator {
+    pub fn new(mechanism: Box<SaslMechanism+Send>) -> SaslAuthenticator {
+        SaslAuthenticator {
+            mechanism: mechanism,
+            phase: Phase::Idle,
+            challenge: String::new(),
+            result: None,
+            account: None,
+            server_mechanisms: Vec::new(),
+        }
+    }
+
+    /// Authenticate with PLAIN.
+    pub fn plain(account: &str, password: &str) -> SaslAuthenticator {
+        SaslAuthenticator::new(Box::new(Plain::new(account, password)))
+    }
+
+    pub fn get_mechanism(&self) -> &str {
+        self.mechanism.name()
+    }
+
+    pub fn start(&mut self) -> Vec<IrcMsgBuf> {
+        self.phase = Phase::Exchanging;
+        match AuthenticateBuf::new(self.mechanism.name()) {
+            Ok(msg) => vec![msg.into_inner()],
+            Err(_) => {
+                self.finish(Err(SaslError::Aborted));
+                Vec::new()
+            },
+        }
+    }
+
+    pub fn on_irc_msg(&mut self, msg: &IrcMsg) -> Vec<IrcMsgBuf> {
+        if self.phase != Phase::Exchanging {
+            return Vec::new();
+        }
+        if let Ok(auth) = msg.as_tymsg::<&Authenticate>() {
+            return self.on_challenge_chunk(auth.get_payload());
+        }
+
+        let args: Vec<&[u8]> = msg.args().collect();
+        match msg.get_command() {
+            // RPL_LOGGEDIN
+            "900" if args.len() > 2 => {
+                self.account = Some(String::from_utf8_lossy(args[2]).into_owned());
+            },
+            // RPL_LOGGEDOUT
+            "901" => self.account = None,
+            // RPL_SASLSUCCESS
+            "903" => {
+                if self.mechanism.is_complete() {
+                    self.finish(Ok(()));
+                } else {
+                    self.finish(Err(SaslError::Unverified));
+                }
+            },
+            // RPL_SASLMECHS
+            "908" if args.len() > 1 => {
+                self.server_mechanisms = String::from_utf8_lossy(args[1])
+                    .split(',')
+                    .filter(|mech| !mech.is_empty())
+                    .map(|mech| mech.to_string())
+                    .collect();
+            },
+            command => {
+                if let Some(err) = SaslError::from_numeric(command) {
+                    self.finish(Err(err));
+                }
+            },
+        }
+        Vec::new()
+    }
+
+    fn on_challenge_chunk(&mut self, chunk: &[u8]) -> Vec<IrcMsgBuf> {
+        if chunk != b"+" {
+            self.challenge.push_str(&String::from_utf8_lossy(chunk));
+            if chunk.len() == AUTHENTICATE_CHUNK_LEN {
+                // More to come.
+                return Vec::new();
+            }
+        }
+
+        let encoded = ::std::mem::replace(&mut self.challenge, String::new());
+        let response = base64_decode(&encoded)
+            .map_err(|()| SaslError::InvalidChallenge)
+            .and_then(|challenge| self.mechanism.respond(&challenge));
+        match response {
+            Ok(response) => chunk_payload(&response).into_iter()
+                .map(AuthenticateBuf::into_inner)
+                .collect(),
+            Err(err) => {
+                self.finish(Err(err));
+                vec![AuthenticateBuf::abort().into_inner()]
+            },
+        }
+    }
+
+    fn finish(&mut self, result: Result<(), SaslError>) {
+        self.phase = Phase::Finished;
+        self.result = Some(result);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.phase == Phase::Finished
+    }
+
+    /// The outcome, once finished.
+    pub fn result(&self) -> Option<&Result<(), SaslError>> {
+        self.result.as_ref()
+    }
+
+    /// The account we are logged in as, as reported by RPL_LOGGEDIN.
+    pub fn account(&self) -> Option<&str> {
+        self.account.as_ref().map(|account| &account[..])
+    }
+
+    /// The mechanisms the server said it supports, if it said.
+    pub fn server_mechanisms(&self) -> &[String] {
+        &self.server_mechanisms
+    }
+}
+
+pub fn base64_encode(input: &[u8]) -> String {
+    BASE64_STANDARD.encode(input)
+}
+
+pub fn base64_decode(input: &str) -> Result<Vec<u8>, ()> {
+    BASE64_STANDARD.decode(input).map_err(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use ::{IrcMsg, IrcMsgBuf};
+    use super::super::{CapNegotiator, Capabilities, NegotiationVersion};
+    use super::super::ScramSha256;
+    use super::{base64_decode, base64_encode, chunk_payload, is_mechanism_offered};
+    use super::{External, SaslAuthenticator, SaslError, SaslMechanism};
+
+    fn lines(msgs: Vec<IrcMsgBuf>) -> Vec<Vec<u8>> {
+        msgs.into_iter().map(IrcMsgBuf::into_inner).collect()
+    }
+
+    fn msg(line: &[u8]) -> &IrcMsg {
+        IrcMsg::new(line).unwrap()
+    }
+
+    #[test]
+    fn base64_and_chunking() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64_decode("Zm8=").unwrap(), b"fo".to_vec());
+        assert_eq!(base64_decode("Zm9vYmFy").unwrap(), b"foobar".to_vec());
+        assert!(base64_decode("Zm8").is_err());
+        assert!(base64_decode("Zg==Zg==").is_err());
+        assert!(base64_decode("Z!==").is_err());
+
+        let chunks = chunk_payload(b"");
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].get_payload(), b"+");
+
+        // 300 bytes encode to exactly 400, so a terminator follows.
+        let chunks = chunk_payload(&[0; 300]);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].get_payload().len(), 400);
+        assert_eq!(chunks[1].get_payload(), b"+");
+
+        let chunks = chunk_payload(&[0; 301]);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[1].get_payload(), b"AA==");
+    }
+
+    #[test]
+    fn plain_during_negotiation() {
+        let mut negotiator = CapNegotiator::new(NegotiationVersion::V302, &["sasl"]);
+        negotiator.hold_end();
+        negotiator.start();
+        negotiator.on_irc_msg(msg(b":srv CAP * LS :sasl=PLAIN,EXTERNAL"));
+        assert!(is_mechanism_offered(negotiator.available(), "plain"));
+        assert!(negotiator.on_irc_msg(msg(b":srv CAP * ACK :sasl")).is_empty());
+        assert!(negotiator.is_settled() && negotiator.is_enabled("sasl"));
+
+        let mut auth = SaslAuthenticator::plain("jilles", "sesame");
+        assert_eq!(lines(auth.start()), vec![b"AUTHENTICATE PLAIN".to_vec()]);
+        assert_eq!(lines(auth.on_irc_msg(msg(b"AUTHENTICATE +"))),
+            vec![b"AUTHENTICATE AGppbGxlcwBzZXNhbWU=".to_vec()]);
+        auth.on_irc_msg(msg(b":srv 900 jilles jilles!u@h jilles :You are now logged in as jilles"));
+        assert!(!auth.is_finished());
+        auth.on_irc_msg(msg(b":srv 903 jilles :SASL authentication successful"));
+        assert_eq!(auth.result(), Some(&Ok(())));
+        assert_eq!(auth.account(), Some("jilles"));
+
+        assert_eq!(lines(negotiator.release_end()), vec![b"CAP END".to_vec()]);
+        assert!(negotiator.is_finished());
+    }
+
+    #[test]
+    fn plain_failure() {
+        let mut auth = SaslAuthenticator::plain("jilles", "wrong");
+        auth.start();
+        auth.on_irc_msg(msg(b"AUTHENTICATE +"));
+        auth.on_irc_msg(msg(b":srv 908 jilles PLAIN,EXTERNAL :are available SASL mechanisms"));
+        auth.on_irc_msg(msg(b":srv 904 jilles :SASL authentication failed"));
+        assert_eq!(auth.result(), Some(&Err(SaslError::Failed)));
+        assert_eq!(auth.server_mechanisms(), &["PLAIN".to_string(), "EXTERNAL".to_string()]);
+        assert!(auth.account().is_none());
+
+        let mut caps = Capabilities::new();
+        caps.insert_raw("sasl=EXTERNAL");
+        assert!(!is_mechanism_offered(&caps, "PLAIN"));
+    }
+
+    #[test]
+    fn external() {
+        let mut auth = SaslAuthenticator::new(Box::new(External::new()));
+        assert_eq!(lines(auth.start()), vec![b"AUTHENTICATE EXTERNAL".to_vec()]);
+        assert_eq!(lines(auth.on_irc_msg(msg(b"AUTHENTICATE +"))),
+            vec![b"AUTHENTICATE +".to_vec()]);
+        auth.on_irc_msg(msg(b":srv 903 me :SASL authentication successful"));
+        assert_eq!(auth.result(), Some(&Ok(())));
+    }
+
+    #[test]
+    fn scram_through_authenticate() {
+        let scram = ScramSha256::new("user", "pencil", "rOprNGfwEbeRWgbNEkqO");
+        let mut auth = SaslAuthenticator::new(Box::new(scram));
+        assert_eq!(lines(auth.start()), vec![b"AUTHENTICATE SCRAM-SHA-256".to_vec()]);
+        let first = auth.on_irc_msg(msg(b"AUTHENTICATE +"));
+        assert_eq!(base64_decode(::std::str::from_utf8(first[0].args().next().unwrap()).unwrap()).unwrap(),
+            b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO".to_vec());
+
+        let server_first = base64_encode(b"r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+            s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096");
+        let reply = auth.on_irc_msg(msg(format!("AUTHENTICATE {}", server_first).as_bytes()));
+        assert_eq!(reply.len(), 1);
+
+        let bad_final = base64_encode(b"v=AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=");
+        assert_eq!(lines(auth.on_irc_msg(msg(format!("AUTHENTICATE {}", bad_final).as_bytes()))),
+            vec![b"AUTHENTICATE *".to_vec()]);
+        assert_eq!(auth.result(), Some(&Err(SaslError::InvalidChallenge)));
+
+        // Success claimed without the server proving itself.
+        let scram = ScramSha256::new("user", "pencil", "rOprNGfwEbeRWgbNEkqO");
+        let mut auth = SaslAuthenticator::new(Box::new(scram));
+        auth.start();
+        auth.on_irc_msg(msg(b"AUTHENTICATE +"));
+        auth.on_irc_msg(msg(b":srv 903 user :SASL authentication successful"));
+        assert_eq!(auth.result(), Some(&Err(SaslError::Unverified)));
+
+        let scram = ScramSha256::new("user", "pencil", "rOprNGfwEbeRWgbNEkqO");
+        let mut auth = SaslAuthenticator::new(Box::new(scram));
+        auth.start();
+        auth.on_irc_msg(msg(b"AUTHENTICATE +"));
+        auth.on_irc_msg(msg(format!("AUTHENTICATE {}", server_first).as_bytes()));
+        let server_final = base64_encode(b"v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=");
+        assert_eq!(lines(auth.on_irc_msg(msg(format!("AUTHENTICATE {}", server_final).as_bytes()))),
+            vec![b"AUTHENTICATE +".to_vec()]);
+        auth.on_irc_msg(msg(b":srv 903 user :SASL authentication successful"));
+        assert_eq!(auth.result(), Some(&Ok(())));
+    }
+
+    struct Measure(usize);
+
+    impl SaslMechanism for Measure {
+        fn name(&self) -> &str {
+            "X-MEASURE"
+        }
+
+        fn respond(&mut self, challenge: &[u8]) -> Result<Vec<u8>, SaslError> {
+            self.0 = challenge.len();
+            Ok(format!("{}", challenge.len()).into_bytes())
+        }
+    }
+
+    #[test]
+    fn challenge_reassembly() {
+        let mut auth = SaslAuthenticator::new(Box::new(Measure(0)));
+        auth.start();
+        let encoded = base64_encode(&[7; 303]);
+        let (head, tail) = encoded.split_at(400);
+        assert!(auth.on_irc_msg(msg(format!("AUTHENTICATE {}", head).as_bytes())).is_empty());
+        assert_eq!(lines(auth.on_irc_msg(msg(format!("AUTHENTICATE {}", tail).as_bytes()))),
+            vec![b"AUTHENTICATE MzAz".to_vec()]);
+    }
+}
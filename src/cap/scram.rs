@@ -0,0 +1,187 @@
+//! SCRAM-SHA-256 (RFC 5802, RFC 7677).
+
+use std::fmt;
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use super::sasl::{SaslError, SaslMechanism, base64_decode, base64_encode};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Step {
+    ClientFirst,
+    ClientFinal,
+    Verify,
+    /// The server's signature checked out.
+    Done,
+    Failed,
+}
+
+/// The SCRAM-SHA-256 mechanism.  The server's signature is checked
+/// before authentication is allowed to complete.
+pub struct ScramSha256 {
+    username: String,
+    password: String,
+    client_nonce: String,
+    step: Step,
+    client_first_bare: String,
+    server_key: [u8; 32],
+    auth_message: String,
+}
+
+impl fmt::Debug for ScramSha256 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ScramSha256")
+            .field("username", &self.username)
+            .field("step", &self.step)
+            .finish()
+    }
+}
+
+impl ScramSha256 {
+    /// `client_nonce` must be unpredictable and printable, without
+    /// commas; 18 random bytes in base64 will do.
+    pub fn new(username: &str, password: &str, client_nonce: &str) -> ScramSha256 {
+        assert!(!client_nonce.is_empty());
+        assert!(client_nonce.bytes().all(|b| 0x21 <= b && b <= 0x7e && b != b','));
+        ScramSha256 {
+            username: username.to_string(),
+            password: password.to_string(),
+            client_nonce: client_nonce.to_string(),
+            step: Step::ClientFirst,
+            client_first_bare: String::new(),
+            server_key: [0; 32],
+            auth_message: String::new(),
+        }
+    }
+
+    fn client_final(&mut self, server_first: &[u8]) -> Result<Vec<u8>, SaslError> {
+        let server_first = try!(::std::str::from_utf8(server_first)
+            .map_err(|_| SaslError::InvalidChallenge));
+        let nonce = try!(attribute(server_first, 'r'));
+        let salt = try!(attribute(server_first, 's')
+            .and_then(|salt| base64_decode(salt).map_err(|()| SaslError::InvalidChallenge)));
+        let iterations: u32 = try!(attribute(server_first, 'i')
+            .and_then(|i| i.parse().map_err(|_| SaslError::InvalidChallenge)));
+        if !nonce.starts_with(&self.client_nonce[..]) || iterations == 0 {
+            return Err(SaslError::InvalidChallenge);
+        }
+
+        let mut salted_password = [0; 32];
+        ::pbkdf2::pbkdf2_hmac::<Sha256>(self.password.as_bytes(), &salt, iterations, &mut salted_password);
+        let client_key = hmac_sha256(&salted_password, b"Client Key");
+        let stored_key: [u8; 32] = Sha256::digest(&client_key).into();
+        let server_key = hmac_sha256(&salted_password, b"Server Key");
+
+        // `biws` is the base64 of the `n,,` GS2 header.
+        let without_proof = format!("c=biws,r={}", nonce);
+        let auth_message = format!("{},{},{}", self.client_first_bare, server_first, without_proof);
+        let client_signature = hmac_sha256(&stored_key, auth_message.as_bytes());
+        let mut proof = client_key;
+        for (p, s) in proof.iter_mut().zip(client_signature.iter()) {
+            *p ^= *s;
+        }
+        self.server_key = server_key;
+        self.auth_message = auth_message;
+
+        Ok(format!("{},p={}", without_proof, base64_encode(&proof)).into_bytes())
+    }
+
+    fn verify(&self, server_final: &[u8]) -> Result<Vec<u8>, SaslError> {
+        let server_final = try!(::std::str::from_utf8(server_final)
+            .map_err(|_| SaslError::InvalidChallenge));
+        let verifier = try!(attribute(server_final, 'v')
+            .and_then(|v| base64_decode(v).map_err(|()| SaslError::InvalidChallenge)));
+        // `verify_slice` compares in constant time.
+        let mut mac = hmac(&self.server_key);
+        mac.update(self.auth_message.as_bytes());
+        try!(mac.verify_slice(&verifier).map_err(|_| SaslError::InvalidChallenge));
+        Ok(Vec::new())
+    }
+}
+
+impl SaslMechanism for ScramSha256 {
+    fn name(&self) -> &str {
+        "SCRAM-SHA-256"
+    }
+
+    fn respond(&mut self, challenge: &[u8]) -> Result<Vec<u8>, SaslError> {
+        match self.step {
+            Step::ClientFirst => {
+                let username = self.username.replace('=', "=3D").replace(',', "=2C");
+                self.client_first_bare = format!("n={},r={}", username, self.client_nonce);
+                self.step = Step::ClientFinal;
+                Ok(format!("n,,{}", self.client_first_bare).into_bytes())
+            },
+            Step::ClientFinal => {
+                self.step = Step::Verify;
+                self.client_final(challenge)
+            },
+            Step::Verify => {
+                let result = self.verify(challenge);
+                self.step = if result.is_ok() { Step::Done } else { Step::Failed };
+                result
+            },
+            Step::Done | Step::Failed => Err(SaslError::InvalidChallenge),
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.step == Step::Done
+    }
+}
+
+/// The value of the `key=` attribute of a SCRAM message.  A server
+/// reporting an error with `e=` fails the exchange.
+fn attribute(message: &str, key: char) -> Result<&str, SaslError> {
+    if message.starts_with("e=") {
+        return Err(SaslError::Failed);
+    }
+    for attr in message.split(',') {
+        let mut chars = attr.chars();
+        if chars.next() == Some(key) && chars.next() == Some('=') {
+            return Ok(&attr[2..]);
+        }
+    }
+    Err(SaslError::InvalidChallenge)
+}
+
+fn hmac(key: &[u8]) -> Hmac<Sha256> {
+    Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length")
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = hmac(key);
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::sasl::SaslMechanism;
+    use super::ScramSha256;
+
+    // The example exchange from RFC 7677.
+    #[test]
+    fn scram_sha256_exchange() {
+        let mut scram = ScramSha256::new("user", "pencil", "rOprNGfwEbeRWgbNEkqO");
+        assert_eq!(scram.respond(b"").unwrap(), b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO".to_vec());
+
+        let server_first = b"r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+            s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        assert_eq!(&scram.respond(server_first).unwrap()[..],
+            &b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+            p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="[..]);
+
+        let mut forged = ScramSha256::new("user", "pencil", "rOprNGfwEbeRWgbNEkqO");
+        forged.respond(b"").unwrap();
+        forged.respond(server_first).unwrap();
+        assert!(forged.respond(b"v=AAAA").is_err());
+        assert!(!forged.is_complete());
+
+        assert!(!scram.is_complete());
+        assert_eq!(scram.respond(b"v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=").unwrap(),
+            Vec::<u8>::new());
+        assert!(scram.is_complete());
+    }
+}
@@ -0,0 +1,69 @@
+//! Decoding message text for display.
+//!
+//! IRC bodies are arbitrary bytes.  Most clients send UTF-8 today, but
+//! some networks still carry latin-1 or other legacy charsets.  `decode`
+//! borrows the text when it is valid UTF-8, and otherwise hands it to a
+//! `Decoder`, so nothing here panics on bad input.
+
+use std::borrow::Cow;
+
+/// Turns text which isn't valid UTF-8 into a `String`.
+pub trait Decoder {
+    fn decode_fallback(&self, bytes: &[u8]) -> String;
+}
+
+/// Replaces invalid sequences with U+FFFD.  This is the default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LossyUtf8;
+
+impl Decoder for LossyUtf8 {
+    fn decode_fallback(&self, bytes: &[u8]) -> String {
+        String::from_utf8_lossy(bytes).into_owned()
+    }
+}
+
+/// Reads each byte as an ISO 8859-1 character.
+#[cfg(feature = "latin1")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Latin1;
+
+#[cfg(feature = "latin1")]
+impl Decoder for Latin1 {
+    fn decode_fallback(&self, bytes: &[u8]) -> String {
+        bytes.iter().map(|&b| b as char).collect()
+    }
+}
+
+/// `bytes` as UTF-8 if it is valid, or as decoded by `fallback`.
+pub fn decode<'a, D: ?Sized>(bytes: &'a [u8], fallback: &D) -> Cow<'a, str>
+    where D: Decoder
+{
+    match ::std::str::from_utf8(bytes) {
+        Ok(text) => Cow::Borrowed(text),
+        Err(_) => Cow::Owned(fallback.decode_fallback(bytes)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use super::{decode, LossyUtf8};
+
+    #[test]
+    fn utf8_is_borrowed() {
+        match decode("caf\u{e9}".as_bytes(), &LossyUtf8) {
+            Cow::Borrowed(text) => assert_eq!(text, "caf\u{e9}"),
+            Cow::Owned(_) => panic!("valid UTF-8 was copied"),
+        }
+        assert_eq!(decode(b"caf\xe9", &LossyUtf8), "caf\u{fffd}");
+    }
+
+    #[cfg(feature = "latin1")]
+    #[test]
+    fn latin1_fallback() {
+        use super::Latin1;
+
+        assert_eq!(decode(b"caf\xe9", &Latin1), "caf\u{e9}");
+        assert_eq!(decode("caf\u{e9}".as_bytes(), &Latin1), "caf\u{e9}");
+    }
+}
@@ -0,0 +1,379 @@
+//! DCC offers, carried in `DCC` CTCP requests.
+//!
+//! `DCC SEND <file> <address> <port> [<size>]` offers a file and
+//! `DCC CHAT chat <address> <port>` a direct chat.  Addresses are usually
+//! an IPv4 address as one decimal integer, but IPv6 and dotted addresses
+//! are seen too.  A port of 0 with a trailing token is a passive (reverse)
+//! offer: the receiver is asked to listen instead.
+//!
+//! A receiver holding part of an offered file answers with
+//! `DCC RESUME <file> <port> <position>`, and the sender agrees with
+//! `DCC ACCEPT <file> <port> <position>` before the transfer starts.
+//! `DccResumer` drives the receiving side of that exchange.
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::str;
+
+use ::{client, MessageError};
+use super::CtcpRequest;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DccOffer {
+    Send {
+        /// The file name, without any surrounding quotes
+        filename: Vec<u8>,
+        address: IpAddr,
+        port: u16,
+        /// The file size in bytes, if given
+        size: Option<u64>,
+        /// The token of a passive offer
+        token: Option<Vec<u8>>,
+    },
+    Chat {
+        address: IpAddr,
+        port: u16,
+        /// The token of a passive offer
+        token: Option<Vec<u8>>,
+    },
+}
+
+impl DccOffer {
+    /// Parse the parameters of a `DCC` request, such as
+    /// `SEND "my file.txt" 3232235777 5000 1024`.
+    pub fn parse(params: &[u8]) -> Result<DccOffer, ()> {
+        let words = try!(split_words(params));
+        if words.len() < 4 {
+            return Err(());
+        }
+        let address = try!(decode_address(words[2]));
+        let port = try!(parse_number(words[3]));
+        match &words[0].to_ascii_uppercase()[..] {
+            b"SEND" => {
+                let size = match words.get(4) {
+                    Some(size) => Some(try!(parse_number(size))),
+                    None => None,
+                };
+                Ok(DccOffer::Send {
+                    filename: words[1].to_vec(),
+                    address: address,
+                    port: port,
+                    size: size,
+                    token: words.get(5).map(|token| token.to_vec()),
+                })
+            },
+            b"CHAT" => Ok(DccOffer::Chat {
+                address: address,
+                port: port,
+                token: words.get(4).map(|token| token.to_vec()),
+            }),
+            _ => Err(()),
+        }
+    }
+
+    /// The offer in a CTCP request, or `Err` if it isn't a DCC offer.
+    pub fn from_request(request: &CtcpRequest) -> Result<DccOffer, ()> {
+        match *request {
+            CtcpRequest::Dcc(params) => DccOffer::parse(params),
+            _ => Err(()),
+        }
+    }
+
+    pub fn get_address(&self) -> IpAddr {
+        match *self {
+            DccOffer::Send { address, .. } | DccOffer::Chat { address, .. } => address,
+        }
+    }
+
+    pub fn get_port(&self) -> u16 {
+        match *self {
+            DccOffer::Send { port, .. } | DccOffer::Chat { port, .. } => port,
+        }
+    }
+
+    /// Whether the sender asks us to listen, rather than connect to it.
+    pub fn is_passive(&self) -> bool {
+        match *self {
+            DccOffer::Send { ref token, .. } | DccOffer::Chat { ref token, .. } =>
+                self.get_port() == 0 && token.is_some(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResumeKind {
+    /// The receiver asks to continue from a position
+    Resume,
+    /// The sender agrees to continue from a position
+    Accept,
+}
+
+impl ResumeKind {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ResumeKind::Resume => "RESUME",
+            ResumeKind::Accept => "ACCEPT",
+        }
+    }
+}
+
+/// A `DCC RESUME` or `DCC ACCEPT`.  The transfer is identified by its
+/// port, or by its token for a passive offer; the file name is often
+/// mangled by clients and shouldn't be relied upon.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DccResume {
+    pub kind: ResumeKind,
+    pub filename: Vec<u8>,
+    pub port: u16,
+    /// The byte offset to continue from
+    pub position: u64,
+    pub token: Option<Vec<u8>>,
+}
+
+impl DccResume {
+    /// Parse the parameters of a `DCC` request, such as
+    /// `RESUME file.bin 5000 1024`.
+    pub fn parse(params: &[u8]) -> Result<DccResume, ()> {
+        let words = try!(split_words(params));
+        if words.len() < 4 {
+            return Err(());
+        }
+        let kind = match &words[0].to_ascii_uppercase()[..] {
+            b"RESUME" => ResumeKind::Resume,
+            b"ACCEPT" => ResumeKind::Accept,
+            _ => return Err(()),
+        };
+        Ok(DccResume {
+            kind: kind,
+            filename: words[1].to_vec(),
+            port: try!(parse_number(words[2])),
+            position: try!(parse_number(words[3])),
+            token: words.get(4).map(|token| token.to_vec()),
+        })
+    }
+
+    pub fn from_request(request: &CtcpRequest) -> Result<DccResume, ()> {
+        match *request {
+            CtcpRequest::Dcc(params) => DccResume::parse(params),
+            _ => Err(()),
+        }
+    }
+
+    /// Whether this refers to the transfer offered by `offer`.
+    pub fn matches(&self, offer: &DccOffer) -> bool {
+        match *offer {
+            DccOffer::Send { port, ref token, .. } => {
+                if offer.is_passive() {
+                    token.is_some() && *token == self.token
+                } else {
+                    port == self.port
+                }
+            },
+            DccOffer::Chat { .. } => false,
+        }
+    }
+
+    /// The `DCC ACCEPT` a sender replies to this `DCC RESUME` with.
+    pub fn accept(&self) -> DccResume {
+        DccResume { kind: ResumeKind::Accept, .. self.clone() }
+    }
+
+    pub fn to_params(&self) -> Vec<u8> {
+        let mut params = Vec::new();
+        params.extend(self.kind.as_str().as_bytes());
+        params.push(b' ');
+        if self.filename.contains(&b' ') {
+            params.push(b'"');
+            params.extend(&self.filename);
+            params.push(b'"');
+        } else {
+            params.extend(&self.filename);
+        }
+        params.extend(format!(" {} {}", self.port, self.position).as_bytes());
+        if let Some(ref token) = self.token {
+            params.push(b' ');
+            params.extend(token);
+        }
+        params
+    }
+
+    /// The PRIVMSG carrying this to `target`.  Both RESUME and ACCEPT
+    /// are sent as requests.
+    pub fn to_privmsg<T>(&self, target: T) -> Result<client::PrivmsgBuf, MessageError>
+        where T: AsRef<[u8]>
+    {
+        CtcpRequest::Dcc(&self.to_params()).to_privmsg(target)
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResumeState {
+    /// `request` hasn't been called yet
+    Pending,
+    /// We sent a `DCC RESUME` and are waiting for the `DCC ACCEPT`
+    Requested,
+    /// The sender accepted; connect and continue from this position
+    Accepted(u64),
+}
+
+/// The receiving side of resuming a `DCC SEND`.
+#[derive(Clone, Debug)]
+pub struct DccResumer {
+    offer: DccOffer,
+    position: u64,
+    state: ResumeState,
+}
+
+impl DccResumer {
+    /// Resume `offer` from `position`, usually the length of the partial
+    /// file on disk.  Fails unless `offer` is a `DCC SEND` with data
+    /// beyond `position`.
+    pub fn new(offer: DccOffer, position: u64) -> Result<DccResumer, ()> {
+        match offer {
+            DccOffer::Send { size: Some(size), .. } if position >= size => return Err(()),
+            DccOffer::Send { .. } => (),
+            DccOffer::Chat { .. } => return Err(()),
+        }
+        Ok(DccResumer {
+            offer: offer,
+            position: position,
+            state: ResumeState::Pending,
+        })
+    }
+
+    pub fn get_state(&self) -> ResumeState {
+        self.state
+    }
+
+    /// The `DCC RESUME` to send to the offering `peer`.
+    pub fn request<T>(&mut self, peer: T) -> Result<client::PrivmsgBuf, MessageError>
+        where T: AsRef<[u8]>
+    {
+        let (filename, port, token) = match self.offer {
+            DccOffer::Send { ref filename, port, ref token, .. } => (filename, port, token),
+            DccOffer::Chat { .. } => unreachable!(),
+        };
+        let resume = DccResume {
+            kind: ResumeKind::Resume,
+            filename: filename.clone(),
+            port: port,
+            position: self.position,
+            token: token.clone(),
+        };
+        let msg = try!(resume.to_privmsg(peer));
+        self.state = ResumeState::Requested;
+        Ok(msg)
+    }
+
+    /// Feed a CTCP request from the peer.  Returns true if it was the
+    /// `DCC ACCEPT` for this transfer.
+    pub fn on_request(&mut self, request: &CtcpRequest) -> bool {
+        if self.state != ResumeState::Requested {
+            return false;
+        }
+        match DccResume::from_request(request) {
+            Ok(ref accept) if accept.kind == ResumeKind::Accept && accept.matches(&self.offer) => {
+                self.state = ResumeState::Accepted(accept.position);
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Decode a DCC address: an IPv4 address as a decimal integer, or a
+/// literal IPv4 or IPv6 address.
+pub fn decode_address(address: &[u8]) -> Result<IpAddr, ()> {
+    let address = try!(str::from_utf8(address).map_err(|_| ()));
+    if !address.is_empty() && address.bytes().all(|b| b'0' <= b && b <= b'9') {
+        let long: u32 = try!(address.parse().map_err(|_| ()));
+        return Ok(IpAddr::V4(Ipv4Addr::from(long)));
+    }
+    address.parse().map_err(|_| ())
+}
+
+fn parse_number<T: str::FromStr>(value: &[u8]) -> Result<T, ()> {
+    str::from_utf8(value).ok()
+        .and_then(|value| value.parse().ok())
+        .ok_or(())
+}
+
+/// Split on spaces, keeping a double-quoted word (a file name with
+/// spaces) together.
+fn split_words(params: &[u8]) -> Result<Vec<&[u8]>, ()> {
+    let mut words = Vec::new();
+    let mut rest = params;
+    loop {
+        while rest.first() == Some(&b' ') {
+            rest = &rest[1..];
+        }
+        if rest.is_empty() {
+            return Ok(words);
+        }
+        let (word, after) = if rest[0] == b'"' {
+            let close = try!(rest[1..].iter().position(|&b| b == b'"').ok_or(()));
+            (&rest[1..close + 1], &rest[close + 2..])
+        } else {
+            let end = rest.iter().position(|&b| b == b' ').unwrap_or(rest.len());
+            (&rest[..end], &rest[end..])
+        };
+        words.push(word);
+        rest = after;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use super::super::CtcpRequest;
+    use super::{DccOffer, DccResume, DccResumer, ResumeKind, ResumeState, decode_address};
+
+    #[test]
+    fn dcc_offers() {
+        let request = CtcpRequest::from_body(b"\x01DCC SEND \"my file.txt\" 3232235777 5000 1024\x01").unwrap();
+        assert_eq!(DccOffer::from_request(&request), Ok(DccOffer::Send {
+            filename: b"my file.txt".to_vec(),
+            address: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)),
+            port: 5000,
+            size: Some(1024),
+            token: None,
+        }));
+
+        let offer = DccOffer::parse(b"CHAT chat 2001:db8::1 0 42").unwrap();
+        assert_eq!(offer.get_address(), "2001:db8::1".parse::<IpAddr>().unwrap());
+        assert!(offer.is_passive());
+
+        let offer = DccOffer::parse(b"send file.bin 10.0.0.1 4000").unwrap();
+        assert_eq!(offer.get_port(), 4000);
+        assert!(!offer.is_passive());
+
+        assert_eq!(decode_address(b"16909060"), Ok(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))));
+        assert!(decode_address(b"4294967296").is_err());
+        assert!(DccOffer::parse(b"SEND \"unterminated 1 2").is_err());
+        assert!(DccOffer::parse(b"SEND file.bin 1 99999").is_err());
+        assert!(DccOffer::from_request(&CtcpRequest::Version).is_err());
+    }
+
+    #[test]
+    fn dcc_resume() {
+        let offer = DccOffer::parse(b"SEND file.bin 3232235777 5000 4096").unwrap();
+        assert!(DccResumer::new(offer.clone(), 4096).is_err());
+
+        let mut resumer = DccResumer::new(offer, 1024).unwrap();
+        assert_eq!(resumer.get_state(), ResumeState::Pending);
+        let request = resumer.request(b"alice").unwrap();
+        assert_eq!(request.as_bytes(), &b"PRIVMSG alice :\x01DCC RESUME file.bin 5000 1024\x01"[..]);
+        assert_eq!(resumer.get_state(), ResumeState::Requested);
+
+        // The sender's side of the exchange.
+        let body = &request.as_bytes()[b"PRIVMSG alice :".len()..];
+        let resume = DccResume::from_request(&CtcpRequest::from_body(body).unwrap()).unwrap();
+        assert_eq!(resume.kind, ResumeKind::Resume);
+        let accept = resume.accept().to_privmsg(b"bob").unwrap();
+        let accept_body = &accept.as_bytes()[b"PRIVMSG bob :".len()..];
+
+        assert!(!resumer.on_request(&CtcpRequest::from_body(b"\x01DCC ACCEPT file.bin 5001 1024\x01").unwrap()));
+        assert!(resumer.on_request(&CtcpRequest::from_body(accept_body).unwrap()));
+        assert_eq!(resumer.get_state(), ResumeState::Accepted(1024));
+    }
+}
@@ -0,0 +1,241 @@
+//! CTCP requests and responses.
+//!
+//! A CTCP message is a PRIVMSG (request) or NOTICE (response) whose body
+//! is wrapped in `\x01`, such as `\x01VERSION\x01`.  The command is the
+//! first word and the rest of the body are its parameters.  Servers and
+//! clients commonly drop the closing `\x01`, so it is optional here.
+
+use ::{client, server, MessageError};
+
+mod dcc;
+pub use self::dcc::{
+    DccOffer, DccResume, DccResumer, ResumeKind, ResumeState,
+    decode_address,
+};
+
+/// The byte which delimits a CTCP body.
+pub const DELIM: u8 = 0x01;
+
+/// Whether `body` is CTCP-quoted.
+pub fn is_ctcp(body: &[u8]) -> bool {
+    body.first() == Some(&DELIM)
+}
+
+/// Split a CTCP-quoted body into its command and parameters.
+pub fn parse(body: &[u8]) -> Option<(&[u8], &[u8])> {
+    if !is_ctcp(body) {
+        return None;
+    }
+    let mut inner = &body[1..];
+    if inner.last() == Some(&DELIM) {
+        inner = &inner[..inner.len() - 1];
+    }
+    if inner.is_empty() || inner.contains(&DELIM) {
+        return None;
+    }
+    Some(match inner.iter().position(|&b| b == b' ') {
+        Some(idx) => (&inner[..idx], &inner[idx + 1..]),
+        None => (inner, &b""[..]),
+    })
+}
+
+/// Quote a command and parameters into a CTCP body.  Fails if either
+/// contains the delimiter, or the command is empty or contains a space.
+pub fn quote(command: &[u8], params: &[u8]) -> Result<Vec<u8>, MessageError> {
+    if command.is_empty() || command.contains(&b' ') {
+        return Err(MessageError::BadArgument);
+    }
+    if command.contains(&DELIM) || params.contains(&DELIM) {
+        return Err(MessageError::BadArgument);
+    }
+    let mut body = Vec::with_capacity(command.len() + params.len() + 3);
+    body.push(DELIM);
+    body.extend(command);
+    if !params.is_empty() {
+        body.push(b' ');
+        body.extend(params);
+    }
+    body.push(DELIM);
+    Ok(body)
+}
+
+/// A CTCP request, carried in a PRIVMSG.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CtcpRequest<'a> {
+    /// `/me` text
+    Action(&'a [u8]),
+    Version,
+    /// An opaque token, to be echoed back
+    Ping(&'a [u8]),
+    Time,
+    Clientinfo,
+    /// A DCC offer, such as `SEND file 3232235777 5000 1024`
+    Dcc(&'a [u8]),
+    /// Any other command, with its parameters
+    Other(&'a [u8], &'a [u8]),
+}
+
+impl<'a> CtcpRequest<'a> {
+    pub fn from_body(body: &'a [u8]) -> Option<CtcpRequest<'a>> {
+        let (command, params) = match parse(body) {
+            Some(parsed) => parsed,
+            None => return None,
+        };
+        Some(match &command.to_ascii_uppercase()[..] {
+            b"ACTION" => CtcpRequest::Action(params),
+            b"VERSION" => CtcpRequest::Version,
+            b"PING" => CtcpRequest::Ping(params),
+            b"TIME" => CtcpRequest::Time,
+            b"CLIENTINFO" => CtcpRequest::Clientinfo,
+            b"DCC" => CtcpRequest::Dcc(params),
+            _ => CtcpRequest::Other(command, params),
+        })
+    }
+
+    pub fn from_privmsg(msg: &'a server::Privmsg) -> Option<CtcpRequest<'a>> {
+        CtcpRequest::from_body(msg.get_body_raw())
+    }
+
+    pub fn get_command(&self) -> &'a [u8] {
+        match *self {
+            CtcpRequest::Action(_) => b"ACTION",
+            CtcpRequest::Version => b"VERSION",
+            CtcpRequest::Ping(_) => b"PING",
+            CtcpRequest::Time => b"TIME",
+            CtcpRequest::Clientinfo => b"CLIENTINFO",
+            CtcpRequest::Dcc(_) => b"DCC",
+            CtcpRequest::Other(command, _) => command,
+        }
+    }
+
+    pub fn get_params(&self) -> &'a [u8] {
+        match *self {
+            CtcpRequest::Action(params) |
+            CtcpRequest::Ping(params) |
+            CtcpRequest::Dcc(params) |
+            CtcpRequest::Other(_, params) => params,
+            CtcpRequest::Version |
+            CtcpRequest::Time |
+            CtcpRequest::Clientinfo => b"",
+        }
+    }
+
+    pub fn to_body(&self) -> Result<Vec<u8>, MessageError> {
+        quote(self.get_command(), self.get_params())
+    }
+
+    pub fn to_privmsg<T>(&self, target: T) -> Result<client::PrivmsgBuf, MessageError>
+        where T: AsRef<[u8]>
+    {
+        client::PrivmsgBuf::new(target, try!(self.to_body()))
+    }
+}
+
+/// A CTCP response, carried in a NOTICE.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CtcpResponse<'a> {
+    /// The client name and version
+    Version(&'a [u8]),
+    /// The token from the request
+    Ping(&'a [u8]),
+    /// The local time, in a human-readable format
+    Time(&'a [u8]),
+    /// The supported CTCP commands, space-separated
+    Clientinfo(&'a [u8]),
+    /// Any other command, with its parameters
+    Other(&'a [u8], &'a [u8]),
+}
+
+impl<'a> CtcpResponse<'a> {
+    pub fn from_body(body: &'a [u8]) -> Option<CtcpResponse<'a>> {
+        let (command, params) = match parse(body) {
+            Some(parsed) => parsed,
+            None => return None,
+        };
+        Some(match &command.to_ascii_uppercase()[..] {
+            b"VERSION" => CtcpResponse::Version(params),
+            b"PING" => CtcpResponse::Ping(params),
+            b"TIME" => CtcpResponse::Time(params),
+            b"CLIENTINFO" => CtcpResponse::Clientinfo(params),
+            _ => CtcpResponse::Other(command, params),
+        })
+    }
+
+    pub fn from_notice(msg: &'a server::Notice) -> Option<CtcpResponse<'a>> {
+        CtcpResponse::from_body(msg.get_body_raw())
+    }
+
+    pub fn get_command(&self) -> &'a [u8] {
+        match *self {
+            CtcpResponse::Version(_) => b"VERSION",
+            CtcpResponse::Ping(_) => b"PING",
+            CtcpResponse::Time(_) => b"TIME",
+            CtcpResponse::Clientinfo(_) => b"CLIENTINFO",
+            CtcpResponse::Other(command, _) => command,
+        }
+    }
+
+    pub fn get_params(&self) -> &'a [u8] {
+        match *self {
+            CtcpResponse::Version(params) |
+            CtcpResponse::Ping(params) |
+            CtcpResponse::Time(params) |
+            CtcpResponse::Clientinfo(params) |
+            CtcpResponse::Other(_, params) => params,
+        }
+    }
+
+    pub fn to_body(&self) -> Result<Vec<u8>, MessageError> {
+        quote(self.get_command(), self.get_params())
+    }
+
+    pub fn to_notice<T>(&self, target: T) -> Result<client::NoticeBuf, MessageError>
+        where T: AsRef<[u8]>
+    {
+        client::NoticeBuf::new(target, try!(self.to_body()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::{IrcMsg, MessageError};
+    use ::server;
+    use super::{CtcpRequest, CtcpResponse, parse, quote};
+
+    #[test]
+    fn parse_ctcp() {
+        assert_eq!(parse(b"\x01VERSION\x01"), Some((&b"VERSION"[..], &b""[..])));
+        assert_eq!(parse(b"\x01ACTION waves"), Some((&b"ACTION"[..], &b"waves"[..])));
+        assert_eq!(parse(b"hello"), None);
+        assert_eq!(parse(b"\x01\x01"), None);
+
+        assert_eq!(CtcpRequest::from_body(b"\x01action waves hello\x01"),
+            Some(CtcpRequest::Action(b"waves hello")));
+        assert_eq!(CtcpRequest::from_body(b"\x01FINGER\x01"),
+            Some(CtcpRequest::Other(b"FINGER", b"")));
+        assert_eq!(CtcpResponse::from_body(b"\x01PING 1234\x01"),
+            Some(CtcpResponse::Ping(b"1234")));
+
+        let msg = IrcMsg::new(b":n!u@h PRIVMSG bot :\x01DCC CHAT chat 3232235777 5000\x01").unwrap();
+        let privmsg = msg.as_tymsg::<&server::Privmsg>().unwrap();
+        assert_eq!(CtcpRequest::from_privmsg(privmsg),
+            Some(CtcpRequest::Dcc(b"CHAT chat 3232235777 5000")));
+    }
+
+    #[test]
+    fn construct_ctcp() {
+        let msg = CtcpRequest::Action(b"waves").to_privmsg(b"#rust").unwrap();
+        assert_eq!(msg.as_bytes(), b"PRIVMSG #rust :\x01ACTION waves\x01");
+
+        let msg = CtcpResponse::Version(b"rust-irc 0.1").to_notice(b"alice").unwrap();
+        assert_eq!(msg.as_bytes(), b"NOTICE alice :\x01VERSION rust-irc 0.1\x01");
+        let notice = IrcMsg::new(b":bot!u@h NOTICE alice :\x01VERSION rust-irc 0.1\x01").unwrap();
+        assert_eq!(CtcpResponse::from_notice(notice.as_tymsg::<&server::Notice>().unwrap()),
+            Some(CtcpResponse::Version(b"rust-irc 0.1")));
+
+        assert_eq!(quote(b"TIME", b""), Ok(b"\x01TIME\x01".to_vec()));
+        assert_eq!(quote(b"PING", b"a\x01b"), Err(MessageError::BadArgument));
+        assert_eq!(CtcpRequest::Ping(b"a\r\nb").to_privmsg(b"alice").err(),
+            Some(MessageError::ForbiddenByte));
+    }
+}
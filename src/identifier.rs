@@ -1,11 +1,14 @@
 use std::cmp::PartialEq;
 use std::default::Default;
+use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::str::{from_utf8, Utf8Error};
 
 use irccase::CaseMapping;
 
 // nickname   =  ( letter / special ) *8( letter / digit / special / "-" )
+//
+// The length limit is left to the server, which advertises it as NICKLEN.
 // special    =  %x5B-60 / %x7B-7D ; "[", "]", "\", "`", "_", "^", "{", "|", "}"
 // letter     =  %x41-5A / %x61-7A       ; A-Z / a-z
 // digit      =  %x30-39                 ; 0-9
@@ -30,7 +33,9 @@ static DIGIT: &'static [u8] = &[b'0', b'1', b'2', b'3', b'4', b'5', b'6', b'7',
 #[derive(Clone, Eq, Debug)]
 pub struct Channel<CM: CaseMapping>(CM, Vec<u8>);
 
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum ChannelError {
+    Empty,
     InvalidByte(usize),
 }
 
@@ -43,8 +48,12 @@ fn channel_is_valid_byte(target: u8) -> bool {
     }
 }
 
+/// Check `buf` is a valid channel name without allocating.
 #[inline]
-fn channel_validate_buf(buf: &[u8]) -> Result<(), ChannelError> {
+pub fn channel_validate_buf(buf: &[u8]) -> Result<(), ChannelError> {
+    if buf.is_empty() {
+        return Err(ChannelError::Empty);
+    }
     for (idx, &byte) in buf.iter().enumerate() {
         if !channel_is_valid_byte(byte) {
             return Err(ChannelError::InvalidByte(idx));
@@ -66,6 +75,33 @@ impl<CM: CaseMapping> Channel<CM> {
             Err(err) => Err(err),
         }
     }
+
+    /// As `from_bytes`, comparing under `case_mapping` rather than the
+    /// default for `CM`.  Useful with `AnyCaseMapping`.
+    pub fn with_case_mapping<Q>(case_mapping: CM, name: &Q) -> Result<Channel<CM>, ChannelError>
+        where Q: AsRef<[u8]>+?Sized
+    {
+        try!(channel_validate_buf(name.as_ref()));
+        Ok(Channel(case_mapping, name.as_ref().to_vec()))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.1
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.1
+    }
+
+    pub fn get_case_mapping(&self) -> &CM {
+        &self.0
+    }
+}
+
+impl<CM: CaseMapping> AsRef<[u8]> for Channel<CM> {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
 }
 
 impl<CM: CaseMapping> PartialEq for Channel<CM> {
@@ -97,7 +133,9 @@ impl<CM: CaseMapping> Hash for Channel<CM> {
 #[derive(Clone, Eq, Debug)]
 pub struct Nickname<CM: CaseMapping>(CM, Vec<u8>);
 
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum NicknameError {
+    Empty,
     InvalidByte(usize),
     Utf8Error(Utf8Error),
 }
@@ -119,7 +157,7 @@ fn nickname_is_valid_byte(target: u8) -> bool {
             return true;
         }
     }
-    return false;
+    target == b'-'
 }
 
 #[inline]
@@ -138,7 +176,11 @@ fn nickname_is_valid_first_byte(target: u8) -> bool {
 }
 
 #[inline]
-fn nickname_validate_buf(buf: &[u8]) -> Result<(), NicknameError> {
+/// Check `buf` is a valid nickname without allocating.
+pub fn nickname_validate_buf(buf: &[u8]) -> Result<(), NicknameError> {
+    if buf.is_empty() {
+        return Err(NicknameError::Empty);
+    }
     if let Err(err) = from_utf8(buf) {
         return Err(NicknameError::Utf8Error(err));
     }
@@ -170,6 +212,39 @@ impl<CM: CaseMapping> Nickname<CM> {
         }
     }
 
+    /// As `from_bytes`, comparing under `case_mapping` rather than the
+    /// default for `CM`.  Useful with `AnyCaseMapping`.
+    pub fn with_case_mapping<Q>(case_mapping: CM, name: &Q) -> Result<Nickname<CM>, NicknameError>
+        where Q: AsRef<[u8]>+?Sized
+    {
+        try!(nickname_validate_buf(name.as_ref()));
+        Ok(Nickname(case_mapping, name.as_ref().to_vec()))
+    }
+
+    /// The nick component of a prefix such as `:nick!user@host`.  The
+    /// leading `:` is optional.
+    pub fn from_prefix<Q: AsRef<[u8]>+?Sized>(prefix: &Q) -> Result<Nickname<CM>, NicknameError> {
+        let mut prefix = prefix.as_ref();
+        if prefix.starts_with(b":") {
+            prefix = &prefix[1..];
+        }
+        let end = prefix.iter().position(|&b| b == b'!' || b == b'@').unwrap_or(prefix.len());
+        Nickname::from_bytes(&prefix[..end])
+    }
+
+    /// A prefix naming this nick, as `nick!user@host`.
+    pub fn to_prefix(&self, user: &str, host: &str) -> String {
+        format!("{}!{}@{}", self.as_str(), user, host)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.1
+    }
+
+    pub fn get_case_mapping(&self) -> &CM {
+        &self.0
+    }
+
     pub fn as_str(&self) -> &str {
         let Nickname(_, ref data) = *self;
         match from_utf8(data.as_ref()) {
@@ -196,6 +271,18 @@ impl<CM: CaseMapping> Nickname<CM> {
     }
 }
 
+impl<CM: CaseMapping> AsRef<[u8]> for Nickname<CM> {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl<CM: CaseMapping> fmt::Display for Nickname<CM> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 impl<CM: CaseMapping> PartialEq for Nickname<CM> {
     #[inline]
     fn eq(&self, other: &Nickname<CM>) -> bool {
@@ -220,3 +307,49 @@ impl<CM: CaseMapping> Hash for Nickname<CM> {
         case_mapping.hash_ignore_case(data, state);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use irccase::{AnyCaseMapping, AsciiCaseMapping, Rfc1459CaseMapping};
+    use super::{Channel, ChannelError, Nickname, NicknameError};
+
+    #[test]
+    fn nickname_grammar() {
+        assert!(Nickname::<Rfc1459CaseMapping>::from_str("[away]-2").is_ok());
+        assert_eq!(Nickname::<Rfc1459CaseMapping>::from_str("-dash").unwrap_err(),
+            NicknameError::InvalidByte(0));
+        assert_eq!(Nickname::<Rfc1459CaseMapping>::from_str("sp ace").unwrap_err(),
+            NicknameError::InvalidByte(2));
+        assert_eq!(Nickname::<Rfc1459CaseMapping>::from_str("").unwrap_err(),
+            NicknameError::Empty);
+        assert_eq!(Channel::<Rfc1459CaseMapping>::from_str("#a,b").unwrap_err(),
+            ChannelError::InvalidByte(2));
+    }
+
+    #[test]
+    fn nickname_comparisons() {
+        let nick = Nickname::<Rfc1459CaseMapping>::from_prefix(":Nick[m]!user@host").unwrap();
+        assert_eq!(nick.as_str(), "Nick[m]");
+        assert_eq!(nick.as_bytes(), b"Nick[m]");
+        assert_eq!(nick.to_prefix("user", "host"), "Nick[m]!user@host");
+        assert_eq!(nick, Nickname::from_str("nick{M}").unwrap());
+
+        let mut seen = HashSet::new();
+        seen.insert(nick.clone());
+        assert!(seen.contains(&Nickname::from_str("NICK{m}").unwrap()));
+
+        let ascii = Nickname::<AsciiCaseMapping>::from_str("Nick[m]").unwrap();
+        assert!(ascii != Nickname::from_str("nick{m}").unwrap());
+
+        let msg = ::IrcMsg::new(b":Nick{m}!u@h PRIVMSG #Chan :hi").unwrap();
+        let privmsg = msg.as_tymsg::<&::server::Privmsg>().unwrap();
+        assert_eq!(privmsg.get_source_nickname::<Rfc1459CaseMapping>().unwrap(), nick);
+        assert_eq!(privmsg.get_target_channel::<Rfc1459CaseMapping>().unwrap(),
+            Channel::from_str("#chan").unwrap());
+
+        let any = Nickname::with_case_mapping(AnyCaseMapping::Ascii, "a[").unwrap();
+        assert!(any != Nickname::with_case_mapping(AnyCaseMapping::Ascii, "A{").unwrap());
+        assert!(any != Nickname::with_case_mapping(AnyCaseMapping::Rfc1459, "a[").unwrap());
+    }
+}
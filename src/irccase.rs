@@ -180,6 +180,41 @@ impl OwnedIrcAsciiExt for Vec<u8> {
     }
 }
 
+/// Match `target` against a wildcard `mask`, as used for bans and other
+/// hostmask lists: `*` matches any run of bytes and `?` any single byte.
+/// Comparison is case-insensitive under RFC 1459 case mapping.
+pub fn mask_matches(mask: &[u8], target: &[u8]) -> bool {
+    mask_matches_in(&Rfc1459CaseMapping, mask, target)
+}
+
+/// Like `mask_matches`, comparing under `case_mapping` instead.
+pub fn mask_matches_in<CM: ?Sized>(case_mapping: &CM, mask: &[u8], target: &[u8]) -> bool
+    where CM: OSCaseMapping
+{
+    let lower_map = case_mapping.get_lower_map();
+    let (mut m, mut t) = (0, 0);
+    // Where to resume after the most recent `*`: (mask pos, target pos)
+    let mut backtrack = None;
+
+    while t < target.len() {
+        if m < mask.len() && mask[m] == b'*' {
+            m += 1;
+            backtrack = Some((m, t));
+        } else if m < mask.len() && (mask[m] == b'?' ||
+                lower_map[mask[m] as usize] == lower_map[target[t] as usize]) {
+            m += 1;
+            t += 1;
+        } else if let Some((bm, bt)) = backtrack {
+            m = bm;
+            t = bt + 1;
+            backtrack = Some((bm, bt + 1));
+        } else {
+            return false;
+        }
+    }
+    mask[m..].iter().all(|&b| b == b'*')
+}
+
 impl IrcAsciiExt<String> for str {
     #[inline]
     fn to_irc_lower(&self) -> String {
@@ -217,7 +252,7 @@ fn test_old_basics() {
     assert_eq!("^".to_string().into_irc_lower(), "~");
 }
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct AsciiCaseMapping;
 
 impl Default for AsciiCaseMapping {
@@ -234,7 +269,7 @@ impl OSCaseMapping for AsciiCaseMapping {
 impl CaseMapping for AsciiCaseMapping {}
 
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct Rfc1459CaseMapping;
 
 impl Default for Rfc1459CaseMapping {
@@ -250,7 +285,7 @@ impl OSCaseMapping for Rfc1459CaseMapping {
 
 impl CaseMapping for Rfc1459CaseMapping {}
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct StrictRfc1459CaseMapping;
 
 impl Default for StrictRfc1459CaseMapping {
@@ -266,6 +301,66 @@ impl OSCaseMapping for StrictRfc1459CaseMapping {
 
 impl CaseMapping for StrictRfc1459CaseMapping {}
 
+/// A case mapping chosen at runtime, usually from the server's
+/// `CASEMAPPING` ISUPPORT token.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum AnyCaseMapping {
+    Ascii,
+    Rfc1459,
+    StrictRfc1459,
+}
+
+impl AnyCaseMapping {
+    /// The mapping named by a `CASEMAPPING` value, or `None` for one
+    /// this crate doesn't implement.
+    pub fn from_isupport_token(token: &str) -> Option<AnyCaseMapping> {
+        match token {
+            "ascii" => Some(AnyCaseMapping::Ascii),
+            "rfc1459" => Some(AnyCaseMapping::Rfc1459),
+            "strict-rfc1459" => Some(AnyCaseMapping::StrictRfc1459),
+            _ => None,
+        }
+    }
+
+    /// The `CASEMAPPING` value naming this mapping.
+    pub fn as_isupport_token(&self) -> &'static str {
+        match *self {
+            AnyCaseMapping::Ascii => "ascii",
+            AnyCaseMapping::Rfc1459 => "rfc1459",
+            AnyCaseMapping::StrictRfc1459 => "strict-rfc1459",
+        }
+    }
+
+    /// The mapping as a trait object, for callers which hold one behind
+    /// a `Box<OSCaseMapping>`.
+    pub fn as_os_case_mapping(&self) -> &'static (OSCaseMapping+Sync) {
+        match *self {
+            AnyCaseMapping::Ascii => &AsciiCaseMapping,
+            AnyCaseMapping::Rfc1459 => &Rfc1459CaseMapping,
+            AnyCaseMapping::StrictRfc1459 => &StrictRfc1459CaseMapping,
+        }
+    }
+}
+
+impl Default for AnyCaseMapping {
+    /// RFC 1459, which servers are assumed to use until they say otherwise.
+    fn default() -> AnyCaseMapping { AnyCaseMapping::Rfc1459 }
+}
+
+impl OSCaseMapping for AnyCaseMapping {
+    #[inline]
+    fn get_lower_map(&self) -> &[u8] {
+        match *self {
+            AnyCaseMapping::Ascii => &ASCII_LOWER_MAP,
+            AnyCaseMapping::Rfc1459 => &RFC1459_LOWER_MAP,
+            AnyCaseMapping::StrictRfc1459 => &STRICT_RFC1459_LOWER_MAP,
+        }
+    }
+}
+
+impl CaseMapping for AnyCaseMapping {}
+
 
 // Object-safe case mapping
 pub trait OSCaseMapping {
@@ -341,3 +436,17 @@ fn test_basics() {
         b"a{}|^z".to_vec());
 }
 
+
+#[test]
+fn test_any_case_mapping() {
+    let ascii = AnyCaseMapping::from_isupport_token("ascii").unwrap();
+    assert!(!ascii.eq_ignore_case("[", "{"));
+    assert_eq!(ascii.as_isupport_token(), "ascii");
+    assert_eq!(ascii.as_os_case_mapping().get_lower_map()[b'[' as usize], b'[');
+
+    let strict = AnyCaseMapping::from_isupport_token("strict-rfc1459").unwrap();
+    assert_eq!(strict.to_irc_lower("A[]\\^Z"), b"a{}|^z".to_vec());
+
+    assert_eq!(AnyCaseMapping::default(), AnyCaseMapping::Rfc1459);
+    assert_eq!(AnyCaseMapping::from_isupport_token("rfc7613"), None);
+}
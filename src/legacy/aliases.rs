@@ -0,0 +1,109 @@
+//! Mapping vendor commands onto standard ones.
+//!
+//! Some servers and bouncers send non-standard names for messages the
+//! crate already understands.  A `CommandAliases` table rewrites the
+//! command before classification, so the existing typed handlers,
+//! bundlers and `State` see the standard form.
+
+use std::collections::HashMap;
+
+use ::parse_helpers;
+use ::IrcMsgBuf;
+use ::IrcMsg as IrcMsgNew;
+
+use super::IrcMsg;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CommandAliases {
+    /// Upper-cased alias to replacement.
+    aliases: HashMap<String, String>,
+}
+
+impl CommandAliases {
+    pub fn new() -> CommandAliases {
+        CommandAliases { aliases: HashMap::new() }
+    }
+
+    /// Treat `alias` as `command`.  Aliases match case-insensitively.
+    /// Returns `Err` if `command` isn't a valid command name.
+    pub fn add(&mut self, alias: &str, command: &str) -> Result<(), ()> {
+        let valid = !command.is_empty()
+            && command.bytes().all(|b| b.is_ascii_alphanumeric());
+        if !valid {
+            return Err(());
+        }
+        self.aliases.insert(alias.to_ascii_uppercase(), command.to_string());
+        Ok(())
+    }
+
+    pub fn remove(&mut self, alias: &str) -> Option<String> {
+        self.aliases.remove(&alias.to_ascii_uppercase())
+    }
+
+    /// The command `alias` stands for, if it is one.
+    pub fn get(&self, alias: &str) -> Option<&str> {
+        self.aliases.get(&alias.to_ascii_uppercase()).map(|command| &command[..])
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.aliases.is_empty()
+    }
+
+    /// A copy of `msg` with its command replaced, or `None` when its
+    /// command is not an alias.
+    pub fn normalize(&self, msg: &IrcMsg) -> Option<IrcMsg> {
+        self.get(msg.get_command())
+            .and_then(|command| replace_command(msg.as_bytes(), command))
+            .and_then(|data| IrcMsg::new(data).ok())
+    }
+
+    /// As `normalize`, for messages from the new parser.
+    pub fn normalize_msg(&self, msg: &IrcMsgNew) -> Option<IrcMsgBuf> {
+        self.get(msg.get_command())
+            .and_then(|command| replace_command(msg.as_bytes(), command))
+            .and_then(|data| IrcMsgBuf::new(data).ok())
+    }
+}
+
+fn replace_command(data: &[u8], command: &str) -> Option<Vec<u8>> {
+    let (_prefix, rest) = parse_helpers::split_prefix(data);
+    let (old_command, _rest) = parse_helpers::split_command(rest);
+    if old_command.is_empty() {
+        return None;
+    }
+
+    let start = old_command.as_ptr() as usize - data.as_ptr() as usize;
+    let end = start + old_command.len();
+    let mut out = Vec::with_capacity(data.len() + command.len());
+    out.extend(&data[..start]);
+    out.extend(command.as_bytes());
+    out.extend(&data[end..]);
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use ::IrcMsg as IrcMsgNew;
+    use super::super::IrcMsg;
+    use super::CommandAliases;
+
+    #[test]
+    fn normalize_aliases() {
+        let mut aliases = CommandAliases::new();
+        aliases.add("x-wallops", "WALLOPS").unwrap();
+        assert!(aliases.add("oops", "NOT A COMMAND").is_err());
+
+        let msg = IrcMsg::new(b"@time=x :srv X-WALLOPS :hello".to_vec()).unwrap();
+        let normal = aliases.normalize(&msg).unwrap();
+        assert_eq!(normal.as_bytes(), b"@time=x :srv WALLOPS :hello");
+        assert_eq!(normal.get_command(), "WALLOPS");
+
+        let msg = IrcMsgNew::new(b":srv X-Wallops :hello").unwrap();
+        assert_eq!(aliases.normalize_msg(msg).unwrap().as_bytes(), b":srv WALLOPS :hello");
+
+        let msg = IrcMsg::new(b":srv PRIVMSG #c :hello".to_vec()).unwrap();
+        assert!(aliases.normalize(&msg).is_none());
+        assert_eq!(aliases.remove("X-WALLOPS"), Some("WALLOPS".to_string()));
+        assert!(aliases.is_empty());
+    }
+}
@@ -0,0 +1,111 @@
+//! Noticing a server's CASEMAPPING.
+//!
+//! Nicks and channel names are compared under the server's case mapping.
+//! Servers advertise it in RPL_ISUPPORT, and anything keyed on a folded
+//! name must follow, including back to `rfc1459` after something else.
+
+use std::error::Error;
+use std::fmt;
+
+use irccase::AnyCaseMapping;
+use super::IrcMsg;
+
+/// The server advertised a CASEMAPPING.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CaseMappingChange {
+    /// The advertised value, such as `ascii`
+    pub token: String,
+}
+
+impl CaseMappingChange {
+    /// Recognize an RPL_ISUPPORT line carrying a CASEMAPPING token.
+    pub fn from_msg(msg: &IrcMsg) -> Option<CaseMappingChange> {
+        if msg.get_command() != "005" {
+            return None;
+        }
+        CaseMappingChange::from_isupport_args(msg.get_args().into_iter())
+    }
+
+    /// Like `from_msg`, for a message from the zero-copy parser.
+    pub fn from_irc_msg(msg: &::IrcMsg) -> Option<CaseMappingChange> {
+        if msg.get_command() != "005" {
+            return None;
+        }
+        CaseMappingChange::from_isupport_args(msg.args())
+    }
+
+    fn from_isupport_args<'a, I>(args: I) -> Option<CaseMappingChange>
+        where I: Iterator<Item=&'a [u8]>
+    {
+        // Skip our nick; the trailing human-readable text has no `=`.
+        for arg in args.skip(1) {
+            if !arg.starts_with(b"CASEMAPPING=") {
+                continue;
+            }
+            let token = String::from_utf8_lossy(&arg[b"CASEMAPPING=".len()..]);
+            return Some(CaseMappingChange { token: token.into_owned() });
+        }
+        None
+    }
+
+    /// The new case mapping, or an error if the crate can't fold names
+    /// that way.
+    pub fn get_case_mapping(&self) -> Result<AnyCaseMapping, UnsupportedCaseMapping> {
+        AnyCaseMapping::from_isupport_token(&self.token)
+            .ok_or_else(|| UnsupportedCaseMapping { token: self.token.clone() })
+    }
+}
+
+impl fmt::Display for CaseMappingChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CASEMAPPING={}", self.token)
+    }
+}
+
+/// A CASEMAPPING the crate doesn't implement, such as `rfc7613`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnsupportedCaseMapping {
+    pub token: String,
+}
+
+impl fmt::Display for UnsupportedCaseMapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unsupported CASEMAPPING={}: names may be folded incorrectly", self.token)
+    }
+}
+
+impl Error for UnsupportedCaseMapping {
+    fn description(&self) -> &str {
+        "unsupported CASEMAPPING"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use irccase::AnyCaseMapping;
+    use super::super::IrcMsg;
+    use super::CaseMappingChange;
+
+    fn change(line: &[u8]) -> Option<CaseMappingChange> {
+        let change = CaseMappingChange::from_msg(&IrcMsg::new(line.to_vec()).unwrap());
+        assert_eq!(CaseMappingChange::from_irc_msg(::IrcMsg::new(line).unwrap()), change);
+        change
+    }
+
+    #[test]
+    fn case_mapping_changes() {
+        let ascii = change(b":srv 005 me NICKLEN=30 CASEMAPPING=ascii :are supported").unwrap();
+        assert_eq!(ascii.get_case_mapping(), Ok(AnyCaseMapping::Ascii));
+
+        let unknown = change(b":srv 005 me CASEMAPPING=rfc7613 :are supported").unwrap();
+        let err = unknown.get_case_mapping().unwrap_err();
+        assert_eq!(err.token, "rfc7613");
+        assert!(err.to_string().contains("rfc7613"));
+
+        let rfc1459 = change(b":srv 005 me CASEMAPPING=rfc1459 :are supported").unwrap();
+        assert_eq!(rfc1459.get_case_mapping(), Ok(AnyCaseMapping::Rfc1459));
+        assert!(change(b":srv 005 me NICKLEN=30 :are supported").is_none());
+        assert!(change(b":srv 001 me :CASEMAPPING=ascii").is_none());
+    }
+}
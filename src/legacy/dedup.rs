@@ -0,0 +1,97 @@
+//! Dropping messages seen twice.
+//!
+//! When a bouncer reattaches it replays recent history, which can
+//! overlap with what the client already received.  With IRCv3 message
+//! ids the overlap is easy to spot: `MsgIdCache` remembers the last
+//! `capacity` msgids seen, forgetting the least recently seen first.
+
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Clone, Debug)]
+pub struct MsgIdCache {
+    capacity: usize,
+    /// When each msgid was last seen, counted in observations
+    seen: HashMap<String, u64>,
+    /// `seen` inverted, so the least recently seen comes first
+    order: BTreeMap<u64, String>,
+    counter: u64,
+}
+
+impl MsgIdCache {
+    pub fn new(capacity: usize) -> MsgIdCache {
+        MsgIdCache {
+            capacity: capacity,
+            seen: HashMap::new(),
+            order: BTreeMap::new(),
+            counter: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+
+    pub fn contains(&self, msgid: &str) -> bool {
+        self.seen.contains_key(msgid)
+    }
+
+    /// Record `msgid` as seen.  Returns true if it already was, in which
+    /// case the message carrying it is a duplicate.
+    pub fn observe(&mut self, msgid: &str) -> bool {
+        self.counter += 1;
+        if let Some(last_seen) = self.seen.get_mut(msgid) {
+            let id = self.order.remove(last_seen).unwrap();
+            *last_seen = self.counter;
+            self.order.insert(self.counter, id);
+            return true;
+        }
+        if self.capacity == 0 {
+            return false;
+        }
+        while self.capacity <= self.seen.len() {
+            let oldest = *self.order.keys().next().unwrap();
+            let id = self.order.remove(&oldest).unwrap();
+            self.seen.remove(&id);
+        }
+        self.seen.insert(msgid.to_string(), self.counter);
+        self.order.insert(self.counter, msgid.to_string());
+        false
+    }
+
+    pub fn clear(&mut self) {
+        self.seen.clear();
+        self.order.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MsgIdCache;
+
+    #[test]
+    fn forgets_least_recently_seen() {
+        let mut cache = MsgIdCache::new(2);
+        assert!(!cache.observe("a"));
+        assert!(!cache.observe("b"));
+        assert!(cache.observe("a"));
+
+        // `b` is now the least recently seen.
+        assert!(!cache.observe("c"));
+        assert!(!cache.contains("b"));
+        assert!(cache.observe("a"));
+        assert_eq!(cache.len(), 2);
+
+        let mut none = MsgIdCache::new(0);
+        assert!(!none.observe("a"));
+        assert!(!none.observe("a"));
+        assert!(none.is_empty());
+    }
+}
@@ -1,8 +1,11 @@
-use super::{IrcMsg, JoinResult, WhoResult};
+use std::ops::BitOr;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use super::{IrcMsg, JoinResult, NamesResult, ListEntry, ListResult, WhoResult, WhoisResult, ServerNotice, StandardReply, CaseMappingChange};
 
 
 /// An event, which is usually generated by reading a line from the server.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum IrcEvent {
     /// An IRC message from the server
     IrcMsg(IrcMsg),
@@ -11,5 +14,197 @@ pub enum IrcEvent {
     /// The bundled result of a JOIN command
     JoinBundle(JoinResult),
     /// The bundled result of a WHO command
-    WhoBundle(WhoResult)
+    WhoBundle(WhoResult),
+    /// The bundled result of a NAMES command, outside of a JOIN
+    NamesBundle(NamesResult),
+    /// A single channel from a streamed LIST reply
+    ListEntry(ListEntry),
+    /// The bundled result of a LIST command
+    ListBundle(ListResult),
+    /// The bundled result of a WHOIS command
+    WhoisBundle(WhoisResult),
+    /// A NOTICE from the server itself.  Follows the `IrcMsg` event
+    /// for the same message.
+    ServerNotice(ServerNotice),
+    /// A `FAIL`, `WARN` or `NOTE`.  Follows the `IrcMsg` event for the
+    /// same message.
+    StandardReply(StandardReply),
+    /// RPL_ISUPPORT advertised a CASEMAPPING.
+    /// Follows the `IrcMsg` event for the same message.
+    CaseMappingChange(CaseMappingChange),
+    /// A line from the server which couldn't be parsed, without its
+    /// line ending
+    ParseFailure(Vec<u8>),
+}
+
+impl IrcEvent {
+    pub fn kind(&self) -> EventKind {
+        match *self {
+            IrcEvent::IrcMsg(ref msg) => EventKind::from_command(msg.get_command()),
+            IrcEvent::JoinBundle(_) => EventKind::JoinBundle,
+            IrcEvent::WhoBundle(_) => EventKind::WhoBundle,
+            IrcEvent::NamesBundle(_) => EventKind::NamesBundle,
+            IrcEvent::ListEntry(_) => EventKind::ListEntry,
+            IrcEvent::ListBundle(_) => EventKind::ListBundle,
+            IrcEvent::WhoisBundle(_) => EventKind::WhoisBundle,
+            IrcEvent::ServerNotice(_) => EventKind::ServerNotice,
+            IrcEvent::StandardReply(_) => EventKind::StandardReply,
+            IrcEvent::CaseMappingChange(_) => EventKind::CaseMappingChange,
+            IrcEvent::ParseFailure(_) => EventKind::ParseFailure,
+        }
+    }
+}
+
+/// A coarse classification of events, for subscribing to a subset.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    Privmsg,
+    Notice,
+    Join,
+    Part,
+    Quit,
+    Kick,
+    Nick,
+    Mode,
+    Topic,
+    Ping,
+    /// Any other message, including numerics
+    OtherMsg,
+    JoinBundle,
+    WhoBundle,
+    NamesBundle,
+    ListEntry,
+    ListBundle,
+    WhoisBundle,
+    ServerNotice,
+    StandardReply,
+    CaseMappingChange,
+    ParseFailure,
+}
+
+impl EventKind {
+    fn from_command(command: &str) -> EventKind {
+        match &command.to_ascii_uppercase()[..] {
+            "PRIVMSG" => EventKind::Privmsg,
+            "NOTICE" => EventKind::Notice,
+            "JOIN" => EventKind::Join,
+            "PART" => EventKind::Part,
+            "QUIT" => EventKind::Quit,
+            "KICK" => EventKind::Kick,
+            "NICK" => EventKind::Nick,
+            "MODE" => EventKind::Mode,
+            "TOPIC" => EventKind::Topic,
+            "PING" => EventKind::Ping,
+            _ => EventKind::OtherMsg,
+        }
+    }
+
+    fn bit(self) -> u32 {
+        1 << (self as u32)
+    }
+}
+
+/// A set of `EventKind`s, usually built with `|`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct EventKinds(u32);
+
+impl EventKinds {
+    pub fn empty() -> EventKinds {
+        EventKinds(0)
+    }
+
+    pub fn all() -> EventKinds {
+        EventKinds(!0)
+    }
+
+    pub fn contains(&self, kind: EventKind) -> bool {
+        self.0 & kind.bit() != 0
+    }
+}
+
+impl From<EventKind> for EventKinds {
+    fn from(kind: EventKind) -> EventKinds {
+        EventKinds(kind.bit())
+    }
+}
+
+impl BitOr for EventKind {
+    type Output = EventKinds;
+
+    fn bitor(self, rhs: EventKind) -> EventKinds {
+        EventKinds(self.bit() | rhs.bit())
+    }
+}
+
+impl BitOr<EventKind> for EventKinds {
+    type Output = EventKinds;
+
+    fn bitor(self, rhs: EventKind) -> EventKinds {
+        EventKinds(self.0 | rhs.bit())
+    }
+}
+
+impl BitOr for EventKinds {
+    type Output = EventKinds;
+
+    fn bitor(self, rhs: EventKinds) -> EventKinds {
+        EventKinds(self.0 | rhs.0)
+    }
+}
+
+/// Fans events out to subscribers, each with its own queue holding only
+/// the kinds it asked for.  Events are cloned once per interested
+/// subscriber and not at all otherwise.
+#[derive(Default)]
+pub struct EventDispatcher {
+    subscribers: Vec<(EventKinds, Sender<IrcEvent>)>,
+}
+
+impl EventDispatcher {
+    pub fn new() -> EventDispatcher {
+        EventDispatcher { subscribers: Vec::new() }
+    }
+
+    /// Subscribe to the events of the given kinds.  The subscription
+    /// ends when the receiver is dropped.
+    pub fn filter<K: Into<EventKinds>>(&mut self, kinds: K) -> Receiver<IrcEvent> {
+        let (tx, rx) = channel();
+        self.subscribers.push((kinds.into(), tx));
+        rx
+    }
+
+    pub fn dispatch(&mut self, event: &IrcEvent) {
+        let kind = event.kind();
+        self.subscribers.retain(|&(ref kinds, ref tx)| {
+            !kinds.contains(kind) || tx.send(event.clone()).is_ok()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::IrcMsg;
+    use super::{IrcEvent, EventKind, EventDispatcher};
+
+    fn msg(line: &[u8]) -> IrcEvent {
+        IrcEvent::IrcMsg(IrcMsg::new(line.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn subscribers_only_see_their_kinds() {
+        let mut events = EventDispatcher::new();
+        let chat = events.filter(EventKind::Privmsg | EventKind::Notice);
+        let pings = events.filter(EventKind::Ping);
+        drop(events.filter(EventKind::Privmsg));
+
+        events.dispatch(&msg(b":a!b@c PRIVMSG #chan :hello"));
+        events.dispatch(&msg(b"PING :server"));
+        events.dispatch(&msg(b"ping :server"));
+        events.dispatch(&msg(b":server 372 me :motd"));
+
+        assert_eq!(chat.try_iter().count(), 1);
+        assert_eq!(pings.try_iter().map(|e| e.kind()).collect::<Vec<_>>(),
+            vec![EventKind::Ping, EventKind::Ping]);
+        assert_eq!(events.subscribers.len(), 2);
+    }
 }
@@ -0,0 +1,172 @@
+//! Ignoring users by hostmask.
+//!
+//! An `IgnoreList` holds wildcard masks such as `*!*@spam.example`.
+//! Installed in a `BundlerManager`, it drops PRIVMSGs and NOTICEs, CTCPs
+//! included, whose source matches a mask before anything else sees them.
+//! Masks are compared under the server's case mapping, and may be given
+//! an expiry time.
+
+use std::time::Instant;
+
+use irccase::{mask_matches_in, AnyCaseMapping, CaseMapping};
+use super::IrcMsg;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IgnoreEntry {
+    /// A `nick!user@host` mask, with `*` and `?` wildcards
+    pub mask: String,
+    /// When the entry stops applying, or `None` to keep it for good
+    pub expires_at: Option<Instant>,
+}
+
+impl IgnoreEntry {
+    fn is_live(&self, now: Instant) -> bool {
+        self.expires_at.map(|at| now < at).unwrap_or(true)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct IgnoreList {
+    case_mapping: AnyCaseMapping,
+    entries: Vec<IgnoreEntry>,
+}
+
+impl IgnoreList {
+    pub fn new() -> IgnoreList {
+        IgnoreList::default()
+    }
+
+    pub fn get_case_mapping(&self) -> AnyCaseMapping {
+        self.case_mapping
+    }
+
+    /// Compare masks under `case_mapping`.  A `BundlerManager` does this
+    /// on its own when the server advertises CASEMAPPING.
+    pub fn set_case_mapping(&mut self, case_mapping: AnyCaseMapping) {
+        self.case_mapping = case_mapping;
+    }
+
+    /// Ignore sources matching `mask` until `expires_at`, or for good
+    /// with `None`.  Adding a mask already present replaces its expiry.
+    pub fn add(&mut self, mask: &str, expires_at: Option<Instant>) {
+        let entry = IgnoreEntry {
+            mask: mask.to_string(),
+            expires_at: expires_at,
+        };
+        match self.position(mask) {
+            Some(idx) => self.entries[idx] = entry,
+            None => self.entries.push(entry),
+        }
+    }
+
+    /// Stop ignoring `mask`.  Returns false if it wasn't in the list.
+    pub fn remove(&mut self, mask: &str) -> bool {
+        match self.position(mask) {
+            Some(idx) => {
+                self.entries.remove(idx);
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn position(&self, mask: &str) -> Option<usize> {
+        let case_mapping = self.case_mapping;
+        self.entries.iter().position(|entry| case_mapping.eq_ignore_case(&entry.mask[..], mask))
+    }
+
+    /// The entries in the order they were added, possibly including some
+    /// which have expired but not yet been removed by `expire`.
+    pub fn entries(&self) -> &[IgnoreEntry] {
+        &self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Remove the entries which have expired by `now`.
+    pub fn expire(&mut self, now: Instant) {
+        self.entries.retain(|entry| entry.is_live(now));
+    }
+
+    /// Whether `source`, a `nick!user@host` prefix, matches an entry
+    /// which hasn't expired by `now`.
+    pub fn is_ignored(&self, source: &[u8], now: Instant) -> bool {
+        let case_mapping = self.case_mapping;
+        self.entries.iter().any(|entry| {
+            entry.is_live(now) && mask_matches_in(&case_mapping, entry.mask.as_bytes(), source)
+        })
+    }
+
+    /// Whether `msg` is a PRIVMSG or NOTICE from an ignored source.
+    pub fn should_drop(&self, msg: &IrcMsg, now: Instant) -> bool {
+        match msg.get_command() {
+            "PRIVMSG" | "NOTICE" => (),
+            _ => return false,
+        }
+        msg.has_prefix() && self.is_ignored(msg.get_prefix_raw(), now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use irccase::AnyCaseMapping;
+    use super::super::{BundlerManager, IrcMsg};
+    use super::IgnoreList;
+
+    fn msg(line: &[u8]) -> IrcMsg {
+        IrcMsg::new(line.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn ignore_by_mask() {
+        let now = Instant::now();
+        let mut ignore = IgnoreList::new();
+        ignore.add("*!*@spam.example", None);
+        ignore.add("troll[a]!*@*", Some(now + Duration::from_secs(60)));
+
+        assert!(ignore.should_drop(&msg(b":bot!x@spam.example PRIVMSG #c :buy"), now));
+        assert!(ignore.should_drop(&msg(b":bot!x@SPAM.example NOTICE me :\x01VERSION\x01"), now));
+        assert!(!ignore.should_drop(&msg(b":bot!x@spam.example JOIN #c"), now));
+        assert!(!ignore.should_drop(&msg(b":friend!x@ok.example PRIVMSG #c :hi"), now));
+
+        // RFC 1459 folds `[` and `{` together; ASCII doesn't.
+        let troll = msg(b":TROLL{A}!u@h PRIVMSG #c :hi");
+        assert!(ignore.should_drop(&troll, now));
+        ignore.set_case_mapping(AnyCaseMapping::Ascii);
+        assert!(!ignore.should_drop(&troll, now));
+        ignore.set_case_mapping(AnyCaseMapping::Rfc1459);
+
+        let later = now + Duration::from_secs(61);
+        assert!(!ignore.should_drop(&troll, later));
+        ignore.expire(later);
+        assert_eq!(ignore.entries().len(), 1);
+
+        assert!(ignore.remove("*!*@SPAM.EXAMPLE"));
+        assert!(!ignore.remove("*!*@spam.example"));
+        assert!(ignore.is_empty());
+    }
+
+    #[test]
+    fn bundler_manager_drops_ignored() {
+        let now = Instant::now();
+        let mut manager = BundlerManager::new();
+        manager.get_ignore_list_mut().add("spammer!*@*", None);
+        manager.get_ignore_list_mut().add("pest!*@*", Some(now + Duration::from_secs(60)));
+
+        assert!(manager.on_irc_msg(&msg(b":spammer!u@h PRIVMSG #c :buy"), now).is_empty());
+        assert_eq!(manager.on_irc_msg(&msg(b":spammer!u@h JOIN #c"), now).len(), 1);
+        assert_eq!(manager.on_irc_msg(&msg(b":friend!u@h PRIVMSG #c :hi"), now).len(), 1);
+
+        let pest = msg(b":pest!u@h PRIVMSG #c :hi");
+        assert!(manager.on_irc_msg(&pest, now).is_empty());
+        assert_eq!(manager.on_irc_msg(&pest, now + Duration::from_secs(61)).len(), 1);
+        assert_eq!(manager.get_ignore_list().entries().len(), 1);
+
+        manager.on_irc_msg(&msg(b":srv 005 me CASEMAPPING=ascii :are supported"), now);
+        assert_eq!(manager.get_ignore_list().get_case_mapping(), AnyCaseMapping::Ascii);
+    }
+}
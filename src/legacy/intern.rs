@@ -0,0 +1,303 @@
+//! Interning of nicks and channel names.
+//!
+//! A busy connection sees the same few thousand identifiers over and
+//! over.  An `Interner` keeps a single copy of each, handing out
+//! `Symbol`s which are cheap to clone, hash and compare.  Clones of an
+//! `Interner` share one table, so the session, its `BundlerManager` and
+//! a `State` can all intern into the same one.
+//!
+//! Interning is by exact bytes; case folding is left to `State`.
+//!
+//! Entries no `Symbol` refers to any more are dropped lazily: once the
+//! table has taken in as many new identifiers since its last sweep as
+//! half the entries it holds, the next `intern` sweeps it first.  The
+//! cost of a sweep is so spread over the insertions which made it
+//! worthwhile, and nicks seen once in a WHO reply don't pile up even in
+//! a table no `State` uses.  `Interner::purge` sweeps at once.
+//!
+//! With the `serde` feature a table serializes its identifiers in
+//! order, so that symbols deserialized alongside it compare equal to
+//! the table's own, though they no longer share storage with it and
+//! so don't keep its entries from being purged.
+
+use std::cmp::max;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+/// An interned identifier.  Derefs to its bytes.
+#[derive(Clone)]
+pub struct Symbol {
+    id: u32,
+    bytes: Arc<[u8]>,
+}
+
+impl Symbol {
+    /// The identifier's index in its `Interner`.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl Deref for Symbol {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Symbol) -> bool {
+        // Symbols from the same table share storage, so the byte
+        // comparison is only reached for symbols from different tables.
+        self.id == other.id
+            && (Arc::ptr_eq(&self.bytes, &other.bytes) || self.bytes == other.bytes)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Symbol({}, {:?})", self.id, String::from_utf8_lossy(&self.bytes))
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.bytes))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl ::serde::Serialize for Symbol {
+    fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ::serde::Serialize::serialize(&(self.id, &self.bytes[..]), serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> ::serde::Deserialize<'de> for Symbol {
+    fn deserialize<D: ::serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (id, bytes): (u32, Vec<u8>) = try!(::serde::Deserialize::deserialize(deserializer));
+        Ok(Symbol { id: id, bytes: Arc::from(bytes) })
+    }
+}
+
+/// The fewest insertions between two sweeps, so that small tables
+/// aren't swept all the time.
+const MIN_SWEEP_INTERVAL: usize = 256;
+
+#[derive(Debug, Default)]
+struct InternTable {
+    ids: HashMap<Arc<[u8]>, u32>,
+    /// Indexed by id; `None` for purged entries.
+    symbols: Vec<Option<Arc<[u8]>>>,
+    /// Ids of purged entries, for reuse.
+    free: Vec<u32>,
+    /// Insertions since the last sweep
+    inserted: usize,
+}
+
+impl InternTable {
+    fn symbol(&self, id: u32) -> Option<Symbol> {
+        match self.symbols.get(id as usize) {
+            Some(&Some(ref bytes)) => Some(Symbol { id: id, bytes: bytes.clone() }),
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, bytes: &[u8]) -> Symbol {
+        let stored: Arc<[u8]> = Arc::from(bytes);
+        let id = match self.free.pop() {
+            Some(id) => {
+                self.symbols[id as usize] = Some(stored.clone());
+                id
+            },
+            None => {
+                self.symbols.push(Some(stored.clone()));
+                (self.symbols.len() - 1) as u32
+            },
+        };
+        self.ids.insert(stored.clone(), id);
+        self.inserted += 1;
+        Symbol { id: id, bytes: stored }
+    }
+
+    fn is_sweep_due(&self) -> bool {
+        self.inserted >= max(MIN_SWEEP_INTERVAL, self.ids.len() / 2)
+    }
+
+    fn purge(&mut self) -> usize {
+        self.inserted = 0;
+        let mut purged = 0;
+        for (id, slot) in self.symbols.iter_mut().enumerate() {
+            // One reference from `symbols` and one from `ids`.
+            let unused = match *slot {
+                Some(ref bytes) => Arc::strong_count(bytes) == 2,
+                None => false,
+            };
+            if unused {
+                let bytes = slot.take().unwrap();
+                self.ids.remove(&bytes);
+                self.free.push(id as u32);
+                purged += 1;
+            }
+        }
+        purged
+    }
+}
+
+/// A shared table of interned identifiers.
+#[derive(Clone, Debug, Default)]
+pub struct Interner {
+    table: Arc<Mutex<InternTable>>,
+}
+
+impl Interner {
+    pub fn new() -> Interner {
+        Interner::default()
+    }
+
+    /// The symbol for `bytes`, allocating only the first time they are
+    /// seen.
+    pub fn intern(&self, bytes: &[u8]) -> Symbol {
+        let mut table = self.table.lock().unwrap();
+        if let Some(&id) = table.ids.get(bytes) {
+            return table.symbol(id).unwrap();
+        }
+        if table.is_sweep_due() {
+            table.purge();
+        }
+        table.insert(bytes)
+    }
+
+    /// The symbol for `bytes`, if they have been interned.
+    pub fn get(&self, bytes: &[u8]) -> Option<Symbol> {
+        let table = self.table.lock().unwrap();
+        table.ids.get(bytes).and_then(|&id| table.symbol(id))
+    }
+
+    /// The symbol with index `id`, if there is one.
+    pub fn resolve(&self, id: u32) -> Option<Symbol> {
+        self.table.lock().unwrap().symbol(id)
+    }
+
+    /// Drop the entries no `Symbol` refers to any more, returning how
+    /// many were dropped.  Their ids are handed out again to later
+    /// identifiers.
+    pub fn purge(&self) -> usize {
+        self.table.lock().unwrap().purge()
+    }
+
+    pub fn len(&self) -> usize {
+        self.table.lock().unwrap().ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(feature = "serde")]
+impl ::serde::Serialize for Interner {
+    fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let table = self.table.lock().unwrap();
+        serializer.collect_seq(table.symbols.iter().map(|slot| slot.as_ref().map(|bytes| &bytes[..])))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> ::serde::Deserialize<'de> for Interner {
+    fn deserialize<D: ::serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let symbols: Vec<Option<Vec<u8>>> = try!(::serde::Deserialize::deserialize(deserializer));
+        let mut table = InternTable::default();
+        for (id, slot) in symbols.into_iter().enumerate() {
+            match slot {
+                Some(bytes) => {
+                    let stored: Arc<[u8]> = Arc::from(bytes);
+                    table.ids.insert(stored.clone(), id as u32);
+                    table.symbols.push(Some(stored));
+                },
+                None => {
+                    table.symbols.push(None);
+                    table.free.push(id as u32);
+                },
+            }
+        }
+        Ok(Interner { table: Arc::new(Mutex::new(table)) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Interner;
+
+    #[test]
+    fn interned_once() {
+        let interner = Interner::new();
+        let rust = interner.intern(b"#rust");
+        let again = interner.clone().intern(b"#rust");
+        let other = interner.intern(b"#Rust");
+
+        assert_eq!(rust, again);
+        assert!(rust != other);
+        assert_eq!(&rust[..], b"#rust");
+        assert_eq!(interner.len(), 2);
+
+        assert_eq!(interner.get(b"#Rust"), Some(other.clone()));
+        assert_eq!(interner.get(b"#go"), None);
+        assert_eq!(interner.resolve(other.id()), Some(other));
+        assert_eq!(interner.resolve(2), None);
+
+        // Separate tables may give the same index to different names.
+        let elsewhere = Interner::new().intern(b"#go");
+        assert_eq!(elsewhere.id(), rust.id());
+        assert!(elsewhere != rust);
+    }
+
+    #[test]
+    fn purges_unused() {
+        let interner = Interner::new();
+        let rust = interner.intern(b"#rust");
+        let go = interner.intern(b"#go");
+        let go_id = go.id();
+        drop(go);
+
+        assert_eq!(interner.purge(), 1);
+        assert_eq!(interner.len(), 1);
+        assert_eq!(interner.get(b"#go"), None);
+        assert_eq!(interner.resolve(go_id), None);
+        assert_eq!(interner.get(b"#rust"), Some(rust.clone()));
+
+        let zig = interner.intern(b"#zig");
+        assert_eq!(zig.id(), go_id);
+        assert_eq!(interner.purge(), 0);
+    }
+
+    #[test]
+    fn sweeps_as_it_grows() {
+        let interner = Interner::new();
+        let kept = interner.intern(b"#kept");
+        for n in 0..10000 {
+            interner.intern(format!("nick{}", n).as_bytes());
+        }
+        // Only `#kept` survives a sweep, so one comes every
+        // MIN_SWEEP_INTERVAL insertions.
+        assert!(interner.len() <= super::MIN_SWEEP_INTERVAL + 1);
+        assert_eq!(interner.get(b"#kept"), Some(kept));
+    }
+}
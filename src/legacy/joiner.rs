@@ -0,0 +1,382 @@
+//! Joining many channels without flooding off the server.
+//!
+//! `Joiner` packs queued channels into comma-separated JOINs that fit in
+//! a single message, hands out at most one per `spacing`, and follows
+//! the results so the caller can tell which channels didn't make it.
+//! Like `WhoRefresh`, it never touches the network or the clock itself.
+//! Given a `RetryPolicy`, it queues channels again after refusals and
+//! kicks.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use irccase::IrcAsciiExt;
+use ::client::JoinBuf;
+use ::MAX_MESSAGE_LEN;
+
+use super::event::IrcEvent;
+use super::retry::{Backoff, RetryPolicy};
+use super::watchers::JOIN_FAILURES;
+
+/// Where a queued channel is in the joining process.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JoinStatus {
+    Queued,
+    /// Included in a JOIN we handed out; waiting for the server.
+    Sent,
+    Joined,
+    Failed(JoinFailure),
+}
+
+/// Why we aren't in a queued channel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JoinFailure {
+    /// The server refused us, with this numeric
+    Refused(String),
+    /// We were in, and were kicked
+    Kicked,
+    /// The name can't be sent in a JOIN at all
+    Invalid,
+}
+
+/// Refusals which retrying won't fix: ERR_NOSUCHCHANNEL,
+/// ERR_TOOMANYCHANNELS and ERR_BADCHANMASK.
+const PERMANENT_FAILURES: [&'static str; 3] = ["403", "405", "476"];
+
+/// Room for channels in a JOIN, after "JOIN " and the trailing CR-LF.
+const JOIN_BUDGET: usize = MAX_MESSAGE_LEN - 7;
+
+impl JoinFailure {
+    /// Whether joining again can't help.
+    pub fn is_permanent(&self) -> bool {
+        match *self {
+            JoinFailure::Refused(ref numeric) => PERMANENT_FAILURES.contains(&&numeric[..]),
+            JoinFailure::Kicked => false,
+            JoinFailure::Invalid => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Retry {
+    backoff: Backoff,
+    /// The wait before queueing again, until the next `poll` turns it
+    /// into `due`
+    wait: Option<Duration>,
+    due: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct Joiner {
+    spacing: Duration,
+    last_sent: Option<Instant>,
+    channels: Vec<(Vec<u8>, JoinStatus)>,
+    retry: Option<RetryPolicy>,
+    /// Indexes into `channels` of the channels which failed under
+    /// `retry`
+    retries: HashMap<usize, Retry>,
+}
+
+impl Joiner {
+    /// Send at most one JOIN per `spacing`.
+    pub fn new(spacing: Duration) -> Joiner {
+        Joiner {
+            spacing: spacing,
+            last_sent: None,
+            channels: Vec::new(),
+            retry: None,
+            retries: HashMap::new(),
+        }
+    }
+
+    /// Queue channels the server refused, or kicked us from, again
+    /// after waiting as `policy` says.
+    pub fn retry(mut self, policy: RetryPolicy) -> Joiner {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Queue a channel.  Channels already queued are ignored, and names
+    /// which can't be sent in a JOIN fail at once.
+    pub fn add(&mut self, channel: &[u8]) {
+        if self.position(channel).is_some() {
+            return;
+        }
+        let sendable = channel.len() <= JOIN_BUDGET && !channel.contains(&b',')
+            && JoinBuf::new(channel).is_ok();
+        let status = if sendable {
+            JoinStatus::Queued
+        } else {
+            JoinStatus::Failed(JoinFailure::Invalid)
+        };
+        self.channels.push((channel.to_vec(), status));
+    }
+
+    fn position(&self, channel: &[u8]) -> Option<usize> {
+        self.channels.iter().position(|&(ref name, _)| {
+            name[..].eq_ignore_irc_case(channel)
+        })
+    }
+
+    pub fn status(&self, channel: &[u8]) -> Option<&JoinStatus> {
+        self.position(channel).map(|idx| &self.channels[idx].1)
+    }
+
+    /// Every queued channel and its status, in the order they were added.
+    pub fn statuses(&self) -> &[(Vec<u8>, JoinStatus)] {
+        &self.channels
+    }
+
+    /// True once no channel is queued, waiting for the server or
+    /// waiting to be retried.
+    pub fn is_finished(&self) -> bool {
+        let waiting = self.retries.values().any(|retry| {
+            retry.wait.is_some() || retry.due.is_some()
+        });
+        !waiting && self.channels.iter().all(|&(_, ref status)| match *status {
+            JoinStatus::Queued | JoinStatus::Sent => false,
+            _ => true,
+        })
+    }
+
+    /// Note that we were kicked from `channel`, so that it is joined
+    /// again if there is a `RetryPolicy`.
+    pub fn kicked(&mut self, channel: &[u8]) {
+        if let Some(idx) = self.position(channel) {
+            if self.channels[idx].1 == JoinStatus::Joined {
+                self.fail(idx, JoinFailure::Kicked);
+            }
+        }
+    }
+
+    fn fail(&mut self, idx: usize, reason: JoinFailure) {
+        let permanent = reason.is_permanent();
+        self.channels[idx].1 = JoinStatus::Failed(reason);
+        let policy = match self.retry {
+            Some(policy) if !permanent => policy,
+            _ => return,
+        };
+        let retry = self.retries.entry(idx).or_insert_with(|| Retry {
+            backoff: policy.backoff(),
+            wait: None,
+            due: None,
+        });
+        retry.wait = retry.backoff.next_delay();
+        retry.due = None;
+    }
+
+    /// Queue failed channels whose wait is over.
+    fn requeue(&mut self, now: Instant) {
+        for (&idx, retry) in self.retries.iter_mut() {
+            if let Some(wait) = retry.wait.take() {
+                retry.due = Some(now + wait);
+            }
+            match retry.due {
+                Some(due) if due <= now => {
+                    retry.due = None;
+                    self.channels[idx].1 = JoinStatus::Queued;
+                },
+                _ => (),
+            }
+        }
+    }
+
+    /// Returns the next JOIN to send, if one is due.  Each JOIN carries
+    /// as many queued channels as fit in `MAX_MESSAGE_LEN`.
+    pub fn poll(&mut self, now: Instant) -> Option<JoinBuf> {
+        self.requeue(now);
+        if let Some(last_sent) = self.last_sent {
+            if now < last_sent + self.spacing {
+                return None;
+            }
+        }
+
+        let mut targets = Vec::new();
+        let mut batch = Vec::new();
+        for (idx, &(ref name, ref status)) in self.channels.iter().enumerate() {
+            if *status != JoinStatus::Queued {
+                continue;
+            }
+            let extra = if targets.is_empty() { name.len() } else { name.len() + 1 };
+            if targets.len() + extra > JOIN_BUDGET {
+                break;
+            }
+            if !targets.is_empty() {
+                targets.push(b',');
+            }
+            targets.extend(name.iter().cloned());
+            batch.push(idx);
+        }
+        if batch.is_empty() {
+            return None;
+        }
+
+        let join = JoinBuf::new(&targets);
+        for idx in batch.into_iter() {
+            self.channels[idx].1 = match join {
+                Ok(_) => JoinStatus::Sent,
+                Err(_) => JoinStatus::Failed(JoinFailure::Invalid),
+            };
+        }
+        let join = match join {
+            Ok(join) => join,
+            Err(_) => return None,
+        };
+        self.last_sent = Some(now);
+        Some(join)
+    }
+
+    /// Follow join results.  Successes come from the join watchers'
+    /// bundles; refusals are read straight from the numerics, since no
+    /// bundle is started for a channel we never get into.
+    pub fn on_event(&mut self, event: &IrcEvent) {
+        let (channel, status) = match *event {
+            IrcEvent::JoinBundle(Ok(ref join)) => {
+                (join.channel.clone(), JoinStatus::Joined)
+            },
+            IrcEvent::JoinBundle(Err(ref err)) => {
+                (err.channel.clone(), JoinStatus::Failed(JoinFailure::Refused(err.errcode.to_string())))
+            },
+            IrcEvent::IrcMsg(ref msg) => {
+                let command = msg.get_command();
+                if !JOIN_FAILURES.contains(&command) || msg.len() < 2 {
+                    return;
+                }
+                (msg[1].to_vec(), JoinStatus::Failed(JoinFailure::Refused(command.to_string())))
+            },
+            IrcEvent::WhoBundle(_) |
+            IrcEvent::NamesBundle(_) |
+            IrcEvent::ListEntry(_) |
+            IrcEvent::ListBundle(_) |
+            IrcEvent::WhoisBundle(_) |
+            IrcEvent::ServerNotice(_) |
+            IrcEvent::StandardReply(_) |
+            IrcEvent::CaseMappingChange(_) |
+            IrcEvent::ParseFailure(_) => return,
+        };
+        if let Some(idx) = self.position(&channel) {
+            if self.channels[idx].1 != JoinStatus::Sent {
+                return;
+            }
+            match status {
+                JoinStatus::Failed(reason) => self.fail(idx, reason),
+                status => {
+                    self.retries.remove(&idx);
+                    self.channels[idx].1 = status;
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::super::{IrcMsg, IrcEvent, JoinSuccess, RetryPolicy};
+    use super::{Joiner, JoinFailure, JoinStatus};
+
+    #[test]
+    fn packs_and_throttles() {
+        let mut joiner = Joiner::new(Duration::from_secs(2));
+        let mut names = Vec::new();
+        for idx in 0..100 {
+            names.push(format!("#channel-number-{}", idx));
+        }
+        for name in names.iter() {
+            joiner.add(name.as_bytes());
+        }
+        joiner.add(b"#CHANNEL-number-0");
+        assert_eq!(joiner.statuses().len(), 100);
+
+        let start = Instant::now();
+        let first = joiner.poll(start).unwrap();
+        assert!(first.wire_len() <= 512);
+        assert!(joiner.poll(start + Duration::from_secs(1)).is_none());
+
+        let mut sent = first.as_bytes().split(|&b| b == b',').count();
+        let mut now = start;
+        while let Some(join) = joiner.poll(now + Duration::from_secs(2)) {
+            assert!(join.wire_len() <= 512);
+            sent += join.as_bytes().split(|&b| b == b',').count();
+            now = now + Duration::from_secs(2);
+        }
+        assert_eq!(sent, 100);
+        assert!(!joiner.is_finished());
+    }
+
+    #[test]
+    fn tracks_results() {
+        let mut joiner = Joiner::new(Duration::from_secs(0));
+        joiner.add(b"#open");
+        joiner.add(b"#secret");
+        assert_eq!(joiner.poll(Instant::now()).unwrap().as_bytes(), b"JOIN #open,#secret");
+
+        joiner.on_event(&IrcEvent::JoinBundle(Ok(JoinSuccess {
+            channel: b"#Open".to_vec(),
+            channel_name: "#Open".to_string(),
+            nicks: Vec::new(),
+            topic: None,
+            channel_symbol: None,
+        })));
+        let msg = b":server 475 me #secret :Cannot join channel (+k)".to_vec();
+        joiner.on_event(&IrcEvent::IrcMsg(IrcMsg::new(msg).unwrap()));
+
+        assert_eq!(joiner.status(b"#open"), Some(&JoinStatus::Joined));
+        assert_eq!(joiner.status(b"#secret"), Some(&JoinStatus::Failed(JoinFailure::Refused("475".to_string()))));
+        assert!(joiner.is_finished());
+    }
+
+    #[test]
+    fn skips_unsendable_names() {
+        let mut joiner = Joiner::new(Duration::from_secs(0));
+        let long = format!("#{}", "x".repeat(600));
+        joiner.add(long.as_bytes());
+        joiner.add(b"#a b");
+        joiner.add(b"#ok");
+        assert_eq!(joiner.poll(Instant::now()).unwrap().as_bytes(), b"JOIN #ok");
+
+        let invalid = Some(&JoinStatus::Failed(JoinFailure::Invalid));
+        assert_eq!(joiner.status(long.as_bytes()), invalid);
+        assert_eq!(joiner.status(b"#a b"), invalid);
+        assert!(joiner.poll(Instant::now()).is_none());
+    }
+
+    #[test]
+    fn retries_refusals_and_kicks() {
+        let secs = Duration::from_secs;
+        let policy = RetryPolicy::new(secs(10)).max_attempts(1);
+        let mut joiner = Joiner::new(secs(0)).retry(policy);
+        joiner.add(b"#full");
+        joiner.add(b"#gone");
+        let start = Instant::now();
+        assert_eq!(joiner.poll(start).unwrap().as_bytes(), b"JOIN #full,#gone");
+
+        let refuse = |joiner: &mut Joiner, line: &[u8]| {
+            joiner.on_event(&IrcEvent::IrcMsg(IrcMsg::new(line.to_vec()).unwrap()));
+        };
+        refuse(&mut joiner, b":server 471 me #full :Cannot join channel (+l)");
+        refuse(&mut joiner, b":server 403 me #gone :No such channel");
+        assert!(!joiner.is_finished());
+        assert!(joiner.poll(start + secs(1)).is_none());
+        assert_eq!(joiner.poll(start + secs(11)).unwrap().as_bytes(), b"JOIN #full");
+
+        joiner.on_event(&IrcEvent::JoinBundle(Ok(JoinSuccess {
+            channel: b"#full".to_vec(),
+            channel_name: "#full".to_string(),
+            nicks: Vec::new(),
+            topic: None,
+            channel_symbol: None,
+        })));
+        assert!(joiner.is_finished());
+        joiner.kicked(b"#FULL");
+        assert_eq!(joiner.status(b"#full"), Some(&JoinStatus::Failed(JoinFailure::Kicked)));
+        assert!(joiner.poll(start + secs(12)).is_none());
+        assert_eq!(joiner.poll(start + secs(22)).unwrap().as_bytes(), b"JOIN #full");
+
+        // Out of attempts.
+        refuse(&mut joiner, b":server 474 me #full :Cannot join channel (+b)");
+        assert!(joiner.is_finished());
+        assert!(joiner.poll(start + secs(3600)).is_none());
+    }
+}
@@ -1,4 +1,6 @@
+use ::server::ServerInfo;
 use super::super::IrcMsg;
+use super::super::watchers::WhoTarget;
 
 
 fn byte_length_sum(items: &[&str]) -> usize {
@@ -72,6 +74,20 @@ impl Nick {
 }
 
 
+#[derive(Clone, Debug)]
+pub struct Pass(IrcMsg);
+msg_wrapper_common!(Pass);
+
+impl Pass {
+    pub fn new(password: &str) -> Pass {
+        let mut msg = Vec::with_capacity(6 + password.as_bytes().len());
+        msg.extend(b"PASS :");
+        msg.extend(password.as_bytes());
+        Pass(IrcMsg::new(msg).ok().expect("Generated invalid message"))
+    }
+}
+
+
 #[derive(Clone, Debug)]
 pub struct Ping(IrcMsg);
 msg_wrapper_common!(Ping);
@@ -156,10 +172,10 @@ pub struct Who(IrcMsg);
 msg_wrapper_common!(Who);
 
 impl Who {
-    pub fn new(target: &str) -> Who {
+    pub fn new<T>(target: T) -> Who where T: AsRef<[u8]> {
         let mut msg = Vec::new();
         msg.extend(b"WHO ");
-        msg.extend(target.as_bytes());
+        msg.extend(target.as_ref());
 
         Who(IrcMsg::new(msg).ok().expect("Generated invalid message"))
     }
@@ -172,6 +188,17 @@ impl Who {
 
         Who(IrcMsg::new(msg).ok().expect("Generated invalid message"))
     }
+
+    /// A WHO for `target`, if it is valid on the server described by
+    /// `info`.
+    pub fn from_target(target: &WhoTarget, info: &ServerInfo) -> Result<Who, ()> {
+        try!(target.validate(info));
+        if target.is_opers_only() {
+            Ok(Who::new_opers(target.get_mask()))
+        } else {
+            Ok(Who::new(target.get_mask()))
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -187,3 +214,41 @@ impl Quit {
         Quit(IrcMsg::new(msg).ok().expect("Generated invalid message"))
     }
 }
+
+#[derive(Clone, Debug)]
+pub struct Ison(IrcMsg);
+msg_wrapper_common!(Ison);
+
+impl Ison {
+    pub fn new(nicks: &[&str]) -> Ison {
+        let mut msg = Vec::new();
+        msg.extend(b"ISON :");
+        msg.extend(nicks.join(" ").as_bytes());
+
+        Ison(IrcMsg::new(msg).ok().expect("Generated invalid message"))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Monitor(IrcMsg);
+msg_wrapper_common!(Monitor);
+
+impl Monitor {
+    pub fn add(nicks: &[&str]) -> Monitor {
+        Monitor::new(b'+', nicks)
+    }
+
+    pub fn remove(nicks: &[&str]) -> Monitor {
+        Monitor::new(b'-', nicks)
+    }
+
+    fn new(op: u8, nicks: &[&str]) -> Monitor {
+        let mut msg = Vec::new();
+        msg.extend(b"MONITOR ");
+        msg.push(op);
+        msg.extend(b" ");
+        msg.extend(nicks.join(",").as_bytes());
+
+        Monitor(IrcMsg::new(msg).ok().expect("Generated invalid message"))
+    }
+}
@@ -347,7 +347,7 @@ impl_into_incoming_msg!(Notice);
 msg_wrapper_common!(Notice);
 
 impl Notice {
-    /// Target of the MODE command, channel or user
+    /// Target of the NOTICE command, channel or user
     pub fn get_target<'a>(&'a self) -> &'a str {
         let Notice(ref msg) = *self;
         unsafe { str::from_utf8_unchecked(&msg[0]) }
@@ -356,15 +356,15 @@ impl Notice {
 
 impl FromIrcMsg for Notice {
     fn from_irc_msg(msg: IrcMsg) -> Result<Notice, IrcMsg> {
-        if !msg.get_command().eq_ignore_irc_case("MODE") {
+        if !msg.get_command().eq_ignore_irc_case("NOTICE") {
             return Err(msg);
         }
         if msg.len() < 2 {
-            warn!("Invalid MODE: Not enough arguments {}", msg.len());
+            warn!("Invalid NOTICE: Not enough arguments {}", msg.len());
             return Err(msg);
         }
         if !is_full_prefix(msg.get_prefix_str()) {
-            warn!("Invalid MODE: Insufficient prefix `{}`", msg.get_prefix_str());
+            warn!("Invalid NOTICE: Insufficient prefix `{}`", msg.get_prefix_str());
             return Err(msg);
         }
         if !str::from_utf8(&msg[0]).is_ok() {
@@ -614,17 +614,13 @@ impl Quit {
     pub fn get_nick<'a>(&'a self) -> &'a str {
         let Quit(ref msg) = *self;
         let prefix = msg.get_prefix_str();
-        &prefix[..prefix.find('!').unwrap()]
-    }
-
-    pub fn get_channel(&self) -> &str {
-        let Quit(ref msg) = *self;
-        unsafe { str::from_utf8_unchecked(&msg[0]) }
+        &prefix[..prefix.find('!').unwrap_or(prefix.len())]
     }
 
-    pub fn get_body_raw<'a>(&'a self) -> &'a [u8] {
+    /// The quit message, absent for a bare `QUIT`.
+    pub fn get_reason(&self) -> Option<&[u8]> {
         let Quit(ref msg) = *self;
-        &msg[1]
+        msg.get_args().first().map(|reason| *reason)
     }
 }
 
@@ -633,11 +629,8 @@ impl FromIrcMsg for Quit {
         if !msg.get_command().eq_ignore_irc_case("QUIT") {
             return Err(msg);
         }
-        if msg.len() < 1 {
-            warn!("Invalid QUIT: Not enough arguments {}", msg.len());
-            return Err(msg);
-        }
-        if !str::from_utf8(&msg[0]).is_ok() {
+        if msg.len() > 1 {
+            warn!("Invalid QUIT: Too many arguments {}", msg.len());
             return Err(msg);
         }
         Ok(Quit(msg))
@@ -651,6 +644,18 @@ fn test_quit_basics() {
     assert!(ping.is_err());
 }
 
+#[test]
+fn test_quit_reason() {
+    let msg = IrcMsg::new(b":person!user@host QUIT :Gone to lunch".to_vec()).unwrap();
+    let quit: Quit = FromIrcMsg::from_irc_msg(msg).ok().unwrap();
+    assert_eq!(quit.get_nick(), "person");
+    assert_eq!(quit.get_reason(), Some(&b"Gone to lunch"[..]));
+
+    let msg = IrcMsg::new(b":person!user@host QUIT".to_vec()).unwrap();
+    let quit: Quit = FromIrcMsg::from_irc_msg(msg).ok().unwrap();
+    assert_eq!(quit.get_reason(), None);
+}
+
 #[derive(Clone, Debug)]
 pub struct Topic(IrcMsg);
 impl_into_incoming_msg!(Topic);
@@ -3,28 +3,86 @@ use std::ops::Index;
 use std::borrow::Cow;
 
 use super::parse::{ParseError, ParseErrorKind};
+use ::server::ServerInfo;
 use util::{StringSlicer, OptionalStringSlicer};
 
 use irccase::IrcAsciiExt;
 
+mod aliases;
+mod case_mapping;
+mod dedup;
 mod event;
+mod ignore;
+mod intern;
 mod watchers;
 pub mod numerics;
 pub mod message_types;
+mod joiner;
+mod nick_reclaim;
+mod permissions;
+mod rate_limit;
+mod register;
+mod retry;
+mod services;
+mod session;
+mod snotice;
+mod standard_reply;
 mod state;
-
-pub use self::event::IrcEvent;
+mod who_refresh;
+
+pub use self::aliases::CommandAliases;
+pub use self::case_mapping::{CaseMappingChange, UnsupportedCaseMapping};
+pub use self::dedup::MsgIdCache;
+pub use self::event::{IrcEvent, EventKind, EventKinds, EventDispatcher};
+pub use self::ignore::{IgnoreEntry, IgnoreList};
+pub use self::intern::{Interner, Symbol};
 pub use self::state::{
     Channel,
     ChannelId,
     ChannelInfo,
+    ChannelDiffCmd,
+    ChannelModes,
+    Desynced,
+    Mask,
+    ModeParamError,
+    PatchError,
     State,
+    StateCommand,
     FrozenState,
+    StateDiff,
+    TopicSetter,
     User,
     UserId,
     UserInfo,
+    UserDiffCmd,
     MessageEndpoint,
 };
+#[cfg(feature = "serde")]
+pub use self::state::DecodeError;
+pub use self::joiner::{Joiner, JoinFailure, JoinStatus};
+pub use self::nick_reclaim::NickReclaimer;
+pub use self::permissions::{Action, ChannelPermissions};
+pub use self::rate_limit::RateLimiter;
+pub use self::retry::{Backoff, RetryPolicy};
+pub use self::register::{
+    NickGenerator,
+    NickGeneratorClone,
+    RegisterReq,
+    RegisterReqBuilder,
+    RegisterStatus,
+    SuffixNickGenerator,
+};
+pub use self::services::{
+    AccessEntry,
+    Services,
+    ServicesDialect,
+    ServicesReply,
+    ServicesRequest,
+};
+pub use self::session::IrcSession;
+pub use self::snotice::{ServerNotice, SnoticeKind};
+pub use self::standard_reply::{ReplyKind, StandardReply};
+pub use self::who_refresh::WhoRefresh;
 pub use self::watchers::{
     RegisterError,
     RegisterErrorType,
@@ -32,19 +90,39 @@ pub use self::watchers::{
     JoinResult,
     JoinSuccess,
     JoinError,
+    NamesEntry,
+    NamesIter,
+    TopicMeta,
+
+    NamesResult,
+    NamesBundler,
+    NamesBundlerTrigger,
+
+    ListEntry,
+    ListResult,
+    ListBundler,
+    ListBundlerTrigger,
 
     WhoResult,
     WhoRecord,
     WhoSuccess,
     WhoError,
+    WhoTarget,
+    WhoRequests,
+
+    WhoisResult,
+    WhoisSuccess,
+    WhoisError,
 
     BundlerManager,
     JoinBundlerTrigger,
     WhoBundlerTrigger,
+    WhoEventWatcher,
+    WhoisBundler,
+    WhoisBundlerTrigger,
+    SessionIdentity,
 };
 
-static CHANNEL_PREFIX_CHARS: [char; 4] = ['&', '#', '+', '!'];
-
 // Commands which target a msgtarget or channel
 static CHANNEL_TARGETED_COMMANDS: [&'static str; 6] = [
     "KICK",
@@ -67,17 +145,10 @@ pub fn can_target_channel(identifier: &str) -> bool {
 }
 
 /// Determines whether or not an identifier is a channel, by checking
-/// the first character.
+/// the first character against the default CHANTYPES.  Servers may
+/// advertise others; `ServerInfo::is_channel` follows them.
 pub fn is_channel(identifier: &str) -> bool {
-    if identifier.chars().count() == 0 {
-        return false;
-    }
-    for &character in CHANNEL_PREFIX_CHARS.iter() {
-        if identifier.chars().next() == Some(character) {
-            return true;
-        }
-    }
-    false
+    ServerInfo::new().is_channel(identifier)
 }
 
 
@@ -108,6 +179,7 @@ pub fn is_full_prefix(prefix: &str) -> bool {
 #[derive(Copy, Clone)]
 enum IrcParserState {
     Initial,
+    Tags,
     Prefix,
     CommandStart,
     Command,
@@ -159,6 +231,8 @@ impl IrcParser {
     fn push_byte(&mut self, byte: u8) {
         self.state = match (self.state, byte) {
             (IrcParserState::Initial, b' ') => IrcParserState::Initial,
+            // Message tags are kept in `data` but otherwise skipped.
+            (IrcParserState::Initial, b'@') if self.byte_idx == 0 => IrcParserState::Tags,
             (IrcParserState::Initial, b':') => {
                 self.prefix_start = self.byte_idx + 1;
                 IrcParserState::Prefix
@@ -168,6 +242,9 @@ impl IrcParser {
                 IrcParserState::Command
             },
 
+            (IrcParserState::Tags, b' ') => IrcParserState::Initial,
+            (IrcParserState::Tags, _) => IrcParserState::Tags,
+
             (IrcParserState::Prefix, b' ') => {
                 self.prefix_end = self.byte_idx;
                 IrcParserState::CommandStart
@@ -228,9 +305,13 @@ impl IrcParser {
     fn finish(&mut self) -> Result<(), ParseErrorKind> {
         match self.state {
             IrcParserState::Initial => Err(ParseErrorKind::Truncated),
+            IrcParserState::Tags => Err(ParseErrorKind::Truncated),
             IrcParserState::Prefix => Err(ParseErrorKind::Truncated),
             IrcParserState::CommandStart => Err(ParseErrorKind::Truncated),
-            IrcParserState::Command => Err(ParseErrorKind::Truncated),
+            IrcParserState::Command => {
+                self.command_end = self.byte_idx;
+                Ok(())
+            },
             IrcParserState::ArgOverflow => Err(ParseErrorKind::TooManyArguments),
             IrcParserState::ArgStart => Ok(()),
             IrcParserState::EndOfLine => Ok(()),
@@ -246,7 +327,7 @@ impl IrcParser {
         }
     }
 
-    fn parse(message: Vec<u8>) -> Result<IrcMsg, ParseError> {
+    fn parse_with(message: Vec<u8>, lossless: bool) -> Result<IrcMsg, ParseError> {
         let mut parser = IrcParser::new();
         for &value in message.iter() {
             parser.push_byte(value);
@@ -257,10 +338,6 @@ impl IrcParser {
 
         assert_eq!(parser.byte_idx as usize, message.len());
 
-        if parser.arg_len == 0 {
-            return Err(ParseError::new(ParseErrorKind::Truncated, message));
-        }
-
         let mut parsed = IrcMsg {
             data: message,
             prefix: (parser.prefix_start, parser.prefix_end),
@@ -275,15 +352,36 @@ impl IrcParser {
         }
 
         // Newline and Carriage return removal
-        let last_idx = (parsed.arg_len - 1) as usize;
-        let (arg_start, mut arg_end) = parsed.args[last_idx];
+        let arg_end = if parsed.arg_len == 0 {
+            // Commands such as a bare QUIT have no arguments at all.
+            let (command_start, mut command_end) = parsed.command;
+            while command_end > command_start &&
+                    (parsed.data[command_end as usize - 1] == b'\r' ||
+                     parsed.data[command_end as usize - 1] == b'\n') {
+                command_end -= 1;
+            }
+            parsed.command = (command_start, command_end);
+            command_end
+        } else {
+            let last_idx = (parsed.arg_len - 1) as usize;
+            let (arg_start, mut arg_end) = parsed.args[last_idx];
+
+            if parsed.data[arg_end as usize - 1] == b'\r' {
+                arg_end -= 1;
+                parsed.args[last_idx] = (arg_start, arg_end);
+            }
+            arg_end
+        };
 
-        if parsed.data[arg_end as usize - 1] == b'\r' {
-            arg_end -= 1;
-            parsed.args[last_idx] = (arg_start, arg_end);
+        if lossless {
+            let mut line_len = ::parse_helpers::first_line(&parsed.data).len();
+            if parsed.data[..line_len].last() == Some(&b'\r') {
+                line_len -= 1;
+            }
+            parsed.data.truncate(line_len);
+        } else {
+            parsed.data.truncate(arg_end as usize);
         }
-
-        parsed.data.truncate(arg_end as usize);
         Ok(parsed)
     }
 }
@@ -308,8 +406,20 @@ pub struct IrcMsg {
 }
 
 impl IrcMsg {
+    /// Parse a message.  Trailing whitespace after the last argument is
+    /// dropped; use `new_lossless` to keep it.
     pub fn new(data: Vec<u8>) -> Result<IrcMsg, ParseError> {
-        let parsed = match IrcParser::parse(data) {
+        IrcMsg::_new(data, false)
+    }
+
+    /// Parse a message, keeping every byte before the line ending so that
+    /// `as_bytes` and `into_bytes` give back exactly what was received.
+    pub fn new_lossless(data: Vec<u8>) -> Result<IrcMsg, ParseError> {
+        IrcMsg::_new(data, true)
+    }
+
+    fn _new(data: Vec<u8>, lossless: bool) -> Result<IrcMsg, ParseError> {
+        let parsed = match IrcParser::parse_with(data, lossless) {
             Ok(parsed) => parsed,
             Err(err) => return Err(err)
         };
@@ -391,7 +501,7 @@ mod tests {
         {
             let example: Vec<_> = b":prefix PING  foo bar baz".iter().map(|&x| x).collect();
 
-            let parsed = match IrcParser::parse(example) {
+            let parsed = match IrcParser::parse_with(example, false) {
                 Ok(parsed) => parsed,
                 Err(err) => panic!("err: {:?}", err)
             };
@@ -407,14 +517,14 @@ mod tests {
         {
             let example: Vec<_> = b"PING a b c d e f g h i j k l m n o p q r s t u v w x y z\n".iter().map(|&x| x).collect();
             assert_eq!(
-                IrcParser::parse(example).err().unwrap().kind,
+                IrcParser::parse_with(example, false).err().unwrap().kind,
                 ParseErrorKind::TooManyArguments);
         }
 
         {
             let example: Vec<_> = b":prefix PING  foo :bar baz\r\n".iter().map(|&x| x).collect();
 
-            let parsed = match IrcParser::parse(example) {
+            let parsed = match IrcParser::parse_with(example, false) {
                 Ok(parsed) => parsed,
                 Err(err) => panic!("err: {:?}", err)
             };
@@ -431,7 +541,7 @@ mod tests {
     #[test]
     fn test_security() {
         let example: Vec<_> = b":prefix PING foo\r\n:prefix2 PING bar\r\n".iter().map(|&x| x).collect();
-        let safe = match IrcParser::parse(example) {
+        let safe = match IrcParser::parse_with(example, false) {
             Ok(parsed) => parsed.into_bytes(),
             Err(err) => panic!("Should have been able to parse. err: {:?}", err)
         };
@@ -550,3 +660,18 @@ impl<'a> fmt::Debug for IrcMsgPrefix<'a> {
         write!(f, "IrcMsgPrefix::new({:?})", self.as_slice())
     }
 }
+
+#[cfg(feature = "serde")]
+impl<'a> ::serde::Serialize for IrcMsgPrefix<'a> {
+    fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_slice())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> ::serde::Deserialize<'de> for IrcMsgPrefix<'static> {
+    fn deserialize<D: ::serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let prefix: String = try!(::serde::Deserialize::deserialize(deserializer));
+        Ok(IrcMsgPrefix::new(Cow::Owned(prefix)))
+    }
+}
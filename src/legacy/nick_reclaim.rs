@@ -0,0 +1,282 @@
+//! Reclaiming our preferred nick after registering under another one.
+//!
+//! When the nick we want is taken at registration we settle for an
+//! alternative.  `NickReclaimer` watches for the wanted nick becoming
+//! free, through MONITOR, ISON polling, or its holder quitting or
+//! changing nick, and answers with a NICK.  It can also ask services to
+//! GHOST the holder first, claiming the nick once they confirm it in the
+//! words of the `ServicesDialect`.  With a `RetryPolicy` it schedules
+//! its own ISON polls, backing off while the nick stays taken.
+//!
+//! Nothing here updates our own nick directly: the server echoes the
+//! NICK back, and `SessionIdentity` and `State` follow it from there.
+
+use std::time::Instant;
+
+use irccase::IrcAsciiExt;
+use ::client::Sanitizer;
+
+use super::IrcMsg;
+use super::message_types::client;
+use super::retry::{Backoff, RetryPolicy};
+use super::services::ServicesDialect;
+use super::watchers::SessionIdentity;
+
+#[derive(Debug)]
+pub struct NickReclaimer {
+    wanted: String,
+    identity: SessionIdentity,
+    ghost: Option<(ServicesDialect, String)>,
+    monitoring: bool,
+
+    /// A NICK has been sent and we are waiting for the outcome.
+    pending: bool,
+
+    /// Paces the ISONs handed out by `poll`
+    backoff: Option<Backoff>,
+    next_ison: Option<Instant>,
+}
+
+impl NickReclaimer {
+    pub fn new(wanted: &str, identity: SessionIdentity) -> NickReclaimer {
+        NickReclaimer {
+            wanted: wanted.to_string(),
+            identity: identity,
+            ghost: None,
+            monitoring: false,
+            pending: false,
+            backoff: None,
+            next_ison: None,
+        }
+    }
+
+    /// Have `poll` hand out ISONs, waiting longer between them as
+    /// `policy` says for as long as the nick stays taken.
+    pub fn with_retry(mut self, policy: RetryPolicy) -> NickReclaimer {
+        self.backoff = Some(policy.backoff());
+        self
+    }
+
+    /// Have `start` ask NickServ to GHOST the current holder of the
+    /// wanted nick, using the command and replies of `dialect`.
+    pub fn with_ghost(mut self, dialect: ServicesDialect, password: &str) -> NickReclaimer {
+        self.ghost = Some((dialect, password.to_string()));
+        self
+    }
+
+    pub fn get_wanted(&self) -> &str {
+        &self.wanted
+    }
+
+    /// Whether we currently hold the wanted nick.
+    pub fn is_satisfied(&self) -> bool {
+        self.identity.is_self(self.wanted.as_bytes())
+    }
+
+    /// Messages to send once registration has completed.  With
+    /// `use_monitor`, the server is asked to tell us when the nick goes
+    /// offline; otherwise the caller should send `ison` periodically.
+    pub fn start(&mut self, use_monitor: bool) -> Vec<IrcMsg> {
+        let mut out = Vec::new();
+        if self.is_satisfied() {
+            return out;
+        }
+        if let Some((ref dialect, ref password)) = self.ghost {
+            let command = format!("{} {} {}", dialect.ghost_command, self.wanted, password);
+            out.push(client::Privmsg::new(&dialect.nickserv, command.as_bytes()).into_irc_msg());
+        }
+        if use_monitor {
+            self.monitoring = true;
+            out.push(client::Monitor::add(&[&self.wanted]).into_irc_msg());
+        }
+        out
+    }
+
+    /// An ISON query for the wanted nick, unless it is no longer needed.
+    pub fn ison(&self) -> Option<IrcMsg> {
+        if self.is_satisfied() || self.pending {
+            return None;
+        }
+        Some(client::Ison::new(&[&self.wanted]).into_irc_msg())
+    }
+
+    /// The ISON to send now, if one is due under the `RetryPolicy`.
+    /// The first is due at once; none are once the policy's attempts
+    /// are used up, or if there is no policy.
+    pub fn poll(&mut self, now: Instant) -> Option<IrcMsg> {
+        let ison = match self.ison() {
+            Some(ison) => ison,
+            None => return None,
+        };
+        if let Some(next_ison) = self.next_ison {
+            if now < next_ison {
+                return None;
+            }
+        }
+        let delay = match self.backoff.as_mut().and_then(|backoff| backoff.next_delay()) {
+            Some(delay) => delay,
+            None => return None,
+        };
+        self.next_ison = Some(now + delay);
+        Some(ison)
+    }
+
+    /// Feed an incoming message, returning any messages to send.
+    pub fn on_irc_msg(&mut self, msg: &IrcMsg) -> Vec<IrcMsg> {
+        let prefix = msg.get_prefix();
+        let from_wanted = prefix.nick()
+            .map(|nick| self.is_wanted(nick.as_bytes()))
+            .unwrap_or(false);
+
+        match msg.get_command() {
+            "NICK" if msg.len() > 0 && self.is_wanted(&msg[0]) => {
+                // Either our own NICK succeeded or someone else took it.
+                self.pending = false;
+                // Depending on ordering, the identity may already hold
+                // the new nick.
+                let is_self = self.identity.is_self(&msg[0]) || prefix.nick()
+                    .map(|nick| self.identity.is_self(nick.as_bytes()))
+                    .unwrap_or(false);
+                if is_self && self.monitoring {
+                    self.monitoring = false;
+                    return vec![client::Monitor::remove(&[&self.wanted]).into_irc_msg()];
+                }
+                Vec::new()
+            },
+            "NICK" | "QUIT" if from_wanted => self.claim(),
+            "NOTICE" if msg.len() > 1 && self.is_ghosted(prefix.nick(), &msg[1]) => self.claim(),
+            // ERR_NICKNAMEINUSE, ERR_UNAVAILRESOURCE
+            "433" | "437" if msg.len() > 1 && self.is_wanted(&msg[1]) => {
+                self.pending = false;
+                Vec::new()
+            },
+            // RPL_ISON
+            "303" if msg.len() > 1 => {
+                let online = msg[1].split(|&b| b == b' ').any(|nick| self.is_wanted(nick));
+                if online { Vec::new() } else { self.claim() }
+            },
+            // RPL_MONOFFLINE
+            "731" if msg.len() > 1 => {
+                let offline = msg[1].split(|&b| b == b',').any(|nick| self.is_wanted(nick));
+                if offline { self.claim() } else { Vec::new() }
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    fn claim(&mut self) -> Vec<IrcMsg> {
+        if self.is_satisfied() || self.pending {
+            return Vec::new();
+        }
+        self.pending = true;
+        vec![client::Nick::new(&self.wanted).into_irc_msg()]
+    }
+
+    fn is_wanted(&self, nick: &[u8]) -> bool {
+        nick.eq_ignore_irc_case(self.wanted.as_bytes())
+    }
+
+    /// Whether `text`, from `nick`, is NickServ confirming the GHOST.
+    fn is_ghosted(&self, nick: Option<&str>, text: &[u8]) -> bool {
+        let dialect = match (nick, &self.ghost) {
+            (Some(nick), &Some((ref dialect, _)))
+                if nick.eq_ignore_irc_case(&dialect.nickserv) => dialect,
+            _ => return false,
+        };
+        let text = Sanitizer::new().strip_formatting(true).sanitize(text);
+        let text = String::from_utf8_lossy(&text).to_lowercase();
+        dialect.ghosted.iter().any(|fragment| text.contains(&fragment.to_lowercase()[..]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::super::{IrcMsg, RetryPolicy, ServicesDialect};
+    use super::super::watchers::SessionIdentity;
+    use super::NickReclaimer;
+
+    fn msg(line: &[u8]) -> IrcMsg {
+        IrcMsg::new(line.to_vec()).unwrap()
+    }
+
+    fn lines(msgs: Vec<IrcMsg>) -> Vec<Vec<u8>> {
+        msgs.into_iter().map(|m| m.into_bytes()).collect()
+    }
+
+    #[test]
+    fn reclaims_via_monitor() {
+        let identity = SessionIdentity::new(b"bot_");
+        let mut reclaimer = NickReclaimer::new("bot", identity.clone())
+            .with_ghost(ServicesDialect::atheme(), "hunter2");
+
+        assert_eq!(lines(reclaimer.start(true)), vec![
+            b"PRIVMSG NickServ :GHOST bot hunter2".to_vec(),
+            b"MONITOR + bot".to_vec(),
+        ]);
+
+        let offline = msg(b":server 731 bot_ :Bot");
+        assert_eq!(lines(reclaimer.on_irc_msg(&offline)), vec![b"NICK bot".to_vec()]);
+        // Only one NICK in flight at a time.
+        assert!(reclaimer.on_irc_msg(&offline).is_empty());
+        assert!(reclaimer.ison().is_none());
+
+        let echo = msg(b":bot_!u@h NICK :bot");
+        identity.on_irc_msg(&echo);
+        assert!(reclaimer.is_satisfied());
+        assert_eq!(lines(reclaimer.on_irc_msg(&echo)), vec![b"MONITOR - bot".to_vec()]);
+    }
+
+    #[test]
+    fn reclaims_once_ghosted() {
+        let identity = SessionIdentity::new(b"bot_");
+        let mut reclaimer = NickReclaimer::new("bot", identity)
+            .with_ghost(ServicesDialect::anope(), "hunter2");
+        assert_eq!(lines(reclaimer.start(false)), vec![
+            b"PRIVMSG NickServ :RECOVER bot hunter2".to_vec(),
+        ]);
+
+        assert!(reclaimer.on_irc_msg(&msg(b":NickServ!s@services NOTICE bot_ :Password accepted")).is_empty());
+        assert!(reclaimer.on_irc_msg(&msg(b":Mallory!m@h NOTICE bot_ :User bot has been recovered")).is_empty());
+        assert_eq!(lines(reclaimer.on_irc_msg(&msg(b":NickServ!s@services NOTICE bot_ :User \x02bot\x02 has been recovered."))),
+            vec![b"NICK bot".to_vec()]);
+    }
+
+    #[test]
+    fn retries_after_nick_in_use() {
+        let identity = SessionIdentity::new(b"bot_");
+        let mut reclaimer = NickReclaimer::new("bot", identity);
+        assert!(reclaimer.start(false).is_empty());
+
+        assert!(reclaimer.on_irc_msg(&msg(b":server 303 bot_ :bot")).is_empty());
+        assert_eq!(lines(reclaimer.on_irc_msg(&msg(b":bot!u@h QUIT :gone"))),
+            vec![b"NICK bot".to_vec()]);
+        assert!(reclaimer.on_irc_msg(&msg(b":server 433 bot_ bot :Nickname is already in use")).is_empty());
+        assert_eq!(lines(reclaimer.ison().into_iter().collect()), vec![b"ISON :bot".to_vec()]);
+        assert_eq!(lines(reclaimer.on_irc_msg(&msg(b":server 303 bot_ :"))),
+            vec![b"NICK bot".to_vec()]);
+    }
+
+    #[test]
+    fn backs_off_ison_polls() {
+        let secs = Duration::from_secs;
+        let identity = SessionIdentity::new(b"bot_");
+        let mut reclaimer = NickReclaimer::new("bot", identity)
+            .with_retry(RetryPolicy::new(secs(30)).max_attempts(3));
+        assert!(reclaimer.start(false).is_empty());
+
+        let start = Instant::now();
+        let mut sent = Vec::new();
+        for elapsed in 0..600 {
+            if let Some(ison) = reclaimer.poll(start + secs(elapsed)) {
+                assert_eq!(ison.into_bytes(), b"ISON :bot".to_vec());
+                sent.push(elapsed);
+                assert!(reclaimer.on_irc_msg(&msg(b":server 303 bot_ :bot")).is_empty());
+            }
+        }
+        assert_eq!(sent, vec![0, 30, 90]);
+
+        assert!(NickReclaimer::new("bot", SessionIdentity::new(b"bot_")).poll(start).is_none());
+    }
+}
@@ -0,0 +1,173 @@
+//! Guessing whether the server will let us do something in a channel.
+//!
+//! Sending a TOPIC to a `+t` channel without ops only earns a 482.
+//! `ChannelPermissions` reads our prefix modes and each channel's modes
+//! from a `State` so callers can check first.  The answer is advisory:
+//! servers differ, and bans, quiets and the like aren't considered.
+//!
+//! Channel modes are learned from RPL_CHANNELMODEIS (324), so send
+//! `MODE #channel` after joining for answers that depend on them.
+
+use super::{Channel, State, UserId};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+    /// Send PRIVMSG or NOTICE to the channel
+    Speak,
+    SetTopic,
+    Kick,
+    Invite,
+    /// Change channel modes other than list modes we only query
+    SetMode,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ChannelPermissions<'a> {
+    state: &'a State,
+}
+
+impl<'a> ChannelPermissions<'a> {
+    pub fn new(state: &'a State) -> ChannelPermissions<'a> {
+        ChannelPermissions { state: state }
+    }
+
+    fn get_channel(&self, channel: &str) -> Option<&'a Channel> {
+        self.state.identify_channel(channel)
+            .and_then(|chan_id| self.state.resolve_channel(chan_id))
+    }
+
+    fn get_self_id(&self) -> Option<UserId> {
+        self.state.identify_nick(self.state.get_self_nick())
+    }
+
+    /// Whether we are in `channel`, as far as the state knows.
+    pub fn is_joined(&self, channel: &str) -> bool {
+        self.get_channel(channel).is_some()
+    }
+
+    /// Our prefix modes in `channel`, such as `o` for op.
+    pub fn get_prefixes(&self, channel: &str) -> Option<&'a [u8]> {
+        let self_id = match self.get_self_id() {
+            Some(self_id) => self_id,
+            None => return None,
+        };
+        self.get_channel(channel).map(|chan| chan.member_modes(self_id).as_bytes())
+    }
+
+    /// Whether `channel` has `mode` set, or `None` until the modes are
+    /// known.
+    pub fn has_mode(&self, channel: &str, mode: char) -> Option<bool> {
+        self.get_channel(channel)
+            .map(|chan| chan.get_modes())
+            .and_then(|modes| if modes.is_known() { Some(modes.is_set(mode)) } else { None })
+    }
+
+    /// Whether we hold `mode`, or any prefix mode ranked above it.  If
+    /// the server doesn't have `mode`, ops are required.
+    fn has_rank(&self, prefixes: &[u8], mode: u8) -> bool {
+        let ranks = self.state.get_mode_table().prefix_modes();
+        let needed = ranks.iter().position(|&m| m == mode)
+            .or_else(|| ranks.iter().position(|&m| m == b'o'))
+            .unwrap_or(0);
+        prefixes.iter().any(|p| ranks.iter().position(|m| m == p)
+            .map(|rank| rank <= needed)
+            .unwrap_or(false))
+    }
+
+    /// Whether the server should let us perform `action` in `channel`.
+    /// `None` means it depends on channel modes we haven't seen yet.
+    pub fn can_perform(&self, action: Action, channel: &str) -> Option<bool> {
+        let prefixes = match self.get_prefixes(channel) {
+            Some(prefixes) => prefixes,
+            None => return Some(false),
+        };
+        let halfop = self.has_rank(prefixes, b'h');
+        let op = self.has_rank(prefixes, b'o');
+        let mode_set = |mode: char| self.has_mode(channel, mode);
+        match action {
+            Action::Kick => Some(halfop),
+            Action::SetMode => Some(op),
+            Action::SetTopic if halfop => Some(true),
+            Action::SetTopic => mode_set('t').map(|t| !t),
+            Action::Invite if op => Some(true),
+            Action::Invite => mode_set('i').map(|i| !i),
+            Action::Speak if !prefixes.is_empty() => Some(true),
+            Action::Speak => mode_set('m').map(|m| !m),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{IrcEvent, JoinSuccess, State};
+    use super::{Action, ChannelPermissions};
+
+    fn feed(state: &mut State, line: &[u8]) {
+        state.on_message(::IrcMsg::new(line).unwrap());
+    }
+
+    #[test]
+    fn topic_needs_ops_with_plus_t() {
+        let mut state = State::nick_only();
+        feed(&mut state, b":srv 001 bot :Welcome");
+        assert_eq!(ChannelPermissions::new(&state).can_perform(Action::SetTopic, "#rust"), Some(false));
+
+        state.on_event(&IrcEvent::JoinBundle(Ok(JoinSuccess {
+            channel: b"#Rust".to_vec(),
+            channel_name: "#Rust".to_string(),
+            nicks: vec![(b"@".to_vec(), b"alice".to_vec()), (Vec::new(), b"Bot".to_vec())],
+            topic: None,
+            channel_symbol: None,
+        })));
+        {
+            let perms = ChannelPermissions::new(&state);
+            assert!(perms.is_joined("#rust"));
+            assert_eq!(perms.can_perform(Action::SetTopic, "#rust"), None);
+            assert_eq!(perms.can_perform(Action::Kick, "#rust"), Some(false));
+        }
+
+        feed(&mut state, b":srv 324 bot #rust +ntl 10");
+        {
+            let perms = ChannelPermissions::new(&state);
+            assert_eq!(perms.has_mode("#rust", 'l'), Some(true));
+            assert_eq!(perms.can_perform(Action::SetTopic, "#rust"), Some(false));
+            assert_eq!(perms.can_perform(Action::Speak, "#rust"), Some(true));
+        }
+
+        feed(&mut state, b":alice!a@h MODE #rust -t+m");
+        {
+            let perms = ChannelPermissions::new(&state);
+            assert_eq!(perms.can_perform(Action::SetTopic, "#rust"), Some(true));
+            assert_eq!(perms.can_perform(Action::Speak, "#rust"), Some(false));
+        }
+
+        feed(&mut state, b":alice!a@h MODE #rust +t+o bot");
+        {
+            let perms = ChannelPermissions::new(&state);
+            assert_eq!(perms.get_prefixes("#rust"), Some(&b"o"[..]));
+            assert_eq!(perms.can_perform(Action::SetTopic, "#rust"), Some(true));
+            assert_eq!(perms.can_perform(Action::SetMode, "#rust"), Some(true));
+        }
+
+        feed(&mut state, b":alice!a@h KICK #rust bot :bye");
+        assert_eq!(ChannelPermissions::new(&state).can_perform(Action::SetTopic, "#rust"), Some(false));
+    }
+
+    #[test]
+    fn follows_the_case_mapping() {
+        let mut state = State::nick_only();
+        feed(&mut state, b":srv 001 bot :Welcome");
+        feed(&mut state, b":srv 005 bot CASEMAPPING=ascii :are supported by this server");
+        state.on_event(&IrcEvent::JoinBundle(Ok(JoinSuccess {
+            channel: b"#a[b]".to_vec(),
+            channel_name: "#a[b]".to_string(),
+            nicks: vec![(b"@".to_vec(), b"bot".to_vec())],
+            topic: None,
+            channel_symbol: None,
+        })));
+        let perms = ChannelPermissions::new(&state);
+        assert!(perms.is_joined("#A[B]"));
+        assert!(!perms.is_joined("#a{b}"));
+        assert_eq!(perms.can_perform(Action::Kick, "#a[b]"), Some(true));
+    }
+}
@@ -0,0 +1,127 @@
+//! Flood protection for outgoing messages.
+//!
+//! Servers allow a short burst of messages and then roughly one every
+//! couple of seconds; clients which send faster are disconnected with
+//! "Excess Flood".  `RateLimiter` is a token bucket holding `burst`
+//! tokens, refilled at one per `interval`.  Each command costs its
+//! weight in tokens, one unless set otherwise, but never more than a
+//! full bucket.
+
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    burst: u32,
+    interval: Duration,
+    weights: HashMap<String, u32>,
+    /// When the bucket will be full again, if it isn't already
+    refilled_at: Option<Instant>,
+}
+
+impl RateLimiter {
+    /// A bucket of `burst` tokens, refilled at one per `interval`.  A
+    /// `burst` of zero is taken as one, since nothing could be sent.
+    pub fn new(burst: u32, interval: Duration) -> RateLimiter {
+        RateLimiter {
+            burst: max(burst, 1),
+            interval: interval,
+            weights: HashMap::new(),
+            refilled_at: None,
+        }
+    }
+
+    /// Five messages, then one every two seconds; safe on most networks.
+    pub fn conservative() -> RateLimiter {
+        RateLimiter::new(5, Duration::from_secs(2))
+    }
+
+    /// Make `command` cost `weight` tokens.  Heavy commands such as WHO
+    /// or LIST are worth weighting more.
+    pub fn set_weight(&mut self, command: &str, weight: u32) {
+        self.weights.insert(command.to_ascii_uppercase(), weight);
+    }
+
+    pub fn get_weight(&self, command: &str) -> u32 {
+        self.weights.get(&command.to_ascii_uppercase()).cloned().unwrap_or(1)
+    }
+
+    /// The debt after sending `command` at `now`, measured as time until
+    /// the bucket is full again.
+    fn debt_after(&self, command: &str, now: Instant) -> Duration {
+        let debt = match self.refilled_at {
+            Some(at) if now < at => at - now,
+            _ => Duration::from_secs(0),
+        };
+        // Heavier commands wait for a full bucket, rather than forever.
+        debt + self.interval * min(self.get_weight(command), self.burst)
+    }
+
+    /// How long to wait before `command` may be sent.
+    pub fn time_until(&self, command: &str, now: Instant) -> Duration {
+        let allowed = self.interval * self.burst;
+        let debt = self.debt_after(command, now);
+        if debt <= allowed {
+            Duration::from_secs(0)
+        } else {
+            debt - allowed
+        }
+    }
+
+    /// Take the tokens for `command` if there are enough.
+    pub fn try_acquire(&mut self, command: &str, now: Instant) -> bool {
+        if self.time_until(command, now) != Duration::from_secs(0) {
+            return false;
+        }
+        self.refilled_at = Some(now + self.debt_after(command, now));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use super::RateLimiter;
+
+    #[test]
+    fn burst_then_interval() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(2, Duration::from_secs(2));
+        limiter.set_weight("who", 2);
+        assert!(limiter.try_acquire("PRIVMSG", now));
+        assert!(limiter.try_acquire("PRIVMSG", now));
+        assert!(!limiter.try_acquire("PRIVMSG", now));
+        assert_eq!(limiter.time_until("PRIVMSG", now), Duration::from_secs(2));
+        assert_eq!(limiter.time_until("WHO", now), Duration::from_secs(4));
+
+        let later = now + Duration::from_secs(2);
+        assert!(limiter.try_acquire("PRIVMSG", later));
+        assert!(!limiter.try_acquire("PRIVMSG", later));
+
+        let much_later = now + Duration::from_secs(60);
+        assert!(limiter.try_acquire("WHO", much_later));
+        assert!(!limiter.try_acquire("WHO", much_later));
+    }
+
+    #[test]
+    fn heavier_than_burst() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(2, Duration::from_secs(2));
+        limiter.set_weight("WHO", 5);
+        assert_eq!(limiter.time_until("WHO", now), Duration::from_secs(0));
+        assert!(limiter.try_acquire("WHO", now));
+        assert!(!limiter.try_acquire("PRIVMSG", now));
+        assert_eq!(limiter.time_until("WHO", now), Duration::from_secs(4));
+        assert!(limiter.try_acquire("WHO", now + Duration::from_secs(4)));
+    }
+
+    #[test]
+    fn zero_burst_is_one() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(0, Duration::from_secs(2));
+        assert!(limiter.try_acquire("PRIVMSG", now));
+        assert!(!limiter.try_acquire("PRIVMSG", now));
+        assert_eq!(limiter.time_until("PRIVMSG", now), Duration::from_secs(2));
+    }
+}
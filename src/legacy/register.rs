@@ -0,0 +1,408 @@
+//! Registering a connection: PASS, NICK and USER, then waiting for
+//! RPL_WELCOME.
+//!
+//! `RegisterReq` produces the messages to send and follows the replies,
+//! trying the alternate nicks in turn if the server refuses one, then
+//! whatever its `NickGenerator` comes up with.  It does no I/O; send what
+//! it returns, for instance through `IrcSession::send`.
+
+use std::fmt;
+
+use ::MessageError;
+use ::parse_helpers::{has_forbidden_byte, is_middle_arg};
+use super::IrcMsg;
+use super::message_types::client;
+use super::watchers::{RegisterError, RegisterErrorType};
+
+/// RFC 2812 user mode bits for USER
+const USER_MODE_WALLOPS: u8 = 4;
+const USER_MODE_INVISIBLE: u8 = 8;
+
+/// Comes up with nicks to try when the ones asked for are refused.
+/// Generators must be `Clone`, so that a `RegisterReq` can be.
+pub trait NickGenerator: NickGeneratorClone {
+    /// The nick to try after `attempt` generated nicks (starting from 1)
+    /// based on `wanted`, or `None` to give up.
+    fn next_nick(&mut self, wanted: &str, attempt: usize) -> Option<String>;
+}
+
+/// Clones a boxed `NickGenerator`.  Implemented for every generator
+/// which is `Clone`.
+pub trait NickGeneratorClone {
+    fn clone_box(&self) -> Box<NickGenerator+Send>;
+}
+
+impl<T> NickGeneratorClone for T where T: NickGenerator + Clone + Send + 'static {
+    fn clone_box(&self) -> Box<NickGenerator+Send> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<NickGenerator+Send> {
+    fn clone(&self) -> Box<NickGenerator+Send> {
+        self.clone_box()
+    }
+}
+
+/// Appends `_`, then `1`, `2` and so on: `bot_`, `bot1`, `bot2`.
+#[derive(Clone, Debug)]
+pub struct SuffixNickGenerator {
+    max_attempts: usize,
+}
+
+impl SuffixNickGenerator {
+    pub fn new() -> SuffixNickGenerator {
+        SuffixNickGenerator::with_max_attempts(3)
+    }
+
+    /// With `0`, registration fails as soon as the nicks given are used up.
+    pub fn with_max_attempts(max_attempts: usize) -> SuffixNickGenerator {
+        SuffixNickGenerator { max_attempts: max_attempts }
+    }
+}
+
+impl NickGenerator for SuffixNickGenerator {
+    fn next_nick(&mut self, wanted: &str, attempt: usize) -> Option<String> {
+        match attempt {
+            _ if self.max_attempts < attempt => None,
+            1 => Some(format!("{}_", wanted)),
+            n => Some(format!("{}{}", wanted, n - 1)),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RegisterReqBuilder {
+    nick: String,
+    username: String,
+    realname: Option<String>,
+    password: Option<String>,
+    invisible: bool,
+    wallops: bool,
+    alternate_nicks: Vec<String>,
+    nick_generator: Box<NickGenerator+Send>,
+}
+
+impl fmt::Debug for RegisterReqBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RegisterReqBuilder")
+            .field("nick", &self.nick)
+            .field("username", &self.username)
+            .field("alternate_nicks", &self.alternate_nicks)
+            .finish()
+    }
+}
+
+impl RegisterReqBuilder {
+    pub fn new(nick: &str, username: &str) -> RegisterReqBuilder {
+        RegisterReqBuilder {
+            nick: nick.to_string(),
+            username: username.to_string(),
+            realname: None,
+            password: None,
+            invisible: false,
+            wallops: false,
+            alternate_nicks: Vec::new(),
+            nick_generator: Box::new(SuffixNickGenerator::new()),
+        }
+    }
+
+    /// Defaults to the username.
+    pub fn realname(mut self, realname: &str) -> RegisterReqBuilder {
+        self.realname = Some(realname.to_string());
+        self
+    }
+
+    /// The server password, sent with PASS.
+    pub fn password(mut self, password: &str) -> RegisterReqBuilder {
+        self.password = Some(password.to_string());
+        self
+    }
+
+    /// Ask for user mode `+i`.
+    pub fn invisible(mut self, invisible: bool) -> RegisterReqBuilder {
+        self.invisible = invisible;
+        self
+    }
+
+    /// Ask for user mode `+w`.
+    pub fn wallops(mut self, wallops: bool) -> RegisterReqBuilder {
+        self.wallops = wallops;
+        self
+    }
+
+    /// A nick to try if the ones before it are refused.  May be given
+    /// more than once.
+    pub fn alternate_nick(mut self, nick: &str) -> RegisterReqBuilder {
+        self.alternate_nicks.push(nick.to_string());
+        self
+    }
+
+    /// Used once the alternate nicks run out.  Defaults to a
+    /// `SuffixNickGenerator`.
+    pub fn nick_generator(mut self, generator: Box<NickGenerator+Send>) -> RegisterReqBuilder {
+        self.nick_generator = generator;
+        self
+    }
+
+    /// Fails if a nick or the username can't be sent as a single
+    /// argument, or if anything would smuggle a CR, LF or NUL into the
+    /// messages.
+    pub fn build(self) -> Result<RegisterReq, MessageError> {
+        for nick in Some(&self.nick).into_iter().chain(self.alternate_nicks.iter()) {
+            try!(check_no_forbidden(nick));
+            if !is_middle_arg(nick.as_bytes()) {
+                return Err(MessageError::BadTarget);
+            }
+        }
+        try!(check_no_forbidden(&self.username));
+        if !is_middle_arg(self.username.as_bytes()) {
+            return Err(MessageError::BadArgument);
+        }
+        if let Some(ref realname) = self.realname {
+            try!(check_no_forbidden(realname));
+        }
+        if let Some(ref password) = self.password {
+            try!(check_no_forbidden(password));
+        }
+
+        Ok(RegisterReq {
+            current_nick: self.nick.clone(),
+            nick: self.nick,
+            username: self.username,
+            realname: self.realname,
+            password: self.password,
+            invisible: self.invisible,
+            wallops: self.wallops,
+            alternate_nicks: self.alternate_nicks,
+            nick_generator: self.nick_generator,
+            attempts: 0,
+            finished: false,
+        })
+    }
+}
+
+fn check_no_forbidden(text: &str) -> Result<(), MessageError> {
+    if has_forbidden_byte(text.as_bytes()) {
+        return Err(MessageError::ForbiddenByte);
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+pub enum RegisterStatus {
+    /// Still waiting for the server
+    Pending,
+    /// The last nick was refused; send this NICK with the next one
+    Retry(IrcMsg),
+    /// Registered under the given nick
+    Registered(String),
+    Failed(RegisterError),
+}
+
+#[derive(Clone)]
+pub struct RegisterReq {
+    nick: String,
+    current_nick: String,
+    username: String,
+    realname: Option<String>,
+    password: Option<String>,
+    invisible: bool,
+    wallops: bool,
+    alternate_nicks: Vec<String>,
+    nick_generator: Box<NickGenerator+Send>,
+    /// How many nicks have been refused
+    attempts: usize,
+    finished: bool,
+}
+
+impl fmt::Debug for RegisterReq {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RegisterReq")
+            .field("nick", &self.nick)
+            .field("current_nick", &self.current_nick)
+            .field("attempts", &self.attempts)
+            .field("finished", &self.finished)
+            .finish()
+    }
+}
+
+impl RegisterReq {
+    /// The nick most recently asked for.
+    pub fn get_nick(&self) -> &str {
+        &self.current_nick
+    }
+
+    fn next_nick(&mut self) -> Option<String> {
+        let alternates = self.alternate_nicks.len();
+        if self.attempts < alternates {
+            return Some(self.alternate_nicks[self.attempts].clone());
+        }
+        self.nick_generator.next_nick(&self.nick, self.attempts - alternates + 1)
+    }
+
+    fn user_mode(&self) -> u8 {
+        let mut mode = 0;
+        if self.wallops {
+            mode |= USER_MODE_WALLOPS;
+        }
+        if self.invisible {
+            mode |= USER_MODE_INVISIBLE;
+        }
+        mode
+    }
+
+    /// The messages which begin registration, in the order to send them.
+    pub fn start(&self) -> Vec<IrcMsg> {
+        let mut out = Vec::new();
+        if let Some(ref password) = self.password {
+            out.push(client::Pass::new(password).into_irc_msg());
+        }
+        out.push(client::Nick::new(self.get_nick()).into_irc_msg());
+        let realname = self.realname.as_ref().unwrap_or(&self.username);
+        let mode = self.user_mode().to_string();
+        out.push(client::User::new(&self.username, &mode, "*", realname).into_irc_msg());
+        out
+    }
+
+    /// Feed an incoming message.  Messages after registration has
+    /// finished are ignored.
+    pub fn on_irc_msg(&mut self, msg: &IrcMsg) -> RegisterStatus {
+        if self.finished {
+            return RegisterStatus::Pending;
+        }
+        if msg.get_command() == "001" && msg.len() > 0 {
+            self.finished = true;
+            let nick = String::from_utf8_lossy(&msg[0]).into_owned();
+            return RegisterStatus::Registered(nick);
+        }
+        let errtype = match msg.get_command().parse().ok()
+            .and_then(RegisterErrorType::from_ord_known)
+        {
+            Some(errtype) => errtype,
+            None => return RegisterStatus::Pending,
+        };
+        let nick_refused = match errtype {
+            RegisterErrorType::NicknameInUse |
+            RegisterErrorType::UnavailableResource |
+            RegisterErrorType::ErroneousNickname |
+            RegisterErrorType::NicknameCollision => true,
+            _ => false,
+        };
+        if nick_refused {
+            if let Some(nick) = self.next_nick() {
+                self.attempts += 1;
+                self.current_nick = nick;
+                return RegisterStatus::Retry(client::Nick::new(&self.current_nick).into_irc_msg());
+            }
+        }
+        self.finished = true;
+        RegisterStatus::Failed(RegisterError {
+            errtype: errtype,
+            message: msg.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::MessageError;
+    use super::super::IrcMsg;
+    use super::super::watchers::RegisterErrorType;
+    use super::{RegisterReqBuilder, RegisterStatus, SuffixNickGenerator};
+
+    fn msg(line: &[u8]) -> IrcMsg {
+        IrcMsg::new(line.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn register_with_password_and_fallback() {
+        let mut req = RegisterReqBuilder::new("bot", "botuser")
+            .password("secret")
+            .realname("A Bot")
+            .invisible(true)
+            .alternate_nick("bot_")
+            .build().unwrap();
+        let lines: Vec<Vec<u8>> = req.start().into_iter().map(|m| m.into_bytes()).collect();
+        assert_eq!(lines, vec![
+            b"PASS :secret".to_vec(),
+            b"NICK bot".to_vec(),
+            b"USER botuser 8 * :A Bot".to_vec(),
+        ]);
+
+        match req.on_irc_msg(&msg(b":srv 433 * bot :Nickname is already in use")) {
+            RegisterStatus::Retry(ref nick) => assert_eq!(nick.as_bytes(), b"NICK bot_"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(req.get_nick(), "bot_");
+
+        match req.on_irc_msg(&msg(b":srv 001 bot_ :Welcome")) {
+            RegisterStatus::Registered(ref nick) => assert_eq!(nick, "bot_"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn register_fails_without_alternates() {
+        let mut req = RegisterReqBuilder::new("bot", "bot")
+            .wallops(true)
+            .nick_generator(Box::new(SuffixNickGenerator::with_max_attempts(0)))
+            .build().unwrap();
+        assert_eq!(req.start()[1].as_bytes(), b"USER bot 4 * :bot");
+        match req.on_irc_msg(&msg(b":srv 432 * bot :Erroneous nickname")) {
+            RegisterStatus::Failed(ref err) => {
+                assert_eq!(err.errtype, RegisterErrorType::ErroneousNickname);
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn register_generates_nicks() {
+        let mut req = RegisterReqBuilder::new("bot", "bot").alternate_nick("robot").build().unwrap();
+        let in_use = msg(b":srv 433 * x :Nickname is already in use");
+        let mut tried = Vec::new();
+        loop {
+            match req.on_irc_msg(&in_use) {
+                RegisterStatus::Retry(_) => tried.push(req.get_nick().to_string()),
+                RegisterStatus::Failed(_) => break,
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert_eq!(tried, vec!["robot", "bot_", "bot1", "bot2"]);
+    }
+
+    #[test]
+    fn register_req_clones() {
+        let builder = RegisterReqBuilder::new("bot", "bot")
+            .nick_generator(Box::new(SuffixNickGenerator::with_max_attempts(1)));
+        let mut req = builder.clone().build().unwrap();
+        let in_use = msg(b":srv 433 * x :Nickname is already in use");
+        let mut copy = req.clone();
+        match req.on_irc_msg(&in_use) {
+            RegisterStatus::Retry(_) => assert_eq!(req.get_nick(), "bot_"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(copy.get_nick(), "bot");
+        match copy.on_irc_msg(&in_use) {
+            RegisterStatus::Retry(_) => assert_eq!(copy.get_nick(), "bot_"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(builder.build().unwrap().get_nick(), "bot");
+    }
+
+    #[test]
+    fn register_rejects_bad_input() {
+        let build = |builder: RegisterReqBuilder| builder.build().unwrap_err();
+        assert_eq!(build(RegisterReqBuilder::new("bot", "bot").password("a\r\nQUIT :pwned")),
+            MessageError::ForbiddenByte);
+        assert_eq!(build(RegisterReqBuilder::new("bot", "user name")), MessageError::BadArgument);
+        assert_eq!(build(RegisterReqBuilder::new("bot", ":user")), MessageError::BadArgument);
+        assert_eq!(build(RegisterReqBuilder::new("", "bot")), MessageError::BadTarget);
+        assert_eq!(build(RegisterReqBuilder::new("bot", "bot").alternate_nick("b t")),
+            MessageError::BadTarget);
+        assert_eq!(build(RegisterReqBuilder::new("bot", "bot").realname("A\nBot")),
+            MessageError::ForbiddenByte);
+        assert!(RegisterReqBuilder::new("bot", "bot").realname("A Bot").password("").build().is_ok());
+    }
+}
@@ -0,0 +1,179 @@
+//! Retrying with exponential backoff.
+//!
+//! A `RetryPolicy` says how often to try something again: the delay
+//! doubles from `base_delay` after each failure, up to `max_delay`, with
+//! up to `jitter` added so that many clients dropped at once don't all
+//! come back at once.  A `Backoff` follows one thing being retried under
+//! a policy.  Like `Joiner` and `WhoRefresh`, neither touches the clock:
+//! callers add the delays to their own `Instant`s.
+
+use std::cmp::min;
+use std::fmt;
+use std::time::Duration;
+
+use ::rng::{Rng, XorShiftRng};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    max_attempts: Option<u32>,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: Duration,
+}
+
+impl RetryPolicy {
+    /// Retry forever, waiting `base_delay` at first and at most 64 times
+    /// that, without jitter.
+    pub fn new(base_delay: Duration) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: None,
+            base_delay: base_delay,
+            max_delay: base_delay.checked_mul(64).unwrap_or(base_delay),
+            jitter: Duration::from_secs(0),
+        }
+    }
+
+    /// Give up after `max_attempts` retries.
+    pub fn max_attempts(mut self, max_attempts: u32) -> RetryPolicy {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    /// Never wait longer than `max_delay`, before jitter.
+    pub fn max_delay(mut self, max_delay: Duration) -> RetryPolicy {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Add a random delay of up to `jitter` to each wait.
+    pub fn jitter(mut self, jitter: Duration) -> RetryPolicy {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn get_max_attempts(&self) -> Option<u32> {
+        self.max_attempts
+    }
+
+    pub fn get_base_delay(&self) -> Duration {
+        self.base_delay
+    }
+
+    pub fn get_max_delay(&self) -> Duration {
+        self.max_delay
+    }
+
+    pub fn get_jitter(&self) -> Duration {
+        self.jitter
+    }
+
+    /// The wait before retry `attempt`, counting from 1, without jitter.
+    /// `None` if the policy allows no such retry.
+    pub fn delay(&self, attempt: u32) -> Option<Duration> {
+        if attempt == 0 || self.max_attempts.map(|max| max < attempt).unwrap_or(false) {
+            return None;
+        }
+        let doublings = min(attempt - 1, 31);
+        let delay = self.base_delay.checked_mul(1 << doublings).unwrap_or(self.max_delay);
+        Some(min(delay, self.max_delay))
+    }
+
+    pub fn backoff(&self) -> Backoff {
+        Backoff::new(*self)
+    }
+}
+
+/// The retries of one thing under a `RetryPolicy`.
+pub struct Backoff {
+    policy: RetryPolicy,
+    attempts: u32,
+    rng: Box<Rng+Send>,
+}
+
+impl fmt::Debug for Backoff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Backoff")
+            .field("policy", &self.policy)
+            .field("attempts", &self.attempts)
+            .finish()
+    }
+}
+
+impl Backoff {
+    pub fn new(policy: RetryPolicy) -> Backoff {
+        Backoff {
+            policy: policy,
+            attempts: 0,
+            rng: Box::new(XorShiftRng::new()),
+        }
+    }
+
+    /// Draw the jitter from `rng`.
+    pub fn rng(mut self, rng: Box<Rng+Send>) -> Backoff {
+        self.rng = rng;
+        self
+    }
+
+    pub fn get_policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+
+    /// Failures counted since the last success.
+    pub fn get_attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Whether the policy allows no more retries.
+    pub fn is_exhausted(&self) -> bool {
+        self.policy.delay(self.attempts + 1).is_none()
+    }
+
+    /// Count a failure, returning how long to wait before retrying, or
+    /// `None` if we should give up.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        let delay = match self.policy.delay(self.attempts + 1) {
+            Some(delay) => delay,
+            None => return None,
+        };
+        self.attempts += 1;
+        Some(self.rng.jitter(delay, self.policy.jitter))
+    }
+
+    /// Start over after a success.
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use ::testinfra::rng::SequenceRng;
+    use super::RetryPolicy;
+
+    #[test]
+    fn doubles_up_to_limits() {
+        let secs = Duration::from_secs;
+        let policy = RetryPolicy::new(secs(2)).max_delay(secs(10)).max_attempts(4);
+        assert_eq!(policy.delay(0), None);
+        assert_eq!(policy.delay(1), Some(secs(2)));
+        assert_eq!(policy.delay(3), Some(secs(8)));
+        assert_eq!(policy.delay(4), Some(secs(10)));
+        assert_eq!(policy.delay(5), None);
+        assert_eq!(RetryPolicy::new(secs(1)).delay(1000), Some(secs(64)));
+
+        let mut backoff = policy.backoff();
+        let delays: Vec<_> = (0..5).map(|_| backoff.next_delay()).collect();
+        assert_eq!(delays, vec![Some(secs(2)), Some(secs(4)), Some(secs(8)), Some(secs(10)), None]);
+        assert!(backoff.is_exhausted());
+        assert_eq!(backoff.get_attempts(), 4);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Some(secs(2)));
+
+        let jittery = RetryPolicy::new(secs(2)).jitter(secs(1));
+        let mut backoff = jittery.backoff().rng(Box::new(SequenceRng::new(vec![0, 250, 1000])));
+        let delays: Vec<_> = (0..3).map(|_| backoff.next_delay().unwrap()).collect();
+        assert_eq!(delays, vec![secs(2), Duration::from_millis(4250), secs(9)]);
+    }
+}
@@ -0,0 +1,382 @@
+//! Talking to NickServ and ChanServ.
+//!
+//! Services are driven with PRIVMSGs and answer in free-form NOTICEs
+//! whose wording depends on the services package.  `Services` sends
+//! requests and matches the replies against a `ServicesDialect`, a table
+//! of reply fragments, handing back a `ServicesReply` once a request's
+//! outcome is known.  Requests are answered in the order they were
+//! sent, so only the oldest outstanding one is matched at a time.
+//!
+//! Some replies have no recognizable last line, such as Anope's INFO.
+//! Call `flush` after a suitable timeout to take what has arrived.
+
+use std::collections::VecDeque;
+
+use irccase::IrcAsciiExt;
+use ::client::Sanitizer;
+use super::IrcMsg;
+use super::message_types::client;
+
+/// How a services package words its commands and replies.  Reply
+/// fragments are matched case-insensitively against whole lines, with
+/// formatting removed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServicesDialect {
+    pub nickserv: String,
+    pub chanserv: String,
+    /// Whether IDENTIFY accepts an account name before the password
+    pub identify_takes_account: bool,
+    /// The NickServ command which disconnects whoever holds our nick
+    pub ghost_command: String,
+    /// The ChanServ command listing a channel's access, with `{}` in
+    /// place of the channel
+    pub access_list_command: String,
+    /// Whether access list entries give the mask before the level
+    pub access_mask_first: bool,
+    pub identified: Vec<String>,
+    pub bad_password: Vec<String>,
+    pub ghosted: Vec<String>,
+    pub not_registered: Vec<String>,
+    pub not_online: Vec<String>,
+    pub access_denied: Vec<String>,
+    /// The last line of an INFO reply; empty if there isn't one
+    pub end_of_info: Vec<String>,
+    /// The last line of an access list
+    pub end_of_list: Vec<String>,
+}
+
+fn strings(fragments: &[&str]) -> Vec<String> {
+    fragments.iter().map(|fragment| fragment.to_string()).collect()
+}
+
+impl ServicesDialect {
+    /// Atheme, as run by Libera.Chat and many smaller networks.
+    pub fn atheme() -> ServicesDialect {
+        ServicesDialect {
+            nickserv: "NickServ".to_string(),
+            chanserv: "ChanServ".to_string(),
+            identify_takes_account: true,
+            ghost_command: "GHOST".to_string(),
+            access_list_command: "FLAGS {}".to_string(),
+            access_mask_first: true,
+            identified: strings(&["you are now identified", "you are already logged in"]),
+            bad_password: strings(&["invalid password"]),
+            ghosted: strings(&["has been ghosted"]),
+            not_registered: strings(&["is not registered"]),
+            not_online: strings(&["is not online"]),
+            access_denied: strings(&["you are not authorized", "you do not have access"]),
+            end_of_info: strings(&["end of info"]),
+            end_of_list: strings(&["end of flags listing"]),
+        }
+    }
+
+    /// Anope 2.0.
+    pub fn anope() -> ServicesDialect {
+        ServicesDialect {
+            nickserv: "NickServ".to_string(),
+            chanserv: "ChanServ".to_string(),
+            identify_takes_account: true,
+            ghost_command: "RECOVER".to_string(),
+            access_list_command: "ACCESS {} LIST".to_string(),
+            access_mask_first: false,
+            identified: strings(&["password accepted", "you are now identified"]),
+            bad_password: strings(&["password incorrect"]),
+            ghosted: strings(&["has been recovered", "has been ghosted"]),
+            not_registered: strings(&["isn't registered", "is not registered"]),
+            not_online: strings(&["isn't currently in use"]),
+            access_denied: strings(&["access denied", "permission denied"]),
+            end_of_info: Vec::new(),
+            end_of_list: strings(&["end of access list"]),
+        }
+    }
+
+    /// The dialect of a network we know, by its ISUPPORT NETWORK name.
+    pub fn for_network(network: &str) -> Option<ServicesDialect> {
+        match &network.to_ascii_lowercase()[..] {
+            "libera.chat" | "hackint" | "esper.net" => Some(ServicesDialect::atheme()),
+            "rizon" | "swiftirc" => Some(ServicesDialect::anope()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServicesRequest {
+    /// Log in, to `account` or the one matching our nick
+    Identify { account: Option<String>, password: String },
+    /// Disconnect whoever is using `nick`
+    Ghost { nick: String, password: Option<String> },
+    /// NickServ's information about a nick
+    NickInfo(String),
+    /// ChanServ's information about a channel
+    ChanInfo(String),
+    /// ChanServ's access list for a channel
+    AccessList(String),
+}
+
+/// A channel access list entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AccessEntry {
+    /// An account name or hostmask
+    pub mask: String,
+    /// A numeric level or a set of flags, depending on the dialect
+    pub level: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServicesReply {
+    Identified,
+    Ghosted,
+    BadPassword,
+    NotRegistered,
+    NotOnline,
+    AccessDenied,
+    /// The lines of an INFO reply
+    Info(Vec<String>),
+    AccessList(Vec<AccessEntry>),
+    /// No recognizable reply arrived before `flush`
+    Unanswered,
+}
+
+#[derive(Debug)]
+struct Pending {
+    request: ServicesRequest,
+    lines: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct Services {
+    dialect: ServicesDialect,
+    pending: VecDeque<Pending>,
+    sanitizer: Sanitizer,
+}
+
+impl Services {
+    pub fn new(dialect: ServicesDialect) -> Services {
+        Services {
+            dialect: dialect,
+            pending: VecDeque::new(),
+            sanitizer: Sanitizer::new().strip_formatting(true),
+        }
+    }
+
+    pub fn get_dialect(&self) -> &ServicesDialect {
+        &self.dialect
+    }
+
+    /// Whether any request is still waiting for its reply.
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// The message to send for `request`, which is remembered so that
+    /// its reply can be recognized.
+    pub fn request(&mut self, request: ServicesRequest) -> IrcMsg {
+        let command = match request {
+            ServicesRequest::Identify { ref account, ref password } => {
+                match *account {
+                    Some(ref account) if self.dialect.identify_takes_account =>
+                        format!("IDENTIFY {} {}", account, password),
+                    _ => format!("IDENTIFY {}", password),
+                }
+            },
+            ServicesRequest::Ghost { ref nick, ref password } => {
+                match *password {
+                    Some(ref password) =>
+                        format!("{} {} {}", self.dialect.ghost_command, nick, password),
+                    None => format!("{} {}", self.dialect.ghost_command, nick),
+                }
+            },
+            ServicesRequest::NickInfo(ref nick) => format!("INFO {}", nick),
+            ServicesRequest::ChanInfo(ref channel) => format!("INFO {}", channel),
+            ServicesRequest::AccessList(ref channel) =>
+                self.dialect.access_list_command.replace("{}", channel),
+        };
+        let msg = client::Privmsg::new(self.service_for(&request), command.as_bytes()).into_irc_msg();
+        self.pending.push_back(Pending { request: request, lines: Vec::new() });
+        msg
+    }
+
+    /// Feed an incoming message.  Returns the oldest request along with
+    /// its reply once the reply is complete.
+    pub fn on_irc_msg(&mut self, msg: &IrcMsg) -> Option<(ServicesRequest, ServicesReply)> {
+        match msg.get_command() {
+            "NOTICE" | "PRIVMSG" if msg.len() > 1 => (),
+            _ => return None,
+        }
+        let is_from_service = match (msg.get_prefix().nick(), self.pending.front()) {
+            (Some(nick), Some(pending)) => nick.eq_ignore_irc_case(self.service_for(&pending.request)),
+            _ => false,
+        };
+        if !is_from_service {
+            return None;
+        }
+        let line = String::from_utf8_lossy(&self.sanitizer.sanitize(&msg[1])).into_owned();
+        let reply = match self.on_line(line) {
+            Some(reply) => reply,
+            None => return None,
+        };
+        self.pending.pop_front().map(|pending| (pending.request, reply))
+    }
+
+    /// Stop waiting for the oldest request's reply, returning whatever
+    /// has arrived of it.
+    pub fn flush(&mut self) -> Option<(ServicesRequest, ServicesReply)> {
+        self.pending.pop_front().map(|pending| {
+            let reply = match pending.request {
+                ServicesRequest::NickInfo(_) | ServicesRequest::ChanInfo(_)
+                    if !pending.lines.is_empty() => ServicesReply::Info(pending.lines),
+                ServicesRequest::AccessList(_) if !pending.lines.is_empty() =>
+                    ServicesReply::AccessList(parse_access_entries(&self.dialect, &pending.lines)),
+                _ => ServicesReply::Unanswered,
+            };
+            (pending.request, reply)
+        })
+    }
+
+    fn service_for(&self, request: &ServicesRequest) -> &str {
+        match *request {
+            ServicesRequest::Identify { .. } |
+            ServicesRequest::Ghost { .. } |
+            ServicesRequest::NickInfo(_) => &self.dialect.nickserv,
+            ServicesRequest::ChanInfo(_) |
+            ServicesRequest::AccessList(_) => &self.dialect.chanserv,
+        }
+    }
+
+    /// The reply completed by `line`, if it completes one.
+    fn on_line(&mut self, line: String) -> Option<ServicesReply> {
+        let lower = line.to_lowercase();
+        let matches = |fragments: &[String]| {
+            fragments.iter().any(|fragment| lower.contains(&fragment.to_lowercase()[..]))
+        };
+        let dialect = &self.dialect;
+        let pending = self.pending.front_mut().unwrap();
+
+        if matches(&dialect.access_denied) {
+            return Some(ServicesReply::AccessDenied);
+        }
+        if matches(&dialect.not_registered) {
+            return Some(ServicesReply::NotRegistered);
+        }
+        match pending.request {
+            ServicesRequest::Identify { .. } | ServicesRequest::Ghost { .. } => {
+                if matches(&dialect.identified) {
+                    Some(ServicesReply::Identified)
+                } else if matches(&dialect.ghosted) {
+                    Some(ServicesReply::Ghosted)
+                } else if matches(&dialect.bad_password) {
+                    Some(ServicesReply::BadPassword)
+                } else if matches(&dialect.not_online) {
+                    Some(ServicesReply::NotOnline)
+                } else {
+                    None
+                }
+            },
+            ServicesRequest::NickInfo(_) | ServicesRequest::ChanInfo(_) => {
+                let is_end = matches(&dialect.end_of_info);
+                pending.lines.push(line);
+                if is_end {
+                    Some(ServicesReply::Info(pending.lines.split_off(0)))
+                } else {
+                    None
+                }
+            },
+            ServicesRequest::AccessList(_) => {
+                if !matches(&dialect.end_of_list) {
+                    pending.lines.push(line);
+                    return None;
+                }
+                let lines = pending.lines.split_off(0);
+                Some(ServicesReply::AccessList(parse_access_entries(dialect, &lines)))
+            },
+        }
+    }
+}
+
+/// The entries among the lines of an access list, which are numbered;
+/// headers and other text are skipped.
+fn parse_access_entries(dialect: &ServicesDialect, lines: &[String]) -> Vec<AccessEntry> {
+    lines.iter().filter_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 || fields[0].parse::<u32>().is_err() {
+            return None;
+        }
+        let (mask, level) = if dialect.access_mask_first {
+            (fields[1], fields[2])
+        } else {
+            (fields[2], fields[1])
+        };
+        Some(AccessEntry { mask: mask.to_string(), level: level.to_string() })
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::IrcMsg;
+    use super::{AccessEntry, Services, ServicesDialect, ServicesReply, ServicesRequest};
+
+    fn msg(line: &[u8]) -> IrcMsg {
+        IrcMsg::new(line.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn identify_and_ghost() {
+        let mut services = Services::new(ServicesDialect::for_network("Libera.Chat").unwrap());
+        let identify = ServicesRequest::Identify {
+            account: Some("bot".to_string()),
+            password: "hunter2".to_string(),
+        };
+        assert_eq!(services.request(identify.clone()).into_bytes(),
+            b"PRIVMSG NickServ :IDENTIFY bot hunter2".to_vec());
+        let ghost = ServicesRequest::Ghost { nick: "bot".to_string(), password: None };
+        assert_eq!(services.request(ghost.clone()).into_bytes(), b"PRIVMSG NickServ :GHOST bot".to_vec());
+
+        // Neither from services nor a recognizable reply
+        assert_eq!(services.on_irc_msg(&msg(b":alice!u@h NOTICE bot_ :You are now identified")), None);
+        assert_eq!(services.on_irc_msg(&msg(b":NickServ!s@services. NOTICE bot_ :Last login from: x")), None);
+
+        assert_eq!(services.on_irc_msg(&msg(
+            b":NickServ!s@services. NOTICE bot_ :You are now identified for \x02bot\x02.")),
+            Some((identify, ServicesReply::Identified)));
+        assert_eq!(services.on_irc_msg(&msg(
+            b":nickserv!s@services. NOTICE bot_ :\x02bot\x02 has been ghosted.")),
+            Some((ghost, ServicesReply::Ghosted)));
+        assert!(!services.is_pending());
+    }
+
+    #[test]
+    fn lists_and_info() {
+        let mut services = Services::new(ServicesDialect::anope());
+        let access = ServicesRequest::AccessList("#rust".to_string());
+        assert_eq!(services.request(access.clone()).into_bytes(),
+            b"PRIVMSG ChanServ :ACCESS #rust LIST".to_vec());
+        let info = ServicesRequest::NickInfo("alice".to_string());
+        services.request(info.clone());
+
+        for line in &[
+            &b":ChanServ!s@h NOTICE bot :Access list for #rust:"[..],
+            b":ChanServ!s@h NOTICE bot :  Number  Level  Mask",
+            b":ChanServ!s@h NOTICE bot :  1       10000  alice",
+            b":ChanServ!s@h NOTICE bot :  2       5      bob!*@*",
+        ] {
+            assert_eq!(services.on_irc_msg(&msg(line)), None);
+        }
+        assert_eq!(services.on_irc_msg(&msg(b":ChanServ!s@h NOTICE bot :End of access list")),
+            Some((access, ServicesReply::AccessList(vec![
+                AccessEntry { mask: "alice".to_string(), level: "10000".to_string() },
+                AccessEntry { mask: "bob!*@*".to_string(), level: "5".to_string() },
+            ]))));
+
+        // Anope's INFO has no last line to wait for.
+        assert_eq!(services.on_irc_msg(&msg(b":NickServ!s@h NOTICE bot :alice is Alice")), None);
+        assert_eq!(services.flush(),
+            Some((info, ServicesReply::Info(vec!["alice is Alice".to_string()]))));
+
+        let info = ServicesRequest::ChanInfo("#nope".to_string());
+        services.request(info.clone());
+        assert_eq!(services.on_irc_msg(&msg(b":ChanServ!s@h NOTICE bot :Channel #nope isn't registered.")),
+            Some((info, ServicesReply::NotRegistered)));
+        assert_eq!(services.flush(), None);
+    }
+}
@@ -0,0 +1,429 @@
+//! A connection without I/O.
+//!
+//! `IrcSession` owns no socket and no threads.  The caller reads bytes
+//! from wherever it likes and hands them to `feed`, takes events from
+//! `poll_event`, and writes out whatever `pending_output` returns once the
+//! socket is writable.  This fits readiness-based event loops such as mio
+//! as well as plain blocking code.
+//!
+//! With a `RateLimiter` set, sent messages are held back instead: take
+//! them one at a time with `pop_line`, waiting as long as
+//! `time_until_next` says in between.  Automatic PONGs skip the queue.
+//!
+//! With a `MsgIdCache` set, messages whose msgid tag was recently seen
+//! are dropped, as happens when a bouncer replays history.
+//!
+//! With a `Linter` set, sent messages are checked first.  Problems are
+//! logged, and if the linter rejects them the line is not queued but kept
+//! for `take_rejected` instead.
+
+use std::collections::VecDeque;
+use std::str;
+use std::time::{Duration, Instant};
+
+use ::IrcMsg as IrcMsgNew;
+use ::lint::{LintAction, LintError, Linter};
+use ::parse_helpers;
+use ::{MAX_MESSAGE_LEN, MAX_TAGS_LEN};
+use super::{BundlerManager, Interner, IrcEvent, IrcMsg, MsgIdCache, RateLimiter};
+
+pub struct IrcSession {
+    bundler: BundlerManager,
+    /// Bytes received but not yet terminated by a newline
+    inbound: Vec<u8>,
+    /// Bytes waiting to be written
+    outbound: Vec<u8>,
+    limiter: Option<RateLimiter>,
+    /// Lines held back by the rate limiter
+    held: VecDeque<Vec<u8>>,
+    linter: Option<Linter>,
+    /// Lines the linter refused to send
+    rejected: Vec<(Vec<u8>, LintError)>,
+    events: VecDeque<IrcEvent>,
+    /// Recently seen msgids, if duplicates are to be dropped
+    msgids: Option<MsgIdCache>,
+    /// Answer PINGs without involving the caller
+    auto_pong: bool,
+    /// Panic on lines which don't parse
+    strict: bool,
+}
+
+impl IrcSession {
+    pub fn new(bundler: BundlerManager) -> IrcSession {
+        IrcSession {
+            bundler: bundler,
+            inbound: Vec::new(),
+            outbound: Vec::new(),
+            limiter: None,
+            held: VecDeque::new(),
+            linter: None,
+            rejected: Vec::new(),
+            events: VecDeque::new(),
+            msgids: None,
+            auto_pong: true,
+            strict: false,
+        }
+    }
+
+    pub fn get_bundler(&self) -> &BundlerManager {
+        &self.bundler
+    }
+
+    pub fn get_bundler_mut(&mut self) -> &mut BundlerManager {
+        &mut self.bundler
+    }
+
+    /// The connection's interner, shared with its `BundlerManager`.
+    pub fn get_interner(&self) -> &Interner {
+        self.bundler.get_interner()
+    }
+
+    /// Whether PINGs are answered automatically, which is the default.
+    pub fn set_auto_pong(&mut self, auto_pong: bool) {
+        self.auto_pong = auto_pong;
+    }
+
+    /// Panic on lines which don't parse, rather than emitting
+    /// `IrcEvent::ParseFailure`.  Meant for tests.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Hold sent messages back so as to stay within `limiter`.
+    pub fn set_rate_limiter(&mut self, limiter: RateLimiter) {
+        self.limiter = Some(limiter);
+    }
+
+    /// Check sent messages with `linter`, or stop checking with `None`,
+    /// the default.  Automatic PONGs aren't checked.
+    pub fn set_linter(&mut self, linter: Option<Linter>) {
+        self.linter = linter;
+    }
+
+    /// The lines a rejecting linter refused since the last call, with
+    /// what was wrong with them.
+    pub fn take_rejected(&mut self) -> Vec<(Vec<u8>, LintError)> {
+        ::std::mem::replace(&mut self.rejected, Vec::new())
+    }
+
+    /// Drop messages whose msgid is in `cache`, adding the msgids of
+    /// those passed on.  Off, with `None`, by default.
+    pub fn set_msgid_cache(&mut self, cache: Option<MsgIdCache>) {
+        self.msgids = cache;
+    }
+
+    pub fn get_msgid_cache(&self) -> Option<&MsgIdCache> {
+        self.msgids.as_ref()
+    }
+
+    /// Take bytes read from the server at `now`.  They need not be whole
+    /// lines.  Lines which don't parse become `IrcEvent::ParseFailure`s.
+    pub fn feed(&mut self, data: &[u8], now: Instant) {
+        self.inbound.extend(data);
+        let mut start = 0;
+        while let Some(len) = self.inbound[start..].iter().position(|&b| b == b'\n') {
+            let mut line = self.inbound[start..start + len].to_vec();
+            start += len + 1;
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            if !line.is_empty() {
+                self.on_line(line, now);
+            }
+        }
+        self.inbound.drain(..start);
+        if MAX_TAGS_LEN + MAX_MESSAGE_LEN < self.inbound.len() {
+            warn!("dropping {} bytes without a line ending", self.inbound.len());
+            self.inbound.clear();
+        }
+    }
+
+    fn on_line(&mut self, line: Vec<u8>, now: Instant) {
+        let msg = match IrcMsg::new(line.clone()) {
+            Ok(msg) => msg,
+            Err(err) => {
+                if self.strict {
+                    panic!("Invalid msg: {}: {:?}", err, String::from_utf8_lossy(&line));
+                }
+                warn!("unparseable line: {}", err);
+                self.events.push_back(IrcEvent::ParseFailure(line));
+                return;
+            }
+        };
+        if self.is_duplicate(&line) {
+            return;
+        }
+        if self.auto_pong && msg.get_command() == "PING" {
+            // Echo the arguments back exactly, whatever their form.
+            let (_prefix, rest) = parse_helpers::split_prefix(msg.as_bytes());
+            let (_command, args) = parse_helpers::split_command(rest);
+            let mut pong = b"PONG".to_vec();
+            if !args.is_empty() {
+                pong.push(b' ');
+                pong.extend(args);
+            }
+            self.queue_line(&pong);
+        }
+        self.events.extend(self.bundler.on_irc_msg(&msg, now));
+    }
+
+    fn is_duplicate(&mut self, line: &[u8]) -> bool {
+        let cache = match self.msgids {
+            Some(ref mut cache) => cache,
+            None => return false,
+        };
+        let msgid = match IrcMsgNew::new(line).ok().and_then(|msg| msg.msgid()) {
+            Some(msgid) => msgid,
+            None => return false,
+        };
+        if cache.observe(&msgid) {
+            debug!("dropping duplicate of msgid {:?}", msgid);
+            return true;
+        }
+        false
+    }
+
+    /// The next event, if any are ready.
+    pub fn poll_event(&mut self) -> Option<IrcEvent> {
+        self.events.pop_front()
+    }
+
+    /// Queue a message to be sent.
+    pub fn send(&mut self, msg: &IrcMsg) {
+        self.queue_limited(msg.as_bytes());
+    }
+
+    /// Queue a message from the new parser to be sent.
+    pub fn send_msg(&mut self, msg: &IrcMsgNew) {
+        self.queue_limited(msg.as_bytes());
+    }
+
+    fn queue_limited(&mut self, line: &[u8]) {
+        if let Some(ref linter) = self.linter {
+            if let Err(err) = linter.check(line) {
+                warn!("outgoing {:?}: {}", String::from_utf8_lossy(line), err);
+                if linter.get_action() == LintAction::Reject {
+                    self.rejected.push((line.to_vec(), err));
+                    return;
+                }
+            }
+        }
+        if self.limiter.is_some() {
+            self.held.push_back(line.to_vec());
+        } else {
+            self.queue_line(line);
+        }
+    }
+
+    fn next_held_command(&self) -> Option<&str> {
+        self.held.front().map(|line| {
+            let (_prefix, rest) = parse_helpers::split_prefix(line);
+            let (command, _args) = parse_helpers::split_command(rest);
+            str::from_utf8(command).unwrap_or("")
+        })
+    }
+
+    /// The next line held back by the rate limiter, with its line ending,
+    /// if it may be sent at `now`.
+    pub fn pop_line(&mut self, now: Instant) -> Option<Vec<u8>> {
+        let command = match self.next_held_command() {
+            Some(command) => command.to_string(),
+            None => return None,
+        };
+        let acquired = self.limiter.as_mut()
+            .map(|limiter| limiter.try_acquire(&command, now))
+            .unwrap_or(false);
+        if !acquired {
+            return None;
+        }
+        let mut line = self.held.pop_front().unwrap();
+        line.extend(b"\r\n");
+        Some(line)
+    }
+
+    /// How long until `pop_line` will return the next held line, or
+    /// `None` if no lines are held.
+    pub fn time_until_next(&self, now: Instant) -> Option<Duration> {
+        match (self.next_held_command(), self.limiter.as_ref()) {
+            (Some(command), Some(limiter)) => Some(limiter.time_until(command, now)),
+            _ => None,
+        }
+    }
+
+    fn queue_line(&mut self, line: &[u8]) {
+        self.outbound.extend(line);
+        self.outbound.extend(b"\r\n");
+    }
+
+    /// The bytes waiting to be written, apart from lines held back by the
+    /// rate limiter.  Call `consume_output` with however many of them
+    /// were written.
+    pub fn pending_output(&self) -> &[u8] {
+        &self.outbound
+    }
+
+    pub fn has_pending_output(&self) -> bool {
+        !self.outbound.is_empty()
+    }
+
+    pub fn consume_output(&mut self, written: usize) {
+        let written = ::std::cmp::min(written, self.outbound.len());
+        self.outbound.drain(..written);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::super::{BundlerManager, IrcEvent, IrcMsg, MsgIdCache, RateLimiter, SessionIdentity, State};
+    use super::IrcSession;
+
+    #[test]
+    fn session_without_io() {
+        let now = Instant::now();
+        let mut session = IrcSession::new(BundlerManager::new());
+        session.feed(b":srv 001 me :Welcome\r\nPING :srv.exa", now);
+        assert!(!session.has_pending_output());
+        session.feed(b"mple.net\r\n", now);
+
+        match session.poll_event() {
+            Some(IrcEvent::IrcMsg(ref msg)) => assert_eq!(msg.get_command(), "001"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(session.poll_event().is_some());
+        assert!(session.poll_event().is_none());
+        assert_eq!(session.pending_output(), b"PONG :srv.example.net\r\n");
+
+        session.consume_output(5);
+        assert_eq!(session.pending_output(), b":srv.example.net\r\n");
+        session.consume_output(18);
+        assert!(!session.has_pending_output());
+
+        session.feed(b"\xff garbage\r\n:srv 002 me :Next\r\n", now);
+        match session.poll_event() {
+            Some(IrcEvent::ParseFailure(ref line)) => assert_eq!(line, b"\xff garbage"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(session.poll_event().is_some());
+    }
+
+    #[test]
+    fn consume_more_than_pending() {
+        let mut session = IrcSession::new(BundlerManager::new());
+        session.feed(b"PING :a\r\nPING :b\r\n", Instant::now());
+        assert_eq!(session.pending_output(), b"PONG :a\r\nPONG :b\r\n");
+        session.consume_output(100);
+        assert!(!session.has_pending_output());
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid msg")]
+    fn strict_session_panics() {
+        let mut session = IrcSession::new(BundlerManager::new());
+        session.set_strict(true);
+        session.feed(b"\xff garbage\r\n", Instant::now());
+    }
+
+    #[test]
+    fn rate_limited_session() {
+        let now = Instant::now();
+        let mut session = IrcSession::new(BundlerManager::new());
+        session.set_rate_limiter(RateLimiter::new(1, Duration::from_secs(2)));
+        assert_eq!(session.time_until_next(now), None);
+
+        let msg = IrcMsg::new(b"PRIVMSG #a :hi".to_vec()).unwrap();
+        session.send(&msg);
+        session.send(&msg);
+        session.feed(b"PING :x\r\n", now);
+        assert_eq!(session.pending_output(), b"PONG :x\r\n");
+
+        assert_eq!(session.pop_line(now), Some(b"PRIVMSG #a :hi\r\n".to_vec()));
+        assert_eq!(session.pop_line(now), None);
+        assert_eq!(session.time_until_next(now), Some(Duration::from_secs(2)));
+        assert!(session.pop_line(now + Duration::from_secs(2)).is_some());
+        assert_eq!(session.time_until_next(now), None);
+    }
+
+    #[test]
+    fn linted_session() {
+        use ::lint::{LintAction, LintError, Linter};
+
+        let mut session = IrcSession::new(BundlerManager::new());
+        session.set_linter(Some(Linter::new(LintAction::Reject)));
+        session.send(&IrcMsg::new(b"PRIVMSG #a hello there".to_vec()).unwrap());
+        session.send(&IrcMsg::new(b"PRIVMSG #a :hello there".to_vec()).unwrap());
+        assert_eq!(session.pending_output(), b"PRIVMSG #a :hello there\r\n");
+        let rejected = session.take_rejected();
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].0, b"PRIVMSG #a hello there".to_vec());
+        match rejected[0].1 {
+            LintError::MissingColon { .. } => (),
+            ref other => panic!("unexpected {:?}", other),
+        }
+
+        session.set_linter(Some(Linter::new(LintAction::Log)));
+        session.send(&IrcMsg::new(b"PRIVMSG #a hi there".to_vec()).unwrap());
+        assert!(session.take_rejected().is_empty());
+        assert!(session.pending_output().ends_with(b"PRIVMSG #a hi there\r\n"));
+    }
+
+    #[test]
+    fn interner_shared_with_state() {
+        let now = Instant::now();
+        let mut session = IrcSession::new(BundlerManager::with_identity(SessionIdentity::new(b"me")));
+        let mut state = State::new();
+        state.set_interner(session.get_interner().clone());
+
+        session.feed(b":srv 001 me :Welcome\r\n:me!u@h JOIN #Rust\r\n", now);
+        session.feed(b":srv 353 me = #Rust :me alice\r\n:srv 366 me #Rust :End of /NAMES list.\r\n", now);
+        let mut symbol = None;
+        while let Some(event) = session.poll_event() {
+            state.on_event(&event);
+            if let IrcEvent::JoinBundle(Ok(ref join)) = event {
+                symbol = join.channel_symbol.clone();
+            }
+        }
+
+        let chan_id = state.identify_channel("#rust").unwrap();
+        let channel = state.resolve_channel(chan_id).unwrap();
+        assert_eq!(Some(channel.get_symbol()), symbol.as_ref());
+        assert_eq!(&channel.get_symbol()[..], b"#Rust");
+        // The channel name, and our own nick as the state's user key.
+        assert_eq!(session.get_interner().len(), 2);
+        assert!(session.get_interner().get(b"me").is_some());
+
+        // Parting releases the name once nothing else holds it.
+        drop(symbol);
+        session.feed(b":me!u@h PART #Rust\r\n", now);
+        while let Some(event) = session.poll_event() {
+            state.on_event(&event);
+        }
+        assert!(state.identify_channel("#rust").is_none());
+        assert_eq!(session.get_interner().purge(), 1);
+        assert_eq!(session.get_interner().get(b"#Rust"), None);
+    }
+
+    #[test]
+    fn duplicate_msgids_dropped() {
+        let now = Instant::now();
+        let mut session = IrcSession::new(BundlerManager::new());
+        let line = b"@msgid=abc :n!u@h PRIVMSG #c :hello\r\n";
+        session.feed(line, now);
+        session.feed(line, now);
+        assert!(session.poll_event().is_some());
+        assert!(session.poll_event().is_some());
+        assert!(session.poll_event().is_none());
+
+        session.set_msgid_cache(Some(MsgIdCache::new(16)));
+        session.feed(line, now);
+        session.feed(line, now);
+        session.feed(b"@msgid=def :n!u@h PRIVMSG #c :hello\r\n:n!u@h PRIVMSG #c :hello\r\n", now);
+        let mut count = 0;
+        while let Some(_) = session.poll_event() {
+            count += 1;
+        }
+        assert_eq!(count, 3);
+        assert!(session.get_msgid_cache().unwrap().contains("def"));
+    }
+}
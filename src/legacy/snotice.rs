@@ -0,0 +1,177 @@
+//! Recognition of server notices.
+//!
+//! Opers receive a steady stream of NOTICEs from the server describing
+//! connects, exits, kills and so on.  These follow a few conventional
+//! text formats; the common ones are parsed into `SnoticeKind` and the
+//! rest are left as `Other` for the caller.
+
+use std::fmt;
+
+use super::IrcMsg;
+
+/// The parsed form of a server notice, where the format was recognized.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum SnoticeKind {
+    /// `Client connecting: nick (user@host) ...`
+    ClientConnect { nick: String, userhost: String },
+    /// `Client exiting: nick (user@host) [reason] ...`
+    ClientExit { nick: String, userhost: String, reason: String },
+    /// `Received KILL message for nick!user@host. From killer ...`
+    Kill { target: String, killer: String },
+    Other,
+}
+
+/// A NOTICE sent by the server itself rather than by a user.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ServerNotice {
+    /// The server name, or empty if the notice had no prefix
+    pub server: String,
+    /// The text, with any `*** Notice -- ` style lead-in removed
+    pub text: String,
+    pub kind: SnoticeKind,
+}
+
+impl fmt::Display for ServerNotice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}] {}", self.server, self.text)
+    }
+}
+
+/// Lead-ins used by various ircds, longest first.
+static LEAD_INS: [&'static str; 3] = ["*** Notice -- ", "*** NOTICE -- ", "*** "];
+
+fn is_server_prefix(prefix: &str) -> bool {
+    // Nicks can't contain `.`; users always have `!` or `@`.
+    prefix.is_empty() || (prefix.contains('.') && !prefix.contains('!') && !prefix.contains('@'))
+}
+
+/// `nick (user@host) rest` => (nick, user@host, rest)
+fn split_nick_userhost(text: &str) -> Option<(&str, &str, &str)> {
+    let space = match text.find(" (") {
+        Some(idx) => idx,
+        None => return None,
+    };
+    let close = match text[space..].find(')') {
+        Some(idx) => space + idx,
+        None => return None,
+    };
+    Some((&text[..space], &text[space + 2..close], text[close + 1..].trim_left()))
+}
+
+fn bracketed(text: &str) -> &str {
+    if !text.starts_with('[') {
+        return "";
+    }
+    match text.find(']') {
+        Some(idx) => &text[1..idx],
+        None => "",
+    }
+}
+
+fn classify(text: &str) -> SnoticeKind {
+    if text.starts_with("Client connecting: ") {
+        let rest = &text["Client connecting: ".len()..];
+        if let Some((nick, userhost, _)) = split_nick_userhost(rest) {
+            return SnoticeKind::ClientConnect {
+                nick: nick.to_string(),
+                userhost: userhost.to_string(),
+            };
+        }
+    }
+    if text.starts_with("Client exiting: ") {
+        let rest = &text["Client exiting: ".len()..];
+        if let Some((nick, userhost, rest)) = split_nick_userhost(rest) {
+            return SnoticeKind::ClientExit {
+                nick: nick.to_string(),
+                userhost: userhost.to_string(),
+                reason: bracketed(rest).to_string(),
+            };
+        }
+    }
+    if text.starts_with("Received KILL message for ") {
+        let rest = &text["Received KILL message for ".len()..];
+        let target = rest.split(' ').next().unwrap_or("").trim_right_matches('.');
+        let killer = match rest.find("From ") {
+            Some(idx) => rest[idx + 5..].split(' ').next().unwrap_or(""),
+            None => "",
+        };
+        return SnoticeKind::Kill {
+            target: target.to_string(),
+            killer: killer.to_string(),
+        };
+    }
+    SnoticeKind::Other
+}
+
+impl ServerNotice {
+    /// Recognize `msg` as a server notice.  Returns `None` for anything
+    /// else, including NOTICEs from users.
+    pub fn from_msg(msg: &IrcMsg) -> Option<ServerNotice> {
+        if msg.get_command() != "NOTICE" || msg.len() < 2 {
+            return None;
+        }
+        let prefix = msg.get_prefix_str();
+        if !is_server_prefix(prefix) {
+            return None;
+        }
+
+        let raw = String::from_utf8_lossy(&msg[msg.len() - 1]).into_owned();
+        let mut text = &raw[..];
+        for lead_in in LEAD_INS.iter() {
+            if text.starts_with(lead_in) {
+                text = &text[lead_in.len()..];
+                break;
+            }
+        }
+
+        Some(ServerNotice {
+            server: prefix.to_string(),
+            text: text.to_string(),
+            kind: classify(text),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::IrcMsg;
+    use super::{ServerNotice, SnoticeKind};
+
+    fn snotice(line: &[u8]) -> Option<ServerNotice> {
+        ServerNotice::from_msg(&IrcMsg::new(line.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn classify_snotices() {
+        let connect = snotice(concat!(":irc.example.net NOTICE * :*** Notice -- ",
+            "Client connecting: bob (~bob@host.example.com) [192.0.2.1] {users} [Bob]").as_bytes());
+        assert_eq!(connect.unwrap().kind, SnoticeKind::ClientConnect {
+            nick: "bob".to_string(),
+            userhost: "~bob@host.example.com".to_string(),
+        });
+
+        let exit = snotice(concat!(":irc.example.net NOTICE oper :*** Notice -- ",
+            "Client exiting: bob (~bob@host.example.com) [Quit: bye] [192.0.2.1]").as_bytes());
+        assert_eq!(exit.unwrap().kind, SnoticeKind::ClientExit {
+            nick: "bob".to_string(),
+            userhost: "~bob@host.example.com".to_string(),
+            reason: "Quit: bye".to_string(),
+        });
+
+        let kill = snotice(concat!(":irc.example.net NOTICE oper :*** Notice -- ",
+            "Received KILL message for spam!x@bad.host. From alice Path: x!alice (spam)").as_bytes());
+        assert_eq!(kill.unwrap().kind, SnoticeKind::Kill {
+            target: "spam!x@bad.host".to_string(),
+            killer: "alice".to_string(),
+        });
+
+        let auth = snotice(b":irc.example.net NOTICE * :*** Looking up your hostname...").unwrap();
+        assert_eq!(auth.text, "Looking up your hostname...");
+        assert_eq!(auth.kind, SnoticeKind::Other);
+
+        assert!(snotice(b":bob!u@h NOTICE #chan :*** Notice -- not really").is_none());
+        assert!(snotice(b":irc.example.net PRIVMSG me :hi").is_none());
+    }
+}
@@ -0,0 +1,118 @@
+//! IRCv3 standard replies: `FAIL`, `WARN` and `NOTE`.
+//!
+//! Newer commands report errors and advice with these instead of
+//! numerics.  They all share one shape:
+//!
+//! `FAIL <command> <code> [<context>...] :<description>`
+
+use std::fmt;
+
+use super::IrcMsg;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ReplyKind {
+    /// The command failed
+    Fail,
+    /// The command succeeded, but something is worth the user's attention
+    Warn,
+    /// Information only
+    Note,
+}
+
+impl ReplyKind {
+    pub fn from_command(command: &str) -> Option<ReplyKind> {
+        match command {
+            "FAIL" => Some(ReplyKind::Fail),
+            "WARN" => Some(ReplyKind::Warn),
+            "NOTE" => Some(ReplyKind::Note),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ReplyKind::Fail => "FAIL",
+            ReplyKind::Warn => "WARN",
+            ReplyKind::Note => "NOTE",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct StandardReply {
+    pub kind: ReplyKind,
+    /// The command the reply is about, or `*` if it isn't about one
+    pub command: String,
+    /// A machine-readable code, such as `ACCOUNT_REQUIRED`
+    pub code: String,
+    /// Any further parameters, whose meaning depends on the code
+    pub context: Vec<String>,
+    /// Human-readable text
+    pub description: String,
+}
+
+impl fmt::Display for StandardReply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}: {}", self.kind.as_str(), self.command, self.code, self.description)
+    }
+}
+
+impl StandardReply {
+    /// Recognize `msg` as a standard reply.  Returns `None` for anything
+    /// else, including replies with too few parameters.
+    pub fn from_msg(msg: &IrcMsg) -> Option<StandardReply> {
+        let kind = match ReplyKind::from_command(msg.get_command()) {
+            Some(kind) => kind,
+            None => return None,
+        };
+        if msg.len() < 3 {
+            return None;
+        }
+
+        let arg = |idx: usize| String::from_utf8_lossy(&msg[idx]).into_owned();
+        let last = msg.len() - 1;
+        Some(StandardReply {
+            kind: kind,
+            command: arg(0),
+            code: arg(1),
+            context: (2..last).map(&arg).collect(),
+            description: arg(last),
+        })
+    }
+
+    /// True if this is about the given command, under ASCII case folding.
+    pub fn is_about(&self, command: &str) -> bool {
+        self.command.eq_ignore_ascii_case(command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::IrcMsg;
+    use super::{ReplyKind, StandardReply};
+
+    fn reply(line: &[u8]) -> Option<StandardReply> {
+        StandardReply::from_msg(&IrcMsg::new(line.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn parse_standard_replies() {
+        let fail = reply(b":irc.example.com FAIL CHATHISTORY MESSAGE_ERROR the_given_command :Messages could not be retrieved").unwrap();
+        assert_eq!(fail.kind, ReplyKind::Fail);
+        assert!(fail.is_about("chathistory"));
+        assert_eq!(fail.code, "MESSAGE_ERROR");
+        assert_eq!(fail.context, vec!["the_given_command".to_string()]);
+        assert_eq!(fail.description, "Messages could not be retrieved");
+
+        let note = reply(b":irc.example.com NOTE * OPER_MESSAGE :The server is restarting").unwrap();
+        assert_eq!(note.kind, ReplyKind::Note);
+        assert_eq!(note.command, "*");
+        assert!(note.context.is_empty());
+        assert_eq!(note.to_string(), "NOTE * OPER_MESSAGE: The server is restarting");
+
+        assert!(reply(b":irc.example.com WARN REHASH :too few").is_none());
+        assert!(reply(b":irc.example.com NOTICE * :*** hi").is_none());
+    }
+}
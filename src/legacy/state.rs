@@ -4,16 +4,29 @@ use std::cmp::max;
 use std::default::Default;
 use std::collections::{
     hash_map,
+    hash_set,
+    BTreeMap,
+    BTreeSet,
     HashMap,
     HashSet,
 };
 use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
 use std::ops::Deref;
-
-use super::message_types::server;
-use super::{IrcMsg, IrcMsgPrefix, IrcEvent};
+use std::str;
+
+use irccase::AnyCaseMapping;
+use ::{server, IrcMsg};
+use ::tags::ServerTime;
+use ::parse::ArgumentIter;
+use ::server::{ModeChanges, ModeKind, ModeTable, ServerInfo, Sign};
+use super::{IrcMsgPrefix, IrcEvent, CaseMappingChange, UnsupportedCaseMapping};
+use super::{Interner, Symbol};
 use super::watchers::{
     JoinSuccess,
+    NamesIter,
+    NamesResult,
     WhoRecord,
     WhoSuccess,
 };
@@ -45,40 +58,62 @@ pub enum MessageEndpoint {
     AnonymousUser,
 }
 
+/// Identifies a user within one `State`.  Ids are handed out in
+/// increasing order and never reused, even after the user is forgotten.
 #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct UserId(u64);
 
+impl UserId {
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
 
 mod irc_identifier {
+    use std::fmt;
     use std::ops;
-    use irccase::IrcAsciiExt;
+    use irccase::{AnyCaseMapping, CaseMapping};
 
-    fn channel_deprefix(target: &str) -> &str {
-        match target.find('#') {
+    fn channel_deprefix(target: &[u8]) -> &[u8] {
+        match target.iter().position(|&b| b == b'#') {
             Some(idx) => &target[idx..],
             None => target
         }
     }
 
-    #[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
-    pub struct IrcIdentifier(String);
+    /// A case-folded nick or channel name.  Names are folded as the
+    /// raw bytes the server sent, so two names which only differ in
+    /// invalid UTF-8 stay distinct.
+    #[derive(Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct IrcIdentifier(Vec<u8>);
 
     impl IrcIdentifier {
-        pub fn from_str(mut val: &str) -> IrcIdentifier {
-            val = channel_deprefix(val);
-            IrcIdentifier(val.to_irc_lower())
+        pub fn new<T>(case_mapping: AnyCaseMapping, val: T) -> IrcIdentifier
+            where T: AsRef<[u8]>
+        {
+            let val = channel_deprefix(val.as_ref());
+            IrcIdentifier(case_mapping.to_irc_lower(val))
         }
 
-        pub fn as_slice(&self) -> &str {
-            let IrcIdentifier(ref string) = *self;
-            &string
+        pub fn as_slice(&self) -> &[u8] {
+            let IrcIdentifier(ref bytes) = *self;
+            &bytes
+        }
+    }
+
+    impl fmt::Debug for IrcIdentifier {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "IrcIdentifier({:?})", String::from_utf8_lossy(&self.0))
         }
     }
 
     impl ops::Deref for IrcIdentifier {
-        type Target = str;
+        type Target = [u8];
 
-        fn deref(&self) -> &str {
+        fn deref(&self) -> &[u8] {
             &self.0
         }
     }
@@ -92,11 +127,27 @@ trait Patch<DiffType> {
     fn patch(&self, diff: &DiffType) -> Self;
 }
 
+fn lossy(bytes: &[u8]) -> Cow<str> {
+    String::from_utf8_lossy(bytes)
+}
+
+/// The nick in `msg`'s prefix, if it has one.
+fn prefix_nick(msg: &IrcMsg) -> Option<&[u8]> {
+    msg.get_prefix().map(|prefix| {
+        prefix.as_bytes().splitn(2, |&b| b == b'!').next().unwrap()
+    })
+}
+
 #[derive(Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct User {
     id: UserId,
     prefix: IrcMsgPrefix<'static>,
-    channels: HashSet<ChannelId>
+    channels: HashSet<ChannelId>,
+    /// The away message, if the user is away
+    away: Option<String>,
+    /// The account the user is logged in to
+    account: Option<String>,
 }
 
 impl User {
@@ -105,6 +156,19 @@ impl User {
             id: id,
             prefix: who.get_prefix().to_owned(),
             channels: Default::default(),
+            away: None,
+            account: None,
+        }
+    }
+
+    /// A user known only by nick, as tracked by a nick-only `State`.
+    fn from_nick(id: UserId, nick: &str) -> User {
+        User {
+            id: id,
+            prefix: IrcMsgPrefix::new(Cow::Owned(format!("{}!*@*", nick))),
+            channels: Default::default(),
+            away: None,
+            account: None,
         }
     }
 
@@ -113,9 +177,17 @@ impl User {
             id: user_info.id,
             prefix: user_info.prefix.clone(),
             channels: Default::default(),
+            away: None,
+            account: None,
         }
     }
 
+    /// The user's full `nick!user@host`, e.g. for building ban masks.
+    /// A nick-only `State` doesn't learn these, and gives `nick!*@*`.
+    pub fn get_prefix(&self) -> &IrcMsgPrefix<'static> {
+        &self.prefix
+    }
+
     pub fn get_nick(&self) -> &str {
         let prefix = self.prefix.as_slice();
         match prefix.find('!') {
@@ -124,6 +196,22 @@ impl User {
         }
     }
 
+    /// The user's away message, if we know them to be away.  Learned
+    /// from AWAY messages with `away-notify` and from RPL_AWAY.
+    pub fn get_away(&self) -> Option<&str> {
+        self.away.as_ref().map(|away| &away[..])
+    }
+
+    pub fn is_away(&self) -> bool {
+        self.away.is_some()
+    }
+
+    /// The account the user is logged in to, if known.  Learned from
+    /// ACCOUNT messages with `account-notify` and from `extended-join`.
+    pub fn get_account(&self) -> Option<&str> {
+        self.account.as_ref().map(|account| &account[..])
+    }
+
     fn set_nick(&mut self, nick: &str) {
         self.prefix = self.prefix.with_nick(nick).expect("Need nicked prefix");
     }
@@ -141,6 +229,12 @@ impl Diff<Vec<UserDiffCmd>> for User {
         for &removed_channel in self.channels.difference(&other.channels) {
             cmds.push(UserDiffCmd::RemoveChannel(removed_channel));
         }
+        if self.away != other.away {
+            cmds.push(UserDiffCmd::ChangeAway(other.away.clone()));
+        }
+        if self.account != other.account {
+            cmds.push(UserDiffCmd::ChangeAccount(other.account.clone()));
+        }
         cmds
     }
 }
@@ -159,6 +253,12 @@ impl Patch<Vec<UserDiffCmd>> for User {
                 },
                 UserDiffCmd::RemoveChannel(chan_id) => {
                     other.channels.remove(&chan_id);
+                },
+                UserDiffCmd::ChangeAway(ref away) => {
+                    other.away = away.clone();
+                },
+                UserDiffCmd::ChangeAccount(ref account) => {
+                    other.account = account.clone();
                 }
             }
         }
@@ -166,32 +266,412 @@ impl Patch<Vec<UserDiffCmd>> for User {
     }
 }
 
+/// Identifies a channel within one `State`, with the same guarantees
+/// as `UserId`.  Rejoining a channel gives it a new id.
 #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ChannelId(u64);
 
+impl ChannelId {
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Who set a channel's topic, and when.
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TopicSetter {
+    /// The setter's `nick!user@host`, or only the nick on some servers
+    pub set_by: String,
+    /// Seconds since the UNIX epoch, if known.  Live topic changes
+    /// only have it from a `server-time` tag.
+    pub set_at: Option<u64>,
+}
+
+/// A list mode entry, such as a ban mask.  Besides `nick!user@host`
+/// globs this may be an extban such as `$a:account`, so it is kept as
+/// the server sent it.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Mask(String);
+
+impl Mask {
+    /// Rejects empty masks and those with spaces or control bytes.
+    pub fn new(mask: &[u8]) -> Result<Mask, ModeParamError> {
+        let valid = !mask.is_empty() && !mask.iter().any(|&b| b <= b' ');
+        match ::std::str::from_utf8(mask) {
+            Ok(mask) if valid => Ok(Mask(mask.to_string())),
+            _ => Err(ModeParamError::BadMask(mask.to_vec())),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Mask {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A channel mode parameter the server sent which doesn't make sense.
+/// The change it came with is ignored.
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ModeParamError {
+    /// A mode which needs a parameter came without one
+    Missing(char),
+    /// A member limit `l` which isn't a number that fits in a `u32`
+    BadLimit(Vec<u8>),
+    /// A key `k` which is empty or contains a space, comma or control
+    /// byte
+    BadKey(Vec<u8>),
+    /// A list entry which is empty, not UTF-8, or contains a space or
+    /// control byte
+    BadMask(Vec<u8>),
+}
+
+impl fmt::Display for ModeParamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ModeParamError::Missing(mode) => write!(f, "mode {} without a parameter", mode),
+            ModeParamError::BadLimit(ref limit) =>
+                write!(f, "malformed limit {:?}", String::from_utf8_lossy(limit)),
+            ModeParamError::BadKey(ref key) =>
+                write!(f, "malformed key {:?}", String::from_utf8_lossy(key)),
+            ModeParamError::BadMask(ref mask) =>
+                write!(f, "malformed mask {:?}", String::from_utf8_lossy(mask)),
+        }
+    }
+}
+
+impl Error for ModeParamError {
+    fn description(&self) -> &str {
+        match *self {
+            ModeParamError::Missing(_) => "missing mode parameter",
+            ModeParamError::BadLimit(_) => "malformed limit",
+            ModeParamError::BadKey(_) => "malformed key",
+            ModeParamError::BadMask(_) => "malformed mask",
+        }
+    }
+}
+
+fn parse_limit(limit: &[u8]) -> Result<u32, ModeParamError> {
+    ::std::str::from_utf8(limit).ok()
+        .and_then(|limit| limit.parse().ok())
+        .ok_or_else(|| ModeParamError::BadLimit(limit.to_vec()))
+}
+
+fn check_key(key: &[u8]) -> Result<Vec<u8>, ModeParamError> {
+    if key.is_empty() || key.iter().any(|&b| b <= b' ' || b == b',') {
+        return Err(ModeParamError::BadKey(key.to_vec()));
+    }
+    Ok(key.to_vec())
+}
+
+/// A channel's own modes, as opposed to its members' prefix modes.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ChannelModes {
+    /// Set modes without an argument, such as `n` and `t`
+    flags: BTreeSet<char>,
+    /// The channel key, mode `k`
+    key: Option<Vec<u8>>,
+    /// The member limit, mode `l`
+    limit: Option<u32>,
+    /// Set modes with an argument other than the key and limit
+    params: BTreeMap<char, String>,
+    /// The entries of list modes, such as bans `b`.  Lists without
+    /// entries are absent.
+    lists: BTreeMap<char, Vec<Mask>>,
+    /// Whether RPL_CHANNELMODEIS has been seen
+    known: bool,
+}
+
+impl ChannelModes {
+    pub fn new() -> ChannelModes {
+        ChannelModes::default()
+    }
+
+    /// Whether `mode` is set.  A list mode is set if it has entries.
+    pub fn is_set(&self, mode: char) -> bool {
+        match mode {
+            'k' => self.key.is_some(),
+            'l' => self.limit.is_some(),
+            _ => self.flags.contains(&mode) || self.params.contains_key(&mode)
+                || self.lists.contains_key(&mode),
+        }
+    }
+
+    /// The argument of `mode`, if it is set.  The key and limit have
+    /// their own accessors.
+    pub fn get_param(&self, mode: char) -> Option<&str> {
+        self.params.get(&mode).map(|param| &param[..])
+    }
+
+    /// The channel key, mode `k`.  Servers hide it from non-members,
+    /// usually by sending `*`.
+    pub fn get_key(&self) -> Option<&[u8]> {
+        self.key.as_ref().map(|key| &key[..])
+    }
+
+    /// The member limit, mode `l`.
+    pub fn get_limit(&self) -> Option<u32> {
+        self.limit
+    }
+
+    /// The entries of list mode `mode`, in the order we learned them.
+    pub fn get_list(&self, mode: char) -> &[Mask] {
+        self.lists.get(&mode).map(|list| &list[..]).unwrap_or(&[])
+    }
+
+    /// The ban masks, mode `b`.
+    pub fn bans(&self) -> &[Mask] {
+        self.get_list('b')
+    }
+
+    /// Whether the settings are known, from RPL_CHANNELMODEIS.  Until
+    /// then, modes which aren't set may only be unseen.
+    pub fn is_known(&self) -> bool {
+        self.known
+    }
+
+    fn apply(&mut self, kind: Option<ModeKind>, sign: Sign, mode: char, arg: Option<&[u8]>)
+             -> Result<(), ModeParamError> {
+        match (kind, sign) {
+            (Some(ModeKind::Prefix), _) => (),
+            (Some(ModeKind::List), _) => {
+                let mask = try!(Mask::new(try!(arg.ok_or(ModeParamError::Missing(mode)))));
+                if sign == Sign::Plus {
+                    let list = self.lists.entry(mode).or_insert_with(Vec::new);
+                    if !list.contains(&mask) {
+                        list.push(mask);
+                    }
+                    return Ok(());
+                }
+                let now_empty = match self.lists.get_mut(&mode) {
+                    Some(list) => {
+                        list.retain(|entry| *entry != mask);
+                        list.is_empty()
+                    },
+                    None => false,
+                };
+                if now_empty {
+                    self.lists.remove(&mode);
+                }
+            },
+            (Some(ModeKind::Always), Sign::Plus) | (Some(ModeKind::OnSet), Sign::Plus) => {
+                let arg = try!(arg.ok_or(ModeParamError::Missing(mode)));
+                match mode {
+                    'k' => self.key = Some(try!(check_key(arg))),
+                    'l' => self.limit = Some(try!(parse_limit(arg))),
+                    _ => {
+                        self.params.insert(mode, String::from_utf8_lossy(arg).into_owned());
+                    },
+                }
+            },
+            (Some(ModeKind::Always), Sign::Minus) | (Some(ModeKind::OnSet), Sign::Minus) => {
+                match mode {
+                    'k' => self.key = None,
+                    'l' => self.limit = None,
+                    _ => {
+                        self.params.remove(&mode);
+                    },
+                }
+            },
+            (_, Sign::Plus) => {
+                self.flags.insert(mode);
+            },
+            (_, Sign::Minus) => {
+                self.flags.remove(&mode);
+            },
+        }
+        Ok(())
+    }
+
+    /// Forget all but the lists before reading RPL_CHANNELMODEIS, which
+    /// makes the settings known.
+    fn clear_settings(&mut self) {
+        self.known = true;
+        self.flags.clear();
+        self.key = None;
+        self.limit = None;
+        self.params.clear();
+    }
+
+    fn set_list(&mut self, mode: char, entries: Vec<Mask>) {
+        if entries.is_empty() {
+            self.lists.remove(&mode);
+        } else {
+            self.lists.insert(mode, entries);
+        }
+    }
+}
 
 #[derive(Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Channel {
     id: ChannelId,
-    name: String,
+    /// The case-folded name, used for lookups.
+    key: IrcIdentifier,
+    /// The channel name as the server reported it, interned.  It may
+    /// not be UTF-8.
+    symbol: Symbol,
     topic: String,
-    users: HashSet<UserId>
+    users: HashSet<UserId>,
+    /// Prefix mode letters held by each member, e.g. `"ov"`.  Members
+    /// without any are absent.
+    member_modes: HashMap<UserId, String>,
+    topic_setter: Option<TopicSetter>,
+    /// From RPL_CREATIONTIME, in seconds since the UNIX epoch
+    created_at: Option<u64>,
+    channel_modes: ChannelModes,
 }
 
 impl Channel {
-    fn from_info(chan_info: &ChannelInfo) -> Channel {
+    fn from_info(chan_info: &ChannelInfo, case_mapping: AnyCaseMapping, interner: &Interner) -> Channel {
         Channel {
             id: chan_info.id,
-            name: chan_info.name.clone(),
+            key: IrcIdentifier::new(case_mapping, &chan_info.name),
+            symbol: interner.intern(&chan_info.name),
             topic: chan_info.topic.clone(),
             users: Default::default(),
+            member_modes: Default::default(),
+            topic_setter: None,
+            created_at: None,
+            channel_modes: ChannelModes::new(),
         }
     }
 
+    pub fn get_id(&self) -> ChannelId {
+        self.id
+    }
+
+    /// The channel name with the capitalization the server uses, for
+    /// display.  Invalid UTF-8 is replaced, so use `get_name_raw` when
+    /// sending it back to the server.
+    pub fn get_name(&self) -> Cow<str> {
+        lossy(&self.symbol)
+    }
+
+    /// The channel name exactly as the server sent it.
+    pub fn get_name_raw(&self) -> &[u8] {
+        &self.symbol
+    }
+
+    /// The channel name interned in the `State`'s interner.
+    pub fn get_symbol(&self) -> &Symbol {
+        &self.symbol
+    }
+
+    /// The case-folded channel name.  Two names refer to the same
+    /// channel iff their keys are equal.
+    pub fn get_key(&self) -> &[u8] {
+        self.key.as_slice()
+    }
+
+    pub fn get_topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Who set the topic and when, if we know.
+    pub fn get_topic_setter(&self) -> Option<&TopicSetter> {
+        self.topic_setter.as_ref()
+    }
+
+    /// When the channel was created, in seconds since the UNIX epoch,
+    /// if the server has said.
+    pub fn get_created_at(&self) -> Option<u64> {
+        self.created_at
+    }
+
+    /// The channel's modes as far as we know them.  Servers send them
+    /// in reply to `MODE #channel`, and ban lists to `MODE #channel b`.
+    pub fn get_modes(&self) -> &ChannelModes {
+        &self.channel_modes
+    }
+
+    /// The number of members we know of.
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    pub fn contains(&self, uid: UserId) -> bool {
+        self.users.contains(&uid)
+    }
+
+    /// The members we know of, in no particular order.
+    pub fn users(&self) -> hash_set::Iter<UserId> {
+        self.users.iter()
+    }
+
+    /// The prefix mode letters `uid` holds here, in the order they
+    /// were set.  Empty if none, or if `uid` isn't a member.
+    pub fn member_modes(&self, uid: UserId) -> &str {
+        self.member_modes.get(&uid).map(|m| &m[..]).unwrap_or("")
+    }
+
+    /// Whether `uid` holds prefix mode `mode`, e.g. `'o'` for ops.
+    pub fn has_mode(&self, uid: UserId, mode: char) -> bool {
+        self.member_modes(uid).contains(mode)
+    }
+
+    /// The members holding prefix mode `mode`, in no particular order.
+    pub fn members_with_mode(&self, mode: char) -> Vec<UserId> {
+        self.member_modes.iter()
+            .filter(|&(_, modes)| modes.contains(mode))
+            .map(|(&uid, _)| uid)
+            .collect()
+    }
+
     fn set_topic(&mut self, topic: &str) {
         self.topic.clear();
         self.topic.push_str(topic);
     }
+
+    fn set_member_mode(&mut self, uid: UserId, mode: char, on: bool) {
+        if !self.users.contains(&uid) {
+            return;
+        }
+        if on {
+            let modes = self.member_modes.entry(uid).or_insert_with(String::new);
+            if !modes.contains(mode) {
+                modes.push(mode);
+            }
+            return;
+        }
+        let now_empty = match self.member_modes.get_mut(&uid) {
+            Some(modes) => {
+                modes.retain(|m| m != mode);
+                modes.is_empty()
+            },
+            None => false,
+        };
+        if now_empty {
+            self.member_modes.remove(&uid);
+        }
+    }
+
+    /// Replace all of `uid`'s prefix modes, as NAMES and WHO report them.
+    fn set_member_modes(&mut self, uid: UserId, modes: &str) {
+        if modes.is_empty() || !self.users.contains(&uid) {
+            self.member_modes.remove(&uid);
+        } else {
+            self.member_modes.insert(uid, modes.to_string());
+        }
+    }
+
+    fn remove_member(&mut self, uid: UserId) {
+        self.users.remove(&uid);
+        self.member_modes.remove(&uid);
+    }
 }
 
 impl Diff<Vec<ChannelDiffCmd>> for Channel {
@@ -200,12 +680,31 @@ impl Diff<Vec<ChannelDiffCmd>> for Channel {
         if self.topic != other.topic {
             cmds.push(ChannelDiffCmd::ChangeTopic(other.topic.clone()));
         }
+        if self.topic_setter != other.topic_setter {
+            cmds.push(ChannelDiffCmd::ChangeTopicSetter(other.topic_setter.clone()));
+        }
+        if self.created_at != other.created_at {
+            cmds.push(ChannelDiffCmd::ChangeCreatedAt(other.created_at));
+        }
+        if self.channel_modes != other.channel_modes {
+            cmds.push(ChannelDiffCmd::ChangeModes(other.channel_modes.clone()));
+        }
         for &added_user in other.users.difference(&self.users) {
             cmds.push(ChannelDiffCmd::AddUser(added_user));
         }
         for &removed_user in self.users.difference(&other.users) {
             cmds.push(ChannelDiffCmd::RemoveUser(removed_user));
         }
+        for (&uid, modes) in other.member_modes.iter() {
+            if self.member_modes.get(&uid) != Some(modes) {
+                cmds.push(ChannelDiffCmd::SetMemberModes(uid, modes.clone()));
+            }
+        }
+        for &uid in self.member_modes.keys() {
+            if other.users.contains(&uid) && !other.member_modes.contains_key(&uid) {
+                cmds.push(ChannelDiffCmd::SetMemberModes(uid, String::new()));
+            }
+        }
         assert_eq!(self.clone().patch(&cmds), *other);
         cmds
     }
@@ -219,11 +718,23 @@ impl Patch<Vec<ChannelDiffCmd>> for Channel {
                 ChannelDiffCmd::ChangeTopic(ref topic) => {
                     other.topic = topic.clone();
                 },
+                ChannelDiffCmd::ChangeTopicSetter(ref setter) => {
+                    other.topic_setter = setter.clone();
+                },
+                ChannelDiffCmd::ChangeCreatedAt(created_at) => {
+                    other.created_at = created_at;
+                },
+                ChannelDiffCmd::ChangeModes(ref modes) => {
+                    other.channel_modes = modes.clone();
+                },
                 ChannelDiffCmd::AddUser(user_id) => {
                     other.users.insert(user_id);
                 },
                 ChannelDiffCmd::RemoveUser(user_id) => {
-                    other.users.remove(&user_id);
+                    other.remove_member(user_id);
+                },
+                ChannelDiffCmd::SetMemberModes(user_id, ref modes) => {
+                    other.set_member_modes(user_id, modes);
                 }
             }
         }
@@ -232,6 +743,7 @@ impl Patch<Vec<ChannelDiffCmd>> for Channel {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct UserInfo {
     id: UserId,
     prefix: IrcMsgPrefix<'static>,
@@ -255,9 +767,10 @@ impl UserInfo {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ChannelInfo {
     id: ChannelId,
-    name: String,
+    name: Vec<u8>,
     topic: String
 }
 
@@ -265,7 +778,7 @@ impl ChannelInfo {
     fn from_internal(chan: &Channel) -> ChannelInfo {
         ChannelInfo {
             id: chan.id,
-            name: chan.name.clone(),
+            name: chan.get_name_raw().to_vec(),
             topic: chan.topic.clone()
         }
     }
@@ -276,33 +789,40 @@ impl ChannelInfo {
             None => Vec::new()
         }).ok().expect("non-string");
 
-        let channel_name = ::std::str::from_utf8(&join.channel)
-            .ok().expect("bad chan").to_string();
-
         ChannelInfo {
             id: id,
-            name: channel_name,
+            name: join.channel.clone(),
             topic: topic
         }
     }
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum ChannelDiffCmd {
     ChangeTopic(String),
+    ChangeTopicSetter(Option<TopicSetter>),
+    ChangeCreatedAt(Option<u64>),
+    ChangeModes(ChannelModes),
     AddUser(UserId),
     RemoveUser(UserId),
+    /// Replace a member's prefix modes; an empty string clears them.
+    SetMemberModes(UserId, String),
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum UserDiffCmd {
     ChangePrefix(String),
     AddChannel(ChannelId),
     RemoveChannel(ChannelId),
+    ChangeAway(Option<String>),
+    ChangeAccount(Option<String>),
 }
 
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum StateCommand {
     CreateUser(UserInfo),
     UpdateUser(UserId, Vec<UserDiffCmd>),
@@ -313,16 +833,128 @@ pub enum StateCommand {
     RemoveChannel(ChannelId),
 
     UpdateSelfNick(String),
+    UpdateSnomask(Option<String>),
+    UpdateCaseMapping(AnyCaseMapping),
     SetGeneration(u64),
 }
 
+/// A channel whose tracked membership disagreed with the server, and
+/// the members that were added and removed to repair it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Desynced {
+    pub channel: ChannelId,
+    pub added: Vec<UserId>,
+    pub removed: Vec<UserId>,
+}
+
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct StateDiff {
     from_generation: u64,
     to_generation: u64,
     commands: Vec<StateCommand>
 }
 
+impl StateDiff {
+    /// The generation of the state this diff applies to.
+    pub fn get_from_generation(&self) -> u64 {
+        self.from_generation
+    }
+
+    /// The generation of the state this diff produces.
+    pub fn get_to_generation(&self) -> u64 {
+        self.to_generation
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+}
+
+/// Why `State::try_patch` refused a diff.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PatchError {
+    /// The diff was taken from a state at another generation, as when
+    /// diffs arrive twice, out of order or after one went missing.
+    WrongGeneration { expected: u64, found: u64 },
+    /// Applying the diff left the state inconsistent
+    Inconsistent(String),
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PatchError::WrongGeneration { expected, found } =>
+                write!(f, "diff is for generation {}, not {}", found, expected),
+            PatchError::Inconsistent(ref msg) =>
+                write!(f, "diff leaves the state inconsistent: {}", msg),
+        }
+    }
+}
+
+impl Error for PatchError {
+    fn description(&self) -> &str {
+        match *self {
+            PatchError::WrongGeneration { .. } => "diff for another generation",
+            PatchError::Inconsistent(_) => "diff leaves the state inconsistent",
+        }
+    }
+}
+
+/// Bytes from `to_bytes` which couldn't be decoded.
+#[cfg(feature = "serde")]
+#[derive(Debug)]
+pub enum DecodeError {
+    Malformed(::bincode::Error),
+    /// The bytes decoded to a `State` which breaks its own invariants.
+    Inconsistent(String),
+}
+
+#[cfg(feature = "serde")]
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::Malformed(ref err) => write!(f, "malformed encoding: {}", err),
+            DecodeError::Inconsistent(ref msg) => write!(f, "inconsistent state: {}", msg),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Error for DecodeError {
+    fn description(&self) -> &str {
+        match *self {
+            DecodeError::Malformed(_) => "malformed encoding",
+            DecodeError::Inconsistent(_) => "inconsistent state",
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+fn to_bytes<T: ::serde::Serialize>(value: &T) -> Vec<u8> {
+    // Only sequences of unknown length fail to encode, and we have none.
+    ::bincode::serialize(value).expect("encoding failed")
+}
+
+#[cfg(feature = "serde")]
+fn from_bytes<T: ::serde::de::DeserializeOwned>(bytes: &[u8]) -> Result<T, DecodeError> {
+    ::bincode::deserialize(bytes).map_err(DecodeError::Malformed)
+}
+
+#[cfg(feature = "serde")]
+impl StateDiff {
+    /// A compact binary encoding, for shipping to a replica in another
+    /// process.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        to_bytes(self)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<StateDiff, DecodeError> {
+        from_bytes(bytes)
+    }
+}
+
 pub struct FrozenState(State);
 
 impl Deref for FrozenState {
@@ -338,6 +970,7 @@ unsafe impl Send for FrozenState {}
 unsafe impl Sync for FrozenState {}
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct State {
     // Can this be made diffable by using sorted `users`, `channels`,
     // `users[].channels` and `channels[].users`?  TreeSet.
@@ -347,13 +980,47 @@ pub struct State {
     self_nick: String,
     self_id: UserId,
 
-    user_map: HashMap<IrcIdentifier, UserId>,
+    /// Our server notice mask, without the leading `+`.
+    snomask: Option<String>,
+
+    /// How nicks and channel names are folded for lookups.  Follows the
+    /// server's CASEMAPPING.
+    case_mapping: AnyCaseMapping,
+
+    /// Keyed by the case-folded nick, interned.
+    user_map: HashMap<Symbol, UserId>,
     users: HashMap<UserId, User>,
 
     channel_map: HashMap<IrcIdentifier, ChannelId>,
     channels: HashMap<ChannelId, Channel>,
 
     generation: u64,
+
+    #[cfg_attr(feature = "serde", serde(skip))]
+    desyncs: Vec<Desynced>,
+
+    /// Track users by nick alone, never needing WHO.
+    nick_only: bool,
+
+    /// Interns channel names and user keys, possibly shared with the
+    /// session.
+    interner: Interner,
+
+    /// The server's ISUPPORT tokens so far, and the channel and user
+    /// modes they describe.
+    isupport: ServerInfo,
+    mode_table: ModeTable,
+    user_mode_table: ModeTable,
+
+    /// List mode entries, such as bans, being received for a channel;
+    /// they replace the channel's list once the end of it arrives.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pending_lists: HashMap<(ChannelId, char), Vec<Mask>>,
+
+    /// Malformed channel mode parameters since the last call to
+    /// `take_mode_errors`.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    mode_errors: Vec<(ChannelId, ModeParamError)>,
 }
 
 impl State {
@@ -365,23 +1032,43 @@ impl State {
             user_map: Default::default(),
             users: Default::default(),
             self_id: UserId(0),
+            snomask: None,
+            case_mapping: AnyCaseMapping::default(),
             channel_map: Default::default(),
             channels: Default::default(),
             generation: 0,
-        }
+            desyncs: Vec::new(),
+            nick_only: false,
+            interner: Interner::new(),
+            isupport: ServerInfo::new(),
+            mode_table: ModeTable::new(),
+            user_mode_table: ModeTable::user_modes(),
+            pending_lists: HashMap::new(),
+            mode_errors: Vec::new(),
+        }
+    }
+
+    /// A `State` which tracks users by nick alone, for bots on very
+    /// large channels.  Channel membership comes from the NAMES reply
+    /// on joining and WHO replies are ignored, so nothing need send
+    /// WHO; users' prefixes are reported as `nick!*@*`.
+    pub fn nick_only() -> State {
+        let mut state = State::new();
+        state.nick_only = true;
+        state
     }
 
-    fn on_other_part(&mut self, part: &server::Part) {
-        println!("part.channel = {:?}, part.nick = {:?}",
-            part.get_channel(), part.get_nick());
+    pub fn is_nick_only(&self) -> bool {
+        self.nick_only
+    }
 
-        let channel_name = IrcIdentifier::from_str(part.get_channel());
-        let user_nick = IrcIdentifier::from_str(part.get_nick());
+    fn on_other_part(&mut self, part: &server::Part, nick: &str) {
+        let channel_name = IrcIdentifier::new(self.case_mapping, part.get_channel());
 
         let chan_id = deref_opt_or_return!(self.channel_map.get(&channel_name),
             "Got channel without knowing about it.", ());
 
-        let user_id = deref_opt_or_return!(self.user_map.get(&user_nick),
+        let user_id = deref_opt_or_return!(self.lookup_user(nick),
             "Got user without knowing about it.", ());
 
         self.validate_state_internal_panic();
@@ -393,20 +1080,24 @@ impl State {
         assert!(self.remove_channel_by_name(part.get_channel()).is_some());
     }
 
-    fn on_other_quit(&mut self, quit: &server::Quit) {
-        assert!(self.remove_user_by_nick(quit.get_nick()).is_some());
+    fn on_other_quit(&mut self, nick: &str) {
+        if self.remove_user_by_nick(nick).is_none() {
+            warn!("Strange: unknown nick {:?} quit", nick);
+        }
     }
 
-    fn on_other_join(&mut self, join: &server::Join) {
-        let channel_name = IrcIdentifier::from_str(join.get_channel());
-        let user_nick = IrcIdentifier::from_str(join.get_nick());
+    fn on_other_join(&mut self, join: &server::Join, nick: &str) {
+        let channel_name = IrcIdentifier::new(self.case_mapping, join.get_channel());
 
         let chan_id = match self.channel_map.get(&channel_name) {
             Some(chan_id) => *chan_id,
-            None => panic!("Got message for channel {:?} without knowing about it.", channel_name)
+            None => {
+                warn!("Strange: join to unknown channel {:?}", channel_name);
+                return;
+            }
         };
 
-        let (is_create, user_id) = match self.user_map.get(&user_nick) {
+        let (is_create, user_id) = match self.lookup_user(nick) {
             Some(user_id) => {
                 (false, *user_id)
             },
@@ -417,15 +1108,28 @@ impl State {
             }
         };
         if is_create {
-            let user = User {
-                id: user_id,
-                prefix: join.to_irc_msg().get_prefix().to_owned(),
-                channels: HashSet::new(),
+            let user = if self.nick_only {
+                User::from_nick(user_id, nick)
+            } else {
+                User {
+                    id: user_id,
+                    prefix: IrcMsgPrefix::new(Cow::Owned(lossy(&join.get_source()[1..]).into_owned())),
+                    channels: HashSet::new(),
+                    away: None,
+                    account: None,
+                }
             };
             self.users.insert(user_id, user);
-            self.user_map.insert(user_nick, user_id);
+            let key = self.intern_user_key(nick);
+            self.user_map.insert(key, user_id);
+        }
+        {
+            let user = self.users.get_mut(&user_id).expect("user not found");
+            user.channels.insert(chan_id);
+            if join.is_extended() {
+                user.account = join.get_account().map(|account| lossy(account).into_owned());
+            }
         }
-        self.users.get_mut(&user_id).expect("user not found").channels.insert(chan_id);
 
         assert!(self.update_channel_by_name(channel_name.as_slice(), |channel| {
             channel.users.insert(user_id);
@@ -433,29 +1137,117 @@ impl State {
     }
 
     fn on_self_join(&mut self, join: &JoinSuccess) {
-        let channel_name = ::std::str::from_utf8(&join.channel).ok().unwrap();
-        let channel_name = IrcIdentifier::from_str(channel_name);
+        let channel_name = IrcIdentifier::new(self.case_mapping, &join.channel);
 
         if let Some(_) = self.channel_map.get(&channel_name) {
             warn!("Joining already joined channel {:?}; skipped", join.channel);
             return;
         }
-        warn!("users = {:?}", join.nicks);
+        for entry in join.names() {
+            debug!("{:?} member {:?} with prefixes {:?}",
+                join.channel, entry.nick, entry.prefixes);
+        }
         let new_chan_id = ChannelId(self.channel_seq);
         self.channel_seq += 1;
 
         self.channels.insert(new_chan_id, Channel::from_info(
-            &ChannelInfo::from_join(new_chan_id, join)));
+            &ChannelInfo::from_join(new_chan_id, join), self.case_mapping, &self.interner));
         self.channel_map.insert(channel_name.clone(), new_chan_id);
+        if let Some(ref topic) = join.topic {
+            self.channels.get_mut(&new_chan_id).unwrap().topic_setter = Some(TopicSetter {
+                set_by: topic.set_by.as_slice().to_string(),
+                set_at: Some(topic.set_at),
+            });
+        }
+
+        if self.nick_only {
+            self.add_names_members(new_chan_id, join);
+        }
+        self.apply_names_modes(new_chan_id, join.names());
+    }
+
+    /// The prefix mode letters for membership symbols such as `@+`, as
+    /// NAMES and WHO report them.  Anything else, such as the `H` or `*`
+    /// in WHO flags, is skipped.
+    fn modes_for_symbols(&self, symbols: &[u8]) -> String {
+        symbols.iter()
+            .filter_map(|&symbol| self.mode_table.prefix_mode(symbol))
+            .map(|mode| mode as char)
+            .collect()
+    }
+
+    /// Take the prefix modes of members we know of from a NAMES reply.
+    /// Without `multi-prefix` the server lists only each member's
+    /// highest prefix, so lower ones are forgotten.
+    fn apply_names_modes(&mut self, chan_id: ChannelId, names: NamesIter) {
+        for entry in names {
+            let entry_nick = String::from_utf8_lossy(entry.nick);
+            let nick = entry_nick.split('!').next().unwrap();
+            let user_id = match self.lookup_user(nick) {
+                Some(user_id) => *user_id,
+                None => continue,
+            };
+            let modes = self.modes_for_symbols(entry.prefixes);
+            if let Some(channel) = self.channels.get_mut(&chan_id) {
+                channel.set_member_modes(user_id, &modes);
+            }
+        }
+    }
+
+    fn on_names(&mut self, names: &NamesResult) {
+        let chan_id = match self.get_channel_by_name(&names.channel) {
+            Some((chan_id, _)) => chan_id,
+            None => return,
+        };
+        self.apply_names_modes(chan_id, names.names());
+    }
+
+    /// Take the prefix modes of members we know of from the flags of a
+    /// WHO reply, as with `apply_names_modes`.
+    fn apply_who_modes(&mut self, chan_id: ChannelId, who: &WhoSuccess) {
+        for rec in who.who_records.iter() {
+            let user_id = match self.lookup_user(&rec.nick) {
+                Some(user_id) => *user_id,
+                None => continue,
+            };
+            let modes = self.modes_for_symbols(rec.flags.as_bytes());
+            if let Some(channel) = self.channels.get_mut(&chan_id) {
+                channel.set_member_modes(user_id, &modes);
+            }
+        }
+    }
+
+    /// Fill a nick-only channel's membership from the NAMES reply
+    /// received on joining it.
+    fn add_names_members(&mut self, chan_id: ChannelId, join: &JoinSuccess) {
+        for entry in join.names() {
+            let entry_nick = String::from_utf8_lossy(entry.nick);
+            // With `userhost-in-names` the entry is a full prefix.
+            let nick = entry_nick.split('!').next().unwrap();
+
+            let user_id = match self.lookup_user(nick) {
+                Some(user_id) => *user_id,
+                None => {
+                    let new_user_id = UserId(self.user_seq);
+                    self.user_seq += 1;
+                    self.insert_user(User::from_nick(new_user_id, nick));
+                    new_user_id
+                }
+            };
+            self.users.get_mut(&user_id).expect("user not found").channels.insert(chan_id);
+            self.channels.get_mut(&chan_id).expect("channel not found").users.insert(user_id);
+        }
+        self.validate_state_internal_panic();
     }
 
-    fn validate_state_with_who(&self, who: &WhoSuccess) {
-        let channel_name = ::std::str::from_utf8(&who.channel).ok().unwrap();
-        let channel_name = IrcIdentifier::from_str(channel_name);
+    /// Compare our view of a channel's membership against a WHO reply,
+    /// logging any difference.  Returns true if they agree.
+    fn validate_state_with_who(&self, who: &WhoSuccess) -> bool {
+        let channel_name = IrcIdentifier::new(self.case_mapping, &who.channel);
 
         let (_, channel) = match self.get_channel_by_name(channel_name.as_slice()) {
             Some(chan_pair) => chan_pair,
-            None => return
+            None => return true
         };
 
         info!("Validating channel state");
@@ -490,18 +1282,115 @@ impl State {
         } else {
             warn!("Channel state has been validated: desynchronized!");
         }
+        is_valid
+    }
+
+    /// Bring a channel's membership in line with a WHO reply: members
+    /// we missed are added and members who are gone are dropped.  The
+    /// corrective commands are applied and returned; if there were any,
+    /// a `Desynced` is queued for `take_desyncs`.
+    pub fn reconcile_with_who(&mut self, who: &WhoSuccess) -> Vec<StateCommand> {
+        let chan_id = match self.get_channel_by_name(&who.channel) {
+            Some((chan_id, _)) => chan_id,
+            None => return Vec::new(),
+        };
+
+        let mut commands = Vec::new();
+        let mut desync = Desynced {
+            channel: chan_id,
+            added: Vec::new(),
+            removed: Vec::new(),
+        };
+
+        let mut who_nicks = HashSet::new();
+        let mut user_seq = self.user_seq;
+        for rec in who.who_records.iter() {
+            let nick = IrcIdentifier::new(self.case_mapping, &rec.nick);
+            who_nicks.insert(nick.clone());
+
+            let user_id = match self.lookup_user_by_key(&nick) {
+                Some(user_id) => *user_id,
+                None => {
+                    let user_id = UserId(user_seq);
+                    user_seq += 1;
+                    commands.push(StateCommand::CreateUser(
+                        UserInfo::from_internal(&User::from_who(user_id, rec))));
+                    user_id
+                }
+            };
+            if !self.channels[&chan_id].users.contains(&user_id) {
+                desync.added.push(user_id);
+                commands.push(StateCommand::UpdateUser(user_id,
+                    vec![UserDiffCmd::AddChannel(chan_id)]));
+                commands.push(StateCommand::UpdateChannel(chan_id,
+                    vec![ChannelDiffCmd::AddUser(user_id)]));
+            }
+        }
+
+        for &user_id in self.channels[&chan_id].users.iter() {
+            if user_id == self.self_id {
+                continue;
+            }
+            let user = &self.users[&user_id];
+            if who_nicks.contains(&IrcIdentifier::new(self.case_mapping, user.get_nick())) {
+                continue;
+            }
+            desync.removed.push(user_id);
+            commands.push(StateCommand::UpdateChannel(chan_id,
+                vec![ChannelDiffCmd::RemoveUser(user_id)]));
+            if user.channels.len() == 1 {
+                commands.push(StateCommand::RemoveUser(user_id));
+            } else {
+                commands.push(StateCommand::UpdateUser(user_id,
+                    vec![UserDiffCmd::RemoveChannel(chan_id)]));
+            }
+        }
+
+        for command in commands.iter() {
+            self.apply_command(command);
+        }
+        self.user_seq = max(self.user_seq, user_seq);
+        self.validate_state_internal_panic();
+
+        if !commands.is_empty() {
+            warn!("{:?} desynchronized: +{:?} -{:?}",
+                chan_id, desync.added, desync.removed);
+            self.desyncs.push(desync);
+        }
+        commands
+    }
+
+    /// Desynchronizations found and repaired since the last call.
+    pub fn take_desyncs(&mut self) -> Vec<Desynced> {
+        ::std::mem::replace(&mut self.desyncs, Vec::new())
+    }
+
+    /// Channel mode parameters the server sent which didn't make sense,
+    /// since the last call.  The changes they came with were ignored.
+    pub fn take_mode_errors(&mut self) -> Vec<(ChannelId, ModeParamError)> {
+        ::std::mem::replace(&mut self.mode_errors, Vec::new())
+    }
+
+    fn mode_error(&mut self, chan_id: ChannelId, err: ModeParamError) {
+        warn!("{:?}: {}", chan_id, err);
+        self.mode_errors.push((chan_id, err));
     }
 
     fn on_who(&mut self, who: &WhoSuccess) {
+        if self.nick_only {
+            return;
+        }
         // If we WHO a channel that we aren't in, we aren't changing any
         // state.
-        let channel_name = ::std::str::from_utf8(&who.channel).ok().unwrap();
-        let channel_name = IrcIdentifier::from_str(channel_name);
+        let channel_name = IrcIdentifier::new(self.case_mapping, &who.channel);
 
         let chan_id = match self.get_channel_by_name(&*channel_name) {
             Some((chan_id, channel)) => {
                 if !channel.users.is_empty() {
-                    self.validate_state_with_who(who);
+                    if !self.validate_state_with_who(who) {
+                        self.reconcile_with_who(who);
+                    }
+                    self.apply_who_modes(chan_id, who);
                     return;
                 }
                 chan_id
@@ -513,8 +1402,8 @@ impl State {
         let mut user_ids = Vec::with_capacity(who.who_records.len());
 
         for rec in who.who_records.iter() {
-            let nick = IrcIdentifier::from_str(&rec.nick);
-            user_ids.push(match self.user_map.get(&nick) {
+            let nick = IrcIdentifier::new(self.case_mapping, &rec.nick);
+            user_ids.push(match self.lookup_user_by_key(&nick) {
                 Some(user_id) => *user_id,
                 None => {
                     let new_user_id = UserId(self.user_seq);
@@ -546,29 +1435,47 @@ impl State {
             info!("Added {:?} users for channel {:?}", added, tmp_chan_name);
             channel.users.extend(user_ids.into_iter());
         }), "Got message for channel {:?} without knowing about it.");
+        self.apply_who_modes(chan_id, who);
     }
 
-    fn on_topic(&mut self, topic: &server::Topic) {
+    fn on_topic(&mut self, topic: &server::Topic, time: Option<ServerTime>) {
+        let text = lossy(topic.get_text());
+        let setter = if text.is_empty() {
+            None
+        } else {
+            Some(TopicSetter {
+                set_by: lossy(&topic.get_source()[1..]).into_owned(),
+                set_at: time.map(|time| time.unix_millis() / 1000),
+            })
+        };
         assert!(self.update_channel_by_name(topic.get_channel(), |channel| {
-            let topic = String::from_utf8_lossy(topic.get_body_raw()).into_owned();
-            channel.set_topic(&topic);
+            channel.set_topic(&text);
+            channel.topic_setter = setter;
         }));
     }
 
-    fn on_nick(&mut self, nick: &server::Nick) {
-        assert!(self.update_user_by_nick(nick.get_nick(), |user| {
-            user.set_nick(nick.get_new_nick());
-        }))
+    fn on_nick(&mut self, nick: &server::Nick, old_nick: &str) {
+        let new_nick = lossy(nick.get_new_nick());
+        let is_self = IrcIdentifier::new(self.case_mapping, old_nick)
+            == IrcIdentifier::new(self.case_mapping, &self.self_nick);
+        if !self.update_user_by_nick(old_nick, |user| user.set_nick(&new_nick)) {
+            warn!("Strange: unknown nick {:?} changed nick", old_nick);
+            return;
+        }
+        if is_self {
+            // update_user has already re-keyed user_map.
+            self.self_nick = new_nick.into_owned();
+        }
     }
 
     //
     fn on_kick(&mut self, kick: &server::Kick) {
-        let channel_name = IrcIdentifier::from_str(kick.get_channel());
-        let kicked_user_nick = IrcIdentifier::from_str(kick.get_kicked_nick());
+        let channel_name = IrcIdentifier::new(self.case_mapping, kick.get_channel());
+        let kicked_user_nick = IrcIdentifier::new(self.case_mapping, &*lossy(kick.get_kicked_nick()));
 
         let (chan_id, user_id) = match (
             self.channel_map.get(&channel_name),
-            self.user_map.get(&kicked_user_nick)
+            self.lookup_user_by_key(&kicked_user_nick)
         ) {
             (Some(chan_id), Some(user_id)) => (*chan_id, *user_id),
             (None, Some(_)) => {
@@ -584,69 +1491,406 @@ impl State {
                 return;
             }
         };
+        if user_id == self.self_id {
+            // As with our own PART, the channel goes with us.
+            assert!(self.remove_channel_by_id(chan_id));
+            return;
+        }
         self.unlink_user_channel(user_id, chan_id);
     }
 
-    pub fn is_self_join(&self, msg: &IrcMsg) -> Option<server::Join> {
-        use super::message_types::server::IncomingMsg::Join;
+    pub fn is_self_join<'a>(&self, msg: &'a IrcMsg) -> Option<&'a server::Join> {
+        if !self.is_from_self(msg) {
+            return None;
+        }
+        msg.as_tymsg::<&server::Join>().ok()
+    }
 
-        let is_self = msg.get_prefix().nick().and_then(|nick| {
-            Some(nick == self.self_nick)
-        }).unwrap_or(false);
+    fn is_from_self(&self, msg: &IrcMsg) -> bool {
+        prefix_nick(msg) == Some(self.self_nick.as_bytes())
+    }
 
-        if !is_self {
-            return None;
+    pub fn on_message(&mut self, msg: &IrcMsg) {
+        self.generation += 1;
+        let is_self = self.is_from_self(msg);
+        // Decoded lossily, like channel names, so that no nick the
+        // server sends can upset us.
+        let nick = prefix_nick(msg).map(lossy);
+        let nick = nick.as_ref().map(|nick| &nick[..]).unwrap_or("");
+
+        if let Ok(part) = msg.as_tymsg::<&server::Part>() {
+            if is_self {
+                self.on_self_part(part);
+            } else {
+                self.on_other_part(part, nick);
+            }
+            return;
+        }
+        if let Ok(_) = msg.as_tymsg::<&server::Quit>() {
+            if !is_self && !nick.is_empty() {
+                self.on_other_quit(nick);
+            }
+            return;
+        }
+        if let Ok(join) = msg.as_tymsg::<&server::Join>() {
+            // Our own joins are tracked from the JoinBundle.
+            if !is_self {
+                self.on_other_join(join, nick);
+            }
+            return;
+        }
+        if let Ok(topic) = msg.as_tymsg::<&server::Topic>() {
+            return self.on_topic(topic, msg.server_time());
+        }
+        if let Ok(nick_msg) = msg.as_tymsg::<&server::Nick>() {
+            return self.on_nick(nick_msg, nick);
         }
-        match server::IncomingMsg::from_msg(msg.clone()) {
-            Join(join) => Some(join),
-            _ => None,
+        if let Ok(kick) = msg.as_tymsg::<&server::Kick>() {
+            return self.on_kick(kick);
+        }
+        if let Ok(away) = msg.as_tymsg::<&server::Away>() {
+            let message = away.get_message().map(|message| lossy(message).into_owned());
+            return self.set_user_away(prefix_nick(msg).unwrap(), message);
+        }
+        if let Ok(account) = msg.as_tymsg::<&server::Account>() {
+            let name = account.get_account().map(|name| lossy(name).into_owned());
+            if let Some(user_id) = self.find_user(prefix_nick(msg).unwrap()) {
+                self.update_user(user_id, |user| user.account = name);
+            }
+            return;
+        }
+        if let Ok(mode) = msg.as_tymsg::<&server::Mode>() {
+            if self.on_channel_mode(mode) {
+                return;
+            }
+        }
+
+        let mut args = msg.args();
+        match msg.get_command() {
+            "001" => {
+                if let Some(nick) = args.next() {
+                    self.initialize_self_nick(&lossy(nick));
+                }
+            },
+            // RPL_SNOMASK
+            "008" => {
+                if let Some(mask) = args.nth(1) {
+                    self.snomask = Some(lossy(mask).trim_left_matches('+').to_string());
+                }
+            },
+            // RPL_ISUPPORT
+            "005" => self.on_isupport(msg),
+            // RPL_CHANNELMODEIS
+            "324" => self.on_channel_mode_is(msg),
+            // RPL_CREATIONTIME
+            "329" => {
+                if let (Some(channel), Some(created_at)) = (args.nth(1), args.next()) {
+                    let created_at = lossy(created_at).parse().ok();
+                    self.update_known_channel(channel, |channel| channel.created_at = created_at);
+                }
+            },
+            // RPL_TOPIC, outside of joining
+            "332" => {
+                if let (Some(channel), Some(text)) = (args.nth(1), args.next()) {
+                    self.update_known_channel(channel, |channel| channel.set_topic(&lossy(text)));
+                }
+            },
+            // RPL_TOPICWHOTIME
+            "333" => {
+                if let (Some(channel), Some(set_by), Some(set_at)) = (args.nth(1), args.next(), args.next()) {
+                    let setter = Some(TopicSetter {
+                        set_by: lossy(set_by).into_owned(),
+                        set_at: lossy(set_at).parse().ok(),
+                    });
+                    self.update_known_channel(channel, |channel| channel.topic_setter = setter);
+                }
+            },
+            // RPL_INVITELIST, RPL_EXCEPTLIST and RPL_BANLIST
+            "346" => self.on_list_entry(msg, 'I'),
+            "348" => self.on_list_entry(msg, 'e'),
+            "367" => self.on_list_entry(msg, 'b'),
+            // and their ends
+            "347" => self.on_list_end(msg, 'I'),
+            "349" => self.on_list_end(msg, 'e'),
+            "368" => self.on_list_end(msg, 'b'),
+            // RPL_AWAY
+            "301" => {
+                if let (Some(nick), Some(message)) = (args.nth(1), args.next()) {
+                    self.set_user_away(nick, Some(lossy(message).into_owned()));
+                }
+            },
+            "MODE" => {
+                if let (Some(target), Some(modes)) = (args.next(), args.next()) {
+                    self.on_user_mode(target, modes, args);
+                }
+            },
+            _ => (),
         }
     }
 
-    pub fn on_message(&mut self, msg: &IrcMsg) {
-        use super::message_types::server::IncomingMsg::{Part, Quit, Join, Topic, Kick, Nick};
+    /// Apply `modfunc` to the channel named `name` if we are in it.
+    fn update_known_channel<F>(&mut self, name: &[u8], modfunc: F) where
+        F: FnOnce(&mut Channel) -> ()
+    {
+        if let Some((chan_id, _)) = self.get_channel_by_name(name) {
+            self.update_channel(chan_id, modfunc);
+        }
+    }
 
-        let ty_msg = server::IncomingMsg::from_msg(msg.clone());
-        let is_self = msg.get_prefix().nick().and_then(|nick| {
-            Some(nick == self.self_nick)
-        }).unwrap_or(false);
+    fn on_channel_mode_is(&mut self, msg: &IrcMsg) {
+        let mut args = msg.args();
+        let chan_id = match args.nth(1).and_then(|name| self.get_channel_by_name(name)) {
+            Some((chan_id, _)) => chan_id,
+            None => return,
+        };
+        let modes = match args.next() {
+            Some(modes) => modes,
+            None => return,
+        };
+        let mut errors = Vec::new();
+        {
+            let table = &self.mode_table;
+            let channel = self.channels.get_mut(&chan_id).expect("channel not found");
+            channel.channel_modes.clear_settings();
+            for (sign, mode, arg) in ModeChanges::new(table, modes, args) {
+                if let Err(err) = channel.channel_modes.apply(table.kind(mode as u8), sign, mode, arg) {
+                    errors.push(err);
+                }
+            }
+        }
+        for err in errors.into_iter() {
+            self.mode_error(chan_id, err);
+        }
+    }
 
-        match (&ty_msg, is_self) {
-            (&Part(ref part), true) => return self.on_self_part(part),
-            (&Part(ref part), false) => return self.on_other_part(part),
-            (&Quit(ref quit), false) => return self.on_other_quit(quit),
-            // is this JOIN right?
-            (&Join(ref join), false) => return self.on_other_join(join),
-            (&Topic(ref topic), _) => return self.on_topic(topic),
-            (&Nick(ref nick), _) => return self.on_nick(nick),
-            (&Kick(ref kick), _) => return self.on_kick(kick),
-            (_, _) => ()
+    fn on_list_entry(&mut self, msg: &IrcMsg, mode: char) {
+        let mut args = msg.args();
+        if let (Some(channel), Some(entry)) = (args.nth(1), args.next()) {
+            if let Some((chan_id, _)) = self.get_channel_by_name(channel) {
+                match Mask::new(entry) {
+                    Ok(mask) => self.pending_lists.entry((chan_id, mode)).or_insert_with(Vec::new)
+                        .push(mask),
+                    Err(err) => self.mode_error(chan_id, err),
+                }
+            }
         }
+    }
 
-        if msg.get_command() == "001" {
-            let channel_name = ::std::str::from_utf8(&msg[0]).ok().unwrap();
-            self.initialize_self_nick(channel_name);
+    fn on_list_end(&mut self, msg: &IrcMsg, mode: char) {
+        let chan_id = match msg.args().nth(1).and_then(|name| self.get_channel_by_name(name)) {
+            Some((chan_id, _)) => chan_id,
+            None => return,
+        };
+        let entries = self.pending_lists.remove(&(chan_id, mode)).unwrap_or(Vec::new());
+        self.update_channel(chan_id, |channel| channel.channel_modes.set_list(mode, entries));
+    }
+
+    fn intern_user_key<T>(&self, nick: T) -> Symbol
+        where T: AsRef<[u8]>
+    {
+        self.interner.intern(&IrcIdentifier::new(self.case_mapping, nick))
+    }
+
+    fn lookup_user<T>(&self, nick: T) -> Option<&UserId>
+        where T: AsRef<[u8]>
+    {
+        self.lookup_user_by_key(&IrcIdentifier::new(self.case_mapping, nick))
+    }
+
+    /// A nick which was never interned can't be a key of `user_map`, so
+    /// lookups don't intern.
+    fn lookup_user_by_key(&self, nick: &IrcIdentifier) -> Option<&UserId> {
+        self.interner.get(nick).and_then(|key| self.user_map.get(&key))
+    }
+
+    fn find_user(&self, nick: &[u8]) -> Option<UserId> {
+        self.lookup_user(&*lossy(nick)).map(|id| *id)
+    }
+
+    fn set_user_away(&mut self, nick: &[u8], message: Option<String>) {
+        if let Some(user_id) = self.find_user(nick) {
+            self.update_user(user_id, |user| user.away = message);
+        }
+    }
+
+    fn on_isupport(&mut self, msg: &IrcMsg) {
+        if self.isupport.on_irc_msg(msg) {
+            self.mode_table = self.isupport.mode_table();
+            self.user_mode_table = self.isupport.user_mode_table();
+        }
+        if let Some(change) = CaseMappingChange::from_irc_msg(msg) {
+            if let Err(err) = self.on_case_mapping_change(&change) {
+                warn!("{}, keeping {}", err, self.case_mapping.as_isupport_token());
+            }
+        }
+    }
+
+    /// Re-key nicks and channels under the advertised case mapping.  An
+    /// unsupported mapping is rejected, leaving the current one in place.
+    pub fn on_case_mapping_change(&mut self, change: &CaseMappingChange)
+                                  -> Result<(), UnsupportedCaseMapping> {
+        let case_mapping = try!(change.get_case_mapping());
+        self.set_case_mapping(case_mapping);
+        Ok(())
+    }
+
+    /// Track mode changes, such as `+o` or `+b`, on a channel we are in.
+    /// Returns false if `mode` isn't for such a channel.
+    fn on_channel_mode(&mut self, mode: &server::Mode) -> bool {
+        let chan_id = match self.get_channel_by_name(mode.get_target()) {
+            Some((chan_id, _)) => chan_id,
+            None => return false,
+        };
+        let mut member_changes = Vec::new();
+        let mut errors = Vec::new();
+        let mut channel_modes = self.channels[&chan_id].channel_modes.clone();
+        for (sign, mode_char, arg) in mode.changes(&self.mode_table) {
+            let kind = self.mode_table.kind(mode_char as u8);
+            if kind != Some(ModeKind::Prefix) {
+                if let Err(err) = channel_modes.apply(kind, sign, mode_char, arg) {
+                    errors.push(err);
+                }
+                continue;
+            }
+            let nick = match arg {
+                Some(nick) => lossy(nick),
+                None => continue,
+            };
+            match self.lookup_user(&*nick) {
+                Some(&user_id) => member_changes.push((user_id, mode_char, sign == Sign::Plus)),
+                None => warn!("Strange: mode {} for unknown nick {:?}", mode_char, nick),
+            }
+        }
+        let channel = self.channels.get_mut(&chan_id).expect("channel not found");
+        channel.channel_modes = channel_modes;
+        for (user_id, mode_char, on) in member_changes.into_iter() {
+            channel.set_member_mode(user_id, mode_char, on);
+        }
+        for err in errors.into_iter() {
+            self.mode_error(chan_id, err);
+        }
+        true
+    }
+
+    /// Losing user mode `+s` clears the server notice mask.  Servers
+    /// report the new mask with RPL_SNOMASK whenever it is set.
+    fn on_user_mode(&mut self, target: &[u8], modes: &[u8], params: ArgumentIter) {
+        if IrcIdentifier::new(self.case_mapping, &*lossy(target))
+            != IrcIdentifier::new(self.case_mapping, &self.self_nick) {
+            return;
+        }
+        for (sign, mode, _) in ModeChanges::new(&self.user_mode_table, modes, params) {
+            if sign == Sign::Minus && mode == 's' {
+                self.snomask = None;
+            }
         }
     }
 
     pub fn on_event(&mut self, event: &IrcEvent) {
+        match *event {
+            // `on_message` counts messages itself.
+            IrcEvent::IrcMsg(_) => (),
+            _ => self.generation += 1,
+        }
         let () = match *event {
-            IrcEvent::IrcMsg(ref message) => self.on_message(message),
+            IrcEvent::IrcMsg(ref message) => {
+                // The legacy parser accepts a little more than `IrcMsg`
+                // does; such lines can't be anything `State` tracks.
+                if let Ok(msg) = IrcMsg::new(message.as_bytes()) {
+                    self.on_message(msg);
+                }
+            },
             IrcEvent::JoinBundle(Ok(ref join_bun)) => self.on_self_join(join_bun),
             IrcEvent::JoinBundle(Err(_)) => (),
             IrcEvent::WhoBundle(Ok(ref who_bun)) => self.on_who(who_bun),
             IrcEvent::WhoBundle(Err(_)) => (),
+            IrcEvent::NamesBundle(ref names) => self.on_names(names),
+            IrcEvent::ListEntry(_) => (),
+            IrcEvent::ListBundle(_) => (),
+            IrcEvent::WhoisBundle(_) => (),
+            IrcEvent::ServerNotice(_) => (),
+            IrcEvent::StandardReply(_) => (),
+            // Handled with the 005 itself, in `on_message`
+            IrcEvent::CaseMappingChange(_) => (),
+            IrcEvent::ParseFailure(_) => (),
         };
     }
 
+    /// Our server notice mask, such as `cCkK`, or `None` without user
+    /// mode `+s`.
+    pub fn get_snomask(&self) -> Option<&str> {
+        self.snomask.as_ref().map(|mask| &mask[..])
+    }
+
+    pub fn has_snomask(&self, notice_type: char) -> bool {
+        self.snomask.as_ref().map(|mask| mask.contains(notice_type)).unwrap_or(false)
+    }
+
+    /// The server's ISUPPORT tokens so far.
+    pub fn get_server_info(&self) -> &ServerInfo {
+        &self.isupport
+    }
+
+    /// The channel modes the server advertised in ISUPPORT, or the
+    /// RFC 1459 ones if it hasn't.
+    pub fn get_mode_table(&self) -> &ModeTable {
+        &self.mode_table
+    }
+
+    /// Counts the messages and events applied to the state, for matching
+    /// `StateDiff`s to the replicas they apply to.
+    pub fn get_generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn get_interner(&self) -> &Interner {
+        &self.interner
+    }
+
+    /// Intern channel names into `interner`, such as the one from
+    /// `IrcSession::get_interner`, so that channels' symbols match those
+    /// of the session's events.  Set this before joining anything:
+    /// channels already known keep their old symbols.
+    pub fn set_interner(&mut self, interner: Interner) {
+        self.user_map = self.user_map.drain()
+            .map(|(key, id)| (interner.intern(&key), id))
+            .collect();
+        self.interner = interner;
+    }
+
+    pub fn get_case_mapping(&self) -> AnyCaseMapping {
+        self.case_mapping
+    }
+
+    /// Switch to a different case mapping, re-folding every known nick
+    /// and channel name.  This happens on its own when the server
+    /// advertises CASEMAPPING.
+    pub fn set_case_mapping(&mut self, case_mapping: AnyCaseMapping) {
+        if self.case_mapping == case_mapping {
+            return;
+        }
+        self.case_mapping = case_mapping;
+
+        self.user_map.clear();
+        for (&id, user) in self.users.iter() {
+            let nick = IrcIdentifier::new(case_mapping, user.get_nick());
+            self.user_map.insert(self.interner.intern(&nick), id);
+        }
+        self.channel_map.clear();
+        for (&id, channel) in self.channels.iter_mut() {
+            channel.key = IrcIdentifier::new(case_mapping, channel.get_name_raw());
+            self.channel_map.insert(channel.key.clone(), id);
+        }
+    }
+
     pub fn get_self_nick<'a>(&'a self) -> &'a str {
         &self.self_nick
     }
 
     pub fn set_self_nick(&mut self, new_nick_str: &str) {
-        let new_nick = IrcIdentifier::from_str(new_nick_str);
-        let old_nick = IrcIdentifier::from_str(&self.self_nick);
         if &self.self_nick != "" {
+            let new_nick = self.intern_user_key(new_nick_str);
+            let old_nick = self.intern_user_key(&self.self_nick);
             let user_id = match self.user_map.remove(&old_nick) {
                 Some(user_id) => user_id,
                 None => panic!("inconsistent user_map: {:?}[{:?}]",
@@ -658,7 +1902,7 @@ impl State {
     }
 
     fn initialize_self_nick(&mut self, new_nick_str: &str) {
-        let new_nick = IrcIdentifier::from_str(new_nick_str);
+        let new_nick = self.intern_user_key(new_nick_str);
         self.user_map.insert(new_nick, self.self_id);
 
         let hack_prefix = format!("{}!someone@somewhere", new_nick_str);
@@ -667,13 +1911,15 @@ impl State {
             // FIXME: hack
             prefix: IrcMsgPrefix::new(Cow::Owned(hack_prefix)),
             channels: HashSet::new(),
+            away: None,
+            account: None,
         });
         self.set_self_nick(new_nick_str);
     }
 
     fn apply_update_self_nick(&mut self, new_nick_str: &str) {
-        let new_nick = IrcIdentifier::from_str(new_nick_str);
-        let old_nick = IrcIdentifier::from_str(&self.self_nick);
+        let new_nick = self.intern_user_key(new_nick_str);
+        let old_nick = self.intern_user_key(&self.self_nick);
         assert!(self.user_map.remove(&old_nick).is_some());
         self.set_self_nick(&new_nick_str);
         self.user_map.insert(new_nick, self.self_id);
@@ -686,10 +1932,10 @@ impl State {
 
     fn apply_create_chan(&mut self, chan_info: &ChannelInfo) {
         let ChannelId(chan_id) = chan_info.id;
-        self.channel_seq = max(self.channel_seq, chan_id);
+        self.channel_seq = max(self.channel_seq, chan_id + 1);
 
-        self.channels.insert(chan_info.id, Channel::from_info(chan_info));
-        let channel_name = IrcIdentifier::from_str(&chan_info.name);
+        self.channels.insert(chan_info.id, Channel::from_info(chan_info, self.case_mapping, &self.interner));
+        let channel_name = IrcIdentifier::new(self.case_mapping, &chan_info.name);
         self.channel_map.insert(channel_name, chan_info.id);
     }
 
@@ -705,24 +1951,25 @@ impl State {
 
     fn apply_create_user(&mut self, user_info: &UserInfo) {
         let UserId(user_id) = user_info.id;
-        self.user_seq = max(self.user_seq, user_id);
+        self.user_seq = max(self.user_seq, user_id + 1);
 
 
         self.users.insert(user_info.id, User::from_info(user_info));
-        self.user_map.insert(IrcIdentifier::from_str(user_info.get_nick()), user_info.id);
+        let key = self.intern_user_key(user_info.get_nick());
+        self.user_map.insert(key, user_info.id);
     }
 
     fn apply_update_user(&mut self, id: UserId, diff: &Vec<UserDiffCmd>) {
         match self.users.entry(id) {
             hash_map::Entry::Occupied(mut entry) => {
 
-                let old_nick = IrcIdentifier::from_str(entry.get().get_nick());
+                let old_nick = IrcIdentifier::new(self.case_mapping, entry.get().get_nick());
                 let new_user = entry.get().patch(diff);
-                let new_nick = IrcIdentifier::from_str(new_user.get_nick());
+                let new_nick = IrcIdentifier::new(self.case_mapping, new_user.get_nick());
 
                 if old_nick != new_nick {
-                    assert_eq!(self.user_map.remove(&old_nick), Some(id));
-                    self.user_map.insert(new_nick, id);
+                    assert_eq!(self.user_map.remove(&self.interner.intern(&old_nick)), Some(id));
+                    self.user_map.insert(self.interner.intern(&new_nick), id);
                 }
                 entry.insert(new_user);
             }
@@ -736,7 +1983,7 @@ impl State {
             Some(user_info) => user_info,
             None => panic!("cannot apply command: {:?} not found.", id)
         };
-        let user_nick = IrcIdentifier::from_str(user_info.get_nick());
+        let user_nick = self.intern_user_key(user_info.get_nick());
         match self.user_map.remove(&user_nick) {
             Some(user_id) => assert_eq!(user_id, id),
             None => panic!("inconsistent user_mapm: {:?}[{:?}]",
@@ -748,6 +1995,10 @@ impl State {
         match *cmd {
             StateCommand::UpdateSelfNick(ref new_nick) =>
                 self.apply_update_self_nick(&new_nick),
+            StateCommand::UpdateSnomask(ref snomask) =>
+                self.snomask = snomask.clone(),
+            StateCommand::UpdateCaseMapping(case_mapping) =>
+                self.set_case_mapping(case_mapping),
             StateCommand::SetGeneration(generation) =>
                 self.generation = generation,
 
@@ -789,7 +2040,7 @@ impl State {
                 if entry.get().users.len() == 1 && entry.get().users.contains(&uid) {
                     true
                 } else {
-                    entry.get_mut().users.remove(&uid);
+                    entry.get_mut().remove_member(uid);
                     false
                 }
             },
@@ -813,20 +2064,23 @@ impl State {
         }
     }
 
-    fn update_channel_by_name<F>(&mut self, name: &str, modfunc: F) -> bool where
+    fn update_channel_by_name<T, F>(&mut self, name: T, modfunc: F) -> bool where
+        T: AsRef<[u8]>,
         F: FnOnce(&mut Channel) -> ()
     {
         let chan_id = deref_opt_or_return!(
-            self.channel_map.get(&IrcIdentifier::from_str(name)),
+            self.channel_map.get(&IrcIdentifier::new(self.case_mapping, name)),
             "Unknown channel name", false);
         let result = self.update_channel(chan_id, modfunc);
         self.validate_state_internal_panic();
         result
     }
 
-    fn remove_channel_by_name(&mut self, name: &str) -> Option<ChannelId> {
+    fn remove_channel_by_name<T>(&mut self, name: T) -> Option<ChannelId>
+        where T: AsRef<[u8]>
+    {
         let chan_id = deref_opt_or_return!(
-            self.channel_map.get(&IrcIdentifier::from_str(name)),
+            self.channel_map.get(&IrcIdentifier::new(self.case_mapping, name)),
             "Unknown channel name", None);
         assert!(self.remove_channel_by_id(chan_id));
         self.validate_state_internal_panic();
@@ -836,24 +2090,27 @@ impl State {
     fn remove_channel_by_id(&mut self, id: ChannelId) -> bool {
         let (chan_name, users): (_, Vec<_>) = match self.channels.get(&id) {
             Some(chan_state) => (
-                IrcIdentifier::from_str(&chan_state.name),
+                chan_state.key.clone(),
                 chan_state.users.iter().map(|x| *x).collect()
             ),
             None => return false
         };
         for user_id in users.into_iter() {
-            self.channels.get_mut(&id).unwrap().users.remove(&user_id);
+            self.channels.get_mut(&id).unwrap().remove_member(user_id);
             self.users.get_mut(&user_id).unwrap().channels.remove(&id);
             // self.unlink_user_channel(user_id, id);
         }
         self.channels.remove(&id);
         self.channel_map.remove(&chan_name);
+        self.pending_lists.retain(|&(chan_id, _), _| chan_id != id);
         self.validate_state_internal_panic();
         true
     }
 
-    fn get_channel_by_name(&self, name: &str) -> Option<(ChannelId, &Channel)> {
-        let chan_id = match self.channel_map.get(&IrcIdentifier::from_str(name)) {
+    fn get_channel_by_name<T>(&self, name: T) -> Option<(ChannelId, &Channel)>
+        where T: AsRef<[u8]>
+    {
+        let chan_id = match self.channel_map.get(&IrcIdentifier::new(self.case_mapping, name)) {
             Some(chan_id) => *chan_id,
             None => return None
         };
@@ -865,7 +2122,7 @@ impl State {
 
     fn insert_user(&mut self, user: User) {
         let user_id = user.id;
-        let nick = IrcIdentifier::from_str(user.prefix.nick().unwrap());
+        let nick = self.intern_user_key(user.prefix.nick().unwrap());
         assert!(self.users.insert(user_id, user).is_none());
         assert!(self.user_map.insert(nick, user_id).is_none());
         self.validate_state_internal_panic();
@@ -874,8 +2131,7 @@ impl State {
     fn update_user_by_nick<F>(&mut self, nick: &str, modfunc: F) -> bool where
         F: FnOnce(&mut User) -> ()
     {
-        let nick = IrcIdentifier::from_str(nick);
-        let user_id = deref_opt_or_return!(self.user_map.get(&nick),
+        let user_id = deref_opt_or_return!(self.lookup_user(nick),
             "Couldn't find user by nick", false);
         let result = self.update_user(user_id, modfunc);
 
@@ -888,14 +2144,14 @@ impl State {
     {
         match self.users.entry(id) {
             hash_map::Entry::Occupied(mut entry) => {
-                let prev_nick = IrcIdentifier::from_str(entry.get().prefix.nick().unwrap());
+                let prev_nick = IrcIdentifier::new(self.case_mapping, entry.get().prefix.nick().unwrap());
                 modfunc(entry.get_mut());
-                let new_nick = IrcIdentifier::from_str(entry.get().prefix.nick().unwrap());
+                let new_nick = IrcIdentifier::new(self.case_mapping, entry.get().prefix.nick().unwrap());
                 warn!("prev_nick != new_nick || {:?} != {:?}", prev_nick, new_nick);
                 if prev_nick != new_nick {
                     warn!("self.user_map -- REMOVE {:?}; INSERT {:?}", prev_nick, new_nick);
-                    self.user_map.remove(&prev_nick);
-                    self.user_map.insert(new_nick, id);
+                    self.user_map.remove(&self.interner.intern(&prev_nick));
+                    self.user_map.insert(self.interner.intern(&new_nick), id);
                 }
                 true
             }
@@ -904,7 +2160,7 @@ impl State {
     }
 
     fn remove_user_by_nick(&mut self, name: &str) -> Option<UserId> {
-        let user_id = match self.user_map.get(&IrcIdentifier::from_str(name)) {
+        let user_id = match self.lookup_user(name) {
             Some(user_id) => *user_id,
             None => return None
         };
@@ -920,24 +2176,26 @@ impl State {
         }
         let (nick, channels): (_, Vec<_>) = match self.users.get(&id) {
             Some(user_state) => (
-                IrcIdentifier::from_str(user_state.prefix.nick().unwrap()),
+                IrcIdentifier::new(self.case_mapping, user_state.prefix.nick().unwrap()),
                 user_state.channels.iter().map(|x| *x).collect(),
             ),
             None => return false
         };
         for chan_id in channels.into_iter() {
-            self.channels.get_mut(&chan_id).unwrap().users.remove(&id);
+            self.channels.get_mut(&chan_id).unwrap().remove_member(id);
             self.users.get_mut(&id).unwrap().channels.remove(&chan_id);
         }
 
         self.users.remove(&id).unwrap();
-        self.user_map.remove(&nick).unwrap();
+        self.user_map.remove(&self.interner.intern(&nick)).unwrap();
         self.validate_state_internal_panic();
         true
     }
 
-    pub fn identify_channel(&self, chan: &str) -> Option<ChannelId> {
-        match self.channel_map.get(&IrcIdentifier::from_str(chan)) {
+    pub fn identify_channel<T>(&self, chan: T) -> Option<ChannelId>
+        where T: AsRef<[u8]>
+    {
+        match self.channel_map.get(&IrcIdentifier::new(self.case_mapping, chan)) {
             Some(chan_id) => Some(chan_id.clone()),
             None => None
         }
@@ -947,8 +2205,23 @@ impl State {
         self.channels.get(&chid)
     }
 
+    /// The channels both users are in.
+    pub fn common_channels(&self, uid1: UserId, uid2: UserId) -> Vec<ChannelId> {
+        match (self.users.get(&uid1), self.users.get(&uid2)) {
+            (Some(user1), Some(user2)) => {
+                user1.channels.intersection(&user2.channels).map(|x| *x).collect()
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    /// All channels we are currently in.
+    pub fn channels(&self) -> hash_map::Values<ChannelId, Channel> {
+        self.channels.values()
+    }
+
     pub fn identify_nick(&self, nick: &str) -> Option<UserId> {
-        match self.user_map.get(&IrcIdentifier::from_str(nick)) {
+        match self.lookup_user(nick) {
             Some(user_id) => Some(*user_id),
             None => None
         }
@@ -958,6 +2231,23 @@ impl State {
         self.users.get(&uid)
     }
 
+    /// The id the next new user will be given.  Every existing `UserId`
+    /// is below it, and it never decreases, so data cached under an id
+    /// stays valid for as long as the `State` lives.  A fresh `State`,
+    /// as after a reconnect, starts over from the beginning: if this is
+    /// lower than a value seen earlier, caches keyed by id are stale.
+    ///
+    /// A replica built by patching only knows the ids it has been sent,
+    /// so its value may trail the original's.
+    pub fn user_seq(&self) -> u64 {
+        self.user_seq
+    }
+
+    /// The id the next new channel will be given; see `user_seq`.
+    pub fn channel_seq(&self) -> u64 {
+        self.channel_seq
+    }
+
     pub fn clone_frozen(&self) -> FrozenState {
         FrozenState(self.clone())
     }
@@ -977,9 +2267,20 @@ impl State {
             Err(msg) => panic!("invalid state: {:?}, dump = {:?}", msg, self)
         };
     }
+}
 
-
+impl State {
+    /// Check the indexes agree with each other, for states which come
+    /// from elsewhere and, in tests, after every change.
     fn validate_state_internal(&self) -> Result<(), String> {
+        if self.channel_map.len() != self.channels.len() {
+            return Err(format!("{} channels but {} in channel_map",
+                self.channels.len(), self.channel_map.len()));
+        }
+        if self.user_map.len() != self.users.len() {
+            return Err(format!("{} users but {} in user_map",
+                self.users.len(), self.user_map.len()));
+        }
         for (&id, state) in self.channels.iter() {
             if id != state.id {
                 return Err(format!("{:?} at channels[{:?}]", state.id, id));
@@ -1010,7 +2311,7 @@ impl State {
         }
         for (name, &id) in self.channel_map.iter() {
             if let Some(state) = self.channels.get(&id) {
-                if *name != IrcIdentifier::from_str(&state.name) {
+                if *name != state.key {
                     return Err(format!("{:?} at channel_map[{:?}]", state.id, name));
                 }
             } else {
@@ -1019,7 +2320,7 @@ impl State {
         }
         for (name, &id) in self.user_map.iter() {
             if let Some(state) = self.users.get(&id) {
-                if *name != IrcIdentifier::from_str(state.get_nick()) {
+                if name[..] != IrcIdentifier::new(self.case_mapping, state.get_nick())[..] {
                     return Err(format!("{:?} at user_map[{:?}]", state.id, name));
                 }
             } else {
@@ -1038,13 +2339,16 @@ impl Eq for State {}
 
 impl PartialEq for State {
     fn eq(&self, other: &State) -> bool {
-        for (nick, id) in self.user_map.iter() {
-            if Some(id) != other.user_map.get(nick) {
-                return false;
-            }
+        // User keys are compared by their bytes, as the states may
+        // intern into different tables.
+        if self.user_map.len() != other.user_map.len() {
+            return false;
         }
-        for (nick, id) in other.user_map.iter() {
-            if Some(id) != self.user_map.get(nick) {
+        let other_users: HashMap<&[u8], UserId> = other.user_map.iter()
+            .map(|(nick, &id)| (&nick[..], id))
+            .collect();
+        for (nick, id) in self.user_map.iter() {
+            if Some(id) != other_users.get(&nick[..]) {
                 return false;
             }
         }
@@ -1086,6 +2390,12 @@ impl PartialEq for State {
         if self.self_nick != other.self_nick {
             return false;
         }
+        if self.snomask != other.snomask {
+            return false;
+        }
+        if self.case_mapping != other.case_mapping {
+            return false;
+        }
         if self.generation != other.generation {
             return false;
         }
@@ -1093,12 +2403,22 @@ impl PartialEq for State {
     }
 }
 
-impl Diff<StateDiff> for State {
-    fn diff(&self, other: &State) -> StateDiff {
+impl State {
+    /// The commands which turn this state into `other`, such as a later
+    /// state of the same connection.  A replica holding a copy of this
+    /// state catches up by `patch`ing it with them.
+    pub fn diff(&self, other: &State) -> StateDiff {
         let mut commands = Vec::new();
+        // Applied first, so the commands after it fold names correctly.
+        if self.case_mapping != other.case_mapping {
+            commands.push(StateCommand::UpdateCaseMapping(other.case_mapping));
+        }
         if self.self_nick != other.self_nick {
             commands.push(StateCommand::UpdateSelfNick(other.self_nick.clone()));
         }
+        if self.snomask != other.snomask {
+            commands.push(StateCommand::UpdateSnomask(other.snomask.clone()));
+        }
 
         for (&id, cstate) in other.channels.iter() {
             if let Some(old_channel) = self.channels.get(&id) {
@@ -1106,10 +2426,10 @@ impl Diff<StateDiff> for State {
                     commands.push(StateCommand::UpdateChannel(id, old_channel.diff(cstate)));
                 }
             } else {
-                commands.push(StateCommand::CreateChannel(ChannelInfo::from_internal(cstate)));
-                if !cstate.users.is_empty() {
-                    let diff: Vec<_> = cstate.users.iter()
-                        .map(|&x| ChannelDiffCmd::AddUser(x)).collect();
+                let info = ChannelInfo::from_internal(cstate);
+                let diff = Channel::from_info(&info, other.case_mapping, &other.interner).diff(cstate);
+                commands.push(StateCommand::CreateChannel(info));
+                if !diff.is_empty() {
                     commands.push(StateCommand::UpdateChannel(id, diff));
                 }
             }
@@ -1126,10 +2446,10 @@ impl Diff<StateDiff> for State {
                     commands.push(StateCommand::UpdateUser(id, old_user.diff(ustate)));
                 }
             } else {
-                commands.push(StateCommand::CreateUser(UserInfo::from_internal(ustate)));
-                if !ustate.channels.is_empty() {
-                    let diff: Vec<_> = ustate.channels.iter()
-                        .map(|&x| UserDiffCmd::AddChannel(x)).collect();
+                let info = UserInfo::from_internal(ustate);
+                let diff = User::from_info(&info).diff(ustate);
+                commands.push(StateCommand::CreateUser(info));
+                if !diff.is_empty() {
                     commands.push(StateCommand::UpdateUser(id, diff));
                 }
             }
@@ -1150,17 +2470,664 @@ impl Diff<StateDiff> for State {
             commands: commands,
         }
     }
-}
 
-impl Patch<StateDiff> for State {
-    fn patch(&self, diff: &StateDiff) -> State {
+    /// A compact binary encoding of this state, such as to start a
+    /// replica in another process.  Desyncs and mode errors not yet
+    /// taken are left out.
+    #[cfg(feature = "serde")]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        to_bytes(self)
+    }
+
+    /// Decoded channel symbols and user keys are re-interned so that
+    /// they share storage with the decoded interner, keeping its entries
+    /// from being purged.  A state which breaks its invariants is
+    /// refused.
+    #[cfg(feature = "serde")]
+    pub fn from_bytes(bytes: &[u8]) -> Result<State, DecodeError> {
+        let mut state: State = try!(from_bytes(bytes));
+        try!(state.validate_state_internal().map_err(DecodeError::Inconsistent));
+        for channel in state.channels.values_mut() {
+            channel.symbol = state.interner.intern(&channel.symbol);
+        }
+        let interner = state.interner.clone();
+        state.user_map = state.user_map.drain()
+            .map(|(key, id)| (interner.intern(&key), id))
+            .collect();
+        Ok(state)
+    }
+
+    /// This state with `diff` applied.  Panics if `diff` was not taken
+    /// from a state at our generation; see `try_patch`.
+    pub fn patch(&self, diff: &StateDiff) -> State {
+        match self.try_patch(diff) {
+            Ok(new) => new,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// This state with `diff` applied, for replicas which may see
+    /// diffs duplicated, reordered or lost.  Fails without applying
+    /// anything if `diff` was not taken from a state at our generation.
+    pub fn try_patch(&self, diff: &StateDiff) -> Result<State, PatchError> {
+        if self.generation != diff.from_generation {
+            return Err(PatchError::WrongGeneration {
+                expected: self.generation,
+                found: diff.from_generation,
+            });
+        }
         let mut new = self.clone();
-        assert_eq!(self.generation, diff.from_generation);
         for command in diff.commands.iter() {
             new.apply_command(command);
         }
-        assert_eq!(self.generation, diff.from_generation);
-        new
+        if new.generation != diff.to_generation {
+            return Err(PatchError::WrongGeneration {
+                expected: diff.to_generation,
+                found: new.generation,
+            });
+        }
+        try!(new.validate_state_internal().map_err(PatchError::Inconsistent));
+        Ok(new)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::super::{IrcMsg, IrcEvent};
+    use super::super::watchers::{JoinSuccess, WhoRecord, WhoSuccess};
+    use super::State;
+
+    /// A WHO reply listing `nicks`, each optionally led by membership
+    /// prefixes such as `@+`.
+    fn who(channel: &str, nicks: &[&str]) -> IrcEvent {
+        IrcEvent::WhoBundle(Ok(WhoSuccess {
+            channel: channel.as_bytes().to_vec(),
+            channel_name: channel.to_string(),
+            mask: None,
+            who_records: nicks.iter().map(|entry| {
+                let nick = entry.trim_left_matches(|c| "~&@%+".contains(c));
+                (nick, &entry[..entry.len() - nick.len()])
+            }).map(|(nick, prefixes)| WhoRecord {
+                hostname: "host".to_string(),
+                server: "server".to_string(),
+                username: "user".to_string(),
+                nick: nick.to_string(),
+                flags: format!("H{}", prefixes),
+                rest: "0 real".to_string(),
+                nick_symbol: None,
+            }).collect(),
+            channel_symbol: None,
+        }))
+    }
+
+    #[test]
+    fn channel_keeps_server_capitalization() {
+        let mut state = State::new();
+        state.on_message(::IrcMsg::new(b":server 001 me :Welcome").unwrap());
+        state.on_event(&IrcEvent::JoinBundle(Ok(JoinSuccess {
+            channel: b"#Rust-IRC".to_vec(),
+            channel_name: "#Rust-IRC".to_string(),
+            nicks: vec![(Vec::new(), b"me".to_vec())],
+            topic: None,
+            channel_symbol: None,
+        })));
+
+        let chan_id = state.identify_channel("#rust-irc").unwrap();
+        assert_eq!(state.identify_channel("#RUST-irc"), Some(chan_id));
+        let channel = state.resolve_channel(chan_id).unwrap();
+        assert_eq!(channel.get_name(), "#Rust-IRC");
+        assert_eq!(channel.get_key(), b"#rust-irc");
+    }
+
+    #[test]
+    fn join_non_utf8_channel() {
+        let mut state = State::new();
+        state.on_message(::IrcMsg::new(b":server 001 me :Welcome").unwrap());
+        for name in [&b"#caf\xe9"[..], b"#caf\xe8"].iter() {
+            state.on_event(&IrcEvent::JoinBundle(Ok(JoinSuccess {
+                channel: name.to_vec(),
+                channel_name: String::from_utf8_lossy(name).into_owned(),
+                nicks: vec![(Vec::new(), b"me".to_vec())],
+                topic: None,
+                channel_symbol: None,
+            })));
+        }
+
+        // Both names display the same, but they are different channels.
+        let chan_id = state.identify_channel(&b"#caf\xe9"[..]).unwrap();
+        let other_id = state.identify_channel(&b"#caf\xe8"[..]).unwrap();
+        assert!(chan_id != other_id);
+        assert_eq!(state.identify_channel("#caf\u{fffd}"), None);
+        let channel = state.resolve_channel(chan_id).unwrap();
+        assert_eq!(channel.get_name(), "#caf\u{fffd}");
+        assert_eq!(channel.get_name_raw(), b"#caf\xe9");
+
+        state.on_message(::IrcMsg::new(&b":me!u@h PART #caf\xe8"[..]).unwrap());
+        assert_eq!(state.identify_channel(&b"#caf\xe8"[..]), None);
+        assert_eq!(state.identify_channel(&b"#caf\xe9"[..]), Some(chan_id));
+    }
+
+    #[test]
+    fn who_repairs_desynced_channel() {
+        let mut state = State::new();
+        state.on_message(::IrcMsg::new(b":server 001 me :Welcome").unwrap());
+        state.on_event(&IrcEvent::JoinBundle(Ok(JoinSuccess {
+            channel: b"#chan".to_vec(),
+            channel_name: "#chan".to_string(),
+            nicks: Vec::new(),
+            topic: None,
+            channel_symbol: None,
+        })));
+        state.on_event(&who("#chan", &["me", "alice", "bob"]));
+        let alice = state.identify_nick("alice").unwrap();
+        assert!(state.take_desyncs().is_empty());
+
+        state.on_event(&who("#chan", &["me", "bob", "carol"]));
+        let chan_id = state.identify_channel("#chan").unwrap();
+        let channel = state.resolve_channel(chan_id).unwrap();
+        let members: Vec<_> = channel.users()
+            .map(|uid| state.resolve_user(*uid).unwrap().get_nick().to_string())
+            .collect();
+        assert_eq!(channel.len(), 3);
+        assert!(members.contains(&"carol".to_string()));
+        assert!(state.identify_nick("alice").is_none());
+
+        let desyncs = state.take_desyncs();
+        assert_eq!(desyncs.len(), 1);
+        assert_eq!(desyncs[0].added, vec![state.identify_nick("carol").unwrap()]);
+        assert_eq!(desyncs[0].removed, vec![alice]);
+        assert!(state.take_desyncs().is_empty());
+    }
+
+    #[test]
+    fn common_channels() {
+        let mut state = State::new();
+        state.on_message(::IrcMsg::new(b":server 001 me :Welcome").unwrap());
+        for &(channel, nicks) in [
+            ("#a", &["me", "alice", "bob"][..]),
+            ("#b", &["me", "alice"][..]),
+            ("#c", &["me", "bob"][..]),
+        ].iter() {
+            state.on_event(&IrcEvent::JoinBundle(Ok(JoinSuccess {
+                channel: channel.as_bytes().to_vec(),
+                channel_name: channel.to_string(),
+                nicks: Vec::new(),
+                topic: None,
+                channel_symbol: None,
+            })));
+            state.on_event(&who(channel, nicks));
+        }
+
+        let me = state.identify_nick("me").unwrap();
+        let alice = state.identify_nick("alice").unwrap();
+        let bob = state.identify_nick("bob").unwrap();
+
+        let mut shared = state.common_channels(me, alice);
+        shared.sort();
+        assert_eq!(shared, vec![
+            state.identify_channel("#a").unwrap(),
+            state.identify_channel("#b").unwrap(),
+        ]);
+        assert_eq!(state.common_channels(alice, bob),
+            vec![state.identify_channel("#a").unwrap()]);
+        assert!(state.resolve_channel(shared[1]).unwrap().contains(alice));
+        assert!(!state.resolve_channel(shared[1]).unwrap().contains(bob));
+    }
+
+    #[test]
+    fn ids_are_never_reused() {
+        let mut state = State::new();
+        state.on_message(::IrcMsg::new(b":server 001 me :Welcome").unwrap());
+        let registered = state.clone();
+        state.on_event(&IrcEvent::JoinBundle(Ok(JoinSuccess {
+            channel: b"#chan".to_vec(),
+            channel_name: "#chan".to_string(),
+            nicks: Vec::new(),
+            topic: None,
+            channel_symbol: None,
+        })));
+        state.on_event(&who("#chan", &["me", "alice"]));
+        let alice = state.identify_nick("alice").unwrap();
+        let chan = state.identify_channel("#chan").unwrap();
+        assert!(alice.as_u64() < state.user_seq());
+        assert!(chan.as_u64() < state.channel_seq());
+
+        state.on_message(::IrcMsg::new(b":alice!u@h QUIT :bye").unwrap());
+        let user_seq = state.user_seq();
+        state.on_message(::IrcMsg::new(b":alice!u@h JOIN #chan").unwrap());
+        let alice_again = state.identify_nick("alice").unwrap();
+        assert!(alice < alice_again);
+        assert!(user_seq < state.user_seq());
+
+        let replica = registered.patch(&registered.diff(&state));
+        assert!(alice_again.as_u64() < replica.user_seq());
+        assert!(chan.as_u64() < replica.channel_seq());
+        assert!(State::new().user_seq() < state.user_seq());
+    }
+
+    #[test]
+    fn snomask_is_tracked() {
+        let mut state = State::new();
+        state.on_message(::IrcMsg::new(b":server 001 oper :Welcome").unwrap());
+        let registered = state.clone();
+        assert_eq!(state.get_snomask(), None);
+
+        state.on_message(::IrcMsg::new(b":oper MODE oper :+s").unwrap());
+        state.on_message(::IrcMsg::new(b":server 008 oper +cCk :Server notice mask").unwrap());
+        assert_eq!(state.get_snomask(), Some("cCk"));
+        assert!(state.has_snomask('C') && !state.has_snomask('K'));
+        assert_eq!(registered.patch(&registered.diff(&state)).get_snomask(), Some("cCk"));
+
+        state.on_message(::IrcMsg::new(b":other MODE other :-s").unwrap());
+        assert_eq!(state.get_snomask(), Some("cCk"));
+        state.on_message(::IrcMsg::new(b":oper MODE OPER :-is").unwrap());
+        assert_eq!(state.get_snomask(), None);
+    }
+
+    #[test]
+    fn casemapping_follows_isupport() {
+        use irccase::AnyCaseMapping;
+        let mut state = State::new();
+        state.on_message(::IrcMsg::new(b":server 001 me :Welcome").unwrap());
+        let registered = state.clone();
+        state.on_event(&IrcEvent::JoinBundle(Ok(JoinSuccess {
+            channel: b"#dev[x]".to_vec(),
+            channel_name: "#dev[x]".to_string(),
+            nicks: vec![(Vec::new(), b"me".to_vec())],
+            topic: None,
+            channel_symbol: None,
+        })));
+        let chan_id = state.identify_channel("#DEV{x}").unwrap();
+
+        state.on_message(::IrcMsg::new(
+            b":server 005 me CASEMAPPING=ascii :are supported by this server").unwrap());
+        assert_eq!(state.get_case_mapping(), AnyCaseMapping::Ascii);
+        assert_eq!(state.identify_channel("#DEV{x}"), None);
+        assert_eq!(state.identify_channel("#DEV[x]"), Some(chan_id));
+        assert_eq!(state.identify_nick("ME"), state.identify_nick("me"));
+
+        let replica = registered.patch(&registered.diff(&state));
+        assert_eq!(replica.get_case_mapping(), AnyCaseMapping::Ascii);
+        assert_eq!(replica.identify_channel("#dev[X]"), Some(chan_id));
+
+        state.on_message(::IrcMsg::new(
+            b":server 005 me CASEMAPPING=rfc7613 :are supported by this server").unwrap());
+        assert_eq!(state.get_case_mapping(), AnyCaseMapping::Ascii);
+
+        state.on_message(::IrcMsg::new(
+            b":server 005 me CASEMAPPING=rfc1459 :are supported by this server").unwrap());
+        assert_eq!(state.get_case_mapping(), AnyCaseMapping::Rfc1459);
+        assert_eq!(state.identify_channel("#DEV{x}"), Some(chan_id));
+    }
+
+    #[test]
+    fn self_nick_change_is_tracked() {
+        let mut state = State::new();
+        state.on_message(::IrcMsg::new(b":server 001 bot_ :Welcome").unwrap());
+        let me = state.identify_nick("bot_").unwrap();
+
+        state.on_message(::IrcMsg::new(b":bot_!u@h NICK :Bot").unwrap());
+        assert_eq!(state.get_self_nick(), "Bot");
+        assert_eq!(state.identify_nick("bot"), Some(me));
+        assert_eq!(state.identify_nick("bot_"), None);
+    }
+
+    #[test]
+    fn nick_only_tracks_names() {
+        let mut state = State::nick_only();
+        state.on_message(::IrcMsg::new(b":server 001 me :Welcome").unwrap());
+        state.on_event(&IrcEvent::JoinBundle(Ok(JoinSuccess {
+            channel: b"#big".to_vec(),
+            channel_name: "#big".to_string(),
+            nicks: vec![
+                (b"@".to_vec(), b"me".to_vec()),
+                (Vec::new(), b"alice".to_vec()),
+                (b"+".to_vec(), b"bob!b@host".to_vec()),
+            ],
+            topic: None,
+            channel_symbol: None,
+        })));
+        let chan_id = state.identify_channel("#big").unwrap();
+        assert_eq!(state.resolve_channel(chan_id).unwrap().len(), 3);
+
+        let bob = state.identify_nick("BOB").unwrap();
+        assert_eq!(state.resolve_user(bob).unwrap().get_prefix().as_slice(), "bob!*@*");
+
+        // WHO replies are ignored rather than filling in hosts.
+        let mut before = state.clone();
+        state.on_event(&who("#big", &["me", "alice", "bob", "carol"]));
+        assert_eq!(state.get_generation(), before.get_generation() + 1);
+        before.generation = state.generation;
+        assert!(state == before);
+
+        state.on_message(::IrcMsg::new(b":carol!c@host JOIN #big").unwrap());
+        state.on_message(::IrcMsg::new(b":bob!b@host NICK :robert").unwrap());
+        assert_eq!(state.identify_nick("bob"), None);
+        assert_eq!(state.identify_nick("robert"), Some(bob));
+        let carol = state.identify_nick("carol").unwrap();
+        assert_eq!(state.resolve_user(carol).unwrap().get_prefix().as_slice(), "carol!*@*");
+        assert_eq!(state.resolve_channel(chan_id).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn odd_server_lines_are_survived() {
+        let mut state = State::new();
+        state.on_message(::IrcMsg::new(b":server 001 me :Welcome").unwrap());
+        state.on_event(&IrcEvent::JoinBundle(Ok(JoinSuccess {
+            channel: b"#chan".to_vec(),
+            channel_name: "#chan".to_string(),
+            nicks: vec![(Vec::new(), b"me".to_vec())],
+            topic: None,
+            channel_symbol: None,
+        })));
+        let mut before = state.clone();
+
+        for line in [
+            &b":\xff!u@h JOIN #chan"[..],
+            b":\xff!u@h PART #chan",
+            b":\xff!u@h NICK bob",
+            b":\xff!u@h QUIT :bye",
+            b":ghost!u@h NICK bob",
+            b":ghost!u@h QUIT :bye",
+            b":alice!u@h JOIN #elsewhere",
+        ].iter() {
+            state.on_message(::IrcMsg::new(line).unwrap());
+        }
+        before.generation = state.generation;
+        assert!(state == before);
+    }
+
+        #[test]
+    fn tracks_parsed_messages() {
+        let mut state = State::new();
+        state.on_message(::IrcMsg::new(b":server 001 me :Welcome").unwrap());
+        state.on_event(&IrcEvent::JoinBundle(Ok(JoinSuccess {
+            channel: b"#chan".to_vec(),
+            channel_name: "#chan".to_string(),
+            nicks: Vec::new(),
+            topic: None,
+            channel_symbol: None,
+        })));
+        state.on_event(&who("#chan", &["me", "alice", "bob", "carol"]));
+        let chan_id = state.identify_channel("#chan").unwrap();
+
+        state.on_message(::IrcMsg::new(b":alice!u@h TOPIC #chan :caf\xe9").unwrap());
+        assert_eq!(state.resolve_channel(chan_id).unwrap().get_topic(), "caf\u{fffd}");
+
+        state.on_message(::IrcMsg::new(b":alice!u@h KICK #chan bob").unwrap());
+        assert_eq!(state.identify_nick("bob"), None);
+        state.on_message(::IrcMsg::new(b":carol!u@h PART #chan :bye").unwrap());
+        assert_eq!(state.identify_nick("carol"), None);
+        assert_eq!(state.resolve_channel(chan_id).unwrap().len(), 2);
+
+        // Legacy messages still arrive through `on_event`.
+        state.on_event(&IrcEvent::IrcMsg(IrcMsg::new(b":me!u@h PART #chan".to_vec()).unwrap()));
+        assert_eq!(state.identify_channel("#chan"), None);
+    }
+
+    #[test]
+    fn tracks_member_modes() {
+        use super::super::watchers::NamesResult;
+        let mut state = State::new();
+        state.on_message(::IrcMsg::new(b":server 001 me :Welcome").unwrap());
+        state.on_message(::IrcMsg::new(
+            b":server 005 me PREFIX=(qohv)~@%+ :are supported by this server").unwrap());
+        let registered = state.clone();
+        state.on_event(&IrcEvent::JoinBundle(Ok(JoinSuccess {
+            channel: b"#chan".to_vec(),
+            channel_name: "#chan".to_string(),
+            nicks: Vec::new(),
+            topic: None,
+            channel_symbol: None,
+        })));
+        state.on_event(&who("#chan", &["@me", "~@alice", "+bob", "carol"]));
+
+        let chan_id = state.identify_channel("#chan").unwrap();
+        let me = state.identify_nick("me").unwrap();
+        let alice = state.identify_nick("alice").unwrap();
+        let bob = state.identify_nick("bob").unwrap();
+        let carol = state.identify_nick("carol").unwrap();
+        {
+            let channel = state.resolve_channel(chan_id).unwrap();
+            assert_eq!(channel.member_modes(alice), "qo");
+            assert!(channel.has_mode(bob, 'v'));
+            assert_eq!(channel.member_modes(carol), "");
+            let mut ops = channel.members_with_mode('o');
+            ops.sort();
+            assert_eq!(ops, vec![me, alice]);
+        }
+
+        state.on_message(::IrcMsg::new(b":alice!u@h MODE #chan +h-v+b carol bob *!*@bad").unwrap());
+        {
+            let channel = state.resolve_channel(chan_id).unwrap();
+            assert_eq!(channel.member_modes(carol), "h");
+            assert_eq!(channel.member_modes(bob), "");
+        }
+
+        // A later NAMES reply replaces what we knew.
+        state.on_event(&IrcEvent::NamesBundle(NamesResult {
+            channel: b"#chan".to_vec(),
+            visibility: b'=',
+            nicks: vec![
+                (b"@".to_vec(), b"me".to_vec()),
+                (b"@".to_vec(), b"alice".to_vec()),
+                (b"+".to_vec(), b"bob".to_vec()),
+                (b"%".to_vec(), b"carol".to_vec()),
+            ],
+        }));
+        assert_eq!(state.resolve_channel(chan_id).unwrap().member_modes(alice), "o");
+        assert!(state.resolve_channel(chan_id).unwrap().has_mode(bob, 'v'));
+
+        state.on_message(::IrcMsg::new(b":carol!u@h PART #chan").unwrap());
+        assert!(state.resolve_channel(chan_id).unwrap().members_with_mode('h').is_empty());
+
+        let replica = registered.patch(&registered.diff(&state));
+        assert_eq!(replica.resolve_channel(chan_id), state.resolve_channel(chan_id));
+        let from_ops = state.clone();
+        state.on_message(::IrcMsg::new(b":alice!u@h MODE #chan -o+v me me").unwrap());
+        assert_eq!(from_ops.patch(&from_ops.diff(&state)).resolve_channel(chan_id)
+            .unwrap().member_modes(me), "v");
+    }
+
+    #[test]
+    fn tracks_away_and_account() {
+        let mut state = State::new();
+        state.on_message(::IrcMsg::new(b":server 001 me :Welcome").unwrap());
+        let registered = state.clone();
+        state.on_event(&IrcEvent::JoinBundle(Ok(JoinSuccess {
+            channel: b"#chan".to_vec(),
+            channel_name: "#chan".to_string(),
+            nicks: Vec::new(),
+            topic: None,
+            channel_symbol: None,
+        })));
+        state.on_event(&who("#chan", &["me", "alice", "bob"]));
+        let alice = state.identify_nick("alice").unwrap();
+        let bob = state.identify_nick("bob").unwrap();
+
+        state.on_message(::IrcMsg::new(b":alice!u@h AWAY :out to lunch").unwrap());
+        state.on_message(::IrcMsg::new(b":srv 301 me bob :busy").unwrap());
+        state.on_message(::IrcMsg::new(b":alice!u@h ACCOUNT alice_").unwrap());
+        assert_eq!(state.resolve_user(alice).unwrap().get_away(), Some("out to lunch"));
+        assert_eq!(state.resolve_user(bob).unwrap().get_away(), Some("busy"));
+        assert_eq!(state.resolve_user(alice).unwrap().get_account(), Some("alice_"));
+
+        state.on_message(::IrcMsg::new(b":alice!u@h AWAY").unwrap());
+        state.on_message(::IrcMsg::new(b":bob!u@h ACCOUNT *").unwrap());
+        assert!(!state.resolve_user(alice).unwrap().is_away());
+        assert_eq!(state.resolve_user(bob).unwrap().get_account(), None);
+
+        state.on_message(::IrcMsg::new(b":carol!c@h JOIN #chan carol :Carol").unwrap());
+        state.on_message(::IrcMsg::new(b":dave!d@h JOIN #chan * :Dave").unwrap());
+        let carol = state.identify_nick("carol").unwrap();
+        let dave = state.identify_nick("dave").unwrap();
+        assert_eq!(state.resolve_user(carol).unwrap().get_account(), Some("carol"));
+        assert_eq!(state.resolve_user(dave).unwrap().get_account(), None);
+
+        state.on_message(::IrcMsg::new(b":bob!u@h AWAY :gone").unwrap());
+        let replica = registered.patch(&registered.diff(&state));
+        for &uid in [alice, bob, carol, dave].iter() {
+            assert_eq!(replica.resolve_user(uid), state.resolve_user(uid));
+        }
+    }
+
+    #[test]
+    fn tracks_topic_and_channel_modes() {
+        use super::super::watchers::TopicMeta;
+        use super::super::IrcMsgPrefix;
+        use super::{Mask, ModeParamError, TopicSetter};
+
+        let masks = |masks: &[&str]| -> Vec<Mask> {
+            masks.iter().map(|mask| Mask::new(mask.as_bytes()).unwrap()).collect()
+        };
+        let mut state = State::new();
+        state.on_message(::IrcMsg::new(b":server 001 me :Welcome").unwrap());
+        let registered = state.clone();
+        state.on_event(&IrcEvent::JoinBundle(Ok(JoinSuccess {
+            channel: b"#chan".to_vec(),
+            channel_name: "#chan".to_string(),
+            nicks: Vec::new(),
+            topic: Some(TopicMeta {
+                text: b"welcome".to_vec(),
+                set_at: 1300000000,
+                set_by: IrcMsgPrefix::new("op!u@h".into()),
+            }),
+            channel_symbol: None,
+        })));
+        state.on_event(&who("#chan", &["@me", "alice"]));
+        let chan_id = state.identify_channel("#chan").unwrap();
+        assert_eq!(state.resolve_channel(chan_id).unwrap().get_topic_setter(), Some(&TopicSetter {
+            set_by: "op!u@h".to_string(),
+            set_at: Some(1300000000),
+        }));
+
+        state.on_message(::IrcMsg::new(b":srv 324 me #chan +ntkl sekrit 50").unwrap());
+        state.on_message(::IrcMsg::new(b":srv 329 me #chan 1200000000").unwrap());
+        state.on_message(::IrcMsg::new(b":srv 367 me #chan *!*@bad op 1300000000").unwrap());
+        state.on_message(::IrcMsg::new(b":srv 367 me #chan *!*@worse").unwrap());
+        state.on_message(::IrcMsg::new(b":srv 368 me #chan :End of Channel Ban List").unwrap());
+        {
+            let channel = state.resolve_channel(chan_id).unwrap();
+            let modes = channel.get_modes();
+            assert!(modes.is_set('n') && modes.is_set('t'));
+            assert_eq!(modes.get_key(), Some(&b"sekrit"[..]));
+            assert_eq!(modes.get_limit(), Some(50));
+            assert_eq!(modes.bans(), &masks(&["*!*@bad", "*!*@worse"])[..]);
+            assert_eq!(channel.get_created_at(), Some(1200000000));
+        }
+
+        state.on_message(::IrcMsg::new(b":me!u@h MODE #chan -tlk+mb-b sekrit *!*@new *!*@bad").unwrap());
+        state.on_message(::IrcMsg::new(b":alice!u@h TOPIC #chan :new topic").unwrap());
+        {
+            let channel = state.resolve_channel(chan_id).unwrap();
+            let modes = channel.get_modes();
+            assert!(modes.is_set('m') && !modes.is_set('t'));
+            assert_eq!(modes.get_key(), None);
+            assert_eq!(modes.get_limit(), None);
+            assert_eq!(modes.bans(), &masks(&["*!*@worse", "*!*@new"])[..]);
+            assert_eq!(channel.get_topic(), "new topic");
+            assert_eq!(channel.get_topic_setter().unwrap().set_by, "alice!u@h");
+            assert_eq!(channel.get_topic_setter().unwrap().set_at, None);
+        }
+        state.on_message(::IrcMsg::new(
+            b"@time=2011-10-19T16:40:51.620Z :alice!u@h TOPIC #chan :newer topic").unwrap());
+        assert_eq!(state.resolve_channel(chan_id).unwrap().get_topic_setter().unwrap().set_at,
+            Some(1319042451));
+
+        assert_eq!(state.take_mode_errors(), Vec::new());
+
+        // Malformed parameters are reported, and their changes ignored.
+        state.on_message(::IrcMsg::new(b":me!u@h MODE #chan +lk many a,b").unwrap());
+        state.on_message(::IrcMsg::new(b":me!u@h MODE #chan +lb 5").unwrap());
+        {
+            let modes = state.resolve_channel(chan_id).unwrap().get_modes();
+            assert_eq!(modes.get_limit(), Some(5));
+            assert_eq!(modes.get_key(), None);
+        }
+        assert_eq!(state.take_mode_errors(), vec![
+            (chan_id, ModeParamError::BadLimit(b"many".to_vec())),
+            (chan_id, ModeParamError::BadKey(b"a,b".to_vec())),
+            (chan_id, ModeParamError::Missing('b')),
+        ]);
+
+        // A fresh listing replaces the old one.
+        state.on_message(::IrcMsg::new(b":srv 368 me #chan :End of Channel Ban List").unwrap());
+        assert!(!state.resolve_channel(chan_id).unwrap().get_modes().is_set('b'));
+
+        let replica = registered.patch(&registered.diff(&state));
+        assert_eq!(replica.resolve_channel(chan_id), state.resolve_channel(chan_id));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn ships_state_and_diffs() {
+        use super::{DecodeError, StateDiff};
+
+        let mut state = State::new();
+        state.on_message(::IrcMsg::new(b":server 001 me :Welcome").unwrap());
+        let registered = State::from_bytes(&state.to_bytes()).unwrap();
+        assert!(registered == state);
+
+        state.on_event(&IrcEvent::JoinBundle(Ok(JoinSuccess {
+            channel: b"#chan".to_vec(),
+            channel_name: "#chan".to_string(),
+            nicks: Vec::new(),
+            topic: None,
+            channel_symbol: None,
+        })));
+        state.on_event(&who("#chan", &["@me", "+alice"]));
+        state.on_message(::IrcMsg::new(b":srv 324 me #chan +ntk sekrit").unwrap());
+        state.on_message(::IrcMsg::new(b":me!u@h MODE #chan +b *!*@bad").unwrap());
+        let chan_id = state.identify_channel("#chan").unwrap();
+
+        let diff = StateDiff::from_bytes(&registered.diff(&state).to_bytes()).unwrap();
+        assert_eq!(diff.get_from_generation(), registered.get_generation());
+        let replica = registered.patch(&diff);
+        assert!(replica == state);
+        assert_eq!(replica.resolve_channel(chan_id), state.resolve_channel(chan_id));
+
+        // Symbols decoded with the state match its interner's.
+        let copy = State::from_bytes(&state.to_bytes()).unwrap();
+        assert!(copy == state);
+        let channel = copy.resolve_channel(chan_id).unwrap();
+        assert_eq!(copy.get_interner().get(b"#chan").as_ref(), Some(channel.get_symbol()));
+
+        assert!(StateDiff::from_bytes(b"\xff").is_err());
+
+        let mut broken = state.clone();
+        broken.channel_map.clear();
+        match State::from_bytes(&broken.to_bytes()) {
+            Err(DecodeError::Inconsistent(_)) => (),
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn stale_diffs_are_refused() {
+        use super::PatchError;
+
+        let mut state = State::new();
+        state.on_message(::IrcMsg::new(b":server 001 me :Welcome").unwrap());
+        let registered = state.clone();
+        state.on_message(::IrcMsg::new(b":me!u@h JOIN #chan").unwrap());
+        state.on_event(&IrcEvent::JoinBundle(Ok(JoinSuccess {
+            channel: b"#chan".to_vec(),
+            channel_name: "#chan".to_string(),
+            nicks: vec![(Vec::new(), b"me".to_vec())],
+            topic: None,
+            channel_symbol: None,
+        })));
+        let diff = registered.diff(&state);
+        let replica = registered.try_patch(&diff).unwrap();
+        assert!(replica == state);
+
+        // The same diff again, against the already-patched replica.
+        match replica.try_patch(&diff) {
+            Err(PatchError::WrongGeneration { expected, found }) => {
+                assert_eq!(expected, state.get_generation());
+                assert_eq!(found, registered.get_generation());
+            },
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+    }
+}
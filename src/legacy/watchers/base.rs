@@ -1,7 +1,10 @@
 use std::collections::VecDeque;
+use std::time::Instant;
 
 use super::super::IrcMsg;
 use super::super::IrcEvent;
+use super::super::{CommandAliases, IgnoreList, Interner, ServerNotice, StandardReply, CaseMappingChange};
+use super::identity::SessionIdentity;
 
 pub trait MessageWatcher {
     fn on_irc_msg(&mut self, message: &IrcMsg);
@@ -27,6 +30,24 @@ pub trait EventWatcher {
 pub trait Bundler {
     fn on_irc_msg(&mut self, message: &IrcMsg) -> Vec<IrcEvent>;
 
+    /// The commands and numerics this bundler folds into its events.
+    fn consumes(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Whether `message` is one this bundler folds into its events.
+    /// Bundlers which only want replies about one target should narrow
+    /// this down.
+    fn wants(&self, message: &IrcMsg) -> bool {
+        self.consumes().contains(&message.get_command())
+    }
+
+    /// If true, messages this bundler `wants` are absorbed: they are not
+    /// emitted as `IrcEvent::IrcMsg`, so nothing handles them twice.
+    fn absorbs(&self) -> bool {
+        false
+    }
+
     /// If true, the `Bundler` should be removed from the bundler set
     fn is_finished(&mut self) -> bool;
 
@@ -48,6 +69,18 @@ pub struct BundlerManager {
 
     /// Bundler triggers.  They create Bundlers.
     bundler_triggers: Vec<Box<BundlerTrigger+Send+'static>>,
+
+    /// Our own nick, kept up to date before any trigger sees a message.
+    identity: Option<SessionIdentity>,
+
+    /// Vendor commands rewritten before anything sees a message.
+    aliases: CommandAliases,
+
+    /// Sources whose PRIVMSGs and NOTICEs are dropped.
+    ignore: IgnoreList,
+
+    /// Interns the channel names and nicks in bundled events.
+    interner: Interner,
 }
 
 impl BundlerManager {
@@ -56,17 +89,86 @@ impl BundlerManager {
             event_watchers: VecDeque::new(),
             event_bundlers: VecDeque::new(),
             bundler_triggers: Vec::new(),
+            identity: None,
+            aliases: CommandAliases::new(),
+            ignore: IgnoreList::new(),
+            interner: Interner::new(),
         }
     }
 
-    /// Initialise a BundlerManager with JoinBundlerTrigger and
-    /// WhoBundlerTrigger
+    /// Initialise a BundlerManager with WhoBundlerTrigger and
+    /// WhoisBundlerTrigger
     pub fn with_defaults() -> BundlerManager {
         let mut manager = BundlerManager::new();
         manager.add_bundler_trigger(Box::new(super::WhoBundlerTrigger::new()));
+        manager.add_bundler_trigger(Box::new(super::WhoisBundlerTrigger::new()));
+        manager
+    }
+
+    /// Initialise a BundlerManager with the defaults and a
+    /// JoinBundlerTrigger, all sharing `identity`.  The manager keeps
+    /// `identity` up to date as our nick changes.
+    pub fn with_identity(identity: SessionIdentity) -> BundlerManager {
+        let mut manager = BundlerManager::with_defaults();
+        manager.add_bundler_trigger(Box::new(
+            super::JoinBundlerTrigger::with_identity(identity.clone())));
+        manager.identity = Some(identity);
         manager
     }
 
+    /// Initialise a BundlerManager for use with a nick-only `State`:
+    /// like `with_identity`, but without a WhoBundlerTrigger, so WHO
+    /// replies pass through as plain messages rather than being
+    /// collected into bundles.
+    pub fn nick_only(identity: SessionIdentity) -> BundlerManager {
+        let mut manager = BundlerManager::new();
+        manager.add_bundler_trigger(Box::new(super::WhoisBundlerTrigger::new()));
+        manager.add_bundler_trigger(Box::new(
+            super::JoinBundlerTrigger::with_identity(identity.clone())));
+        manager.identity = Some(identity);
+        manager
+    }
+
+    pub fn get_identity(&self) -> Option<&SessionIdentity> {
+        self.identity.as_ref()
+    }
+
+    /// Replace the command alias table.  Messages whose command is an
+    /// alias are rewritten before triggers, bundlers and watchers see
+    /// them, and are emitted in their standard form.
+    pub fn set_aliases(&mut self, aliases: CommandAliases) {
+        self.aliases = aliases;
+    }
+
+    pub fn get_aliases(&self) -> &CommandAliases {
+        &self.aliases
+    }
+
+    /// Replace the ignore list.  PRIVMSGs and NOTICEs from sources it
+    /// matches are dropped without producing any events; entries expire
+    /// by the `now` passed to `on_irc_msg`.
+    pub fn set_ignore_list(&mut self, ignore: IgnoreList) {
+        self.ignore = ignore;
+    }
+
+    pub fn get_ignore_list(&self) -> &IgnoreList {
+        &self.ignore
+    }
+
+    pub fn get_ignore_list_mut(&mut self) -> &mut IgnoreList {
+        &mut self.ignore
+    }
+
+    /// The interner which fills in the symbols of bundled events.
+    /// Hand a clone to `State::set_interner` to share its table.
+    pub fn get_interner(&self) -> &Interner {
+        &self.interner
+    }
+
+    pub fn set_interner(&mut self, interner: Interner) {
+        self.interner = interner;
+    }
+
     // Do we really need +Send here?
     pub fn add_watcher(&mut self, watcher: Box<EventWatcher+Send+'static>) {
         self.event_watchers.push_back(watcher);
@@ -80,19 +182,50 @@ impl BundlerManager {
         self.bundler_triggers.push(bundler);
     }
 
-    pub fn on_irc_msg(&mut self, msg: &IrcMsg) -> Vec<IrcEvent> {
+    /// Handle a message received at `now`.
+    pub fn on_irc_msg(&mut self, msg: &IrcMsg, now: Instant) -> Vec<IrcEvent> {
+        let normalized = self.aliases.normalize(msg);
+        let msg = normalized.as_ref().unwrap_or(msg);
         let mut outgoing_events: Vec<IrcEvent> = Vec::new();
 
+        if let Some(ref identity) = self.identity {
+            identity.on_irc_msg(msg);
+        }
+
+        if !self.ignore.is_empty() {
+            self.ignore.expire(now);
+            if self.ignore.should_drop(msg, now) {
+                return outgoing_events;
+            }
+        }
+
         for new_bundler in bundler_trigger_impl(&mut self.bundler_triggers, msg).into_iter() {
             println!("event_bundlers.push_back(name={:?})", new_bundler.get_name());
             self.event_bundlers.push_back(new_bundler);
         }
 
-        for event in bundler_accept_impl(&mut self.event_bundlers, msg).into_iter() {
-            outgoing_events.push(event);
+        let (events, absorbed) = bundler_accept_impl(&mut self.event_bundlers, msg);
+        outgoing_events.extend(events);
+
+        if !absorbed {
+            outgoing_events.push(IrcEvent::IrcMsg(msg.clone()));
+        }
+        if let Some(snotice) = ServerNotice::from_msg(msg) {
+            outgoing_events.push(IrcEvent::ServerNotice(snotice));
+        }
+        if let Some(reply) = StandardReply::from_msg(msg) {
+            outgoing_events.push(IrcEvent::StandardReply(reply));
+        }
+        if let Some(change) = CaseMappingChange::from_msg(msg) {
+            if let Ok(case_mapping) = change.get_case_mapping() {
+                self.ignore.set_case_mapping(case_mapping);
+            }
+            outgoing_events.push(IrcEvent::CaseMappingChange(change));
         }
 
-        outgoing_events.push(IrcEvent::IrcMsg(msg.clone()));
+        for event in outgoing_events.iter_mut() {
+            intern_symbols(&self.interner, event);
+        }
 
         for event in outgoing_events.iter() {
             for watcher in watcher_accept_impl(&mut self.event_watchers, event).into_iter() {
@@ -104,6 +237,23 @@ impl BundlerManager {
     }
 }
 
+fn intern_symbols(interner: &Interner, event: &mut IrcEvent) {
+    match *event {
+        IrcEvent::JoinBundle(Ok(ref mut join)) => {
+            join.channel_symbol = Some(interner.intern(&join.channel));
+        },
+        IrcEvent::WhoBundle(Ok(ref mut who)) => {
+            if !who.channel.is_empty() {
+                who.channel_symbol = Some(interner.intern(&who.channel));
+            }
+            for record in who.who_records.iter_mut() {
+                record.nick_symbol = Some(interner.intern(record.nick.as_bytes()));
+            }
+        },
+        _ => (),
+    }
+}
+
 fn bundler_trigger_impl(triggers: &mut Vec<Box<BundlerTrigger+Send+'static>>,
                         msg: &IrcMsg
                        ) -> Vec<Box<Bundler+Send>> {
@@ -149,16 +299,22 @@ fn watcher_accept_impl(buf: &mut VecDeque<Box<EventWatcher+Send+'static>>,
 }
 
 
+/// Feed `msg` to the bundlers, returning their events and whether one of
+/// them absorbed it.
 fn bundler_accept_impl(buf: &mut VecDeque<Box<Bundler+Send+'static>>,
                        msg: &IrcMsg
-                      ) -> Vec<IrcEvent> {
+                      ) -> (Vec<IrcEvent>, bool) {
 
     let mut keep_bundlers: VecDeque<Box<Bundler+Send>> = VecDeque::new();
     let mut emit_events: Vec<IrcEvent> = Vec::new();
+    let mut absorbed = false;
 
     loop {
         match buf.pop_front() {
             Some(mut bundler) => {
+                if bundler.absorbs() && bundler.wants(msg) {
+                    absorbed = true;
+                }
                 for event in bundler.on_irc_msg(msg).into_iter() {
                     emit_events.push(event);
                 }
@@ -177,5 +333,5 @@ fn bundler_accept_impl(buf: &mut VecDeque<Box<Bundler+Send+'static>>,
             None => break
         }
     }
-    emit_events
+    (emit_events, absorbed)
 }
@@ -0,0 +1,78 @@
+use std::sync::{Arc, RwLock};
+
+use irccase::IrcAsciiExt;
+
+use super::super::IrcMsg;
+
+/// Our own nick, shared by every component which needs to recognise
+/// messages about ourselves.  Clones are handles to the same identity, so
+/// a nick change observed once is visible to all of them, including
+/// bundlers which were created before the change.
+#[derive(Clone, Debug)]
+pub struct SessionIdentity {
+    nick: Arc<RwLock<Vec<u8>>>,
+}
+
+impl SessionIdentity {
+    pub fn new(nick: &[u8]) -> SessionIdentity {
+        SessionIdentity {
+            nick: Arc::new(RwLock::new(nick.to_vec())),
+        }
+    }
+
+    pub fn get_nick(&self) -> Vec<u8> {
+        self.nick.read().unwrap().clone()
+    }
+
+    pub fn set_nick(&self, nick: &[u8]) {
+        let mut current = self.nick.write().unwrap();
+        current.clear();
+        current.extend(nick);
+    }
+
+    /// Whether `nick` refers to us, under IRC case mapping.
+    pub fn is_self(&self, nick: &[u8]) -> bool {
+        nick.eq_ignore_irc_case(&self.nick.read().unwrap())
+    }
+
+    /// Track our nick from RPL_WELCOME and from our own NICK changes.
+    pub fn on_irc_msg(&self, msg: &IrcMsg) {
+        match msg.get_command() {
+            "001" if msg.len() > 0 => self.set_nick(&msg[0]),
+            "NICK" if msg.len() > 0 => {
+                let is_self = msg.get_prefix().nick()
+                    .map(|nick| self.is_self(nick.as_bytes()))
+                    .unwrap_or(false);
+                if is_self {
+                    info!("detected self nick change {:?} -> {:?}",
+                        String::from_utf8_lossy(&self.get_nick()),
+                        String::from_utf8_lossy(&msg[0]));
+                    self.set_nick(&msg[0]);
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::IrcMsg;
+    use super::SessionIdentity;
+
+    #[test]
+    fn identity_is_shared_and_case_mapped() {
+        let identity = SessionIdentity::new(b"");
+        let other = identity.clone();
+
+        identity.on_irc_msg(&IrcMsg::new(b":server 001 Bot[1] :Welcome".to_vec()).unwrap());
+        assert!(other.is_self(b"bot{1}"));
+
+        identity.on_irc_msg(&IrcMsg::new(b":BOT[1]!u@h NICK :newbot".to_vec()).unwrap());
+        assert!(other.is_self(b"NewBot"));
+        assert!(!other.is_self(b"bot[1]"));
+
+        identity.on_irc_msg(&IrcMsg::new(b":someone!u@h NICK :other".to_vec()).unwrap());
+        assert_eq!(other.get_nick(), b"newbot".to_vec());
+    }
+}
@@ -1,11 +1,19 @@
 use std::fmt;
+use std::borrow::Cow;
 use irccase::IrcAsciiExt;
 
-use super::super::{IrcMsg, IrcEvent};
+use super::super::{IrcMsg, IrcEvent, IrcMsgPrefix, Symbol};
 use super::base::{Bundler, BundlerTrigger};
+use super::identity::SessionIdentity;
 
 pub type JoinResult = Result<JoinSuccess, JoinError>;
 
+/// Numerics by which a server refuses a JOIN, with the channel as the
+/// second argument.
+pub static JOIN_FAILURES: [&'static str; 8] = [
+    "403", "405", "471", "473", "474", "475", "476", "477",
+];
+
 trait ChannelTargeted {
     fn get_channel(&self) -> &[u8];
 }
@@ -19,18 +27,112 @@ impl ChannelTargeted for JoinResult {
     }
 }
 
+/// Channel membership prefixes which may appear in front of a nick in a
+/// NAMES reply, in order of decreasing rank.  With `multi-prefix`, a
+/// nick may carry several of them.
+const NAMES_PREFIX_CHARS: &'static [u8] = b"~&@%+";
+
+/// The bundled result of successfully joining a channel: the JOIN itself,
+/// the topic numerics (332, 333) and the NAMES reply (353, 366).
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct JoinSuccess {
+    /// The channel name, capitalized as the server reported it
     pub channel: Vec<u8>,
-    pub nicks: Vec<String>,
+    /// `channel` for display and lookups, with any invalid UTF-8 replaced
+    pub channel_name: String,
+    /// `(prefixes, nick)` pairs, exactly as received in the NAMES reply
+    pub nicks: Vec<(Vec<u8>, Vec<u8>)>,
+    /// The channel topic, if one is set
     pub topic: Option<TopicMeta>,
+    /// `channel` interned, filled in by the `BundlerManager`
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub channel_symbol: Option<Symbol>,
+}
+
+impl fmt::Display for JoinSuccess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "joined {} ({} members", self.channel_name, self.nicks.len()));
+        match self.topic {
+            Some(ref topic) => write!(f, ", topic: {})", topic),
+            None => write!(f, ")"),
+        }
+    }
+}
+
+impl JoinSuccess {
+    /// Iterate over the members of the channel at join time
+    pub fn names(&self) -> NamesIter {
+        NamesIter::new(&self.nicks)
+    }
+}
+
+/// A channel member as listed in a NAMES reply
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct NamesEntry<'a> {
+    /// Membership prefixes, e.g. `@` or `@+` with `multi-prefix`
+    pub prefixes: &'a [u8],
+    /// The nick, or `nick!user@host` with `userhost-in-names`
+    pub nick: &'a [u8],
+}
+
+impl<'a> NamesEntry<'a> {
+    /// Split a single NAMES token into its prefixes and nick.
+    pub fn parse(token: &'a [u8]) -> NamesEntry<'a> {
+        let mut split_at = 0;
+        while split_at < token.len() && NAMES_PREFIX_CHARS.contains(&token[split_at]) {
+            split_at += 1;
+        }
+        NamesEntry {
+            prefixes: &token[..split_at],
+            nick: &token[split_at..],
+        }
+    }
+
+    pub fn has_prefix(&self, prefix: u8) -> bool {
+        self.prefixes.contains(&prefix)
+    }
+
+    pub fn is_op(&self) -> bool {
+        self.has_prefix(b'@')
+    }
+
+    pub fn is_voiced(&self) -> bool {
+        self.has_prefix(b'+')
+    }
+}
+
+pub struct NamesIter<'a> {
+    inner: ::std::slice::Iter<'a, (Vec<u8>, Vec<u8>)>,
+}
+
+impl<'a> NamesIter<'a> {
+    /// Iterate over `(prefixes, nick)` pairs
+    pub fn new(nicks: &'a [(Vec<u8>, Vec<u8>)]) -> NamesIter<'a> {
+        NamesIter { inner: nicks.iter() }
+    }
+}
+
+impl<'a> Iterator for NamesIter<'a> {
+    type Item = NamesEntry<'a>;
+
+    fn next(&mut self) -> Option<NamesEntry<'a>> {
+        self.inner.next().map(|&(ref prefixes, ref nick)| NamesEntry {
+            prefixes: prefixes,
+            nick: nick,
+        })
+    }
 }
 
+/// A channel topic along with who set it and when
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct TopicMeta {
     pub text: Vec<u8>,
+    /// Seconds since the UNIX epoch
     pub set_at: u64,
-    pub set_by: String,
+    /// The hostmask (or, on some servers, only the nick) of the setter
+    pub set_by: IrcMsgPrefix<'static>,
 }
 
 
@@ -44,41 +146,68 @@ impl TopicMeta {
     }
 }
 
+impl fmt::Display for TopicMeta {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} set by {} at {}",
+            String::from_utf8_lossy(&self.text), self.set_by.as_slice(), self.set_at)
+    }
+}
+
 
+/// A failed JOIN, as reported by an error numeric
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct JoinError {
     pub channel: Vec<u8>,
     pub errcode: i16,
-    pub message: String
+    /// The server's explanation
+    pub message: String,
+    /// The numeric's arguments, exactly as received
+    pub args: Vec<Vec<u8>>,
+}
+
+impl JoinError {
+    fn from_msg(channel: &[u8], msg: &IrcMsg) -> JoinError {
+        let args = msg.get_args();
+        JoinError {
+            channel: channel.to_vec(),
+            errcode: msg.get_command().parse().unwrap_or(0),
+            message: args.last()
+                .map(|text| String::from_utf8_lossy(text).into_owned())
+                .unwrap_or_default(),
+            args: args.iter().map(|arg| arg.to_vec()).collect(),
+        }
+    }
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "failed to join {} ({}): {}",
+            String::from_utf8_lossy(&self.channel), self.errcode, self.message)
+    }
 }
 
 pub struct JoinBundlerTrigger {
-    current_nick: Vec<u8>,
+    identity: SessionIdentity,
 }
 
 
 impl JoinBundlerTrigger {
     pub fn new(nick: &[u8]) -> JoinBundlerTrigger {
-        JoinBundlerTrigger {
-            current_nick: nick.to_vec()
-        }
+        JoinBundlerTrigger::with_identity(SessionIdentity::new(nick))
     }
 
-    fn on_nick(&mut self, msg: &IrcMsg) {
-        let is_self_nick = msg.get_prefix().nick()
-            .and_then(|nick| Some(nick.as_bytes() == &self.current_nick[..]))
-            .unwrap_or(false);
-
-        if is_self_nick {
-            info!("{:?} detected nick change {:?} -> {:?}",
-                self, self.current_nick, &msg[0]);
-            self.current_nick = msg[0].to_vec();
+    /// Create a trigger which recognises our own JOINs through a shared
+    /// identity, so it keeps working across nick changes seen elsewhere.
+    pub fn with_identity(identity: SessionIdentity) -> JoinBundlerTrigger {
+        JoinBundlerTrigger {
+            identity: identity,
         }
     }
 
     fn is_self_join(&self, msg: &IrcMsg) -> bool {
         msg.get_prefix().nick()
-            .map(|nick| nick.as_bytes() == &self.current_nick[..])
+            .map(|nick| self.identity.is_self(nick.as_bytes()))
             .unwrap_or(false)
     }
 }
@@ -89,7 +218,7 @@ impl BundlerTrigger for JoinBundlerTrigger {
         match msg.get_command() {
             "JOIN" => {
                 let mut out = Vec::new();
-                if self.is_self_join(msg) {
+                if msg.len() > 0 && self.is_self_join(msg) {
                     let channel = &msg[0];
                     let bundler: Box<Bundler+Send> = Box::new(JoinBundler::new(channel));
                     out.push(bundler);
@@ -98,7 +227,7 @@ impl BundlerTrigger for JoinBundlerTrigger {
             },
             "NICK" => {
                 // potentially our nick is changing
-                self.on_nick(msg);
+                self.identity.on_irc_msg(msg);
                 Vec::new()
             }
             _ => Vec::new()
@@ -108,13 +237,13 @@ impl BundlerTrigger for JoinBundlerTrigger {
 
 impl fmt::Debug for JoinBundlerTrigger {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "JoinBundlerTrigger(current_nick={:?})", self.current_nick)
+        write!(f, "JoinBundlerTrigger(current_nick={:?})", self.identity.get_nick())
     }
 }
 
 
 struct BundlerTopicMeta {
-    set_by: String,
+    set_by: IrcMsgPrefix<'static>,
     set_at: u64,
 }
 
@@ -131,7 +260,7 @@ impl BundlerTopicMeta {
         match args3.parse().ok() {
             Some(set_at) => {
                 Some(BundlerTopicMeta {
-                    set_by: args2.to_string(),
+                    set_by: IrcMsgPrefix::new(Cow::Owned(args2.to_string())),
                     set_at: set_at,
                 })
             },
@@ -145,7 +274,7 @@ pub struct JoinBundler {
     channel: Vec<u8>,
     topic: Option<Vec<u8>>,
     topic_meta: Option<BundlerTopicMeta>,
-    nicks: Option<Vec<String>>,
+    nicks: Option<Vec<(Vec<u8>, Vec<u8>)>>,
     state: JoinBundlerState,
     result: Option<JoinResult>,
 }
@@ -171,30 +300,22 @@ impl JoinBundler {
     }
 
     fn accept_state_prejoin(&mut self, msg: &IrcMsg) -> Option<JoinBundlerState> {
-        let success = match msg.get_command() {
-            "JOIN" => {
-                if !msg[0].eq_ignore_irc_case(&self.channel) {
-                    return None;
-                }
-                true
-            },
-            "475" => {
-                if !msg[1].eq_ignore_irc_case(&self.channel) {
-                    return None;
-                }
-                false
-            },
-            _ => return None
-        };
-
-        if !success {
-            self.result = Some(Err(JoinError {
-                channel: self.channel[..].to_vec(),
-                errcode: 0,
-                message: String::new(),
-            }));
+        let command = msg.get_command();
+        if command == "JOIN" {
+            if msg.len() == 0 || !msg[0].eq_ignore_irc_case(&self.channel) {
+                return None;
+            }
+            // Prefer the server's capitalization over the one we asked for
+            self.channel = msg[0].to_vec();
+            return Some(JoinBundlerState::Joining);
         }
-        Some(if success { JoinBundlerState::Joining } else { JoinBundlerState::JoinFail })
+        if !JOIN_FAILURES.contains(&command) || msg.len() < 2
+            || !msg[1].eq_ignore_irc_case(&self.channel)
+        {
+            return None;
+        }
+        self.result = Some(Err(JoinError::from_msg(&self.channel, msg)));
+        Some(JoinBundlerState::JoinFail)
     }
 
     fn on_topic(&mut self, msg: &IrcMsg) -> Option<JoinBundlerState> {
@@ -208,13 +329,11 @@ impl JoinBundler {
     }
 
     fn on_names(&mut self, msg: &IrcMsg) -> Option<JoinBundlerState> {
-        // FIXME
-        let nicks_data = String::from_utf8_lossy(&msg[3]);
-
         if let Some(nicks) = self.nicks.as_mut() {
-            for nick in nicks_data.split(' ') {
-                if nick.len() > 0 {
-                    nicks.push(nick.to_string());
+            for token in msg[3].split(|&byte| byte == b' ') {
+                if token.len() > 0 {
+                    let entry = NamesEntry::parse(token);
+                    nicks.push((entry.prefixes.to_vec(), entry.nick.to_vec()));
                 }
             }
         }
@@ -230,8 +349,10 @@ impl JoinBundler {
         };
         self.result = Some(Ok(JoinSuccess {
             channel: self.channel.clone(),
+            channel_name: String::from_utf8_lossy(&self.channel).into_owned(),
             nicks: self.nicks.take().unwrap(),
-            topic: topic
+            topic: topic,
+            channel_symbol: None,
         }));
         Some(JoinBundlerState::Joined)
     }
@@ -291,6 +412,31 @@ impl Bundler for JoinBundler {
         }
     }
 
+    fn consumes(&self) -> &'static [&'static str] {
+        &["332", "333", "353", "366",
+          "403", "405", "471", "473", "474", "475", "476", "477"]
+    }
+
+    /// Our own JOIN is left alone, as it is what started this bundler.
+    fn wants(&self, msg: &IrcMsg) -> bool {
+        let command = msg.get_command();
+        match self.state {
+            JoinBundlerState::PreJoin => JOIN_FAILURES.contains(&command) &&
+                msg.len() > 1 && msg[1].eq_ignore_irc_case(&self.channel),
+            JoinBundlerState::Joining => match command {
+                "332" | "333" | "366" => msg.len() > 1 && msg[1].eq_ignore_irc_case(&self.channel),
+                "353" => msg.len() == 4 && msg[2].eq_ignore_irc_case(&self.channel),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// The replies are all in the `JoinBundle`.
+    fn absorbs(&self) -> bool {
+        true
+    }
+
     fn is_finished(&mut self) -> bool {
         match self.state {
             JoinBundlerState::JoinFail | JoinBundlerState::Joined => true,
@@ -309,3 +455,115 @@ impl fmt::Debug for JoinBundler {
         write!(f, "JoinBundler({:?})", &self.channel)
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::super::super::{IrcMsg, IrcEvent};
+    use super::super::base::{Bundler, BundlerManager, BundlerTrigger};
+    use super::super::identity::SessionIdentity;
+    use super::{JoinBundler, JoinBundlerTrigger, NamesEntry};
+
+    #[test]
+    fn names_entry_parse() {
+        let entry = NamesEntry::parse(b"@+sell");
+        assert_eq!(entry.prefixes, b"@+");
+        assert_eq!(entry.nick, b"sell");
+        assert!(entry.is_op());
+        assert!(entry.is_voiced());
+
+        let entry = NamesEntry::parse(b"ngy|casper");
+        assert_eq!(entry.prefixes, b"");
+        assert_eq!(entry.nick, b"ngy|casper");
+        assert!(!entry.is_op());
+    }
+
+    #[test]
+    fn join_bundle_keeps_prefixes() {
+        let mut bundler = JoinBundler::new(b"#");
+        let mut events = Vec::new();
+        for line in include_str!("../../../testdata/watcher.txt").lines() {
+            let msg = IrcMsg::new(line.as_bytes().to_vec()).unwrap();
+            events.extend(bundler.on_irc_msg(&msg).into_iter());
+        }
+        assert!(bundler.is_finished());
+        assert_eq!(events.len(), 1);
+
+        let join = match events[0] {
+            IrcEvent::JoinBundle(Ok(ref join)) => join,
+            ref other => panic!("unexpected event: {:?}", other),
+        };
+        let names: Vec<_> = join.names().collect();
+        assert_eq!(names.len(), 23);
+        assert_eq!(names[0], NamesEntry { prefixes: b"+", nick: b"sell" });
+        assert_eq!(names[2], NamesEntry { prefixes: b"", nick: b"flsp" });
+        assert_eq!(names.iter().filter(|e| e.is_voiced()).count(), 7);
+
+        let topic = join.topic.as_ref().unwrap();
+        assert_eq!(topic.set_by.nick(), Some("owls"));
+        assert_eq!(topic.set_at, 1414115720);
+        assert!(format!("{}", join).starts_with("joined # (23 members, topic: "));
+    }
+
+    #[test]
+    fn trigger_follows_shared_identity() {
+        let identity = SessionIdentity::new(b"botnick");
+        let mut trigger = JoinBundlerTrigger::with_identity(identity.clone());
+
+        let join = IrcMsg::new(b":BotNick!u@h JOIN :#test".to_vec()).unwrap();
+        assert_eq!(trigger.on_irc_msg(&join).len(), 1);
+
+        identity.set_nick(b"renamed");
+        assert_eq!(trigger.on_irc_msg(&join).len(), 0);
+
+        let join = IrcMsg::new(b":Renamed!u@h JOIN :#test".to_vec()).unwrap();
+        assert_eq!(trigger.on_irc_msg(&join).len(), 1);
+    }
+
+    #[test]
+    fn join_replies_are_absorbed() {
+        let mut manager = BundlerManager::new();
+        manager.add_bundler_trigger(Box::new(JoinBundlerTrigger::new(b"me")));
+
+        let mut raw = Vec::new();
+        let mut bundles = 0;
+        for line in [
+            &b":me!u@h JOIN #rust"[..],
+            b":srv 332 me #rust :Rust",
+            b":srv 333 me #Rust owls 1414115720",
+            b":srv 353 me = #rust :@me alice",
+            b":srv 366 me #rust :End of /NAMES list.",
+            b":srv 332 me #other :Not ours",
+        ].iter() {
+            let msg = IrcMsg::new(line.to_vec()).unwrap();
+            for event in manager.on_irc_msg(&msg, Instant::now()).into_iter() {
+                match event {
+                    IrcEvent::IrcMsg(msg) => raw.push(msg.get_command().to_string()),
+                    IrcEvent::JoinBundle(Ok(_)) => bundles += 1,
+                    _ => (),
+                }
+            }
+        }
+        assert_eq!(bundles, 1);
+        assert_eq!(raw, vec!["JOIN", "332"]);
+    }
+
+    #[test]
+    fn join_error_keeps_reply() {
+        let mut bundler = JoinBundler::new(b"#secret");
+        let msg = IrcMsg::new(b":srv 474 me #Secret :Cannot join channel (+b)".to_vec()).unwrap();
+        let events = bundler.on_irc_msg(&msg);
+        assert!(bundler.is_finished());
+        match events[..] {
+            [IrcEvent::JoinBundle(Err(ref err))] => {
+                assert_eq!(err.errcode, 474);
+                assert_eq!(err.message, "Cannot join channel (+b)");
+                assert_eq!(err.args[1], b"#Secret".to_vec());
+                assert_eq!(err.to_string(), "failed to join #secret (474): Cannot join channel (+b)");
+            },
+            ref other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use std::fmt;
+
+use super::super::{IrcMsg, IrcEvent};
+use super::base::{Bundler, BundlerTrigger};
+
+/// A channel from a LIST reply (322).
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ListEntry {
+    pub channel: Vec<u8>,
+    /// The number of visible users
+    pub users: u32,
+    pub topic: Vec<u8>,
+}
+
+impl ListEntry {
+    /// Read an RPL_LIST: `322 <me> <channel> <users> :<topic>`
+    pub fn from_msg(msg: &IrcMsg) -> Option<ListEntry> {
+        if msg.get_command() != "322" || msg.len() < 3 {
+            return None;
+        }
+        let users = match ::std::str::from_utf8(&msg[2]).ok().and_then(|u| u.parse().ok()) {
+            Some(users) => users,
+            None => return None,
+        };
+        Some(ListEntry {
+            channel: msg[1].to_vec(),
+            users: users,
+            topic: if msg.len() > 3 { msg[3].to_vec() } else { Vec::new() },
+        })
+    }
+}
+
+impl fmt::Display for ListEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({} users): {}",
+            String::from_utf8_lossy(&self.channel), self.users,
+            String::from_utf8_lossy(&self.topic))
+    }
+}
+
+/// The bundled result of a LIST command.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ListResult {
+    /// Every channel listed.  Empty when streaming, as the entries were
+    /// already emitted one by one.
+    pub entries: Vec<ListEntry>,
+    /// The number of channels listed
+    pub total: usize,
+}
+
+impl fmt::Display for ListResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "list ({} channels)", self.total)
+    }
+}
+
+
+/// Emits a `ListBundler` for each LIST reply.  Not among the defaults.
+#[derive(Clone, Debug, Default)]
+pub struct ListBundlerTrigger {
+    streaming: bool,
+    /// Whether a LIST reply is in progress
+    active: bool,
+}
+
+impl ListBundlerTrigger {
+    /// A trigger whose bundlers buffer every entry into one `ListBundle`.
+    pub fn new() -> ListBundlerTrigger {
+        ListBundlerTrigger::default()
+    }
+
+    /// A trigger whose bundlers emit a `ListEntry` event per channel as
+    /// it arrives, then an empty `ListBundle` when the reply ends.  LIST
+    /// on a large network can return tens of thousands of channels.
+    pub fn streaming() -> ListBundlerTrigger {
+        ListBundlerTrigger { streaming: true, active: false }
+    }
+}
+
+impl BundlerTrigger for ListBundlerTrigger {
+    fn on_irc_msg(&mut self, msg: &IrcMsg) -> Vec<Box<Bundler+Send>> {
+        let mut out: Vec<Box<Bundler+Send>> = Vec::new();
+        match msg.get_command() {
+            // Not every server sends RPL_LISTSTART.
+            "321" | "322" if !self.active => {
+                self.active = true;
+                out.push(Box::new(ListBundler::new(self.streaming)));
+            },
+            "323" => self.active = false,
+            _ => (),
+        }
+        out
+    }
+}
+
+
+#[derive(Clone, Debug)]
+pub struct ListBundler {
+    streaming: bool,
+    result: ListResult,
+    finished: bool,
+}
+
+impl ListBundler {
+    pub fn new(streaming: bool) -> ListBundler {
+        ListBundler {
+            streaming: streaming,
+            result: ListResult::default(),
+            finished: false,
+        }
+    }
+}
+
+impl Bundler for ListBundler {
+    fn on_irc_msg(&mut self, msg: &IrcMsg) -> Vec<IrcEvent> {
+        match msg.get_command() {
+            "322" => {
+                let entry = match ListEntry::from_msg(msg) {
+                    Some(entry) => entry,
+                    None => {
+                        warn!("ignoring malformed RPL_LIST: {:?}", msg);
+                        return Vec::new();
+                    }
+                };
+                self.result.total += 1;
+                if self.streaming {
+                    return vec![IrcEvent::ListEntry(entry)];
+                }
+                self.result.entries.push(entry);
+                Vec::new()
+            },
+            "323" => {
+                self.finished = true;
+                let result = ::std::mem::replace(&mut self.result, ListResult::default());
+                vec![IrcEvent::ListBundle(result)]
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    fn consumes(&self) -> &'static [&'static str] {
+        &["321", "322", "323"]
+    }
+
+    /// The replies are all in the `ListBundle` or `ListEntry` events.
+    fn absorbs(&self) -> bool {
+        true
+    }
+
+    fn is_finished(&mut self) -> bool {
+        self.finished
+    }
+
+    fn get_name(&self) -> &'static str {
+        "ListBundler"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::super::super::{IrcMsg, IrcEvent};
+    use super::super::base::BundlerManager;
+    use super::{ListBundlerTrigger, ListEntry, ListResult};
+
+    const LIST_REPLY: &'static [&'static [u8]] = &[
+        b":srv 321 me Channel :Users  Name",
+        b":srv 322 me #rust 1200 :The Rust programming language",
+        b":srv 322 me #empty 3 :",
+        b":srv 323 me :End of /LIST",
+    ];
+
+    fn run(trigger: ListBundlerTrigger) -> (Vec<ListEntry>, Vec<ListResult>) {
+        let mut manager = BundlerManager::new();
+        manager.add_bundler_trigger(Box::new(trigger));
+        let (mut entries, mut bundles) = (Vec::new(), Vec::new());
+        for line in LIST_REPLY.iter() {
+            let msg = IrcMsg::new(line.to_vec()).unwrap();
+            for event in manager.on_irc_msg(&msg, Instant::now()).into_iter() {
+                match event {
+                    IrcEvent::ListEntry(entry) => entries.push(entry),
+                    IrcEvent::ListBundle(bundle) => bundles.push(bundle),
+                    IrcEvent::IrcMsg(msg) => panic!("{:?} was not absorbed", msg),
+                    _ => (),
+                }
+            }
+        }
+        (entries, bundles)
+    }
+
+    #[test]
+    fn list_is_bundled() {
+        let (entries, bundles) = run(ListBundlerTrigger::new());
+        assert!(entries.is_empty());
+        assert_eq!(bundles.len(), 1);
+        assert_eq!(bundles[0].total, 2);
+        assert_eq!(bundles[0].entries[0], ListEntry {
+            channel: b"#rust".to_vec(),
+            users: 1200,
+            topic: b"The Rust programming language".to_vec(),
+        });
+        assert_eq!(bundles[0].entries[1].topic, b"");
+    }
+
+    #[test]
+    fn list_is_streamed() {
+        let (entries, bundles) = run(ListBundlerTrigger::streaming());
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].channel, b"#empty");
+        assert_eq!(bundles, vec![ListResult { entries: Vec::new(), total: 2 }]);
+    }
+}
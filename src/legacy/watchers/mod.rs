@@ -4,12 +4,17 @@ pub use self::join::{
     JoinError,
     JoinBundler,
     JoinBundlerTrigger,
+    JOIN_FAILURES,
+    NamesEntry,
+    NamesIter,
+    TopicMeta,
 };
 pub use self::base::{
     Bundler,
     BundlerManager,
     BundlerTrigger,
 };
+pub use self::identity::SessionIdentity;
 pub use self::register::{
     RegisterError,
     RegisterErrorType,
@@ -20,11 +25,36 @@ pub use self::who::{
     WhoRecord,
     WhoSuccess,
     WhoError,
+    WhoTarget,
+    WhoRequests,
     WhoBundler,
     WhoBundlerTrigger,
+    WhoEventWatcher,
+};
+pub use self::list::{
+    ListEntry,
+    ListResult,
+    ListBundler,
+    ListBundlerTrigger,
+};
+pub use self::names::{
+    NamesResult,
+    NamesBundler,
+    NamesBundlerTrigger,
+};
+pub use self::whois::{
+    WhoisResult,
+    WhoisSuccess,
+    WhoisError,
+    WhoisBundler,
+    WhoisBundlerTrigger,
 };
 
 pub mod join;
 pub mod base;
+pub mod identity;
 pub mod register;
-pub mod who;
\ No newline at end of file
+pub mod list;
+pub mod names;
+pub mod who;
+pub mod whois;
\ No newline at end of file
@@ -0,0 +1,232 @@
+use std::fmt;
+
+use irccase::IrcAsciiExt;
+use super::super::{IrcMsg, IrcEvent};
+use super::base::{Bundler, BundlerTrigger};
+use super::join::{NamesEntry, NamesIter};
+
+/// The bundled NAMES reply for one channel (353s up to the 366), whether
+/// or not it came with a JOIN.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct NamesResult {
+    /// The channel name, capitalized as the server reported it
+    pub channel: Vec<u8>,
+    /// `=` for a public channel, `*` for a private one and `@` for a
+    /// secret one
+    pub visibility: u8,
+    /// `(prefixes, nick)` pairs, exactly as received
+    pub nicks: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl NamesResult {
+    /// Iterate over the members of the channel
+    pub fn names(&self) -> NamesIter {
+        NamesIter::new(&self.nicks)
+    }
+}
+
+impl fmt::Display for NamesResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "names {} ({} members)",
+            String::from_utf8_lossy(&self.channel), self.nicks.len())
+    }
+}
+
+
+/// Emits a `NamesBundler` for each channel in a NAMES reply.  Not among
+/// the defaults: during a self-join, this bundles the same replies the
+/// `JoinBundler` does.
+#[derive(Clone, Debug, Default)]
+pub struct NamesBundlerTrigger {
+    /// Channels with a bundler in progress
+    active: Vec<Vec<u8>>,
+}
+
+impl NamesBundlerTrigger {
+    pub fn new() -> NamesBundlerTrigger {
+        NamesBundlerTrigger::default()
+    }
+
+    fn position(&self, channel: &[u8]) -> Option<usize> {
+        self.active.iter().position(|active| active.eq_ignore_irc_case(channel))
+    }
+}
+
+impl BundlerTrigger for NamesBundlerTrigger {
+    fn on_irc_msg(&mut self, msg: &IrcMsg) -> Vec<Box<Bundler+Send>> {
+        let mut out: Vec<Box<Bundler+Send>> = Vec::new();
+        match msg.get_command() {
+            "353" if msg.len() == 4 => {
+                if self.position(&msg[2]).is_none() {
+                    self.active.push(msg[2].to_vec());
+                    out.push(Box::new(NamesBundler::new(&msg[2])));
+                }
+            },
+            "366" if msg.len() > 1 => {
+                if &msg[1] == b"*" {
+                    self.active.clear();
+                } else if let Some(idx) = self.position(&msg[1]) {
+                    self.active.remove(idx);
+                }
+            },
+            _ => (),
+        }
+        out
+    }
+}
+
+
+/// Collects the NAMES reply for a single channel.  Attach one directly
+/// with `BundlerManager::add_bundler` when sending `NAMES <channel>`.
+#[derive(Clone, Debug)]
+pub struct NamesBundler {
+    channel: Vec<u8>,
+    visibility: u8,
+    nicks: Vec<(Vec<u8>, Vec<u8>)>,
+    finished: bool,
+}
+
+impl NamesBundler {
+    pub fn new(channel: &[u8]) -> NamesBundler {
+        NamesBundler {
+            channel: channel.to_vec(),
+            visibility: b'=',
+            nicks: Vec::new(),
+            finished: false,
+        }
+    }
+
+    fn on_names(&mut self, msg: &IrcMsg) {
+        if let Some(&visibility) = msg[1].first() {
+            self.visibility = visibility;
+        }
+        // The reply uses the server's capitalization.
+        self.channel = msg[2].to_vec();
+        for token in msg[3].split(|&byte| byte == b' ') {
+            if token.len() > 0 {
+                let entry = NamesEntry::parse(token);
+                self.nicks.push((entry.prefixes.to_vec(), entry.nick.to_vec()));
+            }
+        }
+    }
+}
+
+impl Bundler for NamesBundler {
+    fn on_irc_msg(&mut self, msg: &IrcMsg) -> Vec<IrcEvent> {
+        if !self.wants(msg) {
+            return Vec::new();
+        }
+        match msg.get_command() {
+            "353" => {
+                self.on_names(msg);
+                Vec::new()
+            },
+            // A NAMES without a channel ends with a single `366 <me> *`.
+            "366" => {
+                self.finished = true;
+                vec![IrcEvent::NamesBundle(NamesResult {
+                    channel: self.channel.clone(),
+                    visibility: self.visibility,
+                    nicks: ::std::mem::replace(&mut self.nicks, Vec::new()),
+                })]
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    fn consumes(&self) -> &'static [&'static str] {
+        &["353", "366"]
+    }
+
+    fn wants(&self, msg: &IrcMsg) -> bool {
+        match msg.get_command() {
+            "353" => msg.len() == 4 && msg[2].eq_ignore_irc_case(&self.channel),
+            "366" => msg.len() > 1 &&
+                (&msg[1] == b"*" || msg[1].eq_ignore_irc_case(&self.channel)),
+            _ => false,
+        }
+    }
+
+    /// The replies are all in the `NamesBundle`.
+    fn absorbs(&self) -> bool {
+        true
+    }
+
+    fn is_finished(&mut self) -> bool {
+        self.finished
+    }
+
+    fn get_name(&self) -> &'static str {
+        "NamesBundler"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::super::super::{IrcMsg, IrcEvent};
+    use super::super::base::BundlerManager;
+    use super::super::join::NamesEntry;
+    use super::{NamesBundler, NamesBundlerTrigger, NamesResult};
+
+    fn names_bundles(manager: &mut BundlerManager, lines: &[&[u8]]) -> Vec<NamesResult> {
+        let mut out = Vec::new();
+        for line in lines.iter() {
+            let msg = IrcMsg::new(line.to_vec()).unwrap();
+            for event in manager.on_irc_msg(&msg, Instant::now()).into_iter() {
+                if let IrcEvent::NamesBundle(result) = event {
+                    out.push(result);
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn names_are_bundled() {
+        let mut manager = BundlerManager::new();
+        manager.add_bundler_trigger(Box::new(NamesBundlerTrigger::new()));
+
+        let results = names_bundles(&mut manager, &[
+            b":srv 353 me @ #Rust :@+alice bob",
+            b":srv 353 me @ #rust :~carol",
+            b":srv 366 me #rust :End of /NAMES list.",
+        ]);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].channel, b"#rust");
+        assert_eq!(results[0].visibility, b'@');
+        let names: Vec<_> = results[0].names().collect();
+        assert_eq!(names, vec![
+            NamesEntry { prefixes: b"@+", nick: b"alice" },
+            NamesEntry { prefixes: b"", nick: b"bob" },
+            NamesEntry { prefixes: b"~", nick: b"carol" },
+        ]);
+
+        let results = names_bundles(&mut manager, &[
+            b":srv 353 me = #a :alice",
+            b":srv 353 me * #b :bob",
+            b":srv 366 me * :End of /NAMES list.",
+        ]);
+        let channels: Vec<_> = results.iter().map(|r| &r.channel[..]).collect();
+        assert_eq!(channels, vec![&b"#a"[..], b"#b"]);
+    }
+
+    #[test]
+    fn names_replies_are_absorbed() {
+        let mut manager = BundlerManager::new();
+        manager.add_bundler(Box::new(NamesBundler::new(b"#rust")));
+
+        let raw = |manager: &mut BundlerManager, line: &[u8]| {
+            let msg = IrcMsg::new(line.to_vec()).unwrap();
+            manager.on_irc_msg(&msg, Instant::now()).into_iter()
+                .filter(|event| match *event { IrcEvent::IrcMsg(_) => true, _ => false })
+                .count()
+        };
+        assert_eq!(raw(&mut manager, b":srv 353 me = #other :alice"), 1);
+        assert_eq!(raw(&mut manager, b":srv 353 me = #rust :alice"), 0);
+        assert_eq!(raw(&mut manager, b":srv 366 me #rust :End of /NAMES list."), 0);
+        assert_eq!(raw(&mut manager, b":srv 366 me #rust :End of /NAMES list."), 1);
+    }
+}
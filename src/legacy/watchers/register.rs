@@ -1,3 +1,6 @@
+use std::error::Error;
+use std::fmt;
+
 use super::super::{IrcMsg, numerics};
 use super::super::message_types::server;
 
@@ -20,7 +23,22 @@ impl RegisterError {
     }
 }
 
-#[derive(Clone, Debug, Copy)]
+impl fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = self.message.get_args().last()
+            .map(|text| String::from_utf8_lossy(text).into_owned())
+            .unwrap_or_default();
+        write!(f, "registration failed ({}): {}", self.message.get_command(), text)
+    }
+}
+
+impl Error for RegisterError {
+    fn description(&self) -> &str {
+        self.errtype.description()
+    }
+}
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
 pub enum RegisterErrorType {
     NoNicknameGiven,
     NicknameInUse,
@@ -31,6 +49,16 @@ pub enum RegisterErrorType {
 }
 
 impl RegisterErrorType {
+    pub fn description(&self) -> &'static str {
+        match *self {
+            RegisterErrorType::NoNicknameGiven => "no nickname given",
+            RegisterErrorType::NicknameInUse => "nickname in use",
+            RegisterErrorType::UnavailableResource => "nickname temporarily unavailable",
+            RegisterErrorType::ErroneousNickname => "erroneous nickname",
+            RegisterErrorType::NicknameCollision => "nickname collision",
+            RegisterErrorType::Restricted => "connection restricted",
+        }
+    }
 
     pub fn is_known_error(result: i32) -> bool {
         RegisterErrorType::from_ord_known(result).is_some()
@@ -48,3 +76,26 @@ impl RegisterErrorType {
         }
     }
 }
+
+impl fmt::Display for RegisterErrorType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::IrcMsg;
+    use super::{RegisterError, RegisterErrorType};
+
+    #[test]
+    fn register_error_display() {
+        let err = RegisterError {
+            errtype: RegisterErrorType::NicknameInUse,
+            message: IrcMsg::new(b":srv 433 * bot :Nickname is already in use".to_vec()).unwrap(),
+        };
+        assert_eq!(err.to_string(), "registration failed (433): Nickname is already in use");
+        assert_eq!(err.errtype.to_string(), "nickname in use");
+        assert!(err.should_pick_new_nickname());
+    }
+}
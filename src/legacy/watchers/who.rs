@@ -1,9 +1,12 @@
 use std::fmt;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc::SyncSender;
 use std::borrow::Cow;
 
 use irccase::IrcAsciiExt;
-use super::super::{IrcMsg, IrcEvent, IrcMsgPrefix};
+use ::server::ServerInfo;
+use super::super::{IrcMsg, IrcEvent, IrcMsgPrefix, Symbol};
 use super::base::{Bundler, BundlerTrigger, EventWatcher};
 
 use super::super::message_types::server;
@@ -23,17 +26,145 @@ impl ChannelTargeted for WhoResult {
     }
 }
 
+/// What a WHO request asks about.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WhoTarget {
+    /// The members of a channel
+    Channel(String),
+    /// Users whose nick matches a wildcard mask
+    NickMask(String),
+    /// Users whose host matches a wildcard mask
+    HostMask(String),
+    /// Every visible user, or only IRC operators with `opers_only`
+    Everyone { opers_only: bool },
+}
+
+impl WhoTarget {
+    /// Check the target can be sent: channels must look like channels,
+    /// going by the CHANTYPES in `info`, and masks must be a single,
+    /// non-empty argument.
+    pub fn validate(&self, info: &ServerInfo) -> Result<(), ()> {
+        let mask = self.get_mask();
+        let valid_arg = !mask.is_empty()
+            && !mask.starts_with(':')
+            && !mask.bytes().any(|b| b == b' ' || b == b',' || b == b'\r' || b == b'\n' || b == 0);
+        if !valid_arg {
+            return Err(());
+        }
+        match *self {
+            WhoTarget::Channel(ref name) if !info.is_channel(name) => Err(()),
+            _ => Ok(()),
+        }
+    }
+
+    /// The mask argument of the WHO command.  Everyone is asked for
+    /// with the mask `0`.
+    pub fn get_mask(&self) -> &str {
+        match *self {
+            WhoTarget::Channel(ref mask) |
+            WhoTarget::NickMask(ref mask) |
+            WhoTarget::HostMask(ref mask) => mask,
+            WhoTarget::Everyone { .. } => "0",
+        }
+    }
+
+    /// Whether the request carries the `o` flag.
+    pub fn is_opers_only(&self) -> bool {
+        match *self {
+            WhoTarget::Everyone { opers_only } => opers_only,
+            _ => false,
+        }
+    }
+
+    /// Whether RPL_ENDOFWHO for `mask` ends this request.
+    fn is_end_of(&self, mask: &[u8]) -> bool {
+        mask.eq_ignore_irc_case(self.get_mask().as_bytes())
+    }
+
+    /// Whether a RPL_WHOREPLY belongs to this request.  Replies only
+    /// name the channel they were found through, so mask requests take
+    /// every reply until their RPL_ENDOFWHO.
+    fn is_reply_to(&self, channel: &[u8]) -> bool {
+        match *self {
+            WhoTarget::Channel(ref name) => channel.eq_ignore_irc_case(name.as_bytes()),
+            _ => true,
+        }
+    }
+}
+
+/// WHO requests we've sent and not yet seen replies for, oldest first.
+/// Clones are handles to the same queue: hand one to the
+/// `WhoBundlerTrigger` and push each target as its WHO is sent.
+#[derive(Clone, Debug, Default)]
+pub struct WhoRequests {
+    pending: Arc<Mutex<VecDeque<WhoTarget>>>,
+}
+
+impl WhoRequests {
+    pub fn new() -> WhoRequests {
+        WhoRequests::default()
+    }
+
+    pub fn push(&self, target: WhoTarget) {
+        self.pending.lock().unwrap().push_back(target);
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn pop_front(&self) -> Option<WhoTarget> {
+        self.pending.lock().unwrap().pop_front()
+    }
+
+    fn front_is_end_of(&self, mask: &[u8]) -> bool {
+        self.pending.lock().unwrap().front()
+            .map(|target| target.is_end_of(mask))
+            .unwrap_or(false)
+    }
+}
+
+/// The bundled result of a WHO query: every 352 reply up to the
+/// terminating 315.
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct WhoSuccess {
+    /// The channel asked about, as echoed back by the server.  Empty
+    /// when a mask was asked about instead.
     pub channel: Vec<u8>,
+    /// `channel` for display and lookups, with any invalid UTF-8 replaced
+    pub channel_name: String,
+    /// The mask asked about, for requests other than a channel's
+    pub mask: Option<String>,
     pub who_records: Vec<WhoRecord>,
+    /// `channel` interned, filled in by the `BundlerManager`
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub channel_symbol: Option<Symbol>,
+}
+
+impl fmt::Display for WhoSuccess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let target = self.mask.as_ref().unwrap_or(&self.channel_name);
+        write!(f, "WHO {} ({} records)", target, self.who_records.len())
+    }
 }
 
 impl WhoSuccess {
     fn from_bundler(bundler: WhoBundler) -> WhoSuccess {
+        let (channel, mask) = match bundler.target {
+            WhoTarget::Channel(channel) => (channel, None),
+            target => (String::new(), Some(target.get_mask().to_string())),
+        };
         WhoSuccess {
-            channel: bundler.target_channel,
-            who_records: bundler.who_records
+            channel: channel.as_bytes().to_vec(),
+            channel_name: channel,
+            mask: mask,
+            who_records: bundler.who_records,
+            channel_symbol: None,
         }
     }
 }
@@ -41,18 +172,41 @@ impl WhoSuccess {
 
 // Does /WHO even error?
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct WhoError {
     pub channel: Vec<u8>,
 }
 
+impl fmt::Display for WhoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WHO {} failed", String::from_utf8_lossy(&self.channel))
+    }
+}
 
+
+/// A single 352 RPL_WHOREPLY
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct WhoRecord {
     pub hostname: String,
     pub server: String,
     pub username: String,
     pub nick: String,
+    /// `H` (here) or `G` (gone), then `*` for an operator and any
+    /// channel membership prefixes, e.g. `H@+` with `multi-prefix`
+    pub flags: String,
+    /// The trailing argument: hopcount and realname
     pub rest: String,
+    /// `nick` interned, filled in by the `BundlerManager`
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub nick_symbol: Option<Symbol>,
+}
+
+impl fmt::Display for WhoRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}!{}@{} on {}: {}",
+            self.nick, self.username, self.hostname, self.server, self.rest)
+    }
 }
 
 
@@ -65,6 +219,7 @@ impl WhoRecord {
         let hostname = args[3];
         let server = args[4];
         let nick = args[5];
+        let flags = args[6];
         let rest = args[7];
 
         Some(WhoRecord {
@@ -72,7 +227,9 @@ impl WhoRecord {
             server: String::from_utf8_lossy(server).into_owned(),
             username: String::from_utf8_lossy(username).into_owned(),
             nick: String::from_utf8_lossy(nick).into_owned(),
+            flags: String::from_utf8_lossy(flags).into_owned(),
             rest: String::from_utf8_lossy(rest).into_owned(),
+            nick_symbol: None,
         })
     }
 
@@ -86,35 +243,66 @@ impl WhoRecord {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct WhoBundlerTrigger {
-    suppress: bool
+    suppress: bool,
+    requests: Option<WhoRequests>,
 }
 
 
 impl WhoBundlerTrigger {
+    /// Bundle channel WHO replies as they arrive.
     pub fn new() -> WhoBundlerTrigger {
         WhoBundlerTrigger {
-            suppress: false
+            suppress: false,
+            requests: None,
         }
     }
+
+    /// Bundle replies to the requests in `requests`, in the order they
+    /// were sent.  Mask requests can only be bundled this way, since
+    /// their replies don't carry the mask.  Replies to requests that
+    /// weren't recorded are bundled by channel, as with `new`.
+    pub fn with_requests(requests: WhoRequests) -> WhoBundlerTrigger {
+        WhoBundlerTrigger {
+            suppress: false,
+            requests: Some(requests),
+        }
+    }
+
+    fn next_request(&mut self) -> Option<WhoTarget> {
+        self.requests.as_ref().and_then(|requests| requests.pop_front())
+    }
 }
 
 
 impl BundlerTrigger for WhoBundlerTrigger {
     fn on_irc_msg(&mut self, msg: &IrcMsg) -> Vec<Box<Bundler+Send>> {
-        let mut out = Vec::new();
-        if msg.get_command() == "315" && self.suppress {
-            self.suppress = false;
+        let mut out: Vec<Box<Bundler+Send>> = Vec::new();
+        if msg.get_command() == "315" {
+            if self.suppress {
+                self.suppress = false;
+                return out;
+            }
+            // A request with no replies at all.
+            let empty = msg.len() > 1 && self.requests.as_ref()
+                .map(|requests| requests.front_is_end_of(&msg[1]))
+                .unwrap_or(false);
+            if empty {
+                let target = self.next_request().unwrap();
+                out.push(Box::new(WhoBundler::for_target(target)));
+            }
         }
         if msg.get_command() == "352" && !self.suppress {
             if msg.len() <= 2 {
                 return out;
             }
             self.suppress = true;
-            let bundler: WhoBundler = WhoBundler::new(&msg[1]);
-            let boxed_bundler: Box<Bundler+Send> = Box::new(bundler);
-            out.push(boxed_bundler);
+            let bundler = match self.next_request() {
+                Some(target) => WhoBundler::for_target(target),
+                None => WhoBundler::new(&msg[1]),
+            };
+            out.push(Box::new(bundler));
         }
         out
     }
@@ -123,7 +311,7 @@ impl BundlerTrigger for WhoBundlerTrigger {
 
 #[derive(Clone, Debug)]
 pub struct WhoBundler {
-    target_channel: Vec<u8>,
+    target: WhoTarget,
     who_records: Vec<WhoRecord>,
     finished: bool
 }
@@ -131,8 +319,13 @@ pub struct WhoBundler {
 
 impl WhoBundler {
     pub fn new(channel: &[u8]) -> WhoBundler {
+        let channel = String::from_utf8_lossy(channel).into_owned();
+        WhoBundler::for_target(WhoTarget::Channel(channel))
+    }
+
+    pub fn for_target(target: WhoTarget) -> WhoBundler {
         WhoBundler {
-            target_channel: channel.to_vec(),
+            target: target,
             who_records: vec![],
             finished: false
         }
@@ -156,11 +349,11 @@ impl Bundler for WhoBundler {
             return Vec::new();
         }
 
-        if !args[1].eq_ignore_irc_case(&self.target_channel) {
-            return Vec::new();
-        }
-
         match server::IncomingMsg::from_msg(msg.clone()) {
+            server::IncomingMsg::Numeric(352, ref _message) if !self.target.is_reply_to(args[1]) =>
+                Vec::new(),
+            server::IncomingMsg::Numeric(315, ref _message) if !self.target.is_end_of(args[1]) =>
+                Vec::new(),
             server::IncomingMsg::Numeric(352, ref message2) => {
                 let args = message2.to_irc_msg().get_args();
                 self.add_record(&args);
@@ -176,6 +369,26 @@ impl Bundler for WhoBundler {
         }
     }
 
+    fn consumes(&self) -> &'static [&'static str] {
+        &["352", "315"]
+    }
+
+    fn wants(&self, msg: &IrcMsg) -> bool {
+        if msg.len() < 2 {
+            return false;
+        }
+        match msg.get_command() {
+            "352" => self.target.is_reply_to(&msg[1]),
+            "315" => self.target.is_end_of(&msg[1]),
+            _ => false,
+        }
+    }
+
+    /// The replies are all in the `WhoBundle`.
+    fn absorbs(&self) -> bool {
+        true
+    }
+
     fn is_finished(&mut self) -> bool {
         self.finished
     }
@@ -196,6 +409,18 @@ pub struct WhoEventWatcher {
 
 
 impl WhoEventWatcher {
+    /// Watch for the WHO bundle of `channel`.  The channel is compared
+    /// under IRC case mapping, since the server may echo it back with a
+    /// different capitalization than we requested.
+    pub fn new(channel: &[u8], monitor: SyncSender<WhoResult>) -> WhoEventWatcher {
+        WhoEventWatcher {
+            channel: channel.to_vec(),
+            result: None,
+            monitors: vec![monitor],
+            finished: false,
+        }
+    }
+
     fn dispatch_monitors(&mut self) {
         let result = self.result.clone().unwrap();
         for monitor in self.monitors.iter() {
@@ -240,3 +465,73 @@ impl EventWatcher for WhoEventWatcher {
         format!("{:?}", self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use ::server::ServerInfo;
+    use super::super::super::{IrcMsg, IrcEvent};
+    use super::super::base::BundlerManager;
+    use super::super::super::message_types::client;
+    use super::{WhoBundlerTrigger, WhoRequests, WhoTarget};
+
+    fn msg(line: &[u8]) -> IrcMsg {
+        IrcMsg::new(line.to_vec()).unwrap()
+    }
+
+    fn who_bundles(events: Vec<IrcEvent>) -> Vec<(Vec<u8>, Option<String>, usize)> {
+        events.into_iter().filter_map(|event| match event {
+            IrcEvent::WhoBundle(Ok(who)) => Some((who.channel, who.mask, who.who_records.len())),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn who_targets() {
+        let mut info = ServerInfo::new();
+        let opers = WhoTarget::Everyone { opers_only: true };
+        assert_eq!(client::Who::from_target(&opers, &info).unwrap().into_bytes(), b"WHO 0 o".to_vec());
+        assert!(client::Who::from_target(&WhoTarget::Channel("nochan".to_string()), &info).is_err());
+        assert!(client::Who::from_target(&WhoTarget::HostMask("a b".to_string()), &info).is_err());
+        assert!(WhoTarget::NickMask("al*".to_string()).validate(&info).is_ok());
+
+        let plus_channel = WhoTarget::Channel("+chan".to_string());
+        assert!(plus_channel.validate(&info).is_err());
+        info.add_token("CHANTYPES=#+");
+        assert!(plus_channel.validate(&info).is_ok());
+    }
+
+    #[test]
+    fn mask_replies_are_attributed_in_order() {
+        let requests = WhoRequests::new();
+        let mut manager = BundlerManager::new();
+        manager.add_bundler_trigger(Box::new(WhoBundlerTrigger::with_requests(requests.clone())));
+        requests.push(WhoTarget::NickMask("al*".to_string()));
+        requests.push(WhoTarget::Channel("#empty".to_string()));
+        requests.push(WhoTarget::HostMask("*.example.com".to_string()));
+
+        let mut events = Vec::new();
+        for line in [
+            &b":srv 352 me #rust al host1 srv alice H :0 Alice"[..],
+            b":srv 352 me * alf host2 srv alfred H :0 Alfred",
+            b":srv 315 me al* :End of /WHO list.",
+            b":srv 315 me #empty :End of /WHO list.",
+            b":srv 352 me #rust bob a.example.com srv bob H :0 Bob",
+            b":srv 315 me *.example.com :End of /WHO list.",
+        ].iter() {
+            events.extend(manager.on_irc_msg(&msg(line), Instant::now()));
+        }
+
+        assert!(!events.iter().any(|event| match *event {
+            IrcEvent::IrcMsg(_) => true,
+            _ => false,
+        }));
+        assert_eq!(who_bundles(events), vec![
+            (Vec::new(), Some("al*".to_string()), 2),
+            (b"#empty".to_vec(), None, 0),
+            (Vec::new(), Some("*.example.com".to_string()), 1),
+        ]);
+        assert!(requests.is_empty());
+    }
+}
@@ -0,0 +1,272 @@
+use std::fmt;
+
+use irccase::IrcAsciiExt;
+use super::super::{IrcMsg, IrcEvent};
+use super::base::{Bundler, BundlerTrigger};
+
+pub type WhoisResult = Result<WhoisSuccess, WhoisError>;
+
+/// The bundled result of a WHOIS query: the replies from 311 up to the
+/// terminating 318.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct WhoisSuccess {
+    /// The nick, as echoed back by the server
+    pub nick: String,
+    pub username: String,
+    pub hostname: String,
+    pub realname: String,
+    /// The server the user is connected to, from 312
+    pub server: Option<String>,
+    /// The channels the user is visibly in, with membership prefixes
+    pub channels: Vec<String>,
+    /// Seconds since the user last spoke, from 317
+    pub idle: Option<u64>,
+    /// The account the user is logged in to, from 330
+    pub account: Option<String>,
+    /// Whether the user is an IRC operator, from 313
+    pub is_oper: bool,
+}
+
+impl fmt::Display for WhoisSuccess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "WHOIS {}!{}@{} ({})",
+            self.nick, self.username, self.hostname, self.realname));
+        if let Some(ref account) = self.account {
+            try!(write!(f, " account {}", account));
+        }
+        Ok(())
+    }
+}
+
+/// A WHOIS for a nick which is not online.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct WhoisError {
+    pub nick: String,
+    pub errcode: i16,
+    /// The server's explanation
+    pub message: String,
+    /// The numeric's arguments, exactly as received
+    pub args: Vec<Vec<u8>>,
+}
+
+impl fmt::Display for WhoisError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WHOIS {} failed ({}): {}", self.nick, self.errcode, self.message)
+    }
+}
+
+
+/// Emits a `WhoisBundler` for each WHOIS reply.
+#[derive(Clone, Debug, Default)]
+pub struct WhoisBundlerTrigger {
+    /// The nick of the WHOIS being bundled, if any
+    active: Option<Vec<u8>>,
+    /// The most recent ERR_NOSUCHNICK, in case a 318 follows it
+    last_error: Option<WhoisError>,
+}
+
+impl WhoisBundlerTrigger {
+    pub fn new() -> WhoisBundlerTrigger {
+        WhoisBundlerTrigger::default()
+    }
+}
+
+impl BundlerTrigger for WhoisBundlerTrigger {
+    fn on_irc_msg(&mut self, msg: &IrcMsg) -> Vec<Box<Bundler+Send>> {
+        let mut out: Vec<Box<Bundler+Send>> = Vec::new();
+        if msg.len() < 2 {
+            return out;
+        }
+        match msg.get_command() {
+            "311" if self.active.is_none() => {
+                self.active = Some(msg[1].to_vec());
+                out.push(Box::new(WhoisBundler::new(&msg[1])));
+            },
+            "401" => {
+                self.last_error = Some(WhoisError {
+                    nick: String::from_utf8_lossy(&msg[1]).into_owned(),
+                    errcode: 401,
+                    message: String::from_utf8_lossy(&msg[msg.len() - 1]).into_owned(),
+                    args: msg.get_args().iter().map(|arg| arg.to_vec()).collect(),
+                });
+            },
+            "318" => {
+                let was_active = self.active.take()
+                    .map(|nick| nick.eq_ignore_irc_case(&msg[1]))
+                    .unwrap_or(false);
+                let error = self.last_error.take();
+                if was_active {
+                    return out;
+                }
+                // A WHOIS with no 311: the nick wasn't found.
+                if let Some(error) = error {
+                    if error.nick.as_bytes().eq_ignore_irc_case(&msg[1]) {
+                        out.push(Box::new(WhoisBundler::failed(error)));
+                    }
+                }
+            },
+            _ => (),
+        }
+        out
+    }
+}
+
+
+#[derive(Clone, Debug)]
+pub struct WhoisBundler {
+    nick: Vec<u8>,
+    result: WhoisResult,
+    finished: bool,
+}
+
+impl WhoisBundler {
+    pub fn new(nick: &[u8]) -> WhoisBundler {
+        let mut success = WhoisSuccess::default();
+        success.nick = String::from_utf8_lossy(nick).into_owned();
+        WhoisBundler {
+            nick: nick.to_vec(),
+            result: Ok(success),
+            finished: false,
+        }
+    }
+
+    /// A bundler which reports `error` at the 318 ending the WHOIS.
+    fn failed(error: WhoisError) -> WhoisBundler {
+        WhoisBundler {
+            nick: error.nick.as_bytes().to_vec(),
+            result: Err(error),
+            finished: false,
+        }
+    }
+
+    fn add_reply(success: &mut WhoisSuccess, msg: &IrcMsg) {
+        let arg = |idx: usize| String::from_utf8_lossy(&msg[idx]).into_owned();
+        match (msg.get_command(), msg.len()) {
+            // RPL_WHOISUSER <me> <nick> <user> <host> * :<realname>
+            ("311", 6) => {
+                success.nick = arg(1);
+                success.username = arg(2);
+                success.hostname = arg(3);
+                success.realname = arg(5);
+            },
+            // RPL_WHOISSERVER <me> <nick> <server> :<info>
+            ("312", len) if len >= 3 => success.server = Some(arg(2)),
+            ("313", _) => success.is_oper = true,
+            // RPL_WHOISIDLE <me> <nick> <idle> <signon> :<text>
+            ("317", len) if len >= 3 => success.idle = arg(2).parse().ok(),
+            // RPL_WHOISCHANNELS <me> <nick> :<channels>, possibly repeated
+            ("319", 3) => {
+                let channels = arg(2);
+                success.channels.extend(channels.split(' ')
+                    .filter(|chan| !chan.is_empty())
+                    .map(|chan| chan.to_string()));
+            },
+            // RPL_WHOISACCOUNT <me> <nick> <account> :is logged in as
+            ("330", len) if len >= 3 => success.account = Some(arg(2)),
+            _ => (),
+        }
+    }
+}
+
+impl Bundler for WhoisBundler {
+    fn on_irc_msg(&mut self, msg: &IrcMsg) -> Vec<IrcEvent> {
+        if msg.len() < 2 || !msg[1].eq_ignore_irc_case(&self.nick) {
+            return Vec::new();
+        }
+        if msg.get_command() == "318" {
+            self.finished = true;
+            return vec![IrcEvent::WhoisBundle(self.result.clone())];
+        }
+        if let Ok(ref mut success) = self.result {
+            WhoisBundler::add_reply(success, msg);
+        }
+        Vec::new()
+    }
+
+    fn consumes(&self) -> &'static [&'static str] {
+        &["311", "312", "313", "317", "318", "319", "330"]
+    }
+
+    fn wants(&self, msg: &IrcMsg) -> bool {
+        msg.len() > 1 && msg[1].eq_ignore_irc_case(&self.nick)
+            && self.consumes().contains(&msg.get_command())
+    }
+
+    /// The replies are all in the `WhoisBundle`.  RPL_AWAY is left
+    /// alone, as the `State` tracks away messages from it.
+    fn absorbs(&self) -> bool {
+        true
+    }
+
+    fn is_finished(&mut self) -> bool {
+        self.finished
+    }
+
+    fn get_name(&self) -> &'static str {
+        "WhoisBundler"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::super::super::{IrcMsg, IrcEvent};
+    use super::super::base::BundlerManager;
+    use super::{WhoisBundlerTrigger, WhoisResult};
+
+    fn whois_bundles(manager: &mut BundlerManager, lines: &[&[u8]]) -> Vec<WhoisResult> {
+        let mut out = Vec::new();
+        for line in lines.iter() {
+            let msg = IrcMsg::new(line.to_vec()).unwrap();
+            for event in manager.on_irc_msg(&msg, Instant::now()).into_iter() {
+                match event {
+                    IrcEvent::WhoisBundle(result) => out.push(result),
+                    // ERR_NOSUCHNICK arrives before any bundler exists.
+                    IrcEvent::IrcMsg(ref msg) if msg.get_command() == "401" => (),
+                    IrcEvent::IrcMsg(msg) => panic!("{:?} was not absorbed", msg),
+                    _ => (),
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn whois_is_bundled() {
+        let mut manager = BundlerManager::new();
+        manager.add_bundler_trigger(Box::new(WhoisBundlerTrigger::new()));
+
+        let results = whois_bundles(&mut manager, &[
+            b":srv 311 me Alice alice host.example * :Alice Liddell",
+            b":srv 319 me Alice :@#rust +#irc",
+            b":srv 319 me Alice :#wonderland",
+            b":srv 312 me Alice irc.example.net :Example server",
+            b":srv 313 me Alice :is an IRC operator",
+            b":srv 317 me Alice 42 1500000000 :seconds idle, signon time",
+            b":srv 330 me Alice alice :is logged in as",
+            b":srv 318 me alice :End of /WHOIS list.",
+        ]);
+        assert_eq!(results.len(), 1);
+        let whois = results[0].clone().unwrap();
+        assert_eq!(whois.nick, "Alice");
+        assert_eq!(whois.username, "alice");
+        assert_eq!(whois.hostname, "host.example");
+        assert_eq!(whois.realname, "Alice Liddell");
+        assert_eq!(whois.channels, vec!["@#rust", "+#irc", "#wonderland"]);
+        assert_eq!(whois.server, Some("irc.example.net".to_string()));
+        assert_eq!(whois.idle, Some(42));
+        assert_eq!(whois.account, Some("alice".to_string()));
+        assert!(whois.is_oper);
+
+        let results = whois_bundles(&mut manager, &[
+            b":srv 401 me bob :No such nick/channel",
+            b":srv 318 me bob :End of /WHOIS list.",
+        ]);
+        assert_eq!(results.len(), 1);
+        let err = results[0].clone().unwrap_err();
+        assert_eq!((&err.nick[..], err.errcode), ("bob", 401));
+    }
+}
@@ -0,0 +1,125 @@
+//! Periodic WHO refreshes of joined channels.
+//!
+//! `State` repairs channel membership whenever it sees a WHO reply, so
+//! re-WHOing channels every so often keeps it from drifting when a
+//! message was missed.  This module only decides *when* to send; the
+//! caller supplies the clock and sends the returned messages.
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use super::message_types::client;
+use super::state::{ChannelId, State};
+
+/// Schedules WHO requests so each joined channel is refreshed about
+/// once per `interval`, never sending two requests less than `spacing`
+/// apart.
+#[derive(Debug)]
+pub struct WhoRefresh {
+    interval: Duration,
+    spacing: Duration,
+    last_sent: Option<Instant>,
+    refreshed: HashMap<ChannelId, Instant>,
+}
+
+impl WhoRefresh {
+    pub fn new(interval: Duration, spacing: Duration) -> WhoRefresh {
+        WhoRefresh {
+            interval: interval,
+            spacing: spacing,
+            last_sent: None,
+            refreshed: HashMap::new(),
+        }
+    }
+
+    /// Returns the WHO to send now, if any.  Channels first seen by this
+    /// call count as freshly refreshed, which staggers channels joined
+    /// at the same time by the order in which they later come due.
+    /// A nick-only `State` has no use for WHO, so never gets one.
+    pub fn poll(&mut self, state: &State, now: Instant) -> Option<client::Who> {
+        if state.is_nick_only() {
+            return None;
+        }
+        let mut joined = HashSet::new();
+        for channel in state.channels() {
+            joined.insert(channel.get_id());
+            self.refreshed.entry(channel.get_id()).or_insert(now);
+        }
+        self.refreshed.retain(|id, _| joined.contains(id));
+
+        if let Some(last_sent) = self.last_sent {
+            if now < last_sent + self.spacing {
+                return None;
+            }
+        }
+
+        let interval = self.interval;
+        let due = self.refreshed.iter()
+            .filter(|&(_, &at)| at + interval <= now)
+            .min_by_key(|&(_, &at)| at)
+            .map(|(&id, _)| id);
+
+        let chan_id = match due {
+            Some(chan_id) => chan_id,
+            None => return None,
+        };
+        let channel = match state.resolve_channel(chan_id) {
+            Some(channel) => channel,
+            None => return None,
+        };
+
+        self.refreshed.insert(chan_id, now);
+        self.last_sent = Some(now);
+        Some(client::Who::new(channel.get_name_raw()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::super::{IrcEvent, JoinSuccess, State};
+    use super::WhoRefresh;
+
+    fn join(state: &mut State, channel: &[u8]) {
+        state.on_event(&IrcEvent::JoinBundle(Ok(JoinSuccess {
+            channel: channel.to_vec(),
+            channel_name: String::from_utf8_lossy(channel).into_owned(),
+            nicks: vec![(Vec::new(), b"me".to_vec())],
+            topic: None,
+            channel_symbol: None,
+        })));
+    }
+
+    #[test]
+    fn refreshes_are_staggered() {
+        let mut state = State::new();
+        state.on_message(::IrcMsg::new(b":server 001 me :Welcome").unwrap());
+        join(&mut state, b"#a");
+        join(&mut state, b"#b");
+
+        let start = Instant::now();
+        let secs = Duration::from_secs;
+        let mut refresh = WhoRefresh::new(secs(60), secs(10));
+
+        assert!(refresh.poll(&state, start).is_none());
+        assert!(refresh.poll(&state, start + secs(59)).is_none());
+
+        let first = refresh.poll(&state, start + secs(60)).unwrap();
+        assert!(refresh.poll(&state, start + secs(65)).is_none());
+        let second = refresh.poll(&state, start + secs(70)).unwrap();
+        assert!(first.to_irc_msg().as_bytes() != second.to_irc_msg().as_bytes());
+        assert!(refresh.poll(&state, start + secs(80)).is_none());
+    }
+
+    #[test]
+    fn nick_only_never_refreshes() {
+        let mut state = State::nick_only();
+        state.on_message(::IrcMsg::new(b":server 001 me :Welcome").unwrap());
+        join(&mut state, b"#a");
+
+        let start = Instant::now();
+        let mut refresh = WhoRefresh::new(Duration::from_secs(60), Duration::from_secs(10));
+        assert!(refresh.poll(&state, start + Duration::from_secs(3600)).is_none());
+    }
+}
@@ -2,6 +2,13 @@
 
 #[macro_use] extern crate log;
 extern crate unicase;
+#[cfg(feature = "unstable")] extern crate base64;
+#[cfg(feature = "unstable")] extern crate sha2;
+#[cfg(feature = "unstable")] extern crate hmac;
+#[cfg(feature = "unstable")] extern crate pbkdf2;
+#[cfg(feature = "serde")] #[macro_use] extern crate serde;
+#[cfg(feature = "serde")] extern crate bincode;
+#[cfg(test)] extern crate serde_yaml;
 
 pub use self::irccase::{
     OSCaseMapping,
@@ -11,16 +18,24 @@ pub use self::irccase::{
     AsciiCaseMapping,
     Rfc1459CaseMapping,
     StrictRfc1459CaseMapping,
+    AnyCaseMapping,
+    mask_matches,
+    mask_matches_in,
 };
 
 pub use self::parse::{
     IrcMsg,
     IrcMsgBuf,
+    IrcMsgBuilder,
     ParseError,
     ParseErrorKind,
+    MAX_MESSAGE_LEN,
+    MAX_TAGS_LEN,
 };
 
-pub use self::mtype2::{server, client, FromIrcMsg};
+pub use self::validate::{ValidationOptions, ValidationError};
+
+pub use self::mtype2::{server, client, FromIrcMsg, MessageError};
 
 #[cfg(test)] pub mod testinfra;
 
@@ -43,6 +58,27 @@ mod irccase;
 mod mtype2;
 mod parse_helpers;
 
+/// Message length and argument limits
+mod validate;
+
+/// Checking outgoing messages
+pub mod lint;
+
+/// IRCv3 message tags
+pub mod tags;
+
+/// CTCP requests and responses
+pub mod ctcp;
+
+/// Injectable randomness
+pub mod rng;
+
+/// Decoding message text
+pub mod charset;
+
+/// Commonly used types and traits
+pub mod prelude;
+
 #[cfg(feature = "unstable")]
 pub mod cap;
 
@@ -0,0 +1,179 @@
+//! Catching protocol mistakes in outgoing lines.
+//!
+//! Lines put together by hand, or legacy messages parsed from arbitrary
+//! bytes, can break the protocol in ways servers only answer with a
+//! disconnect or silent truncation: a stray newline sends a second
+//! command, and a PRIVMSG whose text lacks its `:` loses every word but
+//! the first.  A `Linter` checks lines for such mistakes, on top of the
+//! limits in `ValidationOptions`, before they are sent.
+
+use std::error::Error;
+use std::fmt;
+
+use ::parse_helpers;
+use ::parse::IrcMsg;
+use ::validate::{ValidationError, ValidationOptions};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LintError {
+    /// The line doesn't parse or is outside the `ValidationOptions`
+    Invalid(ValidationError),
+    /// A NUL, CR or LF byte, which no argument may contain
+    ForbiddenByte { byte: u8, offset: usize },
+    /// A command which is neither letters nor a three-digit numeric
+    BadCommand,
+    /// An empty middle argument, from a doubled or trailing space
+    EmptyArgument { offset: usize },
+    /// More arguments than the command takes, most likely text with
+    /// spaces that wasn't made the trailing argument with a `:`
+    MissingColon { command: String, count: usize, max: usize },
+}
+
+impl fmt::Display for LintError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LintError::Invalid(ref err) => write!(f, "{}", err),
+            LintError::ForbiddenByte { byte, offset } =>
+                write!(f, "forbidden byte {:#04x} at offset {}", byte, offset),
+            LintError::BadCommand => write!(f, "command is not letters or a numeric"),
+            LintError::EmptyArgument { offset } =>
+                write!(f, "empty argument at offset {}", offset),
+            LintError::MissingColon { ref command, count, max } =>
+                write!(f, "{} has {} arguments but takes at most {}; missing `:`?",
+                    command, count, max),
+        }
+    }
+}
+
+impl Error for LintError {
+    fn description(&self) -> &str {
+        match *self {
+            LintError::Invalid(_) => "invalid message",
+            LintError::ForbiddenByte { .. } => "forbidden byte",
+            LintError::BadCommand => "bad command",
+            LintError::EmptyArgument { .. } => "empty argument",
+            LintError::MissingColon { .. } => "missing colon",
+        }
+    }
+}
+
+/// What to do with a line which fails a check.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LintAction {
+    /// Log a warning and send it anyway
+    Log,
+    /// Log a warning and don't send it
+    Reject,
+}
+
+/// The most arguments taken by commands whose last argument is free
+/// text, and so needs a `:` if it contains spaces.
+fn max_text_args(command: &str) -> Option<usize> {
+    match command {
+        "AWAY" | "QUIT" | "WALLOPS" => Some(1),
+        "NOTICE" | "PART" | "PRIVMSG" | "TOPIC" => Some(2),
+        "KICK" => Some(3),
+        _ => None,
+    }
+}
+
+fn is_valid_command(command: &[u8]) -> bool {
+    if command.len() == 3 && command.iter().all(|b| b.is_ascii_digit()) {
+        return true;
+    }
+    !command.is_empty() && command.iter().all(|b| b.is_ascii_alphabetic())
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Linter {
+    options: ValidationOptions,
+    action: LintAction,
+}
+
+impl Linter {
+    /// A linter checking the RFC 1459 limits as well.
+    pub fn new(action: LintAction) -> Linter {
+        Linter {
+            options: ValidationOptions::new(),
+            action: action,
+        }
+    }
+
+    /// Check lengths and argument counts against `options` instead.
+    pub fn options(mut self, options: ValidationOptions) -> Linter {
+        self.options = options;
+        self
+    }
+
+    pub fn get_action(&self) -> LintAction {
+        self.action
+    }
+
+    /// Check `line`, given without its line ending.
+    pub fn check(&self, line: &[u8]) -> Result<(), LintError> {
+        // Parsing stops at the first LF, so look for these first.
+        if let Some(offset) = line.iter().position(|&b| b == 0 || b == b'\r' || b == b'\n') {
+            return Err(LintError::ForbiddenByte { byte: line[offset], offset: offset });
+        }
+        let msg = try!(IrcMsg::new(line)
+            .map_err(|err| LintError::Invalid(ValidationError::Parse(err))));
+        try!(msg.validate(&self.options).map_err(LintError::Invalid));
+
+        let (_prefix, rest) = parse_helpers::split_prefix(line);
+        let (command, _args) = parse_helpers::split_command(rest);
+        if !is_valid_command(command) {
+            return Err(LintError::BadCommand);
+        }
+
+        let (_tags, untagged) = parse_helpers::split_tags(line);
+        let body_offset = line.len() - untagged.len();
+        let middle_len = untagged.windows(2)
+            .position(|pair| pair == b" :")
+            .unwrap_or(untagged.len());
+        let middle = &untagged[..middle_len];
+        if let Some(idx) = middle.windows(2).position(|pair| pair == b"  ") {
+            return Err(LintError::EmptyArgument { offset: body_offset + idx + 1 });
+        }
+        if middle_len == untagged.len() && middle.last() == Some(&b' ') {
+            return Err(LintError::EmptyArgument { offset: line.len() });
+        }
+
+        let command = msg.get_command().to_ascii_uppercase();
+        if let Some(max) = max_text_args(&command) {
+            let count = msg.args().count();
+            if max < count {
+                return Err(LintError::MissingColon { command: command, count: count, max: max });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::validate::{ValidationError, ValidationOptions};
+    use super::{LintAction, LintError, Linter};
+
+    #[test]
+    fn lint_outgoing() {
+        let linter = Linter::new(LintAction::Reject);
+        assert_eq!(linter.check(b"PRIVMSG #rust :hello there"), Ok(()));
+        assert_eq!(linter.check(b"@+draft/typing=active TAGMSG #rust"), Ok(()));
+        assert_eq!(linter.check(b"MODE #rust +o alice"), Ok(()));
+        assert_eq!(linter.check(b"PRIVMSG #rust :hi\r\nQUIT"),
+            Err(LintError::ForbiddenByte { byte: b'\r', offset: 17 }));
+        assert_eq!(linter.check(b"PRIVMSG #rust hello there"), Err(LintError::MissingColon {
+            command: "PRIVMSG".to_string(),
+            count: 3,
+            max: 2,
+        }));
+        assert_eq!(linter.check(b"KICK #rust  bob"), Err(LintError::EmptyArgument { offset: 11 }));
+        assert_eq!(linter.check(b"JOIN #rust "), Err(LintError::EmptyArgument { offset: 11 }));
+        assert_eq!(linter.check(b"PRIV-MSG #rust :x"), Err(LintError::BadCommand));
+        assert_eq!(linter.check(b"0042 #rust"), Err(LintError::BadCommand));
+
+        let strict = Linter::new(LintAction::Log).options(ValidationOptions::new().max_line_len(16));
+        assert_eq!(strict.check(b"PRIVMSG #rust :hello"), Err(LintError::Invalid(
+            ValidationError::LineTooLong { len: 22, max: 16 })));
+    }
+}
@@ -0,0 +1,130 @@
+//! Ban mask construction and the MODE/KICK pairs used to apply bans.
+
+use super::{ModeBuf, KickBuf, MessageError};
+
+/// How much of a user's hostmask a ban should cover.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BanStyle {
+    /// `nick!*@*`
+    Nick,
+    /// `*!*@host`
+    Host,
+    /// `*!*@*.domain`.  IPv4 hosts are banned by /24 (`*!*@1.2.3.*`);
+    /// hosts without a domain part fall back to `Host`.
+    Domain,
+}
+
+/// Split `nick!user@host` (optionally with the leading `:`) into its
+/// parts.
+fn split_hostmask(hostmask: &[u8]) -> Result<(&[u8], &[u8], &[u8]), ()> {
+    let hostmask = if hostmask.first() == Some(&b':') { &hostmask[1..] } else { hostmask };
+    let bang = try!(hostmask.iter().position(|&b| b == b'!').ok_or(()));
+    let at = try!(hostmask.iter().position(|&b| b == b'@').ok_or(()));
+    if at < bang {
+        return Err(());
+    }
+    let (nick, user, host) = (&hostmask[..bang], &hostmask[bang + 1..at], &hostmask[at + 1..]);
+    if nick.len() == 0 || host.len() == 0 {
+        return Err(());
+    }
+    Ok((nick, user, host))
+}
+
+fn is_ipv4(host: &[u8]) -> bool {
+    host.split(|&b| b == b'.').count() == 4
+        && host.iter().all(|&b| b == b'.' || (b'0' <= b && b <= b'9'))
+}
+
+fn domain_mask(host: &[u8]) -> Vec<u8> {
+    let mut out = b"*!*@".to_vec();
+    if is_ipv4(host) {
+        let last_dot = host.iter().rposition(|&b| b == b'.').unwrap();
+        out.extend(host[..last_dot + 1].iter().cloned());
+        out.push(b'*');
+        return out;
+    }
+    match host.iter().position(|&b| b == b'.') {
+        // Don't widen `example.com` to `*.com`.
+        Some(idx) if host[idx + 1..].contains(&b'.') && !host.contains(&b':') => {
+            out.push(b'*');
+            out.extend(host[idx..].iter().cloned());
+        },
+        _ => out.extend(host.iter().cloned()),
+    }
+    out
+}
+
+/// Compute the ban mask for `hostmask` (`nick!user@host`) in `style`.
+pub fn ban_mask<H>(hostmask: H, style: BanStyle) -> Result<Vec<u8>, ()>
+    where H: AsRef<[u8]>
+{
+    let hostmask = hostmask.as_ref();
+    let (nick, _user, host) = try!(split_hostmask(hostmask));
+    Ok(match style {
+        BanStyle::Nick => {
+            let mut out = nick.to_vec();
+            out.extend(b"!*@*".iter().cloned());
+            out
+        },
+        BanStyle::Host => {
+            let mut out = b"*!*@".to_vec();
+            out.extend(host.iter().cloned());
+            out
+        },
+        BanStyle::Domain => domain_mask(host),
+    })
+}
+
+/// Ban the user behind `hostmask` from `channel`.
+pub fn ban<C, H>(channel: C, hostmask: H, style: BanStyle) -> Result<ModeBuf, MessageError>
+    where C: AsRef<[u8]>,
+          H: AsRef<[u8]>
+{
+    let channel = channel.as_ref();
+    let hostmask = hostmask.as_ref();
+    let mask = try!(ban_mask(hostmask, style).map_err(|()| MessageError::BadArgument));
+    let mut modes = try!(ModeBuf::builder(channel).add_ban(&mask).build());
+    Ok(modes.pop().unwrap())
+}
+
+/// Ban the user behind `hostmask` from `channel` and kick them.  Send
+/// the MODE first so the user can't rejoin in between.
+pub fn kickban<C, H>(channel: C, hostmask: H, style: BanStyle, reason: Option<&[u8]>)
+    -> Result<(ModeBuf, KickBuf), MessageError>
+    where C: AsRef<[u8]>,
+          H: AsRef<[u8]>
+{
+    let channel = channel.as_ref();
+    let hostmask = hostmask.as_ref();
+    let (nick, _user, _host) = try!(split_hostmask(hostmask)
+        .map_err(|()| MessageError::BadArgument));
+    let mode = try!(ban(channel, hostmask, style));
+    let kick = try!(KickBuf::new(channel, nick, reason));
+    Ok((mode, kick))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ban_mask, kickban, BanStyle};
+
+    #[test]
+    fn ban_masks() {
+        let prefix = b"sell!~sell@irc.example.co.uk";
+        assert_eq!(ban_mask(prefix, BanStyle::Nick).unwrap(), b"sell!*@*");
+        assert_eq!(ban_mask(prefix, BanStyle::Host).unwrap(), b"*!*@irc.example.co.uk");
+        assert_eq!(ban_mask(prefix, BanStyle::Domain).unwrap(), b"*!*@*.example.co.uk");
+
+        assert_eq!(ban_mask(b"a!b@10.0.0.7", BanStyle::Domain).unwrap(), b"*!*@10.0.0.*");
+        assert_eq!(ban_mask(b"a!b@example.com", BanStyle::Domain).unwrap(), b"*!*@example.com");
+        assert_eq!(ban_mask(b"a!b@user/cloak", BanStyle::Domain).unwrap(), b"*!*@user/cloak");
+        assert!(ban_mask(b"server.example.com", BanStyle::Host).is_err());
+    }
+
+    #[test]
+    fn kickban_orders_mode_first() {
+        let (mode, kick) = kickban(b"#foo", b":spam!x@bad.host.net",
+            BanStyle::Domain, Some(b"bye")).unwrap();
+        assert_eq!(mode.as_bytes(), b"MODE #foo +b *!*@*.host.net");
+        assert_eq!(kick.as_bytes(), b"KICK #foo spam :bye");
+    }
+}
@@ -1,44 +1,69 @@
-use std::borrow::{Borrow, BorrowMut, ToOwned};
-use std::borrow::Cow;
+use std::borrow::{Borrow, ToOwned};
 use std::{mem, ops};
 use std::io::{self, Write};
 
-use super::super::FromIrcMsg;
+use super::super::{FromIrcMsg, MessageError};
 use super::super::cursor_chk_error;
 
-use ::legacy::IrcMsg as IrcMsgLegacy;
 use ::{IrcMsg, IrcMsgBuf};
-use ::parse_helpers;
-use ::cap::{Capabilities, NegotiationVersion};
+use ::cap::{CapabilityChangeIter, NegotiationVersion, is_valid_list_entry};
 
 impl_irc_msg_subtype!(CapLs);
 impl_irc_msg_subtype_buf!(CapLsBuf, CapLs);
 
+/// The version argument to send, if any.  3.1 servers predate the
+/// argument, so it is left off entirely.
 #[inline]
-fn neg_ver_str(version: NegotiationVersion) -> &'static str {
+fn neg_ver_str(version: NegotiationVersion) -> Option<&'static str> {
     use self::NegotiationVersion::*;
-    match NegotiationVersion {
-        V301 => "301",
-        V302 => "302",
+    match version {
+        V301 => None,
+        V302 => Some("302"),
     }
 }
 
 impl CapLs {
-    fn construct<W>(sink: &mut W, version: NegotiationVersion) -> Result<(), ()>
+    fn construct<W>(sink: &mut W, version: NegotiationVersion) -> Result<(), MessageError>
         where W: Write
     {
-        try!(sink.write_all(b"CAP LS ").or_else(cursor_chk_error));
-        let vers_name = neg_ver_str(version).as_bytes();
-        try!(sink.write_all(vers_name).or_else(cursor_chk_error));
+        try!(sink.write_all(b"CAP LS").or_else(cursor_chk_error));
+        if let Some(vers_name) = neg_ver_str(version) {
+            try!(sink.write_all(b" ").or_else(cursor_chk_error));
+            try!(sink.write_all(vers_name.as_bytes()).or_else(cursor_chk_error));
+        }
         Ok(())
     }
 
-    fn validate(_msg: &IrcMsg) -> Result<(), ()> {
-        unimplemented!();
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        use std::ascii::AsciiExt;
+
+        if !AsciiExt::eq_ignore_ascii_case(msg.get_command(), "CAP") {
+            return Err(MessageError::WrongCommand);
+        }
+        let args: Vec<&[u8]> = msg.args().collect();
+        if args.len() < 1 || 2 < args.len() || !AsciiExt::eq_ignore_ascii_case(args[0], b"LS") {
+            return Err(MessageError::BadArgument);
+        }
+        Ok(())
     }
 
+    /// The negotiation version asked for.  A bare `CAP LS` is 3.1, and
+    /// any version from 302 up gets the 3.2 behaviour.  `None` if the
+    /// version isn't a number.
     pub fn get_version(&self) -> Option<NegotiationVersion> {
-        unimplemented!();
+        let version = match self.args().nth(1) {
+            Some(version) => version,
+            None => return Some(NegotiationVersion::V301),
+        };
+        let version: u32 = match ::std::str::from_utf8(version).ok().and_then(|v| v.parse().ok()) {
+            Some(version) => version,
+            None => return None,
+        };
+        if version < 302 {
+            Some(NegotiationVersion::V301)
+        } else {
+            Some(NegotiationVersion::V302)
+        }
     }
 }
 
@@ -53,3 +78,124 @@ impl CapLsBuf {
         CapLsBuf { inner: message }
     }
 }
+
+
+impl_irc_msg_subtype!(CapReq);
+impl_irc_msg_subtype_buf!(CapReqBuf, CapReq);
+
+// Client: CAP REQ :account-notify away-notify extended-join multi-prefix sasl
+// Client: CAP REQ :-multi-prefix
+
+impl CapReq {
+    fn construct<W>(sink: &mut W, caps: &[&str]) -> Result<(), MessageError>
+        where W: Write
+    {
+        if caps.len() == 0 {
+            return Err(MessageError::MissingArgument);
+        }
+        try!(sink.write_all(b"CAP REQ :").or_else(cursor_chk_error));
+        for (idx, cap) in caps.iter().enumerate() {
+            if !is_valid_list_entry(cap) {
+                return Err(MessageError::BadArgument);
+            }
+            if idx > 0 {
+                try!(sink.write_all(b" ").or_else(cursor_chk_error));
+            }
+            try!(sink.write_all(cap.as_bytes()).or_else(cursor_chk_error));
+        }
+        Ok(())
+    }
+
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        use std::ascii::AsciiExt;
+
+        if !AsciiExt::eq_ignore_ascii_case(msg.get_command(), "CAP") {
+            return Err(MessageError::WrongCommand);
+        }
+        let args: Vec<&[u8]> = msg.args().collect();
+        if args.len() != 2 || !AsciiExt::eq_ignore_ascii_case(args[0], b"REQ") {
+            return Err(MessageError::BadArgument);
+        }
+        try!(::std::str::from_utf8(args[1]).map_err(|_| MessageError::BadArgument));
+        Ok(())
+    }
+
+    /// The requested changes.  A `-` prefix asks for a capability to be
+    /// disabled.
+    pub fn capabilities(&self) -> CapabilityChangeIter {
+        let list = self.args().nth(1).unwrap();
+        CapabilityChangeIter::new(::std::str::from_utf8(list).unwrap())
+    }
+}
+
+impl CapReqBuf {
+    /// Request `caps`, each optionally prefixed with `-` to disable it.
+    /// The server accepts or refuses the whole list at once.
+    pub fn new(caps: &[&str]) -> Result<CapReqBuf, MessageError> {
+        let mut wr = io::Cursor::new(Vec::new());
+        try!(CapReq::construct(&mut wr, caps));
+
+        let message = try!(IrcMsgBuf::new(wr.into_inner()).map_err(MessageError::from));
+        try!(CapReq::validate(&message));
+        Ok(CapReqBuf { inner: message })
+    }
+}
+
+impl_irc_msg_subtype!(CapEnd);
+impl_irc_msg_subtype_buf!(CapEndBuf, CapEnd);
+
+impl CapEnd {
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        use std::ascii::AsciiExt;
+
+        if !AsciiExt::eq_ignore_ascii_case(msg.get_command(), "CAP") {
+            return Err(MessageError::WrongCommand);
+        }
+        let args: Vec<&[u8]> = msg.args().collect();
+        if args.len() != 1 || !AsciiExt::eq_ignore_ascii_case(args[0], b"END") {
+            return Err(MessageError::BadArgument);
+        }
+        Ok(())
+    }
+}
+
+impl CapEndBuf {
+    /// End capability negotiation, letting registration complete.
+    pub fn new() -> CapEndBuf {
+        let message = IrcMsgBuf::new(b"CAP END".to_vec()).unwrap();
+        CapEnd::validate(&message).unwrap();
+        CapEndBuf { inner: message }
+    }
+}
+
+#[test]
+fn cap_ls() {
+    let ls = CapLsBuf::new(NegotiationVersion::V302);
+    assert_eq!(ls.as_bytes(), &b"CAP LS 302"[..]);
+    assert_eq!(ls.get_version(), Some(NegotiationVersion::V302));
+
+    let ls = CapLsBuf::new(NegotiationVersion::V301);
+    assert_eq!(ls.as_bytes(), &b"CAP LS"[..]);
+    assert_eq!(ls.get_version(), Some(NegotiationVersion::V301));
+
+    let ls = CapLs::parse(b"CAP LS 303").unwrap();
+    assert_eq!(ls.get_version(), Some(NegotiationVersion::V302));
+    assert_eq!(CapLs::parse(b"CAP LS x").unwrap().get_version(), None);
+}
+
+#[test]
+fn cap_req() {
+    use ::cap::CapabilityChange;
+
+    let req = CapReqBuf::new(&["multi-prefix", "-sasl"]).unwrap();
+    assert_eq!(req.as_bytes(), &b"CAP REQ :multi-prefix -sasl"[..]);
+    assert_eq!(req.capabilities().collect::<Vec<_>>(), vec![
+        CapabilityChange { name: "multi-prefix", enable: true },
+        CapabilityChange { name: "sasl", enable: false },
+    ]);
+
+    assert!(CapReqBuf::new(&[]).is_err());
+    assert!(CapReqBuf::new(&["a\r\nQUIT"]).is_err());
+    assert!(IrcMsg::new(b"CAP REQ sasl").unwrap().as_tymsg::<&CapReq>().is_ok());
+    assert!(IrcMsg::new(b"CAP END x").unwrap().as_tymsg::<&CapReq>().is_err());
+}
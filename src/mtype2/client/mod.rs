@@ -2,107 +2,1636 @@ use std::borrow::{Borrow, ToOwned};
 use std::{mem, ops};
 use std::io::{self, Write};
 
-use super::FromIrcMsg;
+use super::{FromIrcMsg, MessageError};
 use super::cursor_chk_error;
 
-use ::{IrcMsg, IrcMsgBuf};
+use ::{IrcMsg, IrcMsgBuf, IrcMsgBuilder};
+use ::identifier;
+use ::parse::ArgumentIter;
+use ::parse_helpers::{self, is_middle_arg};
+
+mod ban;
+pub use self::ban::{BanStyle, ban_mask, ban, kickban};
+
+mod sanitize;
+pub use self::sanitize::Sanitizer;
+
+mod transform;
+pub use self::transform::{BodyTransform, BodyTransforms, TransformError};
 
 #[cfg(feature = "unstable")] mod cap;
 #[cfg(feature = "unstable")] pub use self::cap::{
     CapLs, CapLsBuf,
+    CapReq, CapReqBuf,
+    CapEnd, CapEndBuf,
 };
 
-impl_irc_msg_subtype!(Invite);
-impl_irc_msg_subtype_buf!(InviteBuf, Invite);
+/// The most leading arguments `validate_command` keeps hold of.
+const KEPT_ARGS: usize = 4;
+
+/// The first few arguments of a message, kept without allocating.
+struct LeadingArgs<'a> {
+    args: [&'a [u8]; KEPT_ARGS],
+    len: usize,
+}
+
+impl<'a> ops::Deref for LeadingArgs<'a> {
+    type Target = [&'a [u8]];
+
+    fn deref(&self) -> &[&'a [u8]] {
+        &self.args[..self.len]
+    }
+}
+
+/// Check `msg` is an unprefixed `command` with between `min_args` and
+/// `max_args` arguments, returning the first `KEPT_ARGS` of them.
+fn validate_command<'a>(msg: &'a IrcMsg, command: &str, min_args: usize, max_args: usize)
+    -> Result<LeadingArgs<'a>, MessageError>
+{
+    let buf = msg.as_bytes();
+    if parse_helpers::has_forbidden_byte(buf) {
+        return Err(MessageError::ForbiddenByte);
+    }
+    let (prefix, rest) = parse_helpers::split_prefix(buf);
+    if prefix.len() != 0 {
+        return Err(MessageError::BadPrefix);
+    }
+    let (got, mut rest) = parse_helpers::split_command(rest);
+    if !got.eq_ignore_ascii_case(command.as_bytes()) {
+        return Err(MessageError::WrongCommand);
+    }
+    let mut args = LeadingArgs { args: [b""; KEPT_ARGS], len: 0 };
+    let mut count = 0;
+    while rest.len() > 0 {
+        let (arg, next) = parse_helpers::split_arg(rest);
+        if count < KEPT_ARGS {
+            args.args[count] = arg;
+            args.len = count + 1;
+        }
+        count += 1;
+        rest = next;
+    }
+    if count < min_args {
+        return Err(MessageError::MissingArgument);
+    }
+    if max_args < count {
+        return Err(MessageError::TooManyArguments);
+    }
+    Ok(args)
+}
+
+/// A comma-separated list of non-empty targets, e.g. `#a,#b` or `nick`.
+fn validate_targets<F>(list: &[u8], valid: F) -> Result<(), MessageError>
+    where F: Fn(&[u8]) -> bool
+{
+    if !is_middle_arg(list) || !list.split(|&b| b == b',').all(|target| valid(target)) {
+        return Err(MessageError::BadTarget);
+    }
+    Ok(())
+}
+
+fn is_channel(name: &[u8]) -> bool {
+    identifier::channel_validate_buf(name).is_ok()
+}
+
+fn is_nick(name: &[u8]) -> bool {
+    identifier::nickname_validate_buf(name).is_ok()
+}
+
+fn is_target(name: &[u8]) -> bool {
+    !name.is_empty()
+}
+
+/// Join `items` with commas, failing with `err` if any is empty or can't
+/// be an item of a comma-separated list.
+fn join_list<T>(items: &[T], err: MessageError) -> Result<Vec<u8>, MessageError>
+    where T: AsRef<[u8]>
+{
+    if items.is_empty() {
+        return Err(MessageError::MissingArgument);
+    }
+    let mut out = Vec::new();
+    for (idx, item) in items.iter().enumerate() {
+        let item = item.as_ref();
+        if !is_middle_arg(item) || item.contains(&b',') {
+            return Err(err);
+        }
+        if idx > 0 {
+            out.push(b',');
+        }
+        out.extend(item);
+    }
+    Ok(out)
+}
+
+/// The items of a comma-separated list such as `#a,#b`.
+pub struct ListIter<'a> {
+    rest: Option<&'a [u8]>,
+}
+
+impl<'a> ListIter<'a> {
+    fn new(list: &'a [u8]) -> ListIter<'a> {
+        ListIter { rest: if list.is_empty() { None } else { Some(list) } }
+    }
+}
+
+impl<'a> Iterator for ListIter<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let rest = match self.rest.take() {
+            Some(rest) => rest,
+            None => return None,
+        };
+        match rest.iter().position(|&b| b == b',') {
+            Some(idx) => {
+                self.rest = Some(&rest[idx + 1..]);
+                Some(&rest[..idx])
+            },
+            None => Some(rest),
+        }
+    }
+}
+
+/// Fail with `err` unless `arg` can be sent as a single argument.
+fn check_middle_arg(arg: &[u8], err: MessageError) -> Result<(), MessageError> {
+    if !is_middle_arg(arg) {
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Run `construct` against `storage`, returning the bytes it wrote.
+fn write_in<'a, F>(storage: &'a mut [u8], construct: F) -> Result<&'a [u8], MessageError>
+    where F: FnOnce(&mut io::Cursor<&mut [u8]>) -> Result<(), MessageError>
+{
+    let end = {
+        let mut wr = io::Cursor::new(&mut *storage);
+        try!(construct(&mut wr));
+        wr.position() as usize
+    };
+    if parse_helpers::has_forbidden_byte(&storage[..end]) {
+        return Err(MessageError::ForbiddenByte);
+    }
+    Ok(&storage[..end])
+}
+
+/// Run `construct` against a new buffer, returning the message it wrote.
+fn write_buf<F>(construct: F) -> Result<IrcMsgBuf, MessageError>
+    where F: FnOnce(&mut io::Cursor<Vec<u8>>) -> Result<(), MessageError>
+{
+    let mut wr = io::Cursor::new(Vec::new());
+    try!(construct(&mut wr));
+
+    let buf = wr.into_inner();
+    if parse_helpers::has_forbidden_byte(&buf) {
+        return Err(MessageError::ForbiddenByte);
+    }
+    IrcMsgBuf::new(buf).map_err(MessageError::from)
+}
+
+
+impl_irc_msg_subtype!(Authenticate);
+impl_irc_msg_subtype_buf!(AuthenticateBuf, Authenticate);
+
+/// The most payload a single AUTHENTICATE may carry.  Longer payloads are
+/// sent in chunks of exactly this size.
+pub const AUTHENTICATE_CHUNK_LEN: usize = 400;
+
+impl Authenticate {
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        use std::ascii::AsciiExt;
+
+        if !AsciiExt::eq_ignore_ascii_case(msg.get_command(), "AUTHENTICATE") {
+            return Err(MessageError::WrongCommand);
+        }
+        let args: Vec<&[u8]> = msg.args().collect();
+        if args.len() != 1 || args[0].is_empty() || AUTHENTICATE_CHUNK_LEN < args[0].len() {
+            return Err(MessageError::BadArgument);
+        }
+        Ok(())
+    }
+
+    /// A mechanism name, a base64 chunk, `+` for an empty chunk or `*`
+    /// to abort.
+    pub fn get_payload(&self) -> &[u8] {
+        self.args().next().unwrap()
+    }
+}
+
+impl AuthenticateBuf {
+    pub fn new<P>(payload: P) -> Result<AuthenticateBuf, MessageError>
+        where P: AsRef<[u8]>
+    {
+        let message = try!(IrcMsgBuilder::new()
+            .command("AUTHENTICATE")
+            .arg(payload)
+            .build()
+            .map_err(MessageError::from));
+        try!(Authenticate::validate(&message));
+        Ok(AuthenticateBuf { inner: message })
+    }
+
+    /// Abort the exchange in progress.
+    pub fn abort() -> AuthenticateBuf {
+        AuthenticateBuf::new("*").unwrap()
+    }
+}
+
+
+impl_irc_msg_subtype!(Away);
+impl_irc_msg_subtype_buf!(AwayBuf, Away);
+
+impl Away {
+    fn construct<W>(sink: &mut W, message: Option<&[u8]>) -> Result<(), MessageError>
+        where W: Write
+    {
+        try!(sink.write_all(b"AWAY").or_else(cursor_chk_error));
+        if let Some(message) = message {
+            try!(sink.write_all(b" :").or_else(cursor_chk_error));
+            try!(sink.write_all(message).or_else(cursor_chk_error));
+        }
+        Ok(())
+    }
+
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        try!(validate_command(msg, "AWAY", 0, 1));
+        Ok(())
+    }
+
+    /// Create a new `Away` in `storage`.  This does not allocate any storage.
+    pub fn new<'a>(storage: &'a mut [u8], message: Option<&[u8]>) -> Result<&'a Away, MessageError> {
+        Away::parse(try!(write_in(storage, |wr| Away::construct(wr, message))))
+    }
+
+    /// The away message, or `None` when marking ourselves back.
+    pub fn get_message(&self) -> Option<&[u8]> {
+        self.args().next()
+    }
+}
+
+impl AwayBuf {
+    /// Mark ourselves away with `message`, or back with `None`.
+    pub fn new(message: Option<&[u8]>) -> Result<AwayBuf, MessageError> {
+        let message = try!(write_buf(|wr| Away::construct(wr, message)));
+        try!(Away::validate(&message));
+        Ok(AwayBuf { inner: message })
+    }
+}
+
+
+impl_irc_msg_subtype!(Invite);
+impl_irc_msg_subtype_buf!(InviteBuf, Invite);
+
+impl Invite {
+    fn construct<W>(sink: &mut W, nickname: &[u8], channel: &[u8]) -> Result<(), MessageError>
+        where W: Write
+    {
+        try!(sink.write_all(b"INVITE ").or_else(cursor_chk_error));
+        try!(sink.write_all(nickname).or_else(cursor_chk_error));
+        try!(sink.write_all(b" ").or_else(cursor_chk_error));
+        try!(sink.write_all(channel).or_else(cursor_chk_error));
+        Ok(())
+    }
+
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        let args = try!(validate_command(msg, "INVITE", 2, 2));
+        try!(validate_targets(args[0], is_nick));
+        validate_targets(args[1], is_channel)
+    }
+}
+
+impl InviteBuf {
+    pub fn new<N, C>(nickname: N, channel: C) -> Result<InviteBuf, MessageError>
+        where N: AsRef<[u8]>,
+              C: AsRef<[u8]>
+    {
+        let nickname = nickname.as_ref();
+        let channel = channel.as_ref();
+        let mut wr = io::Cursor::new(Vec::new());
+        try!(Invite::construct(&mut wr, nickname, channel));
+
+        let buf = wr.into_inner();
+        if parse_helpers::has_forbidden_byte(&buf) {
+            return Err(MessageError::ForbiddenByte);
+        }
+        // maybe we could skip this check later and turn it into a debug-assert?
+        let message = try!(IrcMsgBuf::new(buf).map_err(MessageError::from));
+
+        try!(Invite::validate(&message));
+        Ok(InviteBuf { inner: message })
+    }
+}
+
+
+impl_irc_msg_subtype!(Ison);
+impl_irc_msg_subtype_buf!(IsonBuf, Ison);
+
+impl Ison {
+    fn construct<W, N>(sink: &mut W, nicks: &[N]) -> Result<(), MessageError>
+        where W: Write,
+              N: AsRef<[u8]>
+    {
+        if nicks.is_empty() {
+            return Err(MessageError::MissingArgument);
+        }
+        try!(sink.write_all(b"ISON").or_else(cursor_chk_error));
+        for nick in nicks.iter() {
+            let nick = nick.as_ref();
+            try!(check_middle_arg(nick, MessageError::BadTarget));
+            try!(sink.write_all(b" ").or_else(cursor_chk_error));
+            try!(sink.write_all(nick).or_else(cursor_chk_error));
+        }
+        Ok(())
+    }
+
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        try!(validate_command(msg, "ISON", 1, ::std::usize::MAX));
+        Ok(())
+    }
+
+    /// Create a new `Ison` in `storage`.  This does not allocate any storage.
+    pub fn new<'a, N>(storage: &'a mut [u8], nicks: &[N]) -> Result<&'a Ison, MessageError>
+        where N: AsRef<[u8]>
+    {
+        Ison::parse(try!(write_in(storage, |wr| Ison::construct(wr, nicks))))
+    }
+
+    pub fn get_nicks(&self) -> ArgumentIter {
+        self.args()
+    }
+}
+
+impl IsonBuf {
+    /// Ask which of `nicks` are online.
+    pub fn new<N>(nicks: &[N]) -> Result<IsonBuf, MessageError>
+        where N: AsRef<[u8]>
+    {
+        let message = try!(write_buf(|wr| Ison::construct(wr, nicks)));
+        try!(Ison::validate(&message));
+        Ok(IsonBuf { inner: message })
+    }
+}
+
+
+impl_irc_msg_subtype!(Join);
+impl_irc_msg_subtype_buf!(JoinBuf, Join);
+
+impl Join {
+    fn construct<W>(sink: &mut W, channel: &[u8]) -> Result<(), MessageError>
+        where W: Write
+    {
+        try!(sink.write_all(b"JOIN ").or_else(cursor_chk_error));
+        try!(sink.write_all(channel).or_else(cursor_chk_error));
+        Ok(())
+    }
+
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        let args = try!(validate_command(msg, "JOIN", 1, 2));
+        if args[0] == b"0" {
+            return Ok(());
+        }
+        validate_targets(args[0], is_channel)
+    }
+
+    /// Create a new `Join` in `storage`.  This does not allocate any storage.
+    pub fn new<'a, C>(storage: &'a mut [u8], channel: C) -> Result<&'a Join, MessageError>
+        where C: AsRef<[u8]>
+    {
+        let channel = channel.as_ref();
+        try!(check_middle_arg(channel, MessageError::BadTarget));
+        Join::parse(try!(write_in(storage, |wr| Join::construct(wr, channel))))
+    }
+
+    /// The channels being joined, or just `0` for leaving all of them.
+    pub fn get_channels(&self) -> ListIter {
+        ListIter::new(self.args().next().unwrap())
+    }
+
+    /// The keys, which go with the channels in the same position.
+    pub fn get_keys(&self) -> ListIter {
+        ListIter::new(self.args().nth(1).unwrap_or(b""))
+    }
+}
+
+impl JoinBuf {
+    pub fn new<C>(channel: C) -> Result<JoinBuf, MessageError>
+        where C: AsRef<[u8]>
+    {
+        let channel = channel.as_ref();
+        if !is_middle_arg(channel) {
+            return Err(MessageError::BadTarget);
+        }
+        let mut wr = io::Cursor::new(Vec::new());
+        try!(Join::construct(&mut wr, channel));
+
+        let buf = wr.into_inner();
+        if parse_helpers::has_forbidden_byte(&buf) {
+            return Err(MessageError::ForbiddenByte);
+        }
+        // maybe we could skip this check later and turn it into a debug-assert?
+        let message = try!(IrcMsgBuf::new(buf).map_err(MessageError::from));
+
+        try!(Join::validate(&message));
+        Ok(JoinBuf { inner: message })
+    }
+
+    /// Join several channels at once, e.g. `JOIN #a,#b key1`.  Keys go
+    /// with the channels in the same position, so channels needing a key
+    /// must come first.
+    pub fn new_multi<C, K>(channels: &[C], keys: &[K]) -> Result<JoinBuf, MessageError>
+        where C: AsRef<[u8]>,
+              K: AsRef<[u8]>
+    {
+        if channels.len() < keys.len() {
+            return Err(MessageError::TooManyArguments);
+        }
+        let channels = try!(join_list(channels, MessageError::BadTarget));
+        let mut builder = IrcMsgBuilder::new().command("JOIN").arg(channels);
+        if !keys.is_empty() {
+            builder = builder.arg(try!(join_list(keys, MessageError::BadArgument)));
+        }
+        let message = try!(builder.build().map_err(MessageError::from));
+
+        try!(Join::validate(&message));
+        Ok(JoinBuf { inner: message })
+    }
+}
+
+
+impl_irc_msg_subtype!(Kick);
+impl_irc_msg_subtype_buf!(KickBuf, Kick);
+
+impl Kick {
+    fn construct<W>(sink: &mut W, channel: &[u8], nick: &[u8], reason: Option<&[u8]>) -> Result<(), MessageError>
+        where W: Write
+    {
+        try!(sink.write_all(b"KICK ").or_else(cursor_chk_error));
+        try!(sink.write_all(channel).or_else(cursor_chk_error));
+        try!(sink.write_all(b" ").or_else(cursor_chk_error));
+        try!(sink.write_all(nick).or_else(cursor_chk_error));
+        if let Some(reason) = reason {
+            try!(sink.write_all(b" :").or_else(cursor_chk_error));
+            try!(sink.write_all(reason).or_else(cursor_chk_error));
+        }
+        Ok(())
+    }
+
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        let buf = msg.as_bytes();
+
+        let (prefix, rest) = parse_helpers::split_prefix(buf);
+        if prefix.len() != 0 {
+            return Err(MessageError::BadPrefix);
+        }
+
+        let (command, rest) = parse_helpers::split_command(rest);
+        if !command.eq_ignore_ascii_case(b"KICK") {
+            return Err(MessageError::WrongCommand);
+        }
+
+        let (channel, rest) = parse_helpers::split_arg(rest);
+        if channel.len() < 1 {
+            return Err(MessageError::MissingArgument);
+        }
+
+        let (nick, rest) = parse_helpers::split_arg(rest);
+        if nick.len() < 1 {
+            return Err(MessageError::MissingArgument);
+        }
+
+        let (_reason, must_be_empty) = parse_helpers::split_arg(rest);
+        if must_be_empty.len() != 0 {
+            return Err(MessageError::TooManyArguments);
+        }
+
+        Ok(())
+    }
+
+    /// Create a new `Kick` in `storage`.  This does not allocate any storage.
+    pub fn new<'a, C, N>(storage: &'a mut [u8], channel: C, nick: N, reason: Option<&[u8]>) -> Result<&'a Kick, MessageError>
+        where C: AsRef<[u8]>,
+              N: AsRef<[u8]>
+    {
+        let channel = channel.as_ref();
+        let nick = nick.as_ref();
+        try!(check_middle_arg(channel, MessageError::BadTarget));
+        try!(check_middle_arg(nick, MessageError::BadTarget));
+        Kick::parse(try!(write_in(storage, |wr| Kick::construct(wr, channel, nick, reason))))
+    }
+
+    pub fn get_channel(&self) -> &[u8] {
+        let buf = self.as_bytes();
+        let (_command, rest) = parse_helpers::split_command(buf);
+        let (channel, _rest) = parse_helpers::split_arg(rest);
+        channel
+    }
+
+    pub fn get_nick(&self) -> &[u8] {
+        let buf = self.as_bytes();
+        let (_command, rest) = parse_helpers::split_command(buf);
+        let (_channel, rest) = parse_helpers::split_arg(rest);
+        let (nick, _rest) = parse_helpers::split_arg(rest);
+        nick
+    }
+
+    pub fn get_reason(&self) -> Option<&[u8]> {
+        let buf = self.as_bytes();
+        let (_command, rest) = parse_helpers::split_command(buf);
+        let (_channel, rest) = parse_helpers::split_arg(rest);
+        let (_nick, rest) = parse_helpers::split_arg(rest);
+        if rest.len() == 0 {
+            return None;
+        }
+        let (reason, _rest) = parse_helpers::split_arg(rest);
+        Some(reason)
+    }
+}
+
+impl KickBuf {
+    pub fn new<C, N>(channel: C, nick: N, reason: Option<&[u8]>) -> Result<KickBuf, MessageError>
+        where C: AsRef<[u8]>,
+              N: AsRef<[u8]>
+    {
+        let channel = channel.as_ref();
+        let nick = nick.as_ref();
+        if !is_middle_arg(channel) || !is_middle_arg(nick) {
+            return Err(MessageError::BadTarget);
+        }
+
+        let mut wr = io::Cursor::new(Vec::new());
+        try!(Kick::construct(&mut wr, channel, nick, reason));
+
+        let buf = wr.into_inner();
+        if parse_helpers::has_forbidden_byte(&buf) {
+            return Err(MessageError::ForbiddenByte);
+        }
+        let message = try!(IrcMsgBuf::new(buf).map_err(MessageError::from));
+        try!(Kick::validate(&message));
+        Ok(KickBuf { inner: message })
+    }
+}
+
+
+impl_irc_msg_subtype!(List);
+impl_irc_msg_subtype_buf!(ListBuf, List);
+
+/// A server-side filter for LIST, as described by the `ELIST` ISUPPORT
+/// token.  Times are in minutes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ListCondition<'a> {
+    /// `>n`: more than n users.  Needs `U`.
+    MoreUsersThan(u32),
+    /// `<n`: fewer than n users.  Needs `U`.
+    FewerUsersThan(u32),
+    /// `C<n`: created less than n minutes ago.  Needs `C`.
+    CreatedWithin(u32),
+    /// `C>n`: created more than n minutes ago.  Needs `C`.
+    CreatedBefore(u32),
+    /// `T<n`: topic changed less than n minutes ago.  Needs `T`.
+    TopicWithin(u32),
+    /// `T>n`: topic changed more than n minutes ago.  Needs `T`.
+    TopicBefore(u32),
+    /// A channel name, or a mask like `#rust*`.  Masks need `M`.
+    Mask(&'a [u8]),
+    /// `!mask`: channels not matching the mask.  Needs `N`.
+    NotMask(&'a [u8]),
+}
+
+impl<'a> ListCondition<'a> {
+    /// The ELIST token this condition requires, if any.
+    pub fn elist_token(&self) -> Option<u8> {
+        match *self {
+            ListCondition::MoreUsersThan(_) => Some(b'U'),
+            ListCondition::FewerUsersThan(_) => Some(b'U'),
+            ListCondition::CreatedWithin(_) => Some(b'C'),
+            ListCondition::CreatedBefore(_) => Some(b'C'),
+            ListCondition::TopicWithin(_) => Some(b'T'),
+            ListCondition::TopicBefore(_) => Some(b'T'),
+            ListCondition::Mask(mask) => {
+                if mask.iter().any(|&b| b == b'*' || b == b'?') {
+                    Some(b'M')
+                } else {
+                    None
+                }
+            },
+            ListCondition::NotMask(_) => Some(b'N'),
+        }
+    }
+
+    fn render<W>(&self, sink: &mut W) -> Result<(), MessageError>
+        where W: Write
+    {
+        let (head, num): (&[u8], u32) = match *self {
+            ListCondition::MoreUsersThan(n) => (b">", n),
+            ListCondition::FewerUsersThan(n) => (b"<", n),
+            ListCondition::CreatedWithin(n) => (b"C<", n),
+            ListCondition::CreatedBefore(n) => (b"C>", n),
+            ListCondition::TopicWithin(n) => (b"T<", n),
+            ListCondition::TopicBefore(n) => (b"T>", n),
+            ListCondition::Mask(mask) | ListCondition::NotMask(mask) => {
+                if mask.len() == 0 || mask.iter().any(|&b| b == b',' || b == b' ') {
+                    return Err(MessageError::BadArgument);
+                }
+                if let ListCondition::NotMask(_) = *self {
+                    try!(sink.write_all(b"!").or_else(cursor_chk_error));
+                }
+                try!(sink.write_all(mask).or_else(cursor_chk_error));
+                return Ok(());
+            },
+        };
+        try!(sink.write_all(head).or_else(cursor_chk_error));
+        try!(sink.write_all(num.to_string().as_bytes()).or_else(cursor_chk_error));
+        Ok(())
+    }
+}
+
+/// Check that there is at least one condition and that the server's
+/// `elist` supports each of them.
+fn check_list_conditions(conditions: &[ListCondition], elist: &[u8]) -> Result<(), MessageError> {
+    if conditions.is_empty() {
+        return Err(MessageError::MissingArgument);
+    }
+    for condition in conditions.iter() {
+        if let Some(token) = condition.elist_token() {
+            if !elist.iter().any(|b| b.to_ascii_uppercase() == token) {
+                return Err(MessageError::BadArgument);
+            }
+        }
+    }
+    Ok(())
+}
+
+impl List {
+    fn construct<W>(sink: &mut W, conditions: &[ListCondition]) -> Result<(), MessageError>
+        where W: Write
+    {
+        try!(sink.write_all(b"LIST ").or_else(cursor_chk_error));
+        for (idx, condition) in conditions.iter().enumerate() {
+            if idx > 0 {
+                try!(sink.write_all(b",").or_else(cursor_chk_error));
+            }
+            try!(condition.render(sink));
+        }
+        Ok(())
+    }
+
+    /// Create a new `List` in `storage`; see `ListBuf::with_conditions`.
+    /// This does not allocate any storage.
+    pub fn new<'a, E>(storage: &'a mut [u8], conditions: &[ListCondition], elist: E) -> Result<&'a List, MessageError>
+        where E: AsRef<[u8]>
+    {
+        try!(check_list_conditions(conditions, elist.as_ref()));
+        List::parse(try!(write_in(storage, |wr| List::construct(wr, conditions))))
+    }
+
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        let buf = msg.as_bytes();
+
+        let (prefix, rest) = parse_helpers::split_prefix(buf);
+        if prefix.len() != 0 {
+            return Err(MessageError::BadPrefix);
+        }
+
+        let (command, rest) = parse_helpers::split_command(rest);
+        if !command.eq_ignore_ascii_case(b"LIST") {
+            return Err(MessageError::WrongCommand);
+        }
+
+        let (params, rest) = parse_helpers::split_arg(rest);
+        if params.len() < 1 {
+            return Err(MessageError::MissingArgument);
+        }
+
+        let (_server, must_be_empty) = parse_helpers::split_arg(rest);
+        if must_be_empty.len() != 0 {
+            return Err(MessageError::TooManyArguments);
+        }
+
+        Ok(())
+    }
+
+    /// The comma-separated channels or conditions.
+    pub fn get_params(&self) -> &[u8] {
+        let buf = self.as_bytes();
+        let (_command, rest) = parse_helpers::split_command(buf);
+        let (params, _rest) = parse_helpers::split_arg(rest);
+        params
+    }
+}
+
+impl ListBuf {
+    /// List the channels matching `conditions`, e.g. `>100,<5000,#rust*`.
+    /// `elist` is the value of the server's `ELIST` ISUPPORT token (empty
+    /// if it wasn't advertised); conditions it doesn't support are
+    /// rejected instead of being silently ignored by the server.
+    ///
+    /// At least one condition is required: the parser doesn't accept
+    /// messages without arguments, so a bare `LIST` can't be built yet.
+    pub fn with_conditions<E>(conditions: &[ListCondition], elist: E) -> Result<ListBuf, MessageError>
+        where E: AsRef<[u8]>
+    {
+        try!(check_list_conditions(conditions, elist.as_ref()));
+        let message = try!(write_buf(|wr| List::construct(wr, conditions)));
+        try!(List::validate(&message));
+        Ok(ListBuf { inner: message })
+    }
+}
+
+#[test]
+fn list_with_elist_conditions() {
+    use self::ListCondition::{MoreUsersThan, FewerUsersThan, Mask, TopicWithin};
+
+    assert!(ListBuf::with_conditions(&[], b"MNTU").is_err());
+
+    let conds = [MoreUsersThan(100), FewerUsersThan(5000), Mask(b"#rust*")];
+    let list = ListBuf::with_conditions(&conds, b"MNTU").unwrap();
+    assert_eq!(list.as_bytes(), b"LIST >100,<5000,#rust*");
+    assert_eq!(list.get_params(), b">100,<5000,#rust*");
+
+    // plain channel names need no ELIST support
+    assert!(ListBuf::with_conditions(&[Mask(b"#rust")], b"").is_ok());
+    assert!(ListBuf::with_conditions(&[Mask(b"#rust*")], b"U").is_err());
+    assert!(ListBuf::with_conditions(&[TopicWithin(60)], b"cmu").is_err());
+    assert!(ListBuf::with_conditions(&[Mask(b"#a,#b")], b"M").is_err());
+
+    let mut storage = [0; 32];
+    let list = List::new(&mut storage, &conds, b"MNTU").unwrap();
+    assert_eq!(list.as_bytes(), b"LIST >100,<5000,#rust*");
+    assert_eq!(List::new(&mut [0; 8], &conds, b"MNTU").err(), Some(MessageError::BufferTooSmall));
+    assert_eq!(List::new(&mut storage, &conds, b"").err(), Some(MessageError::BadArgument));
+}
+
+
+impl_irc_msg_subtype!(Mode);
+impl_irc_msg_subtype_buf!(ModeBuf, Mode);
+
+/// The number of parameterised modes per MODE command a server accepts
+/// when it does not advertise `MODES=` in ISUPPORT (RFC 2812).
+pub const DEFAULT_MODES_LIMIT: usize = 3;
+
+impl Mode {
+    fn construct<W>(sink: &mut W, target: &[u8], modes: &[u8], args: &[&[u8]]) -> Result<(), MessageError>
+        where W: Write
+    {
+        try!(sink.write_all(b"MODE ").or_else(cursor_chk_error));
+        try!(sink.write_all(target).or_else(cursor_chk_error));
+        try!(sink.write_all(b" ").or_else(cursor_chk_error));
+        try!(sink.write_all(modes).or_else(cursor_chk_error));
+        for arg in args.iter() {
+            try!(sink.write_all(b" ").or_else(cursor_chk_error));
+            try!(sink.write_all(arg).or_else(cursor_chk_error));
+        }
+        Ok(())
+    }
+
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        let buf = msg.as_bytes();
+
+        let (prefix, rest) = parse_helpers::split_prefix(buf);
+        if prefix.len() != 0 {
+            return Err(MessageError::BadPrefix);
+        }
+
+        let (command, rest) = parse_helpers::split_command(rest);
+        if !command.eq_ignore_ascii_case(b"MODE") {
+            return Err(MessageError::WrongCommand);
+        }
+
+        let (target, rest) = parse_helpers::split_arg(rest);
+        if target.len() < 1 {
+            return Err(MessageError::MissingArgument);
+        }
+
+        let (modes, _rest) = parse_helpers::split_arg(rest);
+        match modes.first() {
+            Some(&b'+') | Some(&b'-') => Ok(()),
+            _ => Err(MessageError::BadArgument),
+        }
+    }
+
+    /// Create a new `Mode` in `storage`.  This does not allocate any storage.
+    pub fn new<'a, T, M>(storage: &'a mut [u8], target: T, modes: M, args: &[&[u8]]) -> Result<&'a Mode, MessageError>
+        where T: AsRef<[u8]>,
+              M: AsRef<[u8]>
+    {
+        let target = target.as_ref();
+        let modes = modes.as_ref();
+        for arg in args.iter() {
+            try!(check_middle_arg(arg, MessageError::BadArgument));
+        }
+        try!(check_middle_arg(target, MessageError::BadTarget));
+        try!(check_middle_arg(modes, MessageError::BadTarget));
+        Mode::parse(try!(write_in(storage, |wr| Mode::construct(wr, target, modes, args))))
+    }
+
+    pub fn get_target(&self) -> &[u8] {
+        let buf = self.as_bytes();
+        let (_command, rest) = parse_helpers::split_command(buf);
+        let (target, _rest) = parse_helpers::split_arg(rest);
+        target
+    }
+
+    /// The mode string, e.g. `+ov-b`.
+    pub fn get_modes(&self) -> &[u8] {
+        let buf = self.as_bytes();
+        let (_command, rest) = parse_helpers::split_command(buf);
+        let (_target, rest) = parse_helpers::split_arg(rest);
+        let (modes, _rest) = parse_helpers::split_arg(rest);
+        modes
+    }
+
+    /// The mode arguments, in the order their modes appear.
+    pub fn get_args(&self) -> Vec<&[u8]> {
+        let buf = self.as_bytes();
+        let (_command, mut rest) = parse_helpers::split_command(buf);
+        let mut out = Vec::new();
+        for _ in 0..2 {
+            rest = parse_helpers::split_arg(rest).1;
+        }
+        while rest.len() > 0 {
+            let (arg, remainder) = parse_helpers::split_arg(rest);
+            out.push(arg);
+            rest = remainder;
+        }
+        out
+    }
+}
+
+impl ModeBuf {
+    /// Build a MODE message from a mode string (e.g. `+ov-b`) and its
+    /// arguments.  No attempt is made to check that the arguments line
+    /// up with the modes; see `ModeBuf::builder` for that.
+    pub fn new<T, M>(target: T, modes: M, args: &[&[u8]]) -> Result<ModeBuf, MessageError>
+        where T: AsRef<[u8]>,
+              M: AsRef<[u8]>
+    {
+        let target = target.as_ref();
+        let modes = modes.as_ref();
+        for arg in args.iter() {
+            if !is_middle_arg(arg) {
+                return Err(MessageError::BadArgument);
+            }
+        }
+        if !is_middle_arg(target) || !is_middle_arg(modes) {
+            return Err(MessageError::BadTarget);
+        }
+
+        let mut wr = io::Cursor::new(Vec::new());
+        try!(Mode::construct(&mut wr, target, modes, args));
+
+        let buf = wr.into_inner();
+        if parse_helpers::has_forbidden_byte(&buf) {
+            return Err(MessageError::ForbiddenByte);
+        }
+        let message = try!(IrcMsgBuf::new(buf).map_err(MessageError::from));
+        try!(Mode::validate(&message));
+        Ok(ModeBuf { inner: message })
+    }
+
+    /// Set our server notice mask, as in `MODE nick +s +cC-k`.  Only
+    /// opers may usually do this; servers answer with RPL_SNOMASK.
+    pub fn snomask<N, M>(nick: N, changes: M) -> Result<ModeBuf, MessageError>
+        where N: AsRef<[u8]>,
+              M: AsRef<[u8]>
+    {
+        let changes = changes.as_ref();
+        match changes.first() {
+            Some(&b'+') | Some(&b'-') => (),
+            _ => return Err(MessageError::BadArgument),
+        }
+        ModeBuf::new(nick, "+s", &[changes])
+    }
+
+    /// Start building a set of mode changes for `target`.
+    pub fn builder<T>(target: T) -> ModeBuilder
+        where T: AsRef<[u8]>
+    {
+        let target = target.as_ref();
+        ModeBuilder {
+            target: target.to_vec(),
+            changes: Vec::new(),
+            modes_limit: DEFAULT_MODES_LIMIT,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ModeChange {
+    add: bool,
+    mode: u8,
+    arg: Option<Vec<u8>>,
+}
+
+/// Accumulates mode changes for one target and renders them as one or
+/// more MODE messages.
+///
+/// ```
+/// use irc::client::ModeBuf;
+///
+/// let msgs = ModeBuf::builder(b"#rust")
+///     .give_op(b"alice")
+///     .set_key(b"hunter2")
+///     .remove_ban(b"*!*@example.com")
+///     .build().unwrap();
+/// assert_eq!(msgs.len(), 1);
+/// assert_eq!(msgs[0].as_bytes(), &b"MODE #rust +ok-b alice hunter2 *!*@example.com"[..]);
+/// ```
+#[derive(Clone, Debug)]
+pub struct ModeBuilder {
+    target: Vec<u8>,
+    changes: Vec<ModeChange>,
+    modes_limit: usize,
+}
+
+impl ModeBuilder {
+    fn push(mut self, add: bool, mode: u8, arg: Option<&[u8]>) -> ModeBuilder {
+        self.changes.push(ModeChange {
+            add: add,
+            mode: mode,
+            arg: arg.map(|a| a.to_vec()),
+        });
+        self
+    }
+
+    /// Limit the number of parameterised modes per message, as given by
+    /// the server's `MODES=` ISUPPORT token.  Defaults to
+    /// `DEFAULT_MODES_LIMIT`.
+    pub fn modes_limit(mut self, limit: usize) -> ModeBuilder {
+        self.modes_limit = limit;
+        self
+    }
+
+    /// Set an arbitrary mode, with an argument if it takes one.
+    pub fn add(self, mode: u8, arg: Option<&[u8]>) -> ModeBuilder {
+        self.push(true, mode, arg)
+    }
+
+    /// Unset an arbitrary mode, with an argument if it takes one.
+    pub fn remove(self, mode: u8, arg: Option<&[u8]>) -> ModeBuilder {
+        self.push(false, mode, arg)
+    }
+
+    pub fn give_op<N>(self, nick: N) -> ModeBuilder
+        where N: AsRef<[u8]>
+    {
+        let nick = nick.as_ref();
+        self.push(true, b'o', Some(nick))
+    }
+
+    pub fn take_op<N>(self, nick: N) -> ModeBuilder
+        where N: AsRef<[u8]>
+    {
+        let nick = nick.as_ref();
+        self.push(false, b'o', Some(nick))
+    }
+
+    pub fn give_voice<N>(self, nick: N) -> ModeBuilder
+        where N: AsRef<[u8]>
+    {
+        let nick = nick.as_ref();
+        self.push(true, b'v', Some(nick))
+    }
+
+    pub fn take_voice<N>(self, nick: N) -> ModeBuilder
+        where N: AsRef<[u8]>
+    {
+        let nick = nick.as_ref();
+        self.push(false, b'v', Some(nick))
+    }
+
+    pub fn add_ban<M>(self, mask: M) -> ModeBuilder
+        where M: AsRef<[u8]>
+    {
+        let mask = mask.as_ref();
+        self.push(true, b'b', Some(mask))
+    }
+
+    pub fn remove_ban<M>(self, mask: M) -> ModeBuilder
+        where M: AsRef<[u8]>
+    {
+        let mask = mask.as_ref();
+        self.push(false, b'b', Some(mask))
+    }
+
+    pub fn set_key<K>(self, key: K) -> ModeBuilder
+        where K: AsRef<[u8]>
+    {
+        let key = key.as_ref();
+        self.push(true, b'k', Some(key))
+    }
+
+    /// Most servers require the current key to unset it.
+    pub fn remove_key<K>(self, key: K) -> ModeBuilder
+        where K: AsRef<[u8]>
+    {
+        let key = key.as_ref();
+        self.push(false, b'k', Some(key))
+    }
+
+    pub fn set_limit(self, limit: u32) -> ModeBuilder {
+        let limit = limit.to_string();
+        self.push(true, b'l', Some(limit.as_bytes()))
+    }
+
+    pub fn remove_limit(self) -> ModeBuilder {
+        self.push(false, b'l', None)
+    }
+
+    /// Render the accumulated changes.  Changes keep their order; a new
+    /// message is started whenever the current one would exceed the
+    /// modes limit.  Fails if a mode or argument can't be represented.
+    pub fn build(self) -> Result<Vec<ModeBuf>, MessageError> {
+        let limit = ::std::cmp::max(self.modes_limit, 1);
+        let mut out = Vec::new();
+        let mut chunk: Vec<&ModeChange> = Vec::new();
+        let mut chunk_args = 0;
+
+        for change in self.changes.iter() {
+            if !(change.mode as char).is_ascii_alphabetic() {
+                return Err(MessageError::BadArgument);
+            }
+            if change.arg.is_some() {
+                if chunk_args == limit {
+                    out.push(try!(ModeBuilder::render(&self.target, &chunk)));
+                    chunk.clear();
+                    chunk_args = 0;
+                }
+                chunk_args += 1;
+            }
+            chunk.push(change);
+        }
+        if !chunk.is_empty() {
+            out.push(try!(ModeBuilder::render(&self.target, &chunk)));
+        }
+        Ok(out)
+    }
+
+    fn render(target: &[u8], changes: &[&ModeChange]) -> Result<ModeBuf, MessageError> {
+        let mut modes = Vec::new();
+        let mut args: Vec<&[u8]> = Vec::new();
+        let mut sign = None;
+        for change in changes.iter() {
+            if sign != Some(change.add) {
+                modes.push(if change.add { b'+' } else { b'-' });
+                sign = Some(change.add);
+            }
+            modes.push(change.mode);
+            if let Some(ref arg) = change.arg {
+                args.push(arg);
+            }
+        }
+        ModeBuf::new(target, &modes, &args)
+    }
+}
+
+#[test]
+fn privmsg_chunks() {
+    use ::MAX_MESSAGE_LEN;
+
+    let body = "word ".repeat(200);
+    let msgs = PrivmsgBuf::new_chunked("#rust", body.trim_right(), MAX_MESSAGE_LEN).unwrap();
+    assert_eq!(msgs.len(), 3);
+    for msg in msgs.iter() {
+        assert!(msg.as_bytes().len() + RELAY_PREFIX_RESERVE + 2 <= MAX_MESSAGE_LEN);
+        assert!(msg.as_bytes().ends_with(b"word"));
+    }
+
+    // No spaces: break between characters, never inside one.
+    let body = "\u{e9}".repeat(300);
+    let msgs = PrivmsgBuf::new_chunked("#rust", &body, MAX_MESSAGE_LEN).unwrap();
+    let rejoined: Vec<u8> = msgs.iter()
+        .flat_map(|msg| msg.as_bytes()[b"PRIVMSG #rust :".len()..].to_vec())
+        .collect();
+    assert_eq!(rejoined, body.as_bytes());
+    assert!(msgs.iter().all(|msg| ::std::str::from_utf8(msg.as_bytes()).is_ok()));
+
+    assert!(PrivmsgBuf::new_chunked("#rust", "hi", 50).is_err());
+    assert!(PrivmsgBuf::new_chunked("#rust", "", MAX_MESSAGE_LEN).unwrap().is_empty());
+}
+
+#[test]
+fn utf8_safe_truncation() {
+    let text = "caf\u{e9} \u{1f600}".as_bytes();
+    assert_eq!(truncate_utf8_safe(text, 100), text);
+    assert_eq!(truncate_utf8_safe(text, 4), b"caf");
+    assert_eq!(truncate_utf8_safe(text, 5), "caf\u{e9}".as_bytes());
+    assert_eq!(truncate_utf8_safe(text, 9), "caf\u{e9} ".as_bytes());
+    assert_eq!(truncate_utf8_safe(text, 10), "caf\u{e9} \u{1f600}".as_bytes());
+    // Not UTF-8: cut where asked.
+    assert_eq!(truncate_utf8_safe(b"\x80\x80\x80\x80\x80", 4), b"\x80\x80\x80\x80");
+}
+
+#[test]
+fn snomask_mode() {
+    let mode = ModeBuf::snomask("oper", "+cC-k").unwrap();
+    assert_eq!(mode.as_bytes(), b"MODE oper +s +cC-k");
+    assert_eq!(mode.get_args(), vec![&b"+cC-k"[..]]);
+    assert!(ModeBuf::snomask("oper", "cC").is_err());
+}
+
+#[test]
+fn constructor_errors() {
+    assert_eq!(KickBuf::new("#rust", "bad nick", None).err(), Some(MessageError::BadTarget));
+    assert_eq!(JoinBuf::new("").err(), Some(MessageError::BadTarget));
+    assert_eq!(JoinBuf::new("#rust\r\nQUIT").err(), Some(MessageError::ForbiddenByte));
+    assert_eq!(PrivmsgBuf::new("#rust", "hi\r\nQUIT").err(), Some(MessageError::ForbiddenByte));
+
+    let notice = IrcMsgBuf::new(b"NOTICE #rust :hi".to_vec()).unwrap();
+    assert_eq!(Kick::validate(&notice).err(), Some(MessageError::WrongCommand));
+    assert_eq!(Kick::parse(b"KICK #rust").err(), Some(MessageError::MissingArgument));
+}
+
+#[test]
+fn client_cons_with_bad_arguments() {
+    assert!(InviteBuf::new(b"nick", b"#foo").is_ok());
+    assert!(InviteBuf::new(b"nick", b"#foo\nQUIT").is_err());
+    assert!(InviteBuf::new(b"#foo", b"#foo").is_err());
+    assert!(InviteBuf::new(b"nick", b"foo bar").is_err());
+
+    assert!(JoinBuf::new(b"#a,#b").is_ok());
+    assert!(JoinBuf::new(b"0").is_ok());
+    assert!(JoinBuf::new(b"#a,,#b").is_err());
+    assert!(JoinBuf::new(b"#a\x00").is_err());
+
+    assert!(NickBuf::new(b"nick_").is_ok());
+    assert!(NickBuf::new(b"1nick").is_err());
+    assert!(NickBuf::new(b"ni ck").is_err());
+    assert!(NickBuf::new(b"nick\r\nQUIT").is_err());
+    assert!(NickBuf::new(b"a,b").is_err());
+
+    assert!(NoticeBuf::new(b"#foo,bar", b"hi").is_ok());
+    assert!(NoticeBuf::new(b"#foo,", b"hi").is_err());
+    assert!(PrivmsgBuf::new(b":foo", b"hi").is_err());
+    assert!(PrivmsgBuf::new(b"#foo", b"hi\x00").is_err());
+
+    assert_eq!(PartBuf::new(b"#a,#b", None).unwrap().as_bytes(), b"PART #a,#b");
+    assert_eq!(PartBuf::new(b"#a", Some(b"bye")).unwrap().as_bytes(), b"PART #a :bye");
+    assert!(PartBuf::new(b"#a,", None).is_err());
+    assert!(PartBuf::new(b"#a", Some(b"bye\r\nQUIT")).is_err());
+
+    assert!(PingBuf::new(b"irc.example.net").is_ok());
+    assert!(PingBuf::new(b"a\rb").is_err());
+    assert!(PongBuf::new(b"a\nPRIVMSG #foo :x").is_err());
+    assert!(QuitBuf::new(b"bye\nQUIT").is_err());
+
+    let user = UserBuf::new(b"bot", 8, b"A Bot").unwrap();
+    assert_eq!(user.as_bytes(), b"USER bot 8 * :A Bot");
+    assert_eq!(user.get_username(), b"bot");
+    assert_eq!(user.get_realname(), b"A Bot");
+    assert!(UserBuf::new(b"b ot", 0, b"x").is_err());
+    assert!(UserBuf::new(b"bot", 0, b"x\r\n").is_err());
+
+    // Parsing checks the same rules.
+    assert!(Privmsg::parse(b"PRIVMSG #foo :hi").is_ok());
+    assert_eq!(Privmsg::parse(b":n!u@h PRIVMSG #foo :hi").err(), Some(MessageError::BadPrefix));
+    assert_eq!(Privmsg::parse(b"PRIVMSG #foo").err(), Some(MessageError::MissingArgument));
+    assert_eq!(Quit::parse(b"QUIT a b").err(), Some(MessageError::TooManyArguments));
+    assert_eq!(User::parse(b"USER bot 0 *").err(), Some(MessageError::MissingArgument));
+    assert!(Notice::parse(b"NOTICE #foo,bar :hi").is_ok());
+    assert_eq!(Notice::parse(b"NOTICE #foo").err(), Some(MessageError::MissingArgument));
+    assert_eq!(Notice::parse(b"NOTICE #foo, :hi").err(), Some(MessageError::BadTarget));
+    assert_eq!(Notice::parse(b"PRIVMSG #foo :hi").err(), Some(MessageError::WrongCommand));
+}
+
+#[test]
+fn multi_target_constructors() {
+    let join = JoinBuf::new_multi(&["#a", "#b", "#c"], &["key1", "key2"]).unwrap();
+    assert_eq!(join.as_bytes(), b"JOIN #a,#b,#c key1,key2");
+    assert_eq!(join.get_channels().collect::<Vec<_>>(), vec![&b"#a"[..], b"#b", b"#c"]);
+    assert_eq!(join.get_keys().collect::<Vec<_>>(), vec![&b"key1"[..], b"key2"]);
+
+    let join = JoinBuf::new_multi::<_, &str>(&["#a"], &[]).unwrap();
+    assert_eq!(join.as_bytes(), b"JOIN #a");
+    assert_eq!(join.get_keys().count(), 0);
+
+    let no_channels: &[&str] = &[];
+    assert_eq!(JoinBuf::new_multi(no_channels, no_channels).err(), Some(MessageError::MissingArgument));
+    assert_eq!(JoinBuf::new_multi(&["#a"], &["k1", "k2"]).err(), Some(MessageError::TooManyArguments));
+    assert_eq!(JoinBuf::new_multi(&["#a,#b"], no_channels).err(), Some(MessageError::BadTarget));
+    assert_eq!(JoinBuf::new_multi(&["#a"], &["k y"]).err(), Some(MessageError::BadArgument));
+
+    let privmsg = PrivmsgBuf::new_multi(&["#a", "nick"], "hi there").unwrap();
+    assert_eq!(privmsg.as_bytes(), b"PRIVMSG #a,nick :hi there");
+    assert_eq!(privmsg.get_targets().collect::<Vec<_>>(), vec![&b"#a"[..], b"nick"]);
+    assert_eq!(PrivmsgBuf::new_multi(&["#a", ""], "hi").err(), Some(MessageError::BadTarget));
+
+    let notice = NoticeBuf::new_multi(&["a", "b"], "hi").unwrap();
+    assert_eq!(notice.as_bytes(), b"NOTICE a,b :hi");
+    assert_eq!(notice.get_targets().count(), 2);
+
+    let parsed = Join::parse(b"JOIN #x,#y k").unwrap();
+    assert_eq!(parsed.get_channels().collect::<Vec<_>>(), vec![&b"#x"[..], b"#y"]);
+    assert_eq!(parsed.get_keys().collect::<Vec<_>>(), vec![&b"k"[..]]);
+}
+
+#[test]
+fn rfc2812_commands() {
+    assert_eq!(AwayBuf::new(Some(b"gone fishing")).unwrap().as_bytes(), b"AWAY :gone fishing");
+    assert_eq!(AwayBuf::new(None).unwrap().get_message(), None);
+    assert_eq!(IsonBuf::new(&["a", "b"]).unwrap().get_nicks().count(), 2);
+    assert_eq!(IsonBuf::new::<&str>(&[]).err(), Some(MessageError::MissingArgument));
+    assert_eq!(MotdBuf::new(None).unwrap().as_bytes(), b"MOTD");
+    assert_eq!(MotdBuf::new(Some(b"irc.example.net")).unwrap().get_target(), Some(&b"irc.example.net"[..]));
+    assert_eq!(NamesBuf::new(Some(b"#a,#b")).unwrap().get_channels().count(), 2);
+    assert_eq!(NamesBuf::new(None).unwrap().get_channels().count(), 0);
+    assert_eq!(NamesBuf::new(Some(b"#a b")).err(), Some(MessageError::BadTarget));
+
+    let oper = OperBuf::new("root", "hunter2").unwrap();
+    assert_eq!(oper.as_bytes(), b"OPER root hunter2");
+    assert_eq!(oper.get_password(), b"hunter2");
+    assert_eq!(OperBuf::new("root", "two words").err(), Some(MessageError::BadArgument));
+    assert_eq!(PassBuf::new("secret").unwrap().as_bytes(), b"PASS :secret");
+    assert_eq!(PassBuf::new("").err(), Some(MessageError::BadArgument));
+
+    assert_eq!(UserhostBuf::new(&["a", "b", "c"]).unwrap().as_bytes(), b"USERHOST a b c");
+    assert_eq!(UserhostBuf::new(&["a"; 6]).err(), Some(MessageError::TooManyArguments));
+    let who = WhoBuf::new("#rust", true).unwrap();
+    assert_eq!(who.as_bytes(), b"WHO #rust o");
+    assert!(who.is_opers_only());
+    let whois = WhoisBuf::new_remote("alice", "alice").unwrap();
+    assert_eq!(whois.as_bytes(), b"WHOIS alice alice");
+    assert_eq!(whois.get_target(), Some(&b"alice"[..]));
+    assert_eq!(WhoisBuf::new("bob").unwrap().get_target(), None);
+    let whowas = WhowasBuf::new("carol", Some(3)).unwrap();
+    assert_eq!(whowas.as_bytes(), b"WHOWAS carol 3");
+    assert_eq!(whowas.get_count(), Some(3));
+    assert!(Whowas::parse(b"WHOWAS carol x").is_err());
+}
+
+#[test]
+fn construct_in_storage() {
+    use ::testinfra::alloc::count_allocations;
+
+    let mut storage = [0; 512];
+    assert_eq!(count_allocations(|| {
+        Join::new(&mut storage, "#rust").unwrap().get_channels().count()
+    }), 0);
+    assert_eq!(count_allocations(|| {
+        Kick::new(&mut storage, "#rust", "troll", Some(b"bye")).unwrap().get_reason().is_some()
+    }), 0);
+    assert_eq!(Nick::new(&mut storage, "nick").unwrap().as_bytes(), b"NICK nick");
+    assert_eq!(Part::new(&mut storage, "#a", Some(b"later")).unwrap().as_bytes(), b"PART #a :later");
+    assert_eq!(Topic::new(&mut storage, "#a", None).unwrap().as_bytes(), b"TOPIC #a");
+    assert_eq!(Mode::new(&mut storage, "#a", "+o", &[b"nick"]).unwrap().as_bytes(), b"MODE #a +o nick");
+    assert_eq!(Whois::new(&mut storage, "nick").unwrap().as_bytes(), b"WHOIS nick");
+    assert_eq!(Who::new(&mut storage, "#a", false).unwrap().get_mask(), b"#a");
+
+    let mut small = [0; 8];
+    assert_eq!(Join::new(&mut small, "#a-long-channel").err(), Some(MessageError::BufferTooSmall));
+    assert_eq!(Join::new(&mut storage, "#a\r\nQUIT").err(), Some(MessageError::ForbiddenByte));
+    assert_eq!(Join::new(&mut storage, "#a b").err(), Some(MessageError::BadTarget));
+}
+
+#[test]
+fn mode_builder_splits_on_modes_limit() {
+    let msgs = ModeBuf::builder(b"#foo")
+        .give_op(b"a")
+        .give_op(b"b")
+        .take_voice(b"c")
+        .remove_limit()
+        .add_ban(b"*!*@bad.host")
+        .modes_limit(3)
+        .build().unwrap();
+    assert_eq!(msgs.len(), 2);
+    assert_eq!(msgs[0].as_bytes(), b"MODE #foo +oo-vl a b c");
+    assert_eq!(msgs[1].as_bytes(), b"MODE #foo +b *!*@bad.host");
+    assert_eq!(msgs[0].get_target(), b"#foo");
+    assert_eq!(msgs[0].get_modes(), b"+oo-vl");
+    assert_eq!(msgs[0].get_args(), vec![&b"a"[..], b"b", b"c"]);
+
+    assert!(ModeBuf::builder(b"#foo").give_op(b"bad nick").build().is_err());
+    assert!(ModeBuf::builder(b"#foo").give_op(b":nick").build().is_err());
+    assert!(ModeBuf::builder(b"#foo").build().unwrap().is_empty());
+}
+
+
+impl_irc_msg_subtype!(Motd);
+impl_irc_msg_subtype_buf!(MotdBuf, Motd);
+
+impl Motd {
+    fn construct<W>(sink: &mut W, target: Option<&[u8]>) -> Result<(), MessageError>
+        where W: Write
+    {
+        try!(sink.write_all(b"MOTD").or_else(cursor_chk_error));
+        if let Some(target) = target {
+            try!(check_middle_arg(target, MessageError::BadTarget));
+            try!(sink.write_all(b" ").or_else(cursor_chk_error));
+            try!(sink.write_all(target).or_else(cursor_chk_error));
+        }
+        Ok(())
+    }
+
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        try!(validate_command(msg, "MOTD", 0, 1));
+        Ok(())
+    }
+
+    /// Create a new `Motd` in `storage`.  This does not allocate any storage.
+    pub fn new<'a>(storage: &'a mut [u8], target: Option<&[u8]>) -> Result<&'a Motd, MessageError> {
+        Motd::parse(try!(write_in(storage, |wr| Motd::construct(wr, target))))
+    }
+
+    /// The server asked, or `None` for the one we're connected to.
+    pub fn get_target(&self) -> Option<&[u8]> {
+        self.args().next()
+    }
+}
+
+impl MotdBuf {
+    /// Ask for the message of the day of `target`, or of the server
+    /// we're connected to.
+    pub fn new(target: Option<&[u8]>) -> Result<MotdBuf, MessageError> {
+        let message = try!(write_buf(|wr| Motd::construct(wr, target)));
+        try!(Motd::validate(&message));
+        Ok(MotdBuf { inner: message })
+    }
+}
+
+
+impl_irc_msg_subtype!(Names);
+impl_irc_msg_subtype_buf!(NamesBuf, Names);
+
+impl Names {
+    fn construct<W>(sink: &mut W, channel: Option<&[u8]>) -> Result<(), MessageError>
+        where W: Write
+    {
+        try!(sink.write_all(b"NAMES").or_else(cursor_chk_error));
+        if let Some(channel) = channel {
+            try!(check_middle_arg(channel, MessageError::BadTarget));
+            try!(sink.write_all(b" ").or_else(cursor_chk_error));
+            try!(sink.write_all(channel).or_else(cursor_chk_error));
+        }
+        Ok(())
+    }
+
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        let args = try!(validate_command(msg, "NAMES", 0, 2));
+        match args.first() {
+            Some(channels) => validate_targets(channels, is_channel),
+            None => Ok(()),
+        }
+    }
+
+    /// Create a new `Names` in `storage`.  This does not allocate any storage.
+    pub fn new<'a>(storage: &'a mut [u8], channel: Option<&[u8]>) -> Result<&'a Names, MessageError> {
+        Names::parse(try!(write_in(storage, |wr| Names::construct(wr, channel))))
+    }
+
+    /// The channels asked about; none means every visible channel.
+    pub fn get_channels(&self) -> ListIter {
+        ListIter::new(self.args().next().unwrap_or(b""))
+    }
+}
+
+impl NamesBuf {
+    /// List the members of `channel`, which may be a comma-separated
+    /// list, or of every visible channel with `None`.
+    pub fn new(channel: Option<&[u8]>) -> Result<NamesBuf, MessageError> {
+        let message = try!(write_buf(|wr| Names::construct(wr, channel)));
+        try!(Names::validate(&message));
+        Ok(NamesBuf { inner: message })
+    }
+}
+
+
+impl_irc_msg_subtype!(Nick);
+impl_irc_msg_subtype_buf!(NickBuf, Nick);
+
+impl Nick {
+    fn construct<W>(sink: &mut W, nick: &[u8]) -> Result<(), MessageError>
+        where W: Write
+    {
+        try!(sink.write_all(b"NICK ").or_else(cursor_chk_error));
+        try!(sink.write_all(nick).or_else(cursor_chk_error));
+        Ok(())
+    }
+
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        let args = try!(validate_command(msg, "NICK", 1, 1));
+        if !is_nick(args[0]) {
+            return Err(MessageError::BadTarget);
+        }
+        Ok(())
+    }
+
+    /// Create a new `Nick` in `storage`.  This does not allocate any storage.
+    pub fn new<'a, N>(storage: &'a mut [u8], nick: N) -> Result<&'a Nick, MessageError>
+        where N: AsRef<[u8]>
+    {
+        let nick = nick.as_ref();
+        Nick::parse(try!(write_in(storage, |wr| Nick::construct(wr, nick))))
+    }
+}
+
+impl NickBuf {
+    pub fn new<N>(nick: N) -> Result<NickBuf, MessageError>
+        where N: AsRef<[u8]>
+    {
+        let nick = nick.as_ref();
+        let mut wr = io::Cursor::new(Vec::new());
+        try!(Nick::construct(&mut wr, nick));
+
+        let buf = wr.into_inner();
+        if parse_helpers::has_forbidden_byte(&buf) {
+            return Err(MessageError::ForbiddenByte);
+        }
+        // maybe we could skip this check later and turn it into a debug-assert?
+        let message = try!(IrcMsgBuf::new(buf).map_err(MessageError::from));
+
+        try!(Nick::validate(&message));
+        Ok(NickBuf { inner: message })
+    }
+}
+
+
+impl_irc_msg_subtype!(Notice);
+impl_irc_msg_subtype_buf!(NoticeBuf, Notice);
+
+impl Notice {
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        let args = try!(validate_command(msg, "NOTICE", 2, 2));
+        validate_targets(args[0], is_target)
+    }
+
+    pub fn get_targets(&self) -> ListIter {
+        ListIter::new(self.args().next().unwrap())
+    }
+}
+
+impl NoticeBuf {
+    pub fn new<T, M>(target: T, message: M) -> Result<NoticeBuf, MessageError>
+        where T: AsRef<[u8]>,
+              M: AsRef<[u8]>
+    {
+        let target = target.as_ref();
+        let message = message.as_ref();
+        if !is_middle_arg(target) {
+            return Err(MessageError::BadTarget);
+        }
+        if parse_helpers::has_forbidden_byte(message) {
+            return Err(MessageError::ForbiddenByte);
+        }
+        let message = try!(IrcMsgBuilder::new()
+            .command("NOTICE")
+            .arg(target)
+            .trailing(message)
+            .build()
+            .map_err(MessageError::from));
 
-impl Invite {
-    fn construct<W>(sink: &mut W, nickname: &[u8], channel: &[u8]) -> Result<(), ()>
+        try!(Notice::validate(&message));
+        Ok(NoticeBuf { inner: message })
+    }
+
+    /// Send one NOTICE to several targets, e.g. `NOTICE #a,nick :hi`.
+    pub fn new_multi<T, M>(targets: &[T], message: M) -> Result<NoticeBuf, MessageError>
+        where T: AsRef<[u8]>,
+              M: AsRef<[u8]>
+    {
+        NoticeBuf::new(try!(join_list(targets, MessageError::BadTarget)), message)
+    }
+}
+
+
+impl_irc_msg_subtype!(Oper);
+impl_irc_msg_subtype_buf!(OperBuf, Oper);
+
+impl Oper {
+    fn construct<W>(sink: &mut W, name: &[u8], password: &[u8]) -> Result<(), MessageError>
         where W: Write
     {
-        try!(sink.write_all(b"INVITE ").or_else(cursor_chk_error));
-        try!(sink.write_all(nickname).or_else(cursor_chk_error));
+        try!(check_middle_arg(name, MessageError::BadArgument));
+        try!(check_middle_arg(password, MessageError::BadArgument));
+        try!(sink.write_all(b"OPER ").or_else(cursor_chk_error));
+        try!(sink.write_all(name).or_else(cursor_chk_error));
         try!(sink.write_all(b" ").or_else(cursor_chk_error));
-        try!(sink.write_all(channel).or_else(cursor_chk_error));
+        try!(sink.write_all(password).or_else(cursor_chk_error));
+        Ok(())
+    }
+
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        try!(validate_command(msg, "OPER", 2, 2));
         Ok(())
     }
 
-    fn validate(_msg: &IrcMsg) -> Result<(), ()> {
-        unimplemented!();
+    /// Create a new `Oper` in `storage`.  This does not allocate any storage.
+    pub fn new<'a, N, P>(storage: &'a mut [u8], name: N, password: P) -> Result<&'a Oper, MessageError>
+        where N: AsRef<[u8]>,
+              P: AsRef<[u8]>
+    {
+        let (name, password) = (name.as_ref(), password.as_ref());
+        Oper::parse(try!(write_in(storage, |wr| Oper::construct(wr, name, password))))
     }
-}
 
-impl InviteBuf {
-    pub fn new(nickname: &[u8], channel: &[u8]) -> Result<InviteBuf, ()> {
-        let mut wr = io::Cursor::new(Vec::new());
-        try!(Invite::construct(&mut wr, nickname, channel));
+    pub fn get_name(&self) -> &[u8] {
+        self.args().next().unwrap()
+    }
 
-        // maybe we could skip this check later and turn it into a debug-assert?
-        let message = try!(IrcMsgBuf::new(wr.into_inner()).map_err(|_| ()));
+    pub fn get_password(&self) -> &[u8] {
+        self.args().nth(1).unwrap()
+    }
+}
 
-        // FIXME: try!(Invite::validate(&message));
-        Ok(InviteBuf { inner: message })
+impl OperBuf {
+    pub fn new<N, P>(name: N, password: P) -> Result<OperBuf, MessageError>
+        where N: AsRef<[u8]>,
+              P: AsRef<[u8]>
+    {
+        let (name, password) = (name.as_ref(), password.as_ref());
+        let message = try!(write_buf(|wr| Oper::construct(wr, name, password)));
+        try!(Oper::validate(&message));
+        Ok(OperBuf { inner: message })
     }
 }
 
 
-impl_irc_msg_subtype!(Join);
-impl_irc_msg_subtype_buf!(JoinBuf, Join);
+impl_irc_msg_subtype!(Part);
+impl_irc_msg_subtype_buf!(PartBuf, Part);
 
-impl Join {
-    fn construct<W>(sink: &mut W, channel: &[u8]) -> Result<(), ()>
+impl Part {
+    fn construct<W>(sink: &mut W, channel: &[u8], reason: Option<&[u8]>) -> Result<(), MessageError>
         where W: Write
     {
-        try!(sink.write_all(b"JOIN ").or_else(cursor_chk_error));
+        try!(check_middle_arg(channel, MessageError::BadTarget));
+        try!(sink.write_all(b"PART ").or_else(cursor_chk_error));
         try!(sink.write_all(channel).or_else(cursor_chk_error));
+        if let Some(reason) = reason {
+            try!(sink.write_all(b" :").or_else(cursor_chk_error));
+            try!(sink.write_all(reason).or_else(cursor_chk_error));
+        }
         Ok(())
     }
 
-    fn validate(_msg: &IrcMsg) -> Result<(), ()> {
-        unimplemented!();
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        let args = try!(validate_command(msg, "PART", 1, 2));
+        validate_targets(args[0], is_channel)
     }
-}
-
-impl JoinBuf {
-    pub fn new(channel: &[u8]) -> Result<JoinBuf, ()> {
-        let mut wr = io::Cursor::new(Vec::new());
-        try!(Join::construct(&mut wr, channel));
 
-        // maybe we could skip this check later and turn it into a debug-assert?
-        let message = try!(IrcMsgBuf::new(wr.into_inner()).map_err(|_| ()));
+    /// Create a new `Part` in `storage`.  This does not allocate any storage.
+    pub fn new<'a, C>(storage: &'a mut [u8], channel: C, reason: Option<&[u8]>) -> Result<&'a Part, MessageError>
+        where C: AsRef<[u8]>
+    {
+        let channel = channel.as_ref();
+        Part::parse(try!(write_in(storage, |wr| Part::construct(wr, channel, reason))))
+    }
+}
 
-        // FIXME: try!(Join::validate(&message));
-        Ok(JoinBuf { inner: message })
+impl PartBuf {
+    /// Leave `channel`, which may be a comma-separated list, optionally
+    /// giving a reason.
+    pub fn new<C>(channel: C, reason: Option<&[u8]>) -> Result<PartBuf, MessageError>
+        where C: AsRef<[u8]>
+    {
+        let channel = channel.as_ref();
+        let message = try!(write_buf(|wr| Part::construct(wr, channel, reason)));
+        try!(Part::validate(&message));
+        Ok(PartBuf { inner: message })
     }
 }
 
 
-impl_irc_msg_subtype!(Nick);
-impl_irc_msg_subtype_buf!(NickBuf, Nick);
+impl_irc_msg_subtype!(Pass);
+impl_irc_msg_subtype_buf!(PassBuf, Pass);
 
-impl Nick {
-    fn construct<W>(sink: &mut W, nick: &[u8]) -> Result<(), ()>
+impl Pass {
+    fn construct<W>(sink: &mut W, password: &[u8]) -> Result<(), MessageError>
         where W: Write
     {
-        try!(sink.write_all(b"NICK ").or_else(cursor_chk_error));
-        try!(sink.write_all(nick).or_else(cursor_chk_error));
+        try!(sink.write_all(b"PASS :").or_else(cursor_chk_error));
+        try!(sink.write_all(password).or_else(cursor_chk_error));
         Ok(())
     }
 
-    fn validate(_msg: &IrcMsg) -> Result<(), ()> {
-        unimplemented!();
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        let args = try!(validate_command(msg, "PASS", 1, 1));
+        if args[0].is_empty() {
+            return Err(MessageError::BadArgument);
+        }
+        Ok(())
     }
-}
 
-impl NickBuf {
-    pub fn new(nick: &[u8]) -> Result<NickBuf, ()> {
-        let mut wr = io::Cursor::new(Vec::new());
-        try!(Nick::construct(&mut wr, nick));
+    /// Create a new `Pass` in `storage`.  This does not allocate any storage.
+    pub fn new<'a, P>(storage: &'a mut [u8], password: P) -> Result<&'a Pass, MessageError>
+        where P: AsRef<[u8]>
+    {
+        let password = password.as_ref();
+        Pass::parse(try!(write_in(storage, |wr| Pass::construct(wr, password))))
+    }
 
-        // maybe we could skip this check later and turn it into a debug-assert?
-        let message = try!(IrcMsgBuf::new(wr.into_inner()).map_err(|_| ()));
+    pub fn get_password(&self) -> &[u8] {
+        self.args().next().unwrap()
+    }
+}
 
-        // FIXME: try!(Nick::validate(&message));
-        Ok(NickBuf { inner: message })
+impl PassBuf {
+    /// The connection password, sent before NICK and USER.
+    pub fn new<P>(password: P) -> Result<PassBuf, MessageError>
+        where P: AsRef<[u8]>
+    {
+        let password = password.as_ref();
+        let message = try!(write_buf(|wr| Pass::construct(wr, password)));
+        try!(Pass::validate(&message));
+        Ok(PassBuf { inner: message })
     }
 }
 
@@ -111,7 +1640,7 @@ impl_irc_msg_subtype!(Ping);
 impl_irc_msg_subtype_buf!(PingBuf, Ping);
 
 impl Ping {
-    fn construct<W>(sink: &mut W, server: &[u8]) -> Result<(), ()>
+    fn construct<W>(sink: &mut W, server: &[u8]) -> Result<(), MessageError>
         where W: Write
     {
         try!(sink.write_all(b"PING :").or_else(cursor_chk_error));
@@ -119,20 +1648,28 @@ impl Ping {
         Ok(())
     }
 
-    fn validate(_msg: &IrcMsg) -> Result<(), ()> {
-        unimplemented!();
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        try!(validate_command(msg, "PING", 1, 2));
+        Ok(())
     }
 }
 
 impl PingBuf {
-    pub fn new(server: &[u8]) -> Result<PingBuf, ()> {
+    pub fn new<S>(server: S) -> Result<PingBuf, MessageError>
+        where S: AsRef<[u8]>
+    {
+        let server = server.as_ref();
         let mut wr = io::Cursor::new(Vec::new());
         try!(Ping::construct(&mut wr, server));
 
+        let buf = wr.into_inner();
+        if parse_helpers::has_forbidden_byte(&buf) {
+            return Err(MessageError::ForbiddenByte);
+        }
         // maybe we could skip this check later and turn it into a debug-assert?
-        let message = try!(IrcMsgBuf::new(wr.into_inner()).map_err(|_| ()));
+        let message = try!(IrcMsgBuf::new(buf).map_err(MessageError::from));
 
-        // FIXME: try!(Ping::validate(&message));
+        try!(Ping::validate(&message));
         Ok(PingBuf { inner: message })
     }
 }
@@ -142,7 +1679,7 @@ impl_irc_msg_subtype!(Pong);
 impl_irc_msg_subtype_buf!(PongBuf, Pong);
 
 impl Pong {
-    fn construct<W>(sink: &mut W, server: &[u8]) -> Result<(), ()>
+    fn construct<W>(sink: &mut W, server: &[u8]) -> Result<(), MessageError>
         where W: Write
     {
         try!(sink.write_all(b"PONG ").or_else(cursor_chk_error));
@@ -150,20 +1687,42 @@ impl Pong {
         Ok(())
     }
 
-    fn validate(_msg: &IrcMsg) -> Result<(), ()> {
-        unimplemented!();
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        try!(validate_command(msg, "PONG", 1, 2));
+        Ok(())
     }
 }
 
 impl PongBuf {
-    pub fn new(source: &[u8]) -> Result<PongBuf, ()> {
+    pub fn new<S>(source: S) -> Result<PongBuf, MessageError>
+        where S: AsRef<[u8]>
+    {
+        let source = source.as_ref();
         let mut wr = io::Cursor::new(Vec::new());
         try!(Pong::construct(&mut wr, source));
 
+        let buf = wr.into_inner();
+        if parse_helpers::has_forbidden_byte(&buf) {
+            return Err(MessageError::ForbiddenByte);
+        }
         // maybe we could skip this check later and turn it into a debug-assert?
-        let message = try!(IrcMsgBuf::new(wr.into_inner()).map_err(|_| ()));
+        let message = try!(IrcMsgBuf::new(buf).map_err(MessageError::from));
+
+        try!(Pong::validate(&message));
+        Ok(PongBuf { inner: message })
+    }
 
-        // FIXME: try!(Pong::validate(&message));
+    /// Answer a PING by repeating its arguments exactly as they were
+    /// received, e.g. `:irc.example.net`.
+    pub fn echo<A>(args: A) -> Result<PongBuf, MessageError>
+        where A: AsRef<[u8]>
+    {
+        let args = args.as_ref();
+        let message = try!(write_buf(|wr| {
+            try!(wr.write_all(b"PONG ").or_else(cursor_chk_error));
+            wr.write_all(args).or_else(cursor_chk_error)
+        }));
+        try!(Pong::validate(&message));
         Ok(PongBuf { inner: message })
     }
 }
@@ -171,33 +1730,124 @@ impl PongBuf {
 impl_irc_msg_subtype!(Privmsg);
 impl_irc_msg_subtype_buf!(PrivmsgBuf, Privmsg);
 
+/// Room left in each line for the `:nick!user@host ` prefix a server
+/// prepends when relaying a message.
+pub const RELAY_PREFIX_RESERVE: usize = 100;
+
+/// The longest prefix of `buf` no longer than `max` bytes which doesn't
+/// end partway through a UTF-8 sequence.  Servers cut over-long lines
+/// without regard for encoding; use this to shorten text before sending
+/// so that doesn't happen.  Bytes which aren't UTF-8 are cut at `max`.
+pub fn truncate_utf8_safe(buf: &[u8], max: usize) -> &[u8] {
+    if buf.len() <= max {
+        return buf;
+    }
+    // A sequence is at most four bytes, so at most three continuation
+    // bytes can precede the cut.
+    let floor = max.saturating_sub(3);
+    let mut end = max;
+    while floor < end && buf[end] & 0xC0 == 0x80 {
+        end -= 1;
+    }
+    if buf[end] & 0xC0 == 0x80 {
+        end = max;
+    }
+    &buf[..end]
+}
+
+/// Split `body` into pieces of at most `limit` bytes, preferring to break
+/// at spaces and never breaking inside a UTF-8 sequence.
+fn split_body(mut body: &[u8], limit: usize) -> Vec<&[u8]> {
+    let mut out = Vec::new();
+    while limit < body.len() {
+        let (chunk, rest) = match body[..limit + 1].iter().rposition(|&b| b == b' ') {
+            Some(space) if space > 0 => (&body[..space], &body[space + 1..]),
+            _ => {
+                let mut end = truncate_utf8_safe(body, limit).len();
+                if end == 0 {
+                    end = limit;
+                }
+                (&body[..end], &body[end..])
+            },
+        };
+        out.push(chunk);
+        body = rest;
+    }
+    if !body.is_empty() {
+        out.push(body);
+    }
+    out
+}
+
 impl Privmsg {
-    fn construct<W>(sink: &mut W, target: &[u8], message: &[u8]) -> Result<(), ()>
-        where W: Write
-    {
-        try!(sink.write_all(b"PRIVMSG ").or_else(cursor_chk_error));
-        try!(sink.write_all(target).or_else(cursor_chk_error));
-        try!(sink.write_all(b" :").or_else(cursor_chk_error));
-        try!(sink.write_all(message).or_else(cursor_chk_error));
-        Ok(())
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        let args = try!(validate_command(msg, "PRIVMSG", 2, 2));
+        validate_targets(args[0], is_target)
     }
 
-    fn validate(_msg: &IrcMsg) -> Result<(), ()> {
-        unimplemented!();
+    pub fn get_targets(&self) -> ListIter {
+        ListIter::new(self.args().next().unwrap())
     }
 }
 
 impl PrivmsgBuf {
-    pub fn new(target: &[u8], message: &[u8]) -> Result<PrivmsgBuf, ()> {
-        let mut wr = io::Cursor::new(Vec::new());
-        try!(Privmsg::construct(&mut wr, target, message));
-
-        // maybe we could skip this check later and turn it into a debug-assert?
-        let message = try!(IrcMsgBuf::new(wr.into_inner()).map_err(|_| ()));
+    pub fn new<T, M>(target: T, message: M) -> Result<PrivmsgBuf, MessageError>
+        where T: AsRef<[u8]>,
+              M: AsRef<[u8]>
+    {
+        let target = target.as_ref();
+        let message = message.as_ref();
+        if !is_middle_arg(target) {
+            return Err(MessageError::BadTarget);
+        }
+        if parse_helpers::has_forbidden_byte(message) {
+            return Err(MessageError::ForbiddenByte);
+        }
+        let message = try!(IrcMsgBuilder::new()
+            .command("PRIVMSG")
+            .arg(target)
+            .trailing(message)
+            .build()
+            .map_err(MessageError::from));
 
-        // FIXME: try!(Privmsg::validate(&message));
+        try!(Privmsg::validate(&message));
         Ok(PrivmsgBuf { inner: message })
     }
+
+    /// Send one PRIVMSG to several targets, e.g. `PRIVMSG #a,nick :hi`.
+    pub fn new_multi<T, M>(targets: &[T], message: M) -> Result<PrivmsgBuf, MessageError>
+        where T: AsRef<[u8]>,
+              M: AsRef<[u8]>
+    {
+        PrivmsgBuf::new(try!(join_list(targets, MessageError::BadTarget)), message)
+    }
+
+    /// Like `new`, but `message` is cleaned up by `sanitizer` first.
+    pub fn new_sanitized<T, M>(target: T, message: M, sanitizer: &Sanitizer) -> Result<PrivmsgBuf, MessageError>
+        where T: AsRef<[u8]>,
+              M: AsRef<[u8]>
+    {
+        PrivmsgBuf::new(target, sanitizer.sanitize(message.as_ref()))
+    }
+
+    /// Split `message` over as many PRIVMSGs as it takes for each to fit
+    /// in `max_len` bytes, usually `MAX_MESSAGE_LEN`, once relayed with a
+    /// prefix of up to `RELAY_PREFIX_RESERVE` bytes.  Breaks fall on
+    /// spaces where possible and never inside a UTF-8 sequence.
+    pub fn new_chunked<T, M>(target: T, message: M, max_len: usize) -> Result<Vec<PrivmsgBuf>, MessageError>
+        where T: AsRef<[u8]>,
+              M: AsRef<[u8]>
+    {
+        let target = target.as_ref();
+        // `PRIVMSG <target> :<message>\r\n`
+        let overhead = RELAY_PREFIX_RESERVE + 8 + target.len() + 2 + 2;
+        if max_len <= overhead {
+            return Err(MessageError::BadArgument);
+        }
+        split_body(message.as_ref(), max_len - overhead).into_iter()
+            .map(|chunk| PrivmsgBuf::new(target, chunk))
+            .collect()
+    }
 }
 
 
@@ -205,7 +1855,7 @@ impl_irc_msg_subtype!(Quit);
 impl_irc_msg_subtype_buf!(QuitBuf, Quit);
 
 impl Quit {
-    fn construct<W>(sink: &mut W, reason: &[u8]) -> Result<(), ()>
+    fn construct<W>(sink: &mut W, reason: &[u8]) -> Result<(), MessageError>
         where W: Write
     {
         try!(sink.write_all(b"QUIT :").or_else(cursor_chk_error));
@@ -213,20 +1863,483 @@ impl Quit {
         Ok(())
     }
 
-    fn validate(_msg: &IrcMsg) -> Result<(), ()> {
-        unimplemented!();
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        try!(validate_command(msg, "QUIT", 0, 1));
+        Ok(())
     }
 }
 
 impl QuitBuf {
-    pub fn new(reason: &[u8]) -> Result<QuitBuf, ()> {
+    pub fn new<R>(reason: R) -> Result<QuitBuf, MessageError>
+        where R: AsRef<[u8]>
+    {
+        let reason = reason.as_ref();
         let mut wr = io::Cursor::new(Vec::new());
         try!(Quit::construct(&mut wr, reason));
 
+        let buf = wr.into_inner();
+        if parse_helpers::has_forbidden_byte(&buf) {
+            return Err(MessageError::ForbiddenByte);
+        }
         // maybe we could skip this check later and turn it into a debug-assert?
-        let message = try!(IrcMsgBuf::new(wr.into_inner()).map_err(|_| ()));
+        let message = try!(IrcMsgBuf::new(buf).map_err(MessageError::from));
 
-        // FIXME: try!(Quit::validate(&message));
+        try!(Quit::validate(&message));
         Ok(QuitBuf { inner: message })
     }
+
+    /// Like `new`, but `reason` is cleaned up by `sanitizer` first.
+    pub fn new_sanitized<R>(reason: R, sanitizer: &Sanitizer) -> Result<QuitBuf, MessageError>
+        where R: AsRef<[u8]>
+    {
+        QuitBuf::new(sanitizer.sanitize(reason.as_ref()))
+    }
+}
+
+
+impl_irc_msg_subtype!(Topic);
+impl_irc_msg_subtype_buf!(TopicBuf, Topic);
+
+impl Topic {
+    fn construct<W>(sink: &mut W, channel: &[u8], text: Option<&[u8]>) -> Result<(), MessageError>
+        where W: Write
+    {
+        try!(sink.write_all(b"TOPIC ").or_else(cursor_chk_error));
+        try!(sink.write_all(channel).or_else(cursor_chk_error));
+        if let Some(text) = text {
+            try!(sink.write_all(b" :").or_else(cursor_chk_error));
+            try!(sink.write_all(text).or_else(cursor_chk_error));
+        }
+        Ok(())
+    }
+
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        let buf = msg.as_bytes();
+
+        let (prefix, rest) = parse_helpers::split_prefix(buf);
+        if prefix.len() != 0 {
+            return Err(MessageError::BadPrefix);
+        }
+
+        let (command, rest) = parse_helpers::split_command(rest);
+        if !command.eq_ignore_ascii_case(b"TOPIC") {
+            return Err(MessageError::WrongCommand);
+        }
+
+        let (channel, rest) = parse_helpers::split_arg(rest);
+        if channel.len() < 1 {
+            return Err(MessageError::MissingArgument);
+        }
+
+        let (_text, must_be_empty) = parse_helpers::split_arg(rest);
+        if must_be_empty.len() != 0 {
+            return Err(MessageError::TooManyArguments);
+        }
+
+        Ok(())
+    }
+
+    pub fn get_channel(&self) -> &[u8] {
+        let buf = self.as_bytes();
+        let (_command, rest) = parse_helpers::split_command(buf);
+        let (channel, _rest) = parse_helpers::split_arg(rest);
+        channel
+    }
+
+    /// Create a new `Topic` in `storage`, a query if `text` is `None`.
+    /// This does not allocate any storage.
+    pub fn new<'a, C>(storage: &'a mut [u8], channel: C, text: Option<&[u8]>) -> Result<&'a Topic, MessageError>
+        where C: AsRef<[u8]>
+    {
+        let channel = channel.as_ref();
+        Topic::parse(try!(write_in(storage, |wr| Topic::construct(wr, channel, text))))
+    }
+
+    /// The requested topic, or `None` if this is a topic query.
+    pub fn get_text(&self) -> Option<&[u8]> {
+        let buf = self.as_bytes();
+        let (_command, rest) = parse_helpers::split_command(buf);
+        let (_channel, rest) = parse_helpers::split_arg(rest);
+        if rest.len() == 0 {
+            return None;
+        }
+        let (text, _rest) = parse_helpers::split_arg(rest);
+        Some(text)
+    }
+
+    /// True if this message asks the server for the current topic
+    /// rather than changing it.
+    pub fn is_query(&self) -> bool {
+        self.get_text().is_none()
+    }
+}
+
+impl TopicBuf {
+    fn _new(channel: &[u8], text: Option<&[u8]>) -> Result<TopicBuf, MessageError> {
+        if !is_middle_arg(channel) {
+            return Err(MessageError::BadTarget);
+        }
+        let mut wr = io::Cursor::new(Vec::new());
+        try!(Topic::construct(&mut wr, channel, text));
+
+        let buf = wr.into_inner();
+        if parse_helpers::has_forbidden_byte(&buf) {
+            return Err(MessageError::ForbiddenByte);
+        }
+        let message = try!(IrcMsgBuf::new(buf).map_err(MessageError::from));
+        try!(Topic::validate(&message));
+        Ok(TopicBuf { inner: message })
+    }
+
+    /// Ask the server for the topic of `channel`.
+    pub fn query<C>(channel: C) -> Result<TopicBuf, MessageError>
+        where C: AsRef<[u8]>
+    {
+        let channel = channel.as_ref();
+        TopicBuf::_new(channel, None)
+    }
+
+    /// Change the topic of `channel`.  An empty `text` clears the topic.
+    pub fn set<C, T>(channel: C, text: T) -> Result<TopicBuf, MessageError>
+        where C: AsRef<[u8]>,
+              T: AsRef<[u8]>
+    {
+        let channel = channel.as_ref();
+        let text = text.as_ref();
+        TopicBuf::_new(channel, Some(text))
+    }
+
+    /// Like `set`, but `text` is cleaned up by `sanitizer` first.
+    pub fn set_sanitized<C, T>(channel: C, text: T, sanitizer: &Sanitizer) -> Result<TopicBuf, MessageError>
+        where C: AsRef<[u8]>,
+              T: AsRef<[u8]>
+    {
+        TopicBuf::set(channel, sanitizer.sanitize(text.as_ref()))
+    }
+}
+
+impl_irc_msg_subtype!(User);
+impl_irc_msg_subtype_buf!(UserBuf, User);
+
+impl User {
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        let args = try!(validate_command(msg, "USER", 4, 4));
+        if !parse_helpers::is_valid_user(args[0]) || args[0].contains(&b'@') {
+            return Err(MessageError::BadArgument);
+        }
+        Ok(())
+    }
+
+    pub fn get_username(&self) -> &[u8] {
+        let (_command, rest) = parse_helpers::split_command(self.as_bytes());
+        let (username, _rest) = parse_helpers::split_arg(rest);
+        username
+    }
+
+    pub fn get_realname(&self) -> &[u8] {
+        self.args().nth(3).unwrap()
+    }
+}
+
+impl UserBuf {
+    /// `mode` is a bitmask as in RFC 2812: 8 asks for `+i` and 4 for
+    /// `+w`.  Most servers ignore it.
+    pub fn new<U, R>(username: U, mode: u8, realname: R) -> Result<UserBuf, MessageError>
+        where U: AsRef<[u8]>,
+              R: AsRef<[u8]>
+    {
+        let username = username.as_ref();
+        let realname = realname.as_ref();
+        if !is_middle_arg(username) {
+            return Err(MessageError::BadArgument);
+        }
+        if parse_helpers::has_forbidden_byte(realname) {
+            return Err(MessageError::ForbiddenByte);
+        }
+        let message = try!(IrcMsgBuilder::new()
+            .command("USER")
+            .arg(username)
+            .arg(mode.to_string())
+            .arg("*")
+            .trailing(realname)
+            .build()
+            .map_err(MessageError::from));
+        try!(User::validate(&message));
+        Ok(UserBuf { inner: message })
+    }
+}
+
+
+/// The most nicks a single USERHOST may ask about (RFC 2812).
+pub const USERHOST_MAX_NICKS: usize = 5;
+
+impl_irc_msg_subtype!(Userhost);
+impl_irc_msg_subtype_buf!(UserhostBuf, Userhost);
+
+impl Userhost {
+    fn construct<W, N>(sink: &mut W, nicks: &[N]) -> Result<(), MessageError>
+        where W: Write,
+              N: AsRef<[u8]>
+    {
+        if nicks.is_empty() {
+            return Err(MessageError::MissingArgument);
+        }
+        if USERHOST_MAX_NICKS < nicks.len() {
+            return Err(MessageError::TooManyArguments);
+        }
+        try!(sink.write_all(b"USERHOST").or_else(cursor_chk_error));
+        for nick in nicks.iter() {
+            let nick = nick.as_ref();
+            try!(check_middle_arg(nick, MessageError::BadTarget));
+            try!(sink.write_all(b" ").or_else(cursor_chk_error));
+            try!(sink.write_all(nick).or_else(cursor_chk_error));
+        }
+        Ok(())
+    }
+
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        try!(validate_command(msg, "USERHOST", 1, USERHOST_MAX_NICKS));
+        Ok(())
+    }
+
+    /// Create a new `Userhost` in `storage`.  This does not allocate any storage.
+    pub fn new<'a, N>(storage: &'a mut [u8], nicks: &[N]) -> Result<&'a Userhost, MessageError>
+        where N: AsRef<[u8]>
+    {
+        Userhost::parse(try!(write_in(storage, |wr| Userhost::construct(wr, nicks))))
+    }
+
+    pub fn get_nicks(&self) -> ArgumentIter {
+        self.args()
+    }
+}
+
+impl UserhostBuf {
+    /// Ask for the `user@host` of up to `USERHOST_MAX_NICKS` nicks.
+    pub fn new<N>(nicks: &[N]) -> Result<UserhostBuf, MessageError>
+        where N: AsRef<[u8]>
+    {
+        let message = try!(write_buf(|wr| Userhost::construct(wr, nicks)));
+        try!(Userhost::validate(&message));
+        Ok(UserhostBuf { inner: message })
+    }
+}
+
+
+impl_irc_msg_subtype!(Who);
+impl_irc_msg_subtype_buf!(WhoBuf, Who);
+
+impl Who {
+    fn construct<W>(sink: &mut W, mask: &[u8], opers_only: bool) -> Result<(), MessageError>
+        where W: Write
+    {
+        try!(check_middle_arg(mask, MessageError::BadTarget));
+        try!(sink.write_all(b"WHO ").or_else(cursor_chk_error));
+        try!(sink.write_all(mask).or_else(cursor_chk_error));
+        if opers_only {
+            try!(sink.write_all(b" o").or_else(cursor_chk_error));
+        }
+        Ok(())
+    }
+
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        try!(validate_command(msg, "WHO", 1, 2));
+        Ok(())
+    }
+
+    /// Create a new `Who` in `storage`.  This does not allocate any storage.
+    pub fn new<'a, M>(storage: &'a mut [u8], mask: M, opers_only: bool) -> Result<&'a Who, MessageError>
+        where M: AsRef<[u8]>
+    {
+        let mask = mask.as_ref();
+        Who::parse(try!(write_in(storage, |wr| Who::construct(wr, mask, opers_only))))
+    }
+
+    /// A channel, or a mask matched against users' details.
+    pub fn get_mask(&self) -> &[u8] {
+        self.args().next().unwrap()
+    }
+
+    pub fn is_opers_only(&self) -> bool {
+        self.args().nth(1) == Some(b"o")
+    }
+}
+
+impl WhoBuf {
+    /// List the users matching `mask`, only the IRC operators among
+    /// them if `opers_only`.
+    pub fn new<M>(mask: M, opers_only: bool) -> Result<WhoBuf, MessageError>
+        where M: AsRef<[u8]>
+    {
+        let mask = mask.as_ref();
+        let message = try!(write_buf(|wr| Who::construct(wr, mask, opers_only)));
+        try!(Who::validate(&message));
+        Ok(WhoBuf { inner: message })
+    }
+}
+
+
+impl_irc_msg_subtype!(Whois);
+impl_irc_msg_subtype_buf!(WhoisBuf, Whois);
+
+impl Whois {
+    fn construct<W>(sink: &mut W, target: Option<&[u8]>, nick: &[u8]) -> Result<(), MessageError>
+        where W: Write
+    {
+        try!(check_middle_arg(nick, MessageError::BadTarget));
+        try!(sink.write_all(b"WHOIS ").or_else(cursor_chk_error));
+        if let Some(target) = target {
+            try!(check_middle_arg(target, MessageError::BadTarget));
+            try!(sink.write_all(target).or_else(cursor_chk_error));
+            try!(sink.write_all(b" ").or_else(cursor_chk_error));
+        }
+        try!(sink.write_all(nick).or_else(cursor_chk_error));
+        Ok(())
+    }
+
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        try!(validate_command(msg, "WHOIS", 1, 2));
+        Ok(())
+    }
+
+    /// Create a new `Whois` in `storage`.  This does not allocate any storage.
+    pub fn new<'a, N>(storage: &'a mut [u8], nick: N) -> Result<&'a Whois, MessageError>
+        where N: AsRef<[u8]>
+    {
+        let nick = nick.as_ref();
+        Whois::parse(try!(write_in(storage, |wr| Whois::construct(wr, None, nick))))
+    }
+
+    /// The server asked, if not the one we're connected to.
+    pub fn get_target(&self) -> Option<&[u8]> {
+        let mut args = self.args();
+        let first = args.next();
+        args.next().and(first)
+    }
+
+    /// The nick, or mask of nicks, asked about.
+    pub fn get_nick(&self) -> &[u8] {
+        self.args().last().unwrap()
+    }
+}
+
+impl WhoisBuf {
+    pub fn new<N>(nick: N) -> Result<WhoisBuf, MessageError>
+        where N: AsRef<[u8]>
+    {
+        let nick = nick.as_ref();
+        WhoisBuf::_new(None, nick)
+    }
+
+    /// Ask the server `nick` is connected to, which also knows how long
+    /// they've been idle.  Pass `nick` as `target` to mean that server.
+    pub fn new_remote<T, N>(target: T, nick: N) -> Result<WhoisBuf, MessageError>
+        where T: AsRef<[u8]>,
+              N: AsRef<[u8]>
+    {
+        WhoisBuf::_new(Some(target.as_ref()), nick.as_ref())
+    }
+
+    fn _new(target: Option<&[u8]>, nick: &[u8]) -> Result<WhoisBuf, MessageError> {
+        let message = try!(write_buf(|wr| Whois::construct(wr, target, nick)));
+        try!(Whois::validate(&message));
+        Ok(WhoisBuf { inner: message })
+    }
+}
+
+
+impl_irc_msg_subtype!(Whowas);
+impl_irc_msg_subtype_buf!(WhowasBuf, Whowas);
+
+impl Whowas {
+    fn construct<W>(sink: &mut W, nick: &[u8], count: Option<u32>) -> Result<(), MessageError>
+        where W: Write
+    {
+        try!(check_middle_arg(nick, MessageError::BadTarget));
+        try!(sink.write_all(b"WHOWAS ").or_else(cursor_chk_error));
+        try!(sink.write_all(nick).or_else(cursor_chk_error));
+        if let Some(count) = count {
+            try!(write!(sink, " {}", count).or_else(cursor_chk_error));
+        }
+        Ok(())
+    }
+
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        let args = try!(validate_command(msg, "WHOWAS", 1, 3));
+        if let Some(count) = args.get(1) {
+            if !count.iter().all(|b| b'0' <= *b && *b <= b'9') {
+                return Err(MessageError::BadArgument);
+            }
+        }
+        Ok(())
+    }
+
+    /// Create a new `Whowas` in `storage`.  This does not allocate any storage.
+    pub fn new<'a, N>(storage: &'a mut [u8], nick: N, count: Option<u32>) -> Result<&'a Whowas, MessageError>
+        where N: AsRef<[u8]>
+    {
+        let nick = nick.as_ref();
+        Whowas::parse(try!(write_in(storage, |wr| Whowas::construct(wr, nick, count))))
+    }
+
+    pub fn get_nick(&self) -> &[u8] {
+        self.args().next().unwrap()
+    }
+
+    /// How many past entries to return, if limited.
+    pub fn get_count(&self) -> Option<u32> {
+        self.args().nth(1)
+            .and_then(|count| ::std::str::from_utf8(count).ok())
+            .and_then(|count| count.parse().ok())
+    }
+}
+
+impl WhowasBuf {
+    /// Ask about a nick which is no longer in use, returning at most
+    /// `count` entries if given.
+    pub fn new<N>(nick: N, count: Option<u32>) -> Result<WhowasBuf, MessageError>
+        where N: AsRef<[u8]>
+    {
+        let nick = nick.as_ref();
+        let message = try!(write_buf(|wr| Whowas::construct(wr, nick, count)));
+        try!(Whowas::validate(&message));
+        Ok(WhowasBuf { inner: message })
+    }
+}
+
+#[test]
+fn sanitized_constructors() {
+    let sanitizer = Sanitizer::new();
+    assert!(PrivmsgBuf::new(b"#foo", b"line one\r\nline two").is_err());
+
+    let msg = PrivmsgBuf::new_sanitized(b"#foo", b"line one\r\nline two", &sanitizer).unwrap();
+    assert_eq!(msg.as_bytes(), b"PRIVMSG #foo :line one line two");
+
+    let strip = Sanitizer::new().strip_formatting(true);
+    let msg = TopicBuf::set_sanitized(b"#foo", b"\x02hi\x02\n", &strip).unwrap();
+    assert_eq!(msg.as_bytes(), b"TOPIC #foo :hi ");
+    let msg = QuitBuf::new_sanitized(b"bye\x00", &sanitizer).unwrap();
+    assert_eq!(msg.as_bytes(), b"QUIT :bye");
+}
+
+#[test]
+fn topic_query_and_set() {
+    let query = TopicBuf::query(b"#foo").unwrap();
+    assert_eq!(query.as_bytes(), b"TOPIC #foo");
+    assert_eq!(query.get_channel(), b"#foo");
+    assert!(query.is_query());
+
+    let set = TopicBuf::set(b"#foo", b"new topic").unwrap();
+    assert_eq!(set.as_bytes(), b"TOPIC #foo :new topic");
+    assert_eq!(set.get_text(), Some(&b"new topic"[..]));
+
+    let clear = TopicBuf::set(b"#foo", b"").unwrap();
+    assert_eq!(clear.get_text(), Some(&b""[..]));
+    assert!(!clear.is_query());
+
+    assert!(TopicBuf::set(b"#foo", b"bad\r\ntopic").is_err());
+    assert!(TopicBuf::query(b"").is_err());
+    // Would otherwise set the topic of #foo to "bar".
+    assert!(TopicBuf::query(b"#foo bar").is_err());
+    assert!(TopicBuf::set(b":#foo", b"x").is_err());
 }
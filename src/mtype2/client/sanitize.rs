@@ -0,0 +1,136 @@
+//! Cleaning up free text before it is sent.
+//!
+//! The plain constructors refuse text containing CR, LF or NUL.  Text
+//! from users or other networks often has them anyway, so a `Sanitizer`
+//! rewrites it into something sendable instead.  Keep one per connection
+//! and pass it to the `*_sanitized` constructors.
+
+use std::borrow::Cow;
+
+/// IRC formatting codes: bold, colour, hex colour, reset, monospace,
+/// reverse, italic, strikethrough and underline.
+const FORMATTING: &'static [u8] = b"\x02\x03\x04\x0f\x11\x16\x1d\x1e\x1f";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sanitizer {
+    strip_formatting: bool,
+    replacement: Option<u8>,
+}
+
+impl Sanitizer {
+    /// A sanitizer which turns line breaks into spaces, removes other
+    /// control characters and keeps formatting.
+    pub fn new() -> Sanitizer {
+        Sanitizer {
+            strip_formatting: false,
+            replacement: None,
+        }
+    }
+
+    /// Also remove formatting codes, including colour numbers.
+    pub fn strip_formatting(mut self, strip: bool) -> Sanitizer {
+        self.strip_formatting = strip;
+        self
+    }
+
+    /// Replace control characters with `replacement` rather than removing
+    /// them.  `replacement` must not itself be a control character.
+    pub fn replace_controls_with(mut self, replacement: Option<u8>) -> Sanitizer {
+        assert!(replacement.map(|b| !is_control(b)).unwrap_or(true));
+        self.replacement = replacement;
+        self
+    }
+
+    /// Clean `text`, borrowing when nothing needed to change.  A CR-LF
+    /// pair becomes a single space.  The `\x01`s framing a CTCP message,
+    /// such as `\x01ACTION waves\x01`, are kept.
+    pub fn sanitize<'a>(&self, text: &'a [u8]) -> Cow<'a, [u8]> {
+        let is_ctcp = text.first() == Some(&b'\x01');
+        let is_framing = |idx: usize| is_ctcp && (idx == 0 || idx == text.len() - 1);
+        let clean = text.iter().enumerate().all(|(idx, &b)| {
+            !is_control(b) || (!self.strip_formatting && FORMATTING.contains(&b))
+                || (b == b'\x01' && is_framing(idx))
+        });
+        if clean {
+            return Cow::Borrowed(text);
+        }
+
+        let mut out = Vec::with_capacity(text.len());
+        let mut idx = 0;
+        while idx < text.len() {
+            let byte = text[idx];
+            idx += 1;
+            match byte {
+                b'\r' if text.get(idx) == Some(&b'\n') => {
+                    idx += 1;
+                    out.push(b' ');
+                },
+                b'\r' | b'\n' => out.push(b' '),
+                b'\x01' if is_framing(idx - 1) => out.push(b'\x01'),
+                b if FORMATTING.contains(&b) => {
+                    if self.strip_formatting {
+                        idx += colour_len(b, &text[idx..]);
+                    } else {
+                        out.push(b);
+                    }
+                },
+                b if is_control(b) => {
+                    if let Some(replacement) = self.replacement {
+                        out.push(replacement);
+                    }
+                },
+                b => out.push(b),
+            }
+        }
+        Cow::Owned(out)
+    }
+}
+
+fn is_control(byte: u8) -> bool {
+    byte < 0x20 || byte == 0x7f
+}
+
+/// The length of the colour specification following a colour code, as in
+/// `\x0304,12` or `\x04FF0000`.
+fn colour_len(code: u8, rest: &[u8]) -> usize {
+    let (max_len, is_digit): (usize, fn(&u8) -> bool) = match code {
+        b'\x03' => (2, |b| b.is_ascii_digit()),
+        b'\x04' => (6, |b| b.is_ascii_hexdigit()),
+        _ => return 0,
+    };
+    let field = |rest: &[u8]| rest.iter().take(max_len).take_while(|b| is_digit(b)).count();
+
+    let fg = field(rest);
+    if fg == 0 {
+        return 0;
+    }
+    if rest.get(fg) == Some(&b',') {
+        let bg = field(&rest[fg+1..]);
+        if bg > 0 {
+            return fg + 1 + bg;
+        }
+    }
+    fg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Sanitizer;
+
+    #[test]
+    fn sanitize_text() {
+        let keep = Sanitizer::new();
+        assert_eq!(&keep.sanitize(b"plain \x02bold\x02")[..], b"plain \x02bold\x02");
+        assert_eq!(&keep.sanitize(b"one\r\ntwo\nthree\rfour")[..], b"one two three four");
+        assert_eq!(&keep.sanitize(b"a\x00b\x01c\x07d")[..], b"abcd");
+        assert_eq!(&keep.sanitize(b"\x01ACTION waves\x01")[..], b"\x01ACTION waves\x01");
+        assert_eq!(&keep.sanitize(b"\x01ACTION wa\x01ves\r\n\x01")[..], b"\x01ACTION waves \x01");
+
+        let replace = Sanitizer::new().replace_controls_with(Some(b'?'));
+        assert_eq!(&replace.sanitize(b"a\x00b\tc")[..], b"a?b?c");
+
+        let strip = Sanitizer::new().strip_formatting(true);
+        assert_eq!(&strip.sanitize(b"\x0304,12red\x03 \x0399 \x03,x \x04FF0000hex\x0f\x1f!")[..],
+            b"red  ,x hex!");
+    }
+}
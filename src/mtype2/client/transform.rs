@@ -0,0 +1,202 @@
+//! Per-target rewriting of PRIVMSG bodies.
+//!
+//! Encryption layers (OTR, blowfish and the like) need to see every body
+//! sent to or received from a particular channel or user.  Register a
+//! `BodyTransform` for that target in a `BodyTransforms`, then build
+//! outgoing PRIVMSGs with `privmsg` and read incoming bodies with
+//! `inbound_body`; everything else about the messages stays the same.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use irccase::IrcAsciiExt;
+use ::{server, MessageError};
+
+use super::PrivmsgBuf;
+
+/// Whether `target` is a channel, possibly with a STATUSMSG prefix such
+/// as `@#chan`.  No nick starts with any of these.
+fn is_channel_target(target: &[u8]) -> bool {
+    match target.first() {
+        Some(&b'#') | Some(&b'&') | Some(&b'!') | Some(&b'+') | Some(&b'@') | Some(&b'%') | Some(&b'~') => true,
+        _ => false,
+    }
+}
+
+pub trait BodyTransform {
+    /// Rewrite a body we are about to send to `target`.
+    fn outbound(&mut self, target: &[u8], body: &[u8]) -> Result<Vec<u8>, ()>;
+
+    /// Rewrite a body received from `source`, as sent to `target`.
+    fn inbound(&mut self, source: &[u8], target: &[u8], body: &[u8]) -> Result<Vec<u8>, ()>;
+}
+
+/// Why `BodyTransforms::privmsg` couldn't build a message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TransformError {
+    /// The target's transform refused the body
+    Transform,
+    /// The PRIVMSG itself couldn't be built
+    Message(MessageError),
+}
+
+impl From<MessageError> for TransformError {
+    fn from(err: MessageError) -> TransformError {
+        TransformError::Message(err)
+    }
+}
+
+impl fmt::Display for TransformError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TransformError::Transform => write!(f, "the body transform failed"),
+            TransformError::Message(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for TransformError {
+    fn description(&self) -> &str {
+        match *self {
+            TransformError::Transform => "body transform failed",
+            TransformError::Message(ref err) => err.description(),
+        }
+    }
+}
+
+/// The transforms in use, keyed by target under RFC1459 case folding.
+pub struct BodyTransforms {
+    by_target: HashMap<Vec<u8>, Box<BodyTransform+Send>>,
+}
+
+impl fmt::Debug for BodyTransforms {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let targets: Vec<_> = self.by_target.keys()
+            .map(|key| String::from_utf8_lossy(key))
+            .collect();
+        f.debug_struct("BodyTransforms").field("targets", &targets).finish()
+    }
+}
+
+impl BodyTransforms {
+    pub fn new() -> BodyTransforms {
+        BodyTransforms { by_target: HashMap::new() }
+    }
+
+    /// Install `transform` for `target`, returning the one it replaces.
+    pub fn register<T>(&mut self, target: T, transform: Box<BodyTransform+Send>) -> Option<Box<BodyTransform+Send>>
+        where T: AsRef<[u8]>
+    {
+        self.by_target.insert(target.as_ref().to_irc_lower(), transform)
+    }
+
+    pub fn unregister<T>(&mut self, target: T) -> Option<Box<BodyTransform+Send>>
+        where T: AsRef<[u8]>
+    {
+        self.by_target.remove(&target.as_ref().to_irc_lower())
+    }
+
+    pub fn is_registered<T>(&self, target: T) -> bool
+        where T: AsRef<[u8]>
+    {
+        self.by_target.contains_key(&target.as_ref().to_irc_lower())
+    }
+
+    /// Build a PRIVMSG to `target`, passing `body` through its transform
+    /// if one is registered.
+    pub fn privmsg<T, B>(&mut self, target: T, body: B) -> Result<PrivmsgBuf, TransformError>
+        where T: AsRef<[u8]>,
+              B: AsRef<[u8]>
+    {
+        let target = target.as_ref();
+        let body = body.as_ref();
+        match self.by_target.get_mut(&target.to_irc_lower()) {
+            Some(transform) => {
+                let body = try!(transform.outbound(target, body)
+                    .map_err(|()| TransformError::Transform));
+                Ok(try!(PrivmsgBuf::new(target, body)))
+            },
+            None => Ok(try!(PrivmsgBuf::new(target, body))),
+        }
+    }
+
+    /// The body of an incoming PRIVMSG, passed through the transform for
+    /// its target.  Private messages arrive addressed to us, so when a
+    /// non-channel target has no transform the sender's nick is tried as
+    /// well.
+    pub fn inbound_body<'a>(&mut self, msg: &'a server::Privmsg) -> Result<Cow<'a, [u8]>, ()> {
+        let source = msg.get_source();
+        let target = msg.get_target();
+        let body = msg.get_body_raw();
+
+        let nick = msg.source_nick().as_bytes();
+        let keys: &[&[u8]] = if is_channel_target(target) { &[target] } else { &[target, nick] };
+        let key = keys.iter()
+            .map(|key| key.to_irc_lower())
+            .find(|key| self.by_target.contains_key(key));
+        match key.and_then(|key| self.by_target.get_mut(&key)) {
+            Some(transform) => Ok(Cow::Owned(try!(transform.inbound(source, target, body)))),
+            None => Ok(Cow::Borrowed(body)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::{IrcMsg, MessageError, server};
+    use super::{BodyTransform, BodyTransforms, TransformError};
+
+    struct Rot13;
+
+    fn rot13(body: &[u8]) -> Vec<u8> {
+        body.iter().map(|&b| match b {
+            b'a'...b'z' => (b - b'a' + 13) % 26 + b'a',
+            b'A'...b'Z' => (b - b'A' + 13) % 26 + b'A',
+            b => b,
+        }).collect()
+    }
+
+    impl BodyTransform for Rot13 {
+        fn outbound(&mut self, _target: &[u8], body: &[u8]) -> Result<Vec<u8>, ()> {
+            Ok(rot13(body))
+        }
+
+        fn inbound(&mut self, _source: &[u8], _target: &[u8], body: &[u8]) -> Result<Vec<u8>, ()> {
+            Ok(rot13(body))
+        }
+    }
+
+    fn privmsg(line: &[u8]) -> &server::Privmsg {
+        IrcMsg::new(line).unwrap().as_tymsg::<&server::Privmsg>().unwrap()
+    }
+
+    #[test]
+    fn transforms_by_target() {
+        let mut transforms = BodyTransforms::new();
+        assert!(transforms.register("#Secret", Box::new(Rot13)).is_none());
+        assert!(transforms.register("alice", Box::new(Rot13)).is_none());
+
+        let out = transforms.privmsg("#secret", "Hello").unwrap();
+        assert_eq!(out.as_bytes(), b"PRIVMSG #secret :Uryyb");
+        let out = transforms.privmsg("#public", "Hello").unwrap();
+        assert_eq!(out.as_bytes(), b"PRIVMSG #public :Hello");
+
+        let body = transforms.inbound_body(privmsg(b":bob!b@h PRIVMSG #SECRET :Uryyb")).unwrap();
+        assert_eq!(&body[..], b"Hello");
+        let body = transforms.inbound_body(privmsg(b":Alice!a@h PRIVMSG me :Uv")).unwrap();
+        assert_eq!(&body[..], b"Hi");
+        let body = transforms.inbound_body(privmsg(b":bob!b@h PRIVMSG me :Uv")).unwrap();
+        assert_eq!(&body[..], b"Uv");
+        // alice's transform is only for private messages.
+        let body = transforms.inbound_body(privmsg(b":alice!a@h PRIVMSG #public :Uv")).unwrap();
+        assert_eq!(&body[..], b"Uv");
+
+        assert_eq!(transforms.privmsg("#secret", "a\r\nb").err(),
+            Some(TransformError::Message(MessageError::ForbiddenByte)));
+
+        assert!(transforms.unregister("#secret").is_some());
+        assert!(!transforms.is_registered("#SECRET"));
+    }
+}
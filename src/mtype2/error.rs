@@ -0,0 +1,65 @@
+use std::error::Error;
+use std::fmt;
+
+use ::ParseError;
+
+/// Why a typed message couldn't be built or viewed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MessageError {
+    /// The bytes aren't an IRC message at all
+    Parse(ParseError),
+    /// The prefix is missing, malformed, or present where it mustn't be
+    BadPrefix,
+    /// The message has a different command
+    WrongCommand,
+    /// A channel or nick is empty or can't be sent as a single argument
+    BadTarget,
+    /// A required argument is missing
+    MissingArgument,
+    /// There are more arguments than the message takes
+    TooManyArguments,
+    /// An argument is present but malformed
+    BadArgument,
+    /// A CR, LF or NUL would end up inside the message
+    ForbiddenByte,
+    /// The message didn't fit in the buffer it was written to
+    BufferTooSmall,
+}
+
+impl From<ParseError> for MessageError {
+    fn from(err: ParseError) -> MessageError {
+        MessageError::Parse(err)
+    }
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MessageError::Parse(ref err) => write!(f, "{}", err),
+            MessageError::BadPrefix => write!(f, "bad or unexpected prefix"),
+            MessageError::WrongCommand => write!(f, "message has a different command"),
+            MessageError::BadTarget => write!(f, "target is empty or not a single argument"),
+            MessageError::MissingArgument => write!(f, "missing argument"),
+            MessageError::TooManyArguments => write!(f, "too many arguments"),
+            MessageError::BadArgument => write!(f, "malformed argument"),
+            MessageError::ForbiddenByte => write!(f, "message would contain CR, LF or NUL"),
+            MessageError::BufferTooSmall => write!(f, "message doesn't fit in the buffer"),
+        }
+    }
+}
+
+impl Error for MessageError {
+    fn description(&self) -> &str {
+        match *self {
+            MessageError::Parse(_) => "parse error",
+            MessageError::BadPrefix => "bad prefix",
+            MessageError::WrongCommand => "wrong command",
+            MessageError::BadTarget => "bad target",
+            MessageError::MissingArgument => "missing argument",
+            MessageError::TooManyArguments => "too many arguments",
+            MessageError::BadArgument => "bad argument",
+            MessageError::ForbiddenByte => "forbidden byte in message",
+            MessageError::BufferTooSmall => "buffer too small",
+        }
+    }
+}
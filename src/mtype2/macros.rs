@@ -16,9 +16,9 @@ macro_rules! impl_irc_msg_subtype {
                 &self.inner
             }
 
-            pub fn parse(buffer: &[u8]) -> Result<&$id, ()> {
+            pub fn parse(buffer: &[u8]) -> Result<&$id, ::MessageError> {
                 // maybe we could skip this check later and turn it into a debug-assert?
-                let message = try!(IrcMsg::new(buffer).map_err(|_| ()));
+                let message = try!(IrcMsg::new(buffer).map_err(::MessageError::from));
                 try!($id::validate(message));
 
                 Ok(unsafe { $id::from_u8_slice_unchecked(buffer) })
@@ -34,9 +34,9 @@ macro_rules! impl_irc_msg_subtype {
         }
 
         impl<'a> FromIrcMsg for &'a $id {
-            type Err = ();
+            type Err = ::MessageError;
 
-            fn from_irc_msg(msg: &IrcMsg) -> Result<&'a $id, ()> {
+            fn from_irc_msg(msg: &IrcMsg) -> Result<&'a $id, ::MessageError> {
                 try!($id::validate(msg));
                 Ok(unsafe {::std::mem::transmute(msg) })
             }
@@ -98,6 +98,13 @@ macro_rules! irc_msg_has_source {
                 let (prefix, _rest) = parse_helpers::split_prefix(buf);
                 prefix
             }
+
+            /// The nick that sent this message, compared under `CM`.
+            pub fn get_source_nickname<CM>(&self) -> Result<::identifier::Nickname<CM>, ::identifier::NicknameError>
+                where CM: ::CaseMapping
+            {
+                ::identifier::Nickname::from_prefix(self.get_source())
+            }
         }
     }
 }
@@ -112,22 +119,23 @@ macro_rules! irc_msg_has_target {
                 let (target, _rest) = parse_helpers::split_arg(rest);
                 target
             }
+
+            /// The target as a channel, compared under `CM`.  Only
+            /// meaningful for messages sent to a channel.
+            pub fn get_target_channel<CM>(&self) -> Result<::identifier::Channel<CM>, ::identifier::ChannelError>
+                where CM: ::CaseMapping
+            {
+                ::identifier::Channel::from_bytes(self.get_target())
+            }
         }
     }
 }
 
-macro_rules! irc_msg_legacy_validator {
-    ($on:ident, $from:ident) => {
+macro_rules! irc_msg_validator {
+    ($on:ident, $command:expr, $prefix:expr, $min_args:expr, $max_args:expr) => {
         impl $on {
-            fn validate(msg: &IrcMsg) -> Result<(), ()> {
-                use ::legacy::message_types::server;
-
-                let legacy = try!(IrcMsgLegacy::new(msg.as_bytes().to_vec()).map_err(|_| ()));
-                match server::IncomingMsg::from_msg(legacy.clone()) {
-                    server::IncomingMsg::$from(ref _msg) => (),
-                    _ => return Err(()),
-                };
-                Ok(())
+            pub fn validate(msg: &IrcMsg) -> Result<(), ::MessageError> {
+                validate_command(msg, $command, $prefix, $min_args, $max_args)
             }
         }
     }
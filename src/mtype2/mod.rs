@@ -8,10 +8,13 @@ use ::IrcMsg;
 
 #[macro_use]
 mod macros;
+mod error;
 
 pub mod server;
 pub mod client;
 
+pub use self::error::MessageError;
+
 pub trait FromIrcMsg: Sized {
     type Err;
 
@@ -20,9 +23,9 @@ pub trait FromIrcMsg: Sized {
     fn from_irc_msg(msg: &IrcMsg) -> Result<Self, Self::Err>;
 }
 
-fn cursor_chk_error(err: io::Error) -> Result<(), ()> {
+fn cursor_chk_error(err: io::Error) -> Result<(), MessageError> {
     match err {
-        ref err if err.kind() == io::ErrorKind::WriteZero => Err(()),
+        ref err if err.kind() == io::ErrorKind::WriteZero => Err(MessageError::BufferTooSmall),
         _ => panic!(),
     }
 }
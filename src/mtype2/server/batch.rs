@@ -0,0 +1,145 @@
+//! IRCv3 batches.
+//!
+//! `BATCH +<ref> <type> [<params>...]` opens a batch and `BATCH -<ref>`
+//! closes it.  Messages in between carry a `batch=<ref>` tag.
+
+use std::borrow::{Borrow, ToOwned};
+use std::{fmt, mem, ops};
+
+use ::{IrcMsg, IrcMsgBuf};
+use super::super::{FromIrcMsg, MessageError};
+
+/// What a batch groups together.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum BatchType {
+    /// QUITs caused by a netsplit; params are the two servers
+    Netsplit,
+    /// JOINs of users returning from a netsplit; params are the two
+    /// servers
+    Netjoin,
+    /// Replayed history; the param is the target
+    Chathistory,
+    /// The response to a command sent with a `label` tag
+    LabeledResponse,
+    /// Any other type, usually vendor-prefixed such as
+    /// `example.com/foo`
+    Vendor(String),
+}
+
+impl BatchType {
+    pub fn from_bytes(batch_type: &[u8]) -> BatchType {
+        match batch_type {
+            b"netsplit" => BatchType::Netsplit,
+            b"netjoin" => BatchType::Netjoin,
+            b"chathistory" => BatchType::Chathistory,
+            b"labeled-response" => BatchType::LabeledResponse,
+            other => BatchType::Vendor(String::from_utf8_lossy(other).into_owned()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match *self {
+            BatchType::Netsplit => "netsplit",
+            BatchType::Netjoin => "netjoin",
+            BatchType::Chathistory => "chathistory",
+            BatchType::LabeledResponse => "labeled-response",
+            BatchType::Vendor(ref batch_type) => batch_type,
+        }
+    }
+}
+
+impl fmt::Display for BatchType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+
+impl_irc_msg_subtype!(Batch);
+impl_irc_msg_subtype_buf!(BatchBuf, Batch);
+
+impl Batch {
+    pub fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        if msg.get_command() != "BATCH" {
+            return Err(MessageError::WrongCommand);
+        }
+        let mut args = msg.args();
+        let reference = try!(args.next().ok_or(MessageError::MissingArgument));
+        if reference.len() < 2 {
+            return Err(MessageError::BadArgument);
+        }
+        match reference[0] {
+            b'+' => {
+                // An opened batch must say what it is.
+                try!(args.next().ok_or(MessageError::MissingArgument));
+                Ok(())
+            },
+            b'-' => Ok(()),
+            _ => Err(MessageError::BadArgument),
+        }
+    }
+
+    fn get_signed_reference(&self) -> &[u8] {
+        self.args().next().unwrap()
+    }
+
+    /// The reference tag, without its `+` or `-`.
+    pub fn get_reference(&self) -> &[u8] {
+        &self.get_signed_reference()[1..]
+    }
+
+    pub fn is_start(&self) -> bool {
+        self.get_signed_reference()[0] == b'+'
+    }
+
+    pub fn is_end(&self) -> bool {
+        !self.is_start()
+    }
+
+    /// The type of an opening BATCH, or `None` for a closing one.
+    pub fn get_batch_type(&self) -> Option<BatchType> {
+        if !self.is_start() {
+            return None;
+        }
+        self.args().nth(1).map(BatchType::from_bytes)
+    }
+
+    /// The type-specific parameters of an opening BATCH.
+    pub fn get_params(&self) -> Vec<&[u8]> {
+        if !self.is_start() {
+            return Vec::new();
+        }
+        self.args().skip(2).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::IrcMsg;
+    use super::{Batch, BatchType};
+
+    #[test]
+    fn batch_types() {
+        let msg = IrcMsg::new(b":irc.host BATCH +yXNAbvnRHTRBv netsplit irc.hub other.host").unwrap();
+        let batch = msg.as_tymsg::<&Batch>().unwrap();
+        assert!(batch.is_start());
+        assert_eq!(batch.get_reference(), b"yXNAbvnRHTRBv");
+        assert_eq!(batch.get_batch_type(), Some(BatchType::Netsplit));
+        assert_eq!(batch.get_params(), vec![&b"irc.hub"[..], b"other.host"]);
+
+        let msg = IrcMsg::new(b":irc.host BATCH +1 example.com/foo").unwrap();
+        let batch_type = msg.as_tymsg::<&Batch>().unwrap().get_batch_type().unwrap();
+        assert_eq!(batch_type, BatchType::Vendor("example.com/foo".to_string()));
+        assert_eq!(batch_type.to_string(), "example.com/foo");
+
+        let msg = IrcMsg::new(b":irc.host BATCH -yXNAbvnRHTRBv").unwrap();
+        let batch = msg.as_tymsg::<&Batch>().unwrap();
+        assert!(batch.is_end());
+        assert_eq!(batch.get_reference(), b"yXNAbvnRHTRBv");
+        assert_eq!(batch.get_batch_type(), None);
+
+        assert!(IrcMsg::new(b":irc.host BATCH +1").unwrap().as_tymsg::<&Batch>().is_err());
+        assert!(IrcMsg::new(b":irc.host BATCH 1 netsplit").unwrap().as_tymsg::<&Batch>().is_err());
+    }
+}
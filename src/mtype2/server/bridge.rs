@@ -0,0 +1,193 @@
+//! Speaking as remote users on a bridging server.
+//!
+//! Relays which inject messages through a server link need a plausible
+//! `nick!user@host` for each remote user.  Display names from other
+//! networks can contain anything, so `BridgePrefixer` folds them down to
+//! a valid nick: accented Latin letters are transliterated, everything
+//! else invalid is dropped, and the result is clamped to the nick length.
+
+use super::{PrivmsgBuf, MessageError};
+use ::parse_helpers;
+
+/// RFC 2812 leaves USERLEN unspecified; this is the common default.
+const USER_LEN: usize = 10;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BridgePrefixer {
+    host: String,
+    nick_len: usize,
+    suffix: String,
+}
+
+impl BridgePrefixer {
+    /// Prefixes under `host`, with nicks of up to nine characters as in
+    /// RFC 2812.  Returns `Err` if `host` cannot appear in a prefix.
+    pub fn new(host: &str) -> Result<BridgePrefixer, ()> {
+        if host.is_empty() || !parse_helpers::is_valid_prefix(host.as_bytes()) {
+            return Err(());
+        }
+        Ok(BridgePrefixer {
+            host: host.to_string(),
+            nick_len: 9,
+            suffix: String::new(),
+        })
+    }
+
+    /// Use the server's NICKLEN rather than nine.
+    pub fn nick_len(mut self, nick_len: usize) -> BridgePrefixer {
+        self.nick_len = nick_len;
+        self
+    }
+
+    /// Append `suffix`, such as `[d]`, to every nick so bridged users
+    /// stand out.  Invalid characters in it are dropped like any other.
+    pub fn suffix(mut self, suffix: &str) -> BridgePrefixer {
+        self.suffix = fold(suffix).into_iter().filter(|&b| is_nick_byte(b)).map(|b| b as char).collect();
+        self
+    }
+
+    /// A valid nick for `display_name`, suffix included.
+    pub fn nick(&self, display_name: &str) -> String {
+        let budget = self.nick_len.saturating_sub(self.suffix.len());
+        let mut nick = base_nick(display_name);
+        nick.truncate(budget);
+        nick.push_str(&self.suffix);
+        if nick.is_empty() {
+            nick.push('_');
+        }
+        nick.truncate(self.nick_len.max(1));
+        nick
+    }
+
+    /// The full `nick!user@host` for `display_name`.
+    pub fn prefix(&self, display_name: &str) -> String {
+        let mut user: String = base_nick(display_name).chars()
+            .filter(|ch| ch.is_ascii_alphanumeric())
+            .map(|ch| ch.to_ascii_lowercase())
+            .take(USER_LEN)
+            .collect();
+        if user.is_empty() {
+            user.push_str("bridge");
+        }
+        format!("{}!{}@{}", self.nick(display_name), user, self.host)
+    }
+
+    /// A PRIVMSG to `target` appearing to come from `display_name`.
+    pub fn privmsg<T, B>(&self, display_name: &str, target: T, body: B) -> Result<PrivmsgBuf, MessageError>
+        where T: AsRef<[u8]>,
+              B: AsRef<[u8]>
+    {
+        PrivmsgBuf::new(self.prefix(display_name), target, body)
+    }
+}
+
+/// `display_name` as a nick, before clamping.  Always ASCII.
+fn base_nick(display_name: &str) -> String {
+    let mut nick: Vec<u8> = fold(display_name).into_iter()
+        .filter(|&b| is_nick_byte(b))
+        .collect();
+    if nick.first().map(|&b| !is_nick_first_byte(b)).unwrap_or(false) {
+        nick.insert(0, b'_');
+    }
+    if nick.is_empty() {
+        nick.extend(b"user".iter());
+    }
+    String::from_utf8(nick).unwrap()
+}
+
+// nickname   =  ( letter / special ) *8( letter / digit / special / "-" )
+fn is_nick_first_byte(byte: u8) -> bool {
+    match byte {
+        b'a'...b'z' | b'A'...b'Z' => true,
+        b'[' | b']' | b'\\' | b'`' | b'_' | b'^' | b'{' | b'|' | b'}' => true,
+        _ => false,
+    }
+}
+
+fn is_nick_byte(byte: u8) -> bool {
+    match byte {
+        b'0'...b'9' | b'-' => true,
+        _ => is_nick_first_byte(byte),
+    }
+}
+
+/// Replace accented Latin letters with their plain ASCII spelling and
+/// whitespace with underscores.  Other non-ASCII characters are dropped.
+fn fold(text: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(text.len());
+    for ch in text.chars() {
+        if ch.is_ascii() {
+            out.push(if ch == ' ' { b'_' } else { ch as u8 });
+            continue;
+        }
+        if ch.is_whitespace() {
+            out.push(b'_');
+            continue;
+        }
+        let folded: &str = match ch {
+            'À'...'Å' => "A",
+            'à'...'å' | 'ā' | 'ă' | 'ą' => "a",
+            'Æ' => "AE",
+            'æ' => "ae",
+            'Ç' | 'Ć' | 'Č' => "C",
+            'ç' | 'ć' | 'č' => "c",
+            'Ð' | 'Ď' | 'Đ' => "D",
+            'ð' | 'ď' | 'đ' => "d",
+            'È'...'Ë' | 'Ē' | 'Ę' | 'Ě' => "E",
+            'è'...'ë' | 'ē' | 'ę' | 'ě' => "e",
+            'Ğ' => "G",
+            'ğ' => "g",
+            'Ì'...'Ï' | 'İ' => "I",
+            'ì'...'ï' | 'ı' => "i",
+            'Ł' => "L",
+            'ł' => "l",
+            'Ñ' | 'Ń' | 'Ň' => "N",
+            'ñ' | 'ń' | 'ň' => "n",
+            'Ò'...'Ö' | 'Ø' | 'Ő' => "O",
+            'ò'...'ö' | 'ø' | 'ő' => "o",
+            'Œ' => "OE",
+            'œ' => "oe",
+            'Ř' => "R",
+            'ř' => "r",
+            'Ś' | 'Š' | 'Ş' => "S",
+            'ś' | 'š' | 'ş' => "s",
+            'ß' => "ss",
+            'Ť' => "T",
+            'ť' => "t",
+            'Þ' => "TH",
+            'þ' => "th",
+            'Ù'...'Ü' | 'Ů' | 'Ű' => "U",
+            'ù'...'ü' | 'ů' | 'ű' => "u",
+            'Ý' | 'Ÿ' => "Y",
+            'ý' | 'ÿ' => "y",
+            'Ź' | 'Ż' | 'Ž' => "Z",
+            'ź' | 'ż' | 'ž' => "z",
+            _ => "",
+        };
+        out.extend(folded.bytes());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BridgePrefixer;
+
+    #[test]
+    fn bridge_prefixes() {
+        let prefixer = BridgePrefixer::new("bridge.example.com").unwrap().nick_len(16);
+        assert_eq!(prefixer.nick("Zoë Ångström"), "Zoe_Angstrom");
+        assert_eq!(prefixer.nick("42 is the answer!"), "_42_is_the_answe");
+        assert_eq!(prefixer.nick("日本語"), "user");
+        assert_eq!(prefixer.prefix("Jürgen"), "Jurgen!jurgen@bridge.example.com");
+
+        let prefixer = prefixer.nick_len(9).suffix("[d]");
+        assert_eq!(prefixer.nick("Stephanie"), "Stepha[d]");
+        assert_eq!(prefixer.prefix("[]"), "[][d]!bridge@bridge.example.com");
+
+        let msg = prefixer.privmsg("Ørjan", "#relay", "hei").unwrap();
+        assert_eq!(msg.as_bytes(), b":Orjan[d]!orjan@bridge.example.com PRIVMSG #relay :hei");
+
+        assert!(BridgePrefixer::new("bad host").is_err());
+    }
+}
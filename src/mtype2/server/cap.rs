@@ -1,19 +1,64 @@
-use std::borrow::{Borrow, BorrowMut, ToOwned};
-use std::borrow::Cow;
+use std::borrow::{Borrow, ToOwned};
 use std::{mem, ops};
 use std::io::{self, Write};
+use std::iter::Peekable;
 
-use super::super::FromIrcMsg;
+use super::super::{FromIrcMsg, MessageError};
 use super::super::cursor_chk_error;
 
-use ::legacy::IrcMsg as IrcMsgLegacy;
 use ::{IrcMsg, IrcMsgBuf};
 use ::parse_helpers;
 
-use ::cap::Capabilities;
+use ::cap::{Capabilities, CapabilityChangeIter, is_valid_list_entry};
 
 // The subcommands for CAP are: LS, LIST, REQ, ACK, NAK, and END.
 
+/// Split a server's CAP reply, `:server CAP <target> <SUB> [*] :<list>`,
+/// into the target, whether a `*` marks more lines to come, and the list.
+fn split_reply<'a>(msg: &'a IrcMsg, subcommand: &str) -> Result<(&'a [u8], bool, &'a str), MessageError> {
+    use std::ascii::AsciiExt;
+
+    if !AsciiExt::eq_ignore_ascii_case(msg.get_command(), "CAP") {
+        return Err(MessageError::WrongCommand);
+    }
+    let args: Vec<&[u8]> = msg.args().collect();
+    let (target, sub, more, list) = match args.len() {
+        3 => (args[0], args[1], false, args[2]),
+        4 if args[2] == b"*" => (args[0], args[1], true, args[3]),
+        _ => return Err(MessageError::BadArgument),
+    };
+    if !AsciiExt::eq_ignore_ascii_case(sub, subcommand.as_bytes()) {
+        return Err(MessageError::WrongCommand);
+    }
+    let list = try!(::std::str::from_utf8(list).map_err(|_| MessageError::BadArgument));
+    Ok((target, more, list))
+}
+
+fn construct_reply<W>(sink: &mut W, source: &[u8], target: &[u8], subcommand: &[u8], caps: &[&str]) -> Result<(), MessageError>
+    where W: Write
+{
+    if !parse_helpers::is_middle_arg(target) {
+        return Err(MessageError::BadTarget);
+    }
+    try!(sink.write_all(b":").or_else(cursor_chk_error));
+    try!(sink.write_all(source).or_else(cursor_chk_error));
+    try!(sink.write_all(b" CAP ").or_else(cursor_chk_error));
+    try!(sink.write_all(target).or_else(cursor_chk_error));
+    try!(sink.write_all(b" ").or_else(cursor_chk_error));
+    try!(sink.write_all(subcommand).or_else(cursor_chk_error));
+    try!(sink.write_all(b" :").or_else(cursor_chk_error));
+    for (idx, cap) in caps.iter().enumerate() {
+        if !is_valid_list_entry(cap) {
+            return Err(MessageError::BadArgument);
+        }
+        if idx > 0 {
+            try!(sink.write_all(b" ").or_else(cursor_chk_error));
+        }
+        try!(sink.write_all(cap.as_bytes()).or_else(cursor_chk_error));
+    }
+    Ok(())
+}
+
 impl_irc_msg_subtype!(CapLs);
 impl_irc_msg_subtype_buf!(CapLsBuf, CapLs);
 
@@ -23,11 +68,11 @@ impl_irc_msg_subtype_buf!(CapLsBuf, CapLs);
 // Server: CAP * LS :userhost-in-names sasl=EXTERNAL,DH-AES,DH-BLOWFISH,ECDSA-NIST256P-CHALLENGE,PLAIN
 
 impl CapLs {
-    fn validate(_msg: &IrcMsg) -> Result<(), ()> {
-        unimplemented!();
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        split_reply(msg, "LS").map(|_| ())
     }
 
-    fn construct<'a, W, I>(sink: &mut W, source: &[u8], caps: &mut I) -> Result<(), ()>
+    fn construct<'a, W, I>(sink: &mut W, source: &[u8], caps: I) -> Result<(), MessageError>
         where
             W: Write,
             I: Iterator<Item=&'a str>,
@@ -36,93 +81,129 @@ impl CapLs {
         try!(sink.write_all(source).or_else(cursor_chk_error));
         try!(sink.write_all(b" CAP * LS :").or_else(cursor_chk_error));
 
-        for cap_phrase in caps.iter_raw() {
+        for (idx, cap_phrase) in caps.enumerate() {
+            if idx > 0 {
+                try!(sink.write_all(b" ").or_else(cursor_chk_error));
+            }
             try!(sink.write_all(cap_phrase.as_bytes()).or_else(cursor_chk_error));
-            try!(sink.write_all(b" ").or_else(cursor_chk_error));
         }
 
         Ok(())
     }
 
-    fn construct_partial<'a, W, I>(sink: &mut W, source: &[u8], caps: &mut I) -> Result<bool, ()>
+    /// Write one line of a multi-line LS, taking as many capabilities from
+    /// `caps` as fit.  Returns true if this was the final line.
+    fn construct_partial<'a, W, I>(sink: &mut W, source: &[u8], caps: &mut Peekable<I>) -> Result<bool, MessageError>
         where
             W: Write,
-            I: Peekable<Iterator<Item=&'a str>>,
+            I: Iterator<Item=&'a str>,
     {
         const MAX_MESSAGE: usize = 256; // really 512 but let's play it safe.
         const PREFIX_STARTER: &[u8] = b":";
-        const CAP_COMMAND: &[u8] = b" CAP * LS :";
+        const CAP_COMMAND: &[u8] = b" CAP * LS * :";
 
         let base_message = PREFIX_STARTER.len() + source.len() + CAP_COMMAND.len();
-        try!(sink.write_all(PREFIX_STARTER).or_else(cursor_chk_error));
-        try!(sink.write_all(source).or_else(cursor_chk_error));
-        try!(sink.write_all(CAP_COMMAND).or_else(cursor_chk_error));
-
-
-        let mut is_finished = true;
+        let mut bytes_written = base_message;
+        let mut line: Vec<u8> = Vec::new();
 
         while let Some(&cap) = caps.peek() {
-            if MAX_MESSAGE <= bytes_written + cap.len() {
-                is_finished = false;
-                break;
-            }
             if MAX_MESSAGE <= base_message + cap.len() {
                 // We'll never be able to emit this message.
-                return Err(());
+                return Err(MessageError::BadArgument);
+            }
+            if MAX_MESSAGE <= bytes_written + cap.len() {
+                break;
             }
 
             let cap_bytes = caps.next().unwrap().as_bytes();
             bytes_written += cap_bytes.len() + 1;
 
-            try!(sink.write_all(cap_bytes).or_else(cursor_chk_error));
-            try!(sink.write_all(b" ").or_else(cursor_chk_error));
+            if line.len() > 0 {
+                line.push(b' ');
+            }
+            line.extend(cap_bytes);
         }
 
+        let is_finished = caps.peek().is_none();
+        try!(sink.write_all(PREFIX_STARTER).or_else(cursor_chk_error));
+        try!(sink.write_all(source).or_else(cursor_chk_error));
+        if is_finished {
+            try!(sink.write_all(b" CAP * LS :").or_else(cursor_chk_error));
+        } else {
+            try!(sink.write_all(CAP_COMMAND).or_else(cursor_chk_error));
+        }
+        try!(sink.write_all(&line).or_else(cursor_chk_error));
+
         Ok(is_finished)
     }
 
     /// True if this is the final line in a multi-line response.
     /// see: [IRCv3.2](http://ircv3.net/specs/core/capability-negotiation-3.2.html)
     pub fn is_final(&self) -> bool {
-        unimplemented!();
+        !split_reply(self, "LS").unwrap().1
     }
 
+    /// The capabilities on this line only.
     pub fn capabilities(&self) -> Capabilities {
-        unimplemented!();
+        let mut caps = Capabilities::new();
+        for cap in self.capability_raw_iter() {
+            caps.insert_raw(cap);
+        }
+        caps
     }
 
+    /// The capabilities on this line, with their values, as in
+    /// `sasl=PLAIN,EXTERNAL`.
     pub fn capability_raw_iter(&self) -> CapLsCapabilityRawIter {
-        unimplemented!();
+        CapLsCapabilityRawIter { inner: split_reply(self, "LS").unwrap().2.split(' ') }
     }
 }
 
 impl CapLsBuf {
-    fn _new(source: &[u8], caps: &Capabilities) -> Result<CapLsBuf, ()> {
-    }
-
-    #[cfg(feature = "unstable")]
-    pub fn new_unstable(source: &[u8], caps: &Capabilities) -> Result<CapLsBuf, ()> {
+    pub fn new_unstable(source: &[u8], caps: &Capabilities) -> Result<CapLsBuf, MessageError> {
         let mut wr = io::Cursor::new(Vec::new());
-        try!(CapLs::construct(&mut wr, source, caps));
+        try!(CapLs::construct(&mut wr, source, caps.iter_raw()));
 
-        let message = try!(IrcMsgBuf::new(wr.into_inner()).map_err(|_| ()));
+        let message = try!(IrcMsgBuf::new(wr.into_inner()).map_err(MessageError::from));
         try!(CapLs::validate(&message));
         Ok(CapLsBuf { inner: message })
     }
 }
 
-pub struct CapLsCapabilityRawIter;
+pub struct CapLsCapabilityRawIter<'a> {
+    inner: ::std::str::Split<'a, char>,
+}
+
+impl<'a> Iterator for CapLsCapabilityRawIter<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        loop {
+            match self.inner.next() {
+                Some("") => continue,
+                other => return other,
+            }
+        }
+    }
+}
 
 pub struct CapLsGroupBuilder<'a> {
     source: &'a [u8],
     caps: &'a Capabilities,
 }
 
-impl CapLsGroupBuilder {
-    pub fn build(&self) -> Result<CapLsGroupBuf, ()> {
-        let wr = io::Cursor::new(Vec::new());
+impl<'a> CapLsGroupBuilder<'a> {
+    pub fn new(source: &'a [u8], caps: &'a Capabilities) -> CapLsGroupBuilder<'a> {
+        CapLsGroupBuilder {
+            source: source,
+            caps: caps,
+        }
+    }
+
+    pub fn build(&self) -> Result<CapLsGroupBuf, MessageError> {
+        let mut wr = io::Cursor::new(Vec::new());
 
-        let caps = self.caps.iter().peekable();
+        let mut caps = self.caps.iter_raw().peekable();
         loop {
             let finished = try!(CapLs::construct_partial(&mut wr, self.source, &mut caps));
             try!(wr.write_all(b"\r\n").or_else(cursor_chk_error));
@@ -131,7 +212,7 @@ impl CapLsGroupBuilder {
             }
         }
 
-        CapLsGroupBuf { data: wr.into_inner() }
+        Ok(CapLsGroupBuf { data: wr.into_inner() })
     }
 }
 
@@ -139,6 +220,13 @@ pub struct CapLsGroupBuf {
     data: Vec<u8>,
 }
 
+impl CapLsGroupBuf {
+    /// The CR-LF terminated lines, ready to send.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
 
 impl_irc_msg_subtype!(CapList);
 impl_irc_msg_subtype_buf!(CapListBuf, CapList);
@@ -148,18 +236,19 @@ impl_irc_msg_subtype_buf!(CapListBuf, CapList);
 // Server: CAP modernclient LIST :invite-notify batch example.org/third-example-cap
 
 impl CapList {
-    fn validate(_msg: &IrcMsg) -> Result<(), ()> {
-        unimplemented!();
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        split_reply(msg, "LIST").map(|_| ())
     }
 
     /// True if this is the final line in a multi-line response.
     /// see: [IRCv3.2](http://ircv3.net/specs/core/capability-negotiation-3.2.html)
     pub fn is_final(&self) -> bool {
-        unimplemented!();
+        !split_reply(self, "LIST").unwrap().1
     }
 
+    /// The capabilities enabled, as listed on this line only.
     pub fn capabilities(&self) -> CapListCapabilityIter {
-        unimplemented!();
+        CapListCapabilityIter { inner: split_reply(self, "LIST").unwrap().2.split(' ') }
     }
 }
 
@@ -167,21 +256,23 @@ impl CapListBuf {
     //
 }
 
-pub struct CapListCapabilityIter;
-
+pub struct CapListCapabilityIter<'a> {
+    inner: ::std::str::Split<'a, char>,
+}
 
-impl_irc_msg_subtype!(CapReq);
-impl_irc_msg_subtype_buf!(CapReqBuf, CapReq);
+impl<'a> Iterator for CapListCapabilityIter<'a> {
+    type Item = &'a str;
 
-impl CapReq {
-    fn validate(_msg: &IrcMsg) -> Result<(), ()> {
-        unimplemented!();
+    fn next(&mut self) -> Option<&'a str> {
+        loop {
+            match self.inner.next() {
+                Some("") => continue,
+                other => return other,
+            }
+        }
     }
 }
 
-impl CapReqBuf {
-    //
-}
 
 impl_irc_msg_subtype!(CapAck);
 impl_irc_msg_subtype_buf!(CapAckBuf, CapAck);
@@ -190,34 +281,80 @@ impl_irc_msg_subtype_buf!(CapAckBuf, CapAck);
 // Client: CAP END
 // Server: CAP * ACK :account-notify away-notify extended-join multi-prefix sasl
 
+/// The server enabling (or, with `-`, disabling) every capability of a
+/// `CAP REQ`.
 impl CapAck {
-    fn validate(_msg: &IrcMsg) -> Result<(), ()> {
-        unimplemented!();
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        split_reply(msg, "ACK").map(|_| ())
+    }
+
+    /// The nick the reply is addressed to, or `*` before registration.
+    pub fn get_target(&self) -> &[u8] {
+        split_reply(self, "ACK").unwrap().0
+    }
+
+    pub fn capabilities(&self) -> CapabilityChangeIter {
+        CapabilityChangeIter::new(split_reply(self, "ACK").unwrap().2)
     }
 }
 
 impl CapAckBuf {
-    //
+    pub fn new<S, T>(source: S, target: T, caps: &[&str]) -> Result<CapAckBuf, MessageError>
+        where S: AsRef<[u8]>,
+              T: AsRef<[u8]>
+    {
+        let source = source.as_ref();
+        let target = target.as_ref();
+        let mut wr = io::Cursor::new(Vec::new());
+        try!(construct_reply(&mut wr, source, target, b"ACK", caps));
+
+        let message = try!(IrcMsgBuf::new(wr.into_inner()).map_err(MessageError::from));
+        try!(CapAck::validate(&message));
+        Ok(CapAckBuf { inner: message })
+    }
 }
 
 impl_irc_msg_subtype!(CapNak);
 impl_irc_msg_subtype_buf!(CapNakBuf, CapNak);
 
+/// The server refusing a `CAP REQ`.  Requests are all-or-nothing, so none
+/// of the listed changes were made.
 impl CapNak {
-    fn validate(_msg: &IrcMsg) -> Result<(), ()> {
-        unimplemented!();
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        split_reply(msg, "NAK").map(|_| ())
+    }
+
+    /// The nick the reply is addressed to, or `*` before registration.
+    pub fn get_target(&self) -> &[u8] {
+        split_reply(self, "NAK").unwrap().0
+    }
+
+    pub fn capabilities(&self) -> CapabilityChangeIter {
+        CapabilityChangeIter::new(split_reply(self, "NAK").unwrap().2)
     }
 }
 
 impl CapNakBuf {
-    //
+    pub fn new<S, T>(source: S, target: T, caps: &[&str]) -> Result<CapNakBuf, MessageError>
+        where S: AsRef<[u8]>,
+              T: AsRef<[u8]>
+    {
+        let source = source.as_ref();
+        let target = target.as_ref();
+        let mut wr = io::Cursor::new(Vec::new());
+        try!(construct_reply(&mut wr, source, target, b"NAK", caps));
+
+        let message = try!(IrcMsgBuf::new(wr.into_inner()).map_err(MessageError::from));
+        try!(CapNak::validate(&message));
+        Ok(CapNakBuf { inner: message })
+    }
 }
 
 impl_irc_msg_subtype!(CapEnd);
 impl_irc_msg_subtype_buf!(CapEndBuf, CapEnd);
 
 impl CapEnd {
-    fn construct<W>(sink: &mut W, source: &[u8]) -> Result<(), ()>
+    fn construct<W>(sink: &mut W, source: &[u8]) -> Result<(), MessageError>
         where W: Write
     {
         try!(sink.write_all(b":").or_else(cursor_chk_error));
@@ -227,7 +364,7 @@ impl CapEnd {
     }
 
     /// Create a new `CapEnd` in `storage`.  This does not allocate any storage.
-    pub fn new<'a>(storage: &'a mut [u8], source: &[u8]) -> Result<&'a CapEnd, ()> {
+    pub fn new<'a>(storage: &'a mut [u8], source: &[u8]) -> Result<&'a CapEnd, MessageError> {
         let mut wr = io::Cursor::new(storage);
         try!(CapEnd::construct(&mut wr, source));
         let end = wr.position() as usize;
@@ -236,20 +373,78 @@ impl CapEnd {
         CapEnd::parse(&storage[..end])
     }
 
-    fn validate(_msg: &IrcMsg) -> Result<(), ()> {
-        unimplemented!();
+    fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        use std::ascii::AsciiExt;
+
+        if !AsciiExt::eq_ignore_ascii_case(msg.get_command(), "CAP") {
+            return Err(MessageError::WrongCommand);
+        }
+        let mut args = msg.args();
+        match args.next() {
+            Some(sub) if AsciiExt::eq_ignore_ascii_case(sub, b"END") => (),
+            Some(_) => return Err(MessageError::WrongCommand),
+            None => return Err(MessageError::MissingArgument),
+        }
+        if args.next().is_some() {
+            return Err(MessageError::TooManyArguments);
+        }
+        Ok(())
     }
 }
 
 impl CapEndBuf {
-    pub fn new(source: &[u8]) -> Result<CapEndBuf, ()> {
+    pub fn new(source: &[u8]) -> Result<CapEndBuf, MessageError> {
         let mut wr = io::Cursor::new(Vec::new());
         try!(CapEnd::construct(&mut wr, source));
 
         // maybe we could skip this check later and turn it into a debug-assert?
-        let message = try!(IrcMsgBuf::new(wr.into_inner()).map_err(|_| ()));
+        let message = try!(IrcMsgBuf::new(wr.into_inner()).map_err(MessageError::from));
 
         try!(CapEnd::validate(&message));
         Ok(CapEndBuf { inner: message })
     }
 }
+
+#[test]
+fn cap_ack_and_nak() {
+    use ::cap::CapabilityChange;
+
+    let ack = CapAckBuf::new("irc.example.com", "*", &["multi-prefix", "-sasl"]).unwrap();
+    assert_eq!(ack.as_bytes(), &b":irc.example.com CAP * ACK :multi-prefix -sasl"[..]);
+    assert_eq!(ack.get_target(), b"*");
+    assert_eq!(ack.capabilities().collect::<Vec<_>>(), vec![
+        CapabilityChange { name: "multi-prefix", enable: true },
+        CapabilityChange { name: "sasl", enable: false },
+    ]);
+
+    let msg = IrcMsg::new(b":server CAP nick NAK :account-notify").unwrap();
+    let nak = msg.as_tymsg::<&CapNak>().unwrap();
+    assert_eq!(nak.get_target(), b"nick");
+    assert_eq!(nak.capabilities().map(|c| c.name).collect::<Vec<_>>(), vec!["account-notify"]);
+    assert!(msg.as_tymsg::<&CapAck>().is_err());
+
+    assert!(IrcMsg::new(b":server CAP * ACK multi-prefix").unwrap().as_tymsg::<&CapAck>().is_ok());
+    assert!(IrcMsg::new(b":server CAP * LS :multi-prefix").unwrap().as_tymsg::<&CapAck>().is_err());
+    assert!(CapAckBuf::new("server", "*", &["bad cap"]).is_err());
+}
+
+#[test]
+fn cap_list_and_end() {
+    let msg = IrcMsg::new(b":server CAP modernclient LIST * :example.org/example-cap account-notify").unwrap();
+    let list = msg.as_tymsg::<&CapList>().unwrap();
+    assert!(!list.is_final());
+    assert_eq!(list.capabilities().collect::<Vec<_>>(), vec!["example.org/example-cap", "account-notify"]);
+
+    let msg = IrcMsg::new(b":server CAP modernclient LIST :invite-notify batch").unwrap();
+    let list = msg.as_tymsg::<&CapList>().unwrap();
+    assert!(list.is_final());
+    assert_eq!(list.capabilities().count(), 2);
+    assert!(msg.as_tymsg::<&CapAck>().is_err());
+
+    let end = CapEndBuf::new(b"nick!u@h").unwrap();
+    assert_eq!(end.as_bytes(), b":nick!u@h CAP END");
+    let mut storage = [0; 32];
+    assert!(CapEnd::new(&mut storage, b"nick").is_ok());
+    assert!(IrcMsg::new(b"CAP END extra").unwrap().as_tymsg::<&CapEnd>().is_err());
+    assert!(IrcMsg::new(b"CAP LS").unwrap().as_tymsg::<&CapEnd>().is_err());
+}
@@ -0,0 +1,218 @@
+//! Collecting the server's RPL_ISUPPORT (005) parameters.
+//!
+//! Servers advertise their limits and syntax over several 005 lines
+//! during registration.  `ServerInfo` accumulates the tokens and answers
+//! the questions other parts of the crate would otherwise hard-code, such
+//! as which characters begin a channel name.
+
+use std::collections::HashMap;
+
+use ::{AnyCaseMapping, IrcMsg};
+use super::ModeTable;
+
+/// RFC 1459 channel types, used when CHANTYPES is not advertised.
+const DEFAULT_CHANTYPES: &'static str = "#&";
+const DEFAULT_CASEMAPPING: &'static str = "rfc1459";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ServerInfo {
+    /// Upper-cased token names to their unescaped values.  Tokens
+    /// without a value map to an empty string.
+    tokens: HashMap<String, String>,
+}
+
+impl ServerInfo {
+    pub fn new() -> ServerInfo {
+        ServerInfo { tokens: HashMap::new() }
+    }
+
+    /// Take the tokens from a 005 line.  Returns false, ignoring the
+    /// message, if it is not RPL_ISUPPORT.
+    pub fn on_irc_msg(&mut self, msg: &IrcMsg) -> bool {
+        if msg.get_command() != "005" {
+            return false;
+        }
+        let args: Vec<&[u8]> = msg.args().collect();
+        // The first argument is our nick and the last is human-readable
+        // text such as `are supported by this server`.
+        if args.len() < 3 {
+            return false;
+        }
+        for token in args[1..args.len() - 1].iter() {
+            self.add_token(&String::from_utf8_lossy(token));
+        }
+        true
+    }
+
+    /// Apply a single token, such as `NICKLEN=30`, `EXCEPTS` or `-KNOCK`.
+    /// A leading `-` withdraws a previously advertised token.
+    pub fn add_token(&mut self, token: &str) {
+        if token.starts_with('-') {
+            self.tokens.remove(&token[1..].to_ascii_uppercase());
+            return;
+        }
+        let (name, value) = match token.find('=') {
+            Some(idx) => (&token[..idx], unescape(&token[idx + 1..])),
+            None => (token, String::new()),
+        };
+        if !name.is_empty() {
+            self.tokens.insert(name.to_ascii_uppercase(), value);
+        }
+    }
+
+    /// The value of a token.  Tokens advertised without a value give
+    /// `Some("")`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.tokens.get(&name.to_ascii_uppercase()).map(|value| &value[..])
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.tokens.contains_key(&name.to_ascii_uppercase())
+    }
+
+    fn get_usize(&self, name: &str) -> Option<usize> {
+        self.get(name).and_then(|value| value.parse().ok())
+    }
+
+    /// The CASEMAPPING token, `rfc1459` if not advertised.
+    pub fn casemapping(&self) -> &str {
+        self.get("CASEMAPPING").unwrap_or(DEFAULT_CASEMAPPING)
+    }
+
+    /// The advertised case mapping, if the crate implements it.
+    pub fn case_mapping(&self) -> Option<AnyCaseMapping> {
+        AnyCaseMapping::from_isupport_token(self.casemapping())
+    }
+
+    /// The characters channel names begin with, `#&` if not advertised.
+    pub fn chantypes(&self) -> &str {
+        self.get("CHANTYPES").unwrap_or(DEFAULT_CHANTYPES)
+    }
+
+    /// Whether `name` is a channel on this server.
+    pub fn is_channel(&self, name: &str) -> bool {
+        match name.chars().next() {
+            Some(first) => self.chantypes().contains(first),
+            None => false,
+        }
+    }
+
+    /// A `ModeTable` built from CHANMODES and PREFIX.  Either falls back
+    /// to the RFC defaults when missing or malformed.
+    pub fn mode_table(&self) -> ModeTable {
+        let mut table = ModeTable::new();
+        if let Some(chanmodes) = self.get("CHANMODES") {
+            if table.set_chanmodes(chanmodes).is_err() {
+                warn!("ignoring malformed CHANMODES={:?}", chanmodes);
+            }
+        }
+        if let Some(prefix) = self.get("PREFIX") {
+            if table.set_prefix(prefix).is_err() {
+                warn!("ignoring malformed PREFIX={:?}", prefix);
+            }
+        }
+        table
+    }
+
+    /// A `ModeTable` of user modes built from USERMODES, which few
+    /// servers send.  Falls back to `ModeTable::user_modes` when
+    /// missing or malformed.
+    pub fn user_mode_table(&self) -> ModeTable {
+        let mut table = ModeTable::user_modes();
+        if let Some(usermodes) = self.get("USERMODES") {
+            if table.set_chanmodes(usermodes).is_err() {
+                warn!("ignoring malformed USERMODES={:?}", usermodes);
+            }
+        }
+        table
+    }
+
+    pub fn nicklen(&self) -> Option<usize> {
+        self.get_usize("NICKLEN")
+    }
+
+    pub fn channellen(&self) -> Option<usize> {
+        self.get_usize("CHANNELLEN")
+    }
+
+    pub fn topiclen(&self) -> Option<usize> {
+        self.get_usize("TOPICLEN")
+    }
+
+    pub fn kicklen(&self) -> Option<usize> {
+        self.get_usize("KICKLEN")
+    }
+
+    /// The most argument-taking modes allowed in one MODE command.
+    /// `None` if not advertised, in which case RFC 2812 suggests 3, or
+    /// if advertised without a limit.
+    pub fn modes(&self) -> Option<usize> {
+        self.get_usize("MODES")
+    }
+
+    pub fn network(&self) -> Option<&str> {
+        self.get("NETWORK")
+    }
+}
+
+/// Decode the `\xHH` escapes allowed in ISUPPORT values.
+fn unescape(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx] == b'\\' && idx + 4 <= bytes.len() && bytes[idx + 1] == b'x' {
+            let hex = ::std::str::from_utf8(&bytes[idx + 2..idx + 4]).ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            if let Some(byte) = hex {
+                out.push(byte);
+                idx += 4;
+                continue;
+            }
+        }
+        out.push(bytes[idx]);
+        idx += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use ::{AnyCaseMapping, IrcMsg};
+    use super::super::ModeKind;
+    use super::ServerInfo;
+
+    #[test]
+    fn server_info_accumulates() {
+        let mut info = ServerInfo::new();
+        assert_eq!(info.casemapping(), "rfc1459");
+        assert!(info.is_channel("#rust"));
+        assert!(!info.is_channel("!rust"));
+
+        assert!(info.on_irc_msg(IrcMsg::new(
+            b":irc.example.net 005 me CHANTYPES=#! NICKLEN=30 EXCEPTS KNOCK \
+              PREFIX=(qov)~@+ :are supported by this server").unwrap()));
+        assert!(info.on_irc_msg(IrcMsg::new(
+            b":irc.example.net 005 me CASEMAPPING=ascii NETWORK=Example\\x20Net \
+              CHANMODES=beI,k,l,imnpst -KNOCK :are supported by this server").unwrap()));
+        assert!(!info.on_irc_msg(IrcMsg::new(b":irc.example.net 001 me :Welcome").unwrap()));
+
+        assert_eq!(info.casemapping(), "ascii");
+        assert_eq!(info.case_mapping(), Some(AnyCaseMapping::Ascii));
+        assert_eq!(info.nicklen(), Some(30));
+        assert_eq!(info.topiclen(), None);
+        assert_eq!(info.network(), Some("Example Net"));
+        assert_eq!(info.get("excepts"), Some(""));
+        assert!(!info.contains("KNOCK"));
+        assert!(info.is_channel("!rust"));
+        assert!(!info.is_channel("&rust"));
+        assert_eq!(info.mode_table().prefix_symbol(b'q'), Some(b'~'));
+        assert_eq!(info.user_mode_table().kind(b's'), Some(ModeKind::OnSet));
+        assert_eq!(info.user_mode_table().kind(b'b'), None);
+
+        info.add_token("USERMODES=,,sx,iow");
+        assert_eq!(info.user_mode_table().kind(b'x'), Some(ModeKind::OnSet));
+        assert_eq!(info.user_mode_table().kind(b'i'), Some(ModeKind::Never));
+    }
+}
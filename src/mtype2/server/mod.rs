@@ -1,39 +1,316 @@
-use std::borrow::{Borrow, ToOwned};
+use std::borrow::{Borrow, Cow, ToOwned};
 use std::{mem, ops};
 use std::io::{self, Write};
 
-use super::{FromIrcMsg, client};
+use super::{FromIrcMsg, MessageError, client};
 use super::cursor_chk_error;
 
-use ::legacy::IrcMsg as IrcMsgLegacy;
-use ::{IrcMsg, IrcMsgBuf, ParseError};
+use ::{IrcMsg, IrcMsgBuf, IrcMsgBuilder};
 use ::parse_helpers;
+use ::charset::{self, Decoder, LossyUtf8};
+use ::parse::ArgumentIter;
+
+mod batch;
+pub use self::batch::{Batch, BatchBuf, BatchType};
+
+mod bridge;
+pub use self::bridge::BridgePrefixer;
+
+mod isupport;
+pub use self::isupport::ServerInfo;
+
+pub mod numerics;
+
+mod mode;
+pub use self::mode::{Sign, ModeKind, ModeTable, ModeChanges};
 
 #[cfg(feature = "unstable")] mod cap;
 #[cfg(feature = "unstable")] pub use self::cap::{
     CapLs, CapLsBuf,
+    CapLsGroupBuilder, CapLsGroupBuf,
     CapList, CapListBuf,
-    CapReq, CapReqBuf,
     CapAck, CapAckBuf,
     CapNak, CapNakBuf,
 };
 
 
+/// What a message's prefix must look like.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PrefixRule {
+    Optional,
+    Required,
+    /// A full `nick!user@host`
+    User,
+}
+
+/// True for a `nick!user@host` prefix whose nick is UTF-8, as the nick
+/// accessors require.
+fn is_user_prefix(prefix: &[u8]) -> bool {
+    match prefix.iter().position(|&b| b == b'!') {
+        Some(bang) => {
+            prefix[bang..].contains(&b'@')
+                && ::std::str::from_utf8(&prefix[..bang]).is_ok()
+        },
+        None => false,
+    }
+}
+
+/// Check `msg` is `command` with a prefix satisfying `prefix_rule` and
+/// between `min_args` and `max_args` arguments.
+fn validate_command(msg: &IrcMsg, command: &str, prefix_rule: PrefixRule, min_args: usize, max_args: usize)
+    -> Result<(), MessageError>
+{
+    let (prefix, rest) = parse_helpers::split_prefix(msg.as_bytes());
+    let prefix_ok = match prefix_rule {
+        PrefixRule::Optional => true,
+        PrefixRule::Required => prefix.len() > 0,
+        PrefixRule::User => is_user_prefix(prefix),
+    };
+    if !prefix_ok {
+        return Err(MessageError::BadPrefix);
+    }
+    let (got, mut rest) = parse_helpers::split_command(rest);
+    if !got.eq_ignore_ascii_case(command.as_bytes()) {
+        return Err(MessageError::WrongCommand);
+    }
+    let mut count = 0;
+    while rest.len() > 0 {
+        let (_arg, next) = parse_helpers::split_arg(rest);
+        rest = next;
+        count += 1;
+    }
+    if count < min_args {
+        return Err(MessageError::MissingArgument);
+    }
+    if max_args < count {
+        return Err(MessageError::TooManyArguments);
+    }
+    Ok(())
+}
+
+/// Everything after the command.
+fn split_args(msg: &IrcMsg) -> &[u8] {
+    let (_prefix, rest) = parse_helpers::split_prefix(msg.as_bytes());
+    let (_command, rest) = parse_helpers::split_command(rest);
+    rest
+}
+
+
+impl_irc_msg_subtype!(Account);
+impl_irc_msg_subtype_buf!(AccountBuf, Account);
+irc_msg_has_source!(Account);
+
+/// A user logging in or out of an account, sent to channel neighbours
+/// when `account-notify` is enabled.
+impl Account {
+    pub fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        validate_command(msg, "ACCOUNT", PrefixRule::Required, 1, 1)
+    }
+
+    /// The account name, or `None` if the user logged out.
+    pub fn get_account(&self) -> Option<&[u8]> {
+        let (account, _rest) = parse_helpers::split_arg(split_args(self));
+        if account == b"*" {
+            None
+        } else {
+            Some(account)
+        }
+    }
+}
+
+impl AccountBuf {
+    /// `account` is `None` to announce a logout.
+    pub fn new<S>(source: S, account: Option<&[u8]>) -> Result<AccountBuf, MessageError>
+        where S: AsRef<[u8]>
+    {
+        let message = try!(IrcMsgBuilder::new()
+            .prefix(source)
+            .command("ACCOUNT")
+            .arg(account.unwrap_or(b"*"))
+            .build()
+            .map_err(MessageError::from));
+        try!(Account::validate(&message));
+        Ok(AccountBuf { inner: message })
+    }
+}
+
+
+impl_irc_msg_subtype!(Authenticate);
+impl_irc_msg_subtype_buf!(AuthenticateBuf, Authenticate);
+
+impl Authenticate {
+    pub fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        use std::ascii::AsciiExt;
+
+        if !AsciiExt::eq_ignore_ascii_case(msg.get_command(), "AUTHENTICATE") {
+            return Err(MessageError::WrongCommand);
+        }
+        let args: Vec<&[u8]> = msg.args().collect();
+        if args.len() != 1 || args[0].is_empty() {
+            return Err(MessageError::BadArgument);
+        }
+        Ok(())
+    }
+
+    /// A base64 challenge chunk, or `+` for an empty one.
+    pub fn get_payload(&self) -> &[u8] {
+        self.args().next().unwrap()
+    }
+
+    /// True for `AUTHENTICATE +`, the server's go-ahead when it has no
+    /// challenge to send.
+    pub fn is_empty_challenge(&self) -> bool {
+        self.get_payload() == b"+"
+    }
+}
+
+impl AuthenticateBuf {
+    pub fn new<P>(payload: P) -> Result<AuthenticateBuf, MessageError>
+        where P: AsRef<[u8]>
+    {
+        let message = try!(IrcMsgBuilder::new()
+            .command("AUTHENTICATE")
+            .arg(payload)
+            .build()
+            .map_err(MessageError::from));
+        try!(Authenticate::validate(&message));
+        Ok(AuthenticateBuf { inner: message })
+    }
+}
+
+
+impl_irc_msg_subtype!(Away);
+impl_irc_msg_subtype_buf!(AwayBuf, Away);
+irc_msg_has_source!(Away);
+
+/// A user going away or coming back, sent to channel neighbours when
+/// `away-notify` is enabled.
+impl Away {
+    pub fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        validate_command(msg, "AWAY", PrefixRule::Required, 0, 1)
+    }
+
+    /// The away message, or `None` if the user is back.
+    pub fn get_message(&self) -> Option<&[u8]> {
+        let args = split_args(self);
+        if args.len() == 0 {
+            return None;
+        }
+        let (message, _rest) = parse_helpers::split_arg(args);
+        Some(message)
+    }
+
+    pub fn is_away(&self) -> bool {
+        self.get_message().is_some()
+    }
+}
+
+impl AwayBuf {
+    /// `message` is `None` when the user comes back.
+    pub fn new<S>(source: S, message: Option<&[u8]>) -> Result<AwayBuf, MessageError>
+        where S: AsRef<[u8]>
+    {
+        let mut builder = IrcMsgBuilder::new()
+            .prefix(source)
+            .command("AWAY");
+        if let Some(message) = message {
+            builder = builder.trailing(message);
+        }
+        let message = try!(builder.build().map_err(MessageError::from));
+        try!(Away::validate(&message));
+        Ok(AwayBuf { inner: message })
+    }
+}
+
+
+impl_irc_msg_subtype!(Chghost);
+impl_irc_msg_subtype_buf!(ChghostBuf, Chghost);
+irc_msg_has_source!(Chghost);
+
+/// A user's username or hostname changing, sent when `chghost` is
+/// enabled.  The source carries the old values.
+impl Chghost {
+    pub fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        validate_command(msg, "CHGHOST", PrefixRule::Required, 2, 2)
+    }
+
+    pub fn get_new_user(&self) -> &[u8] {
+        let (user, _rest) = parse_helpers::split_arg(split_args(self));
+        user
+    }
+
+    pub fn get_new_host(&self) -> &[u8] {
+        let (_user, rest) = parse_helpers::split_arg(split_args(self));
+        let (host, _rest) = parse_helpers::split_arg(rest);
+        host
+    }
+}
+
+impl ChghostBuf {
+    pub fn new<S, U, H>(source: S, user: U, host: H) -> Result<ChghostBuf, MessageError>
+        where S: AsRef<[u8]>,
+              U: AsRef<[u8]>,
+              H: AsRef<[u8]>
+    {
+        let message = try!(IrcMsgBuilder::new()
+            .prefix(source)
+            .command("CHGHOST")
+            .arg(user)
+            .arg(host)
+            .build()
+            .map_err(MessageError::from));
+        try!(Chghost::validate(&message));
+        Ok(ChghostBuf { inner: message })
+    }
+}
+
+
+impl_irc_msg_subtype!(ServerError);
+impl_irc_msg_subtype_buf!(ServerErrorBuf, ServerError);
+
+/// ERROR: the server closing the connection, usually with no prefix.
+impl ServerError {
+    pub fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        validate_command(msg, "ERROR", PrefixRule::Optional, 1, 1)
+    }
+
+    /// Why the connection is being closed, e.g. `Closing Link: ...`.
+    pub fn get_message(&self) -> &[u8] {
+        let (message, _rest) = parse_helpers::split_arg(split_args(self));
+        message
+    }
+}
+
+impl ServerErrorBuf {
+    pub fn new<M>(message: M) -> Result<ServerErrorBuf, MessageError>
+        where M: AsRef<[u8]>
+    {
+        let message = try!(IrcMsgBuilder::new()
+            .command("ERROR")
+            .trailing(message)
+            .build()
+            .map_err(MessageError::from));
+        try!(ServerError::validate(&message));
+        Ok(ServerErrorBuf { inner: message })
+    }
+}
+
+
 impl_irc_msg_subtype!(Invite);
 impl_irc_msg_subtype_buf!(InviteBuf, Invite);
-irc_msg_legacy_validator!(Invite, Invite);
+irc_msg_validator!(Invite, "INVITE", PrefixRule::User, 2, ::std::usize::MAX);
 irc_msg_has_source!(Invite);
 irc_msg_has_target!(Invite);
 
 
 impl_irc_msg_subtype!(Join);
 impl_irc_msg_subtype_buf!(JoinBuf, Join);
-irc_msg_legacy_validator!(Join, Join);
+irc_msg_validator!(Join, "JOIN", PrefixRule::User, 1, ::std::usize::MAX);
 irc_msg_has_source!(Join);
 irc_msg_has_target!(Join);
 
 impl Join {
-    fn construct<W>(sink: &mut W, source: &[u8], channel: &[u8]) -> Result<(), ()>
+    fn construct<W>(sink: &mut W, source: &[u8], channel: &[u8]) -> Result<(), MessageError>
         where W: Write
 {
         try!(sink.write_all(b":").or_else(cursor_chk_error));
@@ -45,7 +322,12 @@ impl Join {
     }
 
     /// Create a new `Join` in `storage`.  This does not allocate any storage.
-    pub fn new<'a>(storage: &'a mut [u8], source: &[u8], channel: &[u8]) -> Result<&'a Join, ()> {
+    pub fn new<'a, S, C>(storage: &'a mut [u8], source: S, channel: C) -> Result<&'a Join, MessageError>
+        where S: AsRef<[u8]>,
+              C: AsRef<[u8]>
+    {
+        let source = source.as_ref();
+        let channel = channel.as_ref();
         let mut wr = io::Cursor::new(storage);
         try!(Join::construct(&mut wr, source, channel));
         let end = wr.position() as usize;
@@ -58,16 +340,45 @@ impl Join {
         let (nick, _, _) = parse_helpers::parse_prefix(self.get_source()).unwrap();
         ::std::str::from_utf8(nick).unwrap()
     }
+
+    pub fn get_channel(&self) -> &[u8] {
+        self.get_target()
+    }
+
+    /// Whether the join carries the account and realname arguments added
+    /// by `extended-join`.
+    pub fn is_extended(&self) -> bool {
+        self.args().count() == 3
+    }
+
+    /// The account the user is logged in to, with `extended-join`.
+    /// `None` if they aren't logged in or the join isn't extended.
+    pub fn get_account(&self) -> Option<&[u8]> {
+        match self.args().nth(1) {
+            Some(b"*") | None => None,
+            Some(account) => Some(account),
+        }
+    }
+
+    /// The user's realname, with `extended-join`.
+    pub fn get_realname(&self) -> Option<&[u8]> {
+        self.args().nth(2)
+    }
 }
 
 impl JoinBuf {
     /// Create a new `JoinBuf`.  Allocates storage.
-    pub fn new(source: &[u8], channel: &[u8]) -> Result<JoinBuf, ()> {
+    pub fn new<S, C>(source: S, channel: C) -> Result<JoinBuf, MessageError>
+        where S: AsRef<[u8]>,
+              C: AsRef<[u8]>
+    {
+        let source = source.as_ref();
+        let channel = channel.as_ref();
         let mut wr = io::Cursor::new(Vec::new());
         try!(Join::construct(&mut wr, source, channel));
 
         // maybe we could skip this check later and turn it into a debug-assert?
-        let message = try!(IrcMsgBuf::new(wr.into_inner()).map_err(|_| ()));
+        let message = try!(IrcMsgBuf::new(wr.into_inner()).map_err(MessageError::from));
 
         try!(Join::validate(&message));
         Ok(JoinBuf { inner: message })
@@ -77,13 +388,49 @@ impl JoinBuf {
 
 impl_irc_msg_subtype!(Kick);
 impl_irc_msg_subtype_buf!(KickBuf, Kick);
-irc_msg_legacy_validator!(Kick, Kick);
+irc_msg_validator!(Kick, "KICK", PrefixRule::User, 2, ::std::usize::MAX);
 irc_msg_has_source!(Kick);
 irc_msg_has_target!(Kick);
 
+impl Kick {
+    /// The nick of whoever did the kicking.
+    pub fn get_nick(&self) -> &str {
+        let (nick, _, _) = parse_helpers::parse_prefix(self.get_source()).unwrap();
+        ::std::str::from_utf8(nick).unwrap()
+    }
+
+    pub fn get_channel(&self) -> &[u8] {
+        self.get_target()
+    }
+
+    pub fn get_kicked_nick(&self) -> &[u8] {
+        let (_channel, rest) = parse_helpers::split_arg(split_args(self));
+        let (kicked, _rest) = parse_helpers::split_arg(rest);
+        kicked
+    }
+
+    /// The kick message, absent if the kicker gave none.
+    pub fn get_reason(&self) -> Option<&[u8]> {
+        let (_channel, rest) = parse_helpers::split_arg(split_args(self));
+        let (_kicked, rest) = parse_helpers::split_arg(rest);
+        if rest.len() == 0 {
+            return None;
+        }
+        let (reason, _rest) = parse_helpers::split_arg(rest);
+        Some(reason)
+    }
+}
+
 
 impl KickBuf {
-    pub fn new(source: &[u8], channel: &[u8], who: &[u8], reason: Option<&[u8]>) -> Result<KickBuf, ()> {
+    pub fn new<S, C, W>(source: S, channel: C, who: W, reason: Option<&[u8]>) -> Result<KickBuf, MessageError>
+        where S: AsRef<[u8]>,
+              C: AsRef<[u8]>,
+              W: AsRef<[u8]>
+    {
+        let source = source.as_ref();
+        let channel = channel.as_ref();
+        let who = who.as_ref();
         let mut out: Vec<u8> = Vec::new();
         out.extend(b":");
         out.extend(source);
@@ -97,7 +444,7 @@ impl KickBuf {
         }
 
         // maybe we could skip this check later and turn it into a debug-assert?
-        let message = try!(IrcMsgBuf::new(out).map_err(|_| ()));
+        let message = try!(IrcMsgBuf::new(out).map_err(MessageError::from));
 
         try!(Kick::validate(&message));
         Ok(KickBuf { inner: message })
@@ -107,49 +454,155 @@ impl KickBuf {
 
 impl_irc_msg_subtype!(Mode);
 impl_irc_msg_subtype_buf!(ModeBuf, Mode);
-irc_msg_legacy_validator!(Mode, Mode);
+irc_msg_validator!(Mode, "MODE", PrefixRule::User, 2, ::std::usize::MAX);
 irc_msg_has_source!(Mode);
 irc_msg_has_target!(Mode);
 
+impl Mode {
+    /// The mode string, such as `+ov-b`.
+    pub fn get_mode_string(&self) -> &[u8] {
+        self.args().nth(1).unwrap_or(b"")
+    }
+
+    /// The arguments following the mode string, which the modes consume
+    /// in order.
+    pub fn params(&self) -> ArgumentIter {
+        let mut args = self.args();
+        let _target = args.next();
+        let _modes = args.next();
+        args
+    }
+
+    /// The mode changes, with arguments assigned according to `table`:
+    /// the channel modes for a channel, and user modes for a user.
+    pub fn changes<'a>(&'a self, table: &'a ModeTable) -> ModeChanges<'a> {
+        ModeChanges::new(table, self.get_mode_string(), self.params())
+    }
+}
+
 
 impl_irc_msg_subtype!(Nick);
 impl_irc_msg_subtype_buf!(NickBuf, Nick);
-irc_msg_legacy_validator!(Nick, Nick);
+irc_msg_validator!(Nick, "NICK", PrefixRule::User, 1, ::std::usize::MAX);
+irc_msg_has_source!(Nick);
+
+impl Nick {
+    /// The nick being changed from.
+    pub fn get_nick(&self) -> &str {
+        let (nick, _, _) = parse_helpers::parse_prefix(self.get_source()).unwrap();
+        ::std::str::from_utf8(nick).unwrap()
+    }
+
+    pub fn get_new_nick(&self) -> &[u8] {
+        let (new_nick, _rest) = parse_helpers::split_arg(split_args(self));
+        new_nick
+    }
+}
 
 
 impl_irc_msg_subtype!(Notice);
 impl_irc_msg_subtype_buf!(NoticeBuf, Notice);
-irc_msg_legacy_validator!(Notice, Notice);
+irc_msg_validator!(Notice, "NOTICE", PrefixRule::User, 2, ::std::usize::MAX);
 irc_msg_has_source!(Notice);
 irc_msg_has_target!(Notice);
 
+impl Notice {
+    pub fn get_body_raw(&self) -> &[u8] {
+        self.args().nth(1).unwrap_or(b"")
+    }
+
+    /// The notice text, as UTF-8 where valid and lossily otherwise.
+    pub fn get_body_decoded(&self) -> Cow<str> {
+        self.get_body_decoded_with(&LossyUtf8)
+    }
+
+    /// The notice text, decoding anything which isn't UTF-8 with `fallback`.
+    pub fn get_body_decoded_with<D: ?Sized + Decoder>(&self, fallback: &D) -> Cow<str> {
+        charset::decode(self.get_body_raw(), fallback)
+    }
+}
+
 
 impl_irc_msg_subtype!(Part);
 impl_irc_msg_subtype_buf!(PartBuf, Part);
-irc_msg_legacy_validator!(Part, Part);
+irc_msg_validator!(Part, "PART", PrefixRule::User, 1, ::std::usize::MAX);
 irc_msg_has_source!(Part);
 irc_msg_has_target!(Part);
 
+impl Part {
+    pub fn get_nick(&self) -> &str {
+        let (nick, _, _) = parse_helpers::parse_prefix(self.get_source()).unwrap();
+        ::std::str::from_utf8(nick).unwrap()
+    }
+
+    pub fn get_channel(&self) -> &[u8] {
+        self.get_target()
+    }
+
+    /// The part message, absent for a bare `PART #channel`.
+    pub fn get_reason(&self) -> Option<&[u8]> {
+        let (_channel, rest) = parse_helpers::split_arg(split_args(self));
+        if rest.len() == 0 {
+            return None;
+        }
+        let (reason, _rest) = parse_helpers::split_arg(rest);
+        Some(reason)
+    }
+
+    /// The part message, with invalid UTF-8 replaced.
+    pub fn get_reason_unicode(&self) -> Option<Cow<str>> {
+        self.get_reason().map(String::from_utf8_lossy)
+    }
+}
+
 
 impl_irc_msg_subtype!(Ping);
 impl_irc_msg_subtype_buf!(PingBuf, Ping);
-irc_msg_legacy_validator!(Ping, Ping);
+irc_msg_validator!(Ping, "PING", PrefixRule::Optional, 1, 2);
 
 
 impl Ping {
     pub fn source(&self) -> &[u8] {
-        // FIXME: invariant not upheld: can panic
-        self.args().nth(0).unwrap()
+        self.get_token()
+    }
+
+    /// The token to echo back, usually the server's name.
+    pub fn get_token(&self) -> &[u8] {
+        self.args().next().unwrap()
+    }
+
+    pub fn get_server2(&self) -> Option<&[u8]> {
+        self.args().nth(1)
     }
 
     pub fn response(&self) -> client::PongBuf {
-        client::PongBuf::new(self.source()).unwrap()
+        self.response_buf()
+    }
+
+    /// The PONG answering this PING, with its arguments echoed back byte
+    /// for byte.
+    pub fn response_buf(&self) -> client::PongBuf {
+        let (_prefix, rest) = parse_helpers::split_prefix(self.as_bytes());
+        let (_command, args) = parse_helpers::split_command(rest);
+        // Validation leaves one or two arguments and no line breaks.
+        client::PongBuf::echo(args).unwrap()
     }
 }
 
 impl_irc_msg_subtype!(Pong);
 impl_irc_msg_subtype_buf!(PongBuf, Pong);
-irc_msg_legacy_validator!(Pong, Pong);
+irc_msg_validator!(Pong, "PONG", PrefixRule::Optional, 1, 2);
+
+impl Pong {
+    /// The token echoed back from our PING.
+    pub fn get_token(&self) -> &[u8] {
+        self.args().next().unwrap()
+    }
+
+    pub fn get_server2(&self) -> Option<&[u8]> {
+        self.args().nth(1)
+    }
+}
 
 
 impl_irc_msg_subtype!(Privmsg);
@@ -159,28 +612,28 @@ irc_msg_has_target!(Privmsg);
 
 
 impl Privmsg {
-    pub fn validate(msg: &IrcMsg) -> Result<(), ()> {
+    pub fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
         use std::ascii::AsciiExt;
 
         let buf = msg.as_bytes();
 
         let (prefix, rest) = parse_helpers::split_prefix(buf); if prefix.len()
-        == 0 {     return Err(()); } if
-        !parse_helpers::is_valid_prefix(prefix) {     return Err(()); }
+        == 0 {     return Err(MessageError::BadPrefix); } if
+        !parse_helpers::is_valid_prefix(prefix) {     return Err(MessageError::BadPrefix); }
 
         let (command, rest) = parse_helpers::split_command(rest);
         if !AsciiExt::eq_ignore_ascii_case(command, b"PRIVMSG") {
-            return Err(());
+            return Err(MessageError::WrongCommand);
         }
 
         let (target, rest) = parse_helpers::split_arg(rest);
         if target.len() < 1 {
-            return Err(());
+            return Err(MessageError::MissingArgument);
         }
 
         let (_body, must_be_empty) = parse_helpers::split_arg(rest);
         if must_be_empty.len() != 0 {
-            return Err(());
+            return Err(MessageError::TooManyArguments);
         }
 
         Ok(())
@@ -209,30 +662,31 @@ impl Privmsg {
 
         body
     }
+
+    /// The message text, as UTF-8 where valid and lossily otherwise.
+    pub fn get_body_decoded(&self) -> Cow<str> {
+        self.get_body_decoded_with(&LossyUtf8)
+    }
+
+    /// The message text, decoding anything which isn't UTF-8 with `fallback`.
+    pub fn get_body_decoded_with<D: ?Sized + Decoder>(&self, fallback: &D) -> Cow<str> {
+        charset::decode(self.get_body_raw(), fallback)
+    }
 }
 
 impl PrivmsgBuf {
-    pub fn new(source: &[u8], target: &[u8], body: &[u8]) -> Result<PrivmsgBuf, ()> {
-        let mut out: Vec<u8> = Vec::new();
-        out.extend(b":");
-        out.extend(source);
-        out.extend(b" PRIVMSG ");
-        out.extend(target);
-        out.extend(b" :");
-        out.extend(body);
-
-        // XXX: IrcMsgBuf should be validating this...
-        for byte in out.iter() {
-            match *byte {
-                b'\0' => return Err(()),
-                b'\r' => return Err(()),
-                b'\n' => return Err(()),
-                _ => (),
-            }
-        }
-
-        // maybe we could skip this check later and turn it into a debug-assert?
-        let message = try!(IrcMsgBuf::new(out).map_err(|_| ()));
+    pub fn new<S, T, B>(source: S, target: T, body: B) -> Result<PrivmsgBuf, MessageError>
+        where S: AsRef<[u8]>,
+              T: AsRef<[u8]>,
+              B: AsRef<[u8]>
+    {
+        let message = try!(IrcMsgBuilder::new()
+            .prefix(source)
+            .command("PRIVMSG")
+            .arg(target)
+            .trailing(body)
+            .build()
+            .map_err(MessageError::from));
         try!(Privmsg::validate(&message));
         Ok(PrivmsgBuf { inner: message })
     }
@@ -241,17 +695,95 @@ impl PrivmsgBuf {
 
 impl_irc_msg_subtype!(Topic);
 impl_irc_msg_subtype_buf!(TopicBuf, Topic);
-irc_msg_legacy_validator!(Topic, Topic);
 irc_msg_has_source!(Topic);
 irc_msg_has_target!(Topic);
 
+/// A user (or server) changing the topic of a channel.  Topic numerics
+/// (332 RPL_TOPIC, 333 RPL_TOPICWHOTIME) are not `Topic` messages.
 impl Topic {
+    pub fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        let buf = msg.as_bytes();
+
+        let (prefix, rest) = parse_helpers::split_prefix(buf);
+        if prefix.len() == 0 {
+            return Err(MessageError::BadPrefix);
+        }
+
+        let (command, rest) = parse_helpers::split_command(rest);
+        if !command.eq_ignore_ascii_case(b"TOPIC") {
+            return Err(MessageError::WrongCommand);
+        }
+
+        let (channel, rest) = parse_helpers::split_arg(rest);
+        if channel.len() < 1 {
+            return Err(MessageError::MissingArgument);
+        }
+
+        // The text is always present, but may be empty when the topic is cleared.
+        if rest.len() == 0 {
+            return Err(MessageError::MissingArgument);
+        }
+        let (_text, must_be_empty) = parse_helpers::split_arg(rest);
+        if must_be_empty.len() != 0 {
+            return Err(MessageError::TooManyArguments);
+        }
+
+        Ok(())
+    }
+
+    /// The channel whose topic is being changed
+    pub fn get_channel(&self) -> &[u8] {
+        self.get_target()
+    }
+
+    /// The new topic text.  This is empty if the topic was cleared.
+    pub fn get_text(&self) -> &[u8] {
+        let buf = self.as_bytes();
+        let (_prefix, rest) = parse_helpers::split_prefix(buf);
+        let (_command, rest) = parse_helpers::split_command(rest);
+        let (_channel, rest) = parse_helpers::split_arg(rest);
+        let (text, _rest) = parse_helpers::split_arg(rest);
+        text
+    }
+
+    /// The new topic text, as UTF-8 where valid and lossily otherwise.
+    pub fn get_body_decoded(&self) -> Cow<str> {
+        self.get_body_decoded_with(&LossyUtf8)
+    }
+
+    /// The new topic text, decoding anything which isn't UTF-8 with `fallback`.
+    pub fn get_body_decoded_with<D: ?Sized + Decoder>(&self, fallback: &D) -> Cow<str> {
+        charset::decode(self.get_text(), fallback)
+    }
+}
+
+impl TopicBuf {
+    pub fn new<S, C, T>(source: S, channel: C, text: T) -> Result<TopicBuf, MessageError>
+        where S: AsRef<[u8]>,
+              C: AsRef<[u8]>,
+              T: AsRef<[u8]>
+    {
+        let source = source.as_ref();
+        let channel = channel.as_ref();
+        let text = text.as_ref();
+        let mut out: Vec<u8> = Vec::new();
+        out.extend(b":");
+        out.extend(source);
+        out.extend(b" TOPIC ");
+        out.extend(channel);
+        out.extend(b" :");
+        out.extend(text);
+
+        let message = try!(IrcMsgBuf::new(out).map_err(MessageError::from));
+        try!(Topic::validate(&message));
+        Ok(TopicBuf { inner: message })
+    }
 }
 
 
 impl_irc_msg_subtype!(Quit);
 impl_irc_msg_subtype_buf!(QuitBuf, Quit);
-irc_msg_legacy_validator!(Quit, Quit);
+irc_msg_validator!(Quit, "QUIT", PrefixRule::Optional, 0, 1);
 irc_msg_has_source!(Quit);
 
 
@@ -266,6 +798,202 @@ impl Quit {
         let nick = source.splitn(2, |x| *x == b'!').nth(0).unwrap();
         ::std::str::from_utf8(nick).unwrap()
     }
+
+    /// The nick which quit, or `None` if the prefix isn't a full
+    /// `nick!user@host`.
+    pub fn get_nick(&self) -> Option<&str> {
+        parse_helpers::parse_prefix(self.get_source()).ok()
+            .and_then(|(nick, _, _)| ::std::str::from_utf8(nick).ok())
+    }
+
+    /// The quit message, absent for a bare `QUIT`.
+    pub fn get_reason(&self) -> Option<&[u8]> {
+        let args = split_args(self);
+        if args.len() == 0 {
+            return None;
+        }
+        let (reason, _rest) = parse_helpers::split_arg(args);
+        Some(reason)
+    }
+
+    /// The quit message, with invalid UTF-8 replaced.
+    pub fn get_reason_unicode(&self) -> Option<Cow<str>> {
+        self.get_reason().map(String::from_utf8_lossy)
+    }
+}
+
+
+impl_irc_msg_subtype!(Wallops);
+impl_irc_msg_subtype_buf!(WallopsBuf, Wallops);
+irc_msg_has_source!(Wallops);
+
+/// A message to operators and users with mode `+w`.
+impl Wallops {
+    pub fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        validate_command(msg, "WALLOPS", PrefixRule::Required, 1, 1)
+    }
+
+    pub fn get_text(&self) -> &[u8] {
+        let (text, _rest) = parse_helpers::split_arg(split_args(self));
+        text
+    }
+}
+
+impl WallopsBuf {
+    pub fn new<S, T>(source: S, text: T) -> Result<WallopsBuf, MessageError>
+        where S: AsRef<[u8]>,
+              T: AsRef<[u8]>
+    {
+        let message = try!(IrcMsgBuilder::new()
+            .prefix(source)
+            .command("WALLOPS")
+            .trailing(text)
+            .build()
+            .map_err(MessageError::from));
+        try!(Wallops::validate(&message));
+        Ok(WallopsBuf { inner: message })
+    }
+}
+
+#[test]
+fn ircv3_notifications() {
+    let msg = IrcMsg::new(b":n!u@h ACCOUNT alice").unwrap();
+    assert_eq!(msg.as_tymsg::<&Account>().unwrap().get_account(), Some(&b"alice"[..]));
+    let logout = AccountBuf::new("n!u@h", None).unwrap();
+    assert_eq!(logout.as_bytes(), b":n!u@h ACCOUNT *");
+    assert_eq!(logout.get_account(), None);
+
+    let away = AwayBuf::new("n!u@h", Some(b"lunch")).unwrap();
+    assert_eq!(away.as_bytes(), b":n!u@h AWAY :lunch");
+    assert_eq!(away.get_message(), Some(&b"lunch"[..]));
+    let back = IrcMsg::new(b":n!u@h AWAY").unwrap().as_tymsg::<&Away>().unwrap();
+    assert!(!back.is_away());
+
+    let chghost = ChghostBuf::new("n!u@h", "user", "new.host").unwrap();
+    assert_eq!(chghost.get_new_user(), b"user");
+    assert_eq!(chghost.get_new_host(), b"new.host");
+    assert!(IrcMsg::new(b":n!u@h CHGHOST user").unwrap().as_tymsg::<&Chghost>().is_err());
+    assert!(IrcMsg::new(b"ACCOUNT alice").unwrap().as_tymsg::<&Account>().is_err());
+}
+
+#[test]
+fn error_and_wallops() {
+    let msg = IrcMsg::new(b"ERROR :Closing Link: host (Ping timeout)").unwrap();
+    let error = msg.as_tymsg::<&ServerError>().unwrap();
+    assert_eq!(error.get_message(), b"Closing Link: host (Ping timeout)");
+    assert_eq!(ServerErrorBuf::new("bye").unwrap().as_bytes(), b"ERROR :bye");
+
+    let wallops = WallopsBuf::new("oper!o@h", "server restarting").unwrap();
+    assert_eq!(wallops.get_text(), b"server restarting");
+    assert_eq!(wallops.get_source(), b":oper!o@h");
+    assert!(IrcMsg::new(b"WALLOPS :x").unwrap().as_tymsg::<&Wallops>().is_err());
+}
+
+#[test]
+fn validation_does_not_allocate() {
+    use ::testinfra::alloc::count_allocations;
+
+    let join = IrcMsg::new(b":n!u@h JOIN #rust").unwrap();
+    let kick = IrcMsg::new(b":n!u@h KICK #rust you :bye").unwrap();
+    let ping = IrcMsg::new(b"PING :irc.example.net").unwrap();
+    assert_eq!(count_allocations(|| {
+        assert!(join.as_tymsg::<&Join>().is_ok());
+        assert!(kick.as_tymsg::<&Kick>().is_ok());
+        assert!(ping.as_tymsg::<&Ping>().is_ok());
+        assert!(ping.as_tymsg::<&Pong>().is_err());
+    }), 0);
+
+    // JOIN needs a full nick!user@host prefix; KICK needs a nick to kick.
+    assert!(IrcMsg::new(b":irc.example.net JOIN #rust").unwrap().as_tymsg::<&Join>().is_err());
+    assert!(IrcMsg::new(b":n!u@h KICK #rust").unwrap().as_tymsg::<&Kick>().is_err());
+
+    // The nick accessors return `&str`, so nicks must be UTF-8.
+    assert!(IrcMsg::new(b":\xff!u@h JOIN #rust").unwrap().as_tymsg::<&Join>().is_err());
+    assert!(IrcMsg::new(b":\xff!u@h PART #rust").unwrap().as_tymsg::<&Part>().is_err());
+    assert!(IrcMsg::new(b":\xff!u@h NICK bob").unwrap().as_tymsg::<&Nick>().is_err());
+    assert!(IrcMsg::new(b":n!\xff@h JOIN #rust").unwrap().as_tymsg::<&Join>().is_ok());
+}
+
+#[test]
+fn quit_reason() {
+    let msg = IrcMsg::new(b":n!u@h QUIT :Ping timeout").unwrap();
+    assert_eq!(msg.as_tymsg::<&Quit>().unwrap().get_reason(), Some(&b"Ping timeout"[..]));
+
+    let msg = IrcMsg::new(b":n!u@h QUIT").unwrap();
+    let quit = msg.as_tymsg::<&Quit>().unwrap();
+    assert_eq!(quit.source_nick(), "n");
+    assert_eq!(quit.get_nick(), Some("n"));
+    assert_eq!(quit.get_reason(), None);
+    assert_eq!(quit.get_reason_unicode(), None);
+
+    let msg = IrcMsg::new(b":n!u@h QUIT :caf\xe9").unwrap();
+    assert_eq!(msg.as_tymsg::<&Quit>().unwrap().get_reason_unicode().unwrap(), "caf\u{fffd}");
+    let msg = IrcMsg::new(b"QUIT :bye").unwrap();
+    assert_eq!(msg.as_tymsg::<&Quit>().unwrap().get_nick(), None);
+}
+
+#[test]
+fn decoded_bodies() {
+    let msg = IrcMsg::new(b":n!u@h PRIVMSG #c :caf\xc3\xa9").unwrap();
+    assert_eq!(msg.as_tymsg::<&Privmsg>().unwrap().get_body_decoded(), "caf\u{e9}");
+    let msg = IrcMsg::new(b":n!u@h NOTICE #c :caf\xe9").unwrap();
+    assert_eq!(msg.as_tymsg::<&Notice>().unwrap().get_body_decoded(), "caf\u{fffd}");
+    let msg = IrcMsg::new(b":n!u@h TOPIC #c :caf\xe9").unwrap();
+    let topic = msg.as_tymsg::<&Topic>().unwrap();
+    assert_eq!(topic.get_body_decoded(), "caf\u{fffd}");
+
+    #[cfg(feature = "latin1")]
+    assert_eq!(topic.get_body_decoded_with(&::charset::Latin1), "caf\u{e9}");
+}
+
+#[test]
+fn part_reason() {
+    let msg = IrcMsg::new(b":n!u@h PART #rust :gone fishing").unwrap();
+    let part = msg.as_tymsg::<&Part>().unwrap();
+    assert_eq!(part.get_nick(), "n");
+    assert_eq!(part.get_target(), b"#rust");
+    assert_eq!(part.get_reason(), Some(&b"gone fishing"[..]));
+    assert_eq!(part.get_reason_unicode().unwrap(), "gone fishing");
+
+    let msg = IrcMsg::new(b":n!u@h PART #rust").unwrap();
+    let part = msg.as_tymsg::<&Part>().unwrap();
+    assert_eq!(part.get_reason(), None);
+    assert_eq!(part.get_reason_unicode(), None);
+}
+
+#[test]
+fn extended_join() {
+    let msg = IrcMsg::new(b":n!u@h JOIN #rust acct :Real Name").unwrap();
+    let join = msg.as_tymsg::<&Join>().unwrap();
+    assert!(join.is_extended());
+    assert_eq!(join.get_account(), Some(&b"acct"[..]));
+    assert_eq!(join.get_realname(), Some(&b"Real Name"[..]));
+
+    let msg = IrcMsg::new(b":n!u@h JOIN #rust * :Real Name").unwrap();
+    assert_eq!(msg.as_tymsg::<&Join>().unwrap().get_account(), None);
+
+    let msg = IrcMsg::new(b":n!u@h JOIN #rust").unwrap();
+    let join = msg.as_tymsg::<&Join>().unwrap();
+    assert!(!join.is_extended());
+    assert_eq!(join.get_account(), None);
+    assert_eq!(join.get_realname(), None);
+}
+
+#[test]
+fn kick_and_nick_accessors() {
+    let msg = IrcMsg::new(b":op!u@h KICK #rust you :bye").unwrap();
+    let kick = msg.as_tymsg::<&Kick>().unwrap();
+    assert_eq!(kick.get_nick(), "op");
+    assert_eq!(kick.get_channel(), b"#rust");
+    assert_eq!(kick.get_kicked_nick(), b"you");
+    assert_eq!(kick.get_reason(), Some(&b"bye"[..]));
+    let msg = IrcMsg::new(b":op!u@h KICK #rust you").unwrap();
+    assert_eq!(msg.as_tymsg::<&Kick>().unwrap().get_reason(), None);
+
+    let msg = IrcMsg::new(b":old!u@h NICK :new").unwrap();
+    let nick = msg.as_tymsg::<&Nick>().unwrap();
+    assert_eq!(nick.get_nick(), "old");
+    assert_eq!(nick.get_new_nick(), b"new");
 }
 
 #[test]
@@ -284,6 +1012,17 @@ fn kick_derefs() {
     assert_eq!("KICK", kick.get_command());
 }
 
+#[test]
+fn constructors_take_str_and_bytes() {
+    let channel = String::from("#foo");
+    let from_str = PrivmsgBuf::new("n!u@h", &channel, "hello").unwrap();
+    let from_bytes = PrivmsgBuf::new(b"n!u@h", b"#foo", b"hello").unwrap();
+    assert_eq!(from_str.as_bytes(), from_bytes.as_bytes());
+
+    let kick = KickBuf::new("n!u@h", channel, "you", Some(b"bye")).unwrap();
+    assert_eq!(kick.as_bytes(), b":n!u@h KICK #foo you :bye");
+}
+
 #[test]
 fn kickbufs_from_borrowed() {
     let kick = KickBuf::new(b"n!u@h", b"#foo", b"you", Some(b"BREAKIN DA RULEZ")).unwrap();
@@ -374,6 +1113,42 @@ fn privmsg_lowercase() {
     let _privmsg = Privmsg::parse(b":n!u@h privmsg #target :body").unwrap();
 }
 
+#[test]
+fn topic_accessors() {
+    let topic = Topic::parse(b":n!u@h TOPIC #foo :a new topic").unwrap();
+    assert_eq!(topic.get_channel(), b"#foo");
+    assert_eq!(topic.get_text(), b"a new topic");
+
+    let cleared = Topic::parse(b":n!u@h TOPIC #foo :").unwrap();
+    assert_eq!(cleared.get_text(), b"");
+
+    assert!(Topic::parse(b":n!u@h TOPIC #foo").is_err());
+    assert!(Topic::parse(b":server 332 nick #foo :a topic").is_err());
+
+    let topic = TopicBuf::new(b"n!u@h", b"#foo", b"hello").unwrap();
+    assert_eq!(topic.get_text(), b"hello");
+}
+
+#[test]
+fn ping_pong_accessors() {
+    let ping = Ping::parse(b"PING :irc.example.net").unwrap();
+    assert_eq!(ping.get_token(), b"irc.example.net");
+    assert_eq!(ping.get_server2(), None);
+    assert_eq!(ping.response_buf().as_bytes(), b"PONG :irc.example.net");
+
+    let ping = Ping::parse(b":srv PING :token with spaces").unwrap();
+    assert_eq!(ping.get_token(), b"token with spaces");
+    assert_eq!(ping.response_buf().as_bytes(), b"PONG :token with spaces");
+
+    let ping = Ping::parse(b"PING a.example b.example").unwrap();
+    assert_eq!(ping.get_server2(), Some(&b"b.example"[..]));
+    assert_eq!(ping.response_buf().as_bytes(), b"PONG a.example b.example");
+
+    let pong = Pong::parse(b":srv PONG srv :tok").unwrap();
+    assert_eq!(pong.get_token(), b"srv");
+    assert_eq!(pong.get_server2(), Some(&b"tok"[..]));
+}
+
 #[test]
 fn construct_join_on_stack() {
     let mut my_mem = [0; 1024];
@@ -0,0 +1,268 @@
+//! Walking the changes in a MODE message.
+//!
+//! Which modes take an argument depends on the server, so parsing needs
+//! the CHANMODES and PREFIX tokens from ISUPPORT, kept in a `ModeTable`.
+//! User modes are described separately, by the USERMODES token where
+//! servers send it.
+
+use ::parse::ArgumentIter;
+
+/// Parameters used by servers that don't advertise their own.
+const DEFAULT_CHANMODES: &'static str = "beI,k,l,imnpst";
+const DEFAULT_PREFIX: &'static str = "(ov)@+";
+/// Only the server notice mask takes an argument, when set.
+const DEFAULT_USERMODES: &'static str = ",,s,";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Sign {
+    Plus,
+    Minus,
+}
+
+/// How a channel mode takes its argument.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ModeKind {
+    /// Modes which maintain a list, such as bans; always has an argument
+    List,
+    /// Always has an argument, such as the key
+    Always,
+    /// Has an argument only when set, such as the limit
+    OnSet,
+    /// Never has an argument
+    Never,
+    /// A membership prefix, such as op; always has a nick argument
+    Prefix,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ModeTable {
+    list: Vec<u8>,
+    always: Vec<u8>,
+    on_set: Vec<u8>,
+    never: Vec<u8>,
+    /// Prefix modes and their symbols, highest rank first.
+    prefixes: Vec<(u8, u8)>,
+}
+
+impl ModeTable {
+    /// The table for a server which does not advertise CHANMODES or
+    /// PREFIX: `beI,k,l,imnpst` and `(ov)@+`.
+    pub fn new() -> ModeTable {
+        ModeTable::from_isupport(DEFAULT_CHANMODES, DEFAULT_PREFIX).unwrap()
+    }
+
+    /// The table of user modes for a server which does not advertise
+    /// USERMODES, where `s` takes the server notice mask when set.
+    pub fn user_modes() -> ModeTable {
+        ModeTable::from_isupport(DEFAULT_USERMODES, "").unwrap()
+    }
+
+    /// Build a table from the values of the CHANMODES and PREFIX tokens.
+    /// USERMODES takes the form of CHANMODES, with an empty PREFIX.
+    pub fn from_isupport(chanmodes: &str, prefix: &str) -> Result<ModeTable, ()> {
+        let mut table = ModeTable {
+            list: Vec::new(),
+            always: Vec::new(),
+            on_set: Vec::new(),
+            never: Vec::new(),
+            prefixes: Vec::new(),
+        };
+        try!(table.set_chanmodes(chanmodes));
+        try!(table.set_prefix(prefix));
+        Ok(table)
+    }
+
+    /// Replace the channel modes with a CHANMODES value, such as
+    /// `eIbq,k,flj,CFLMPQScgimnprstz`.  Groups beyond the fourth are
+    /// ignored, as the specification asks.
+    pub fn set_chanmodes(&mut self, chanmodes: &str) -> Result<(), ()> {
+        let groups: Vec<&str> = chanmodes.split(',').collect();
+        if groups.len() < 4 {
+            return Err(());
+        }
+        self.list = groups[0].bytes().collect();
+        self.always = groups[1].bytes().collect();
+        self.on_set = groups[2].bytes().collect();
+        self.never = groups[3].bytes().collect();
+        Ok(())
+    }
+
+    /// Replace the prefix modes with a PREFIX value, such as
+    /// `(qaohv)~&@%+`.  An empty value means there are none.
+    pub fn set_prefix(&mut self, prefix: &str) -> Result<(), ()> {
+        if prefix.is_empty() {
+            self.prefixes.clear();
+            return Ok(());
+        }
+        if !prefix.starts_with('(') {
+            return Err(());
+        }
+        let close = try!(prefix.find(')').ok_or(()));
+        let modes = &prefix.as_bytes()[1..close];
+        let symbols = &prefix.as_bytes()[close + 1..];
+        if modes.len() != symbols.len() {
+            return Err(());
+        }
+        self.prefixes = modes.iter().cloned().zip(symbols.iter().cloned()).collect();
+        Ok(())
+    }
+
+    pub fn kind(&self, mode: u8) -> Option<ModeKind> {
+        if self.prefixes.iter().any(|&(m, _)| m == mode) {
+            Some(ModeKind::Prefix)
+        } else if self.list.contains(&mode) {
+            Some(ModeKind::List)
+        } else if self.always.contains(&mode) {
+            Some(ModeKind::Always)
+        } else if self.on_set.contains(&mode) {
+            Some(ModeKind::OnSet)
+        } else if self.never.contains(&mode) {
+            Some(ModeKind::Never)
+        } else {
+            None
+        }
+    }
+
+    /// Whether `mode`, changed in the direction of `sign`, consumes an
+    /// argument.  Unknown modes are assumed not to.
+    pub fn takes_arg(&self, sign: Sign, mode: u8) -> bool {
+        match self.kind(mode) {
+            Some(ModeKind::List) | Some(ModeKind::Always) | Some(ModeKind::Prefix) => true,
+            Some(ModeKind::OnSet) => sign == Sign::Plus,
+            Some(ModeKind::Never) | None => false,
+        }
+    }
+
+    /// The prefix symbol for a prefix mode, as in `@` for `o`.
+    pub fn prefix_symbol(&self, mode: u8) -> Option<u8> {
+        self.prefixes.iter().find(|&&(m, _)| m == mode).map(|&(_, symbol)| symbol)
+    }
+
+    /// The prefix mode for a symbol, as in `o` for `@`.
+    pub fn prefix_mode(&self, symbol: u8) -> Option<u8> {
+        self.prefixes.iter().find(|&&(_, s)| s == symbol).map(|&(mode, _)| mode)
+    }
+
+    /// The prefix modes, highest rank first.
+    pub fn prefix_modes(&self) -> Vec<u8> {
+        self.prefixes.iter().map(|&(mode, _)| mode).collect()
+    }
+}
+
+impl Default for ModeTable {
+    fn default() -> ModeTable {
+        ModeTable::new()
+    }
+}
+
+/// The changes in a mode string with their arguments, as
+/// `(sign, mode, argument)`.  A mode whose argument is missing is yielded
+/// with `None`.
+pub struct ModeChanges<'a> {
+    table: &'a ModeTable,
+    modes: &'a [u8],
+    params: ArgumentIter<'a>,
+    sign: Sign,
+}
+
+impl<'a> ModeChanges<'a> {
+    pub fn new(table: &'a ModeTable, modes: &'a [u8], params: ArgumentIter<'a>) -> ModeChanges<'a> {
+        ModeChanges {
+            table: table,
+            modes: modes,
+            params: params,
+            sign: Sign::Plus,
+        }
+    }
+}
+
+impl<'a> Iterator for ModeChanges<'a> {
+    type Item = (Sign, char, Option<&'a [u8]>);
+
+    fn next(&mut self) -> Option<(Sign, char, Option<&'a [u8]>)> {
+        loop {
+            let (&mode, rest) = match self.modes.split_first() {
+                Some(split) => split,
+                None => return None,
+            };
+            self.modes = rest;
+            match mode {
+                b'+' => self.sign = Sign::Plus,
+                b'-' => self.sign = Sign::Minus,
+                mode => {
+                    let arg = if self.table.takes_arg(self.sign, mode) {
+                        self.params.next()
+                    } else {
+                        None
+                    };
+                    return Some((self.sign, mode as char, arg));
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::IrcMsg;
+    use super::super::Mode;
+    use super::{ModeKind, ModeTable, Sign};
+
+    fn mode(line: &[u8]) -> &Mode {
+        IrcMsg::new(line).unwrap().as_tymsg::<&Mode>().unwrap()
+    }
+
+    #[test]
+    fn mode_changes() {
+        let table = ModeTable::new();
+        let changes: Vec<_> = mode(b":n!u@h MODE #c +ov-b+lk alice bob *!*@bad 10 :sekrit")
+            .changes(&table).collect();
+        assert_eq!(changes, vec![
+            (Sign::Plus, 'o', Some(&b"alice"[..])),
+            (Sign::Plus, 'v', Some(&b"bob"[..])),
+            (Sign::Minus, 'b', Some(&b"*!*@bad"[..])),
+            (Sign::Plus, 'l', Some(&b"10"[..])),
+            (Sign::Plus, 'k', Some(&b"sekrit"[..])),
+        ]);
+
+        let msg = mode(b":n!u@h MODE #c +ov-b+lk alice bob *!*@bad 10 :sekrit");
+        assert_eq!(msg.get_mode_string(), b"+ov-b+lk");
+        assert_eq!(msg.params().collect::<Vec<_>>(),
+            vec![&b"alice"[..], b"bob", b"*!*@bad", b"10", b"sekrit"]);
+
+        let changes: Vec<_> = mode(b":n!u@h MODE #c -lt+m").changes(&table).collect();
+        assert_eq!(changes, vec![
+            (Sign::Minus, 'l', None),
+            (Sign::Minus, 't', None),
+            (Sign::Plus, 'm', None),
+        ]);
+    }
+
+    #[test]
+    fn mode_table_from_isupport() {
+        let table = ModeTable::from_isupport("eIbq,k,flj,CFLMPQScgimnprstz", "(qaohv)~&@%+").unwrap();
+        assert_eq!(table.kind(b'q'), Some(ModeKind::Prefix));
+        assert_eq!(table.kind(b'f'), Some(ModeKind::OnSet));
+        assert_eq!(table.prefix_symbol(b'h'), Some(b'%'));
+        assert_eq!(table.prefix_mode(b'~'), Some(b'q'));
+        assert!(!table.takes_arg(Sign::Minus, b'j'));
+
+        let changes: Vec<_> = mode(b":n!u@h MODE #c +qhj alice bob 3:5")
+            .changes(&table).map(|(_, m, arg)| (m, arg.is_some())).collect();
+        assert_eq!(changes, vec![('q', true), ('h', true), ('j', true)]);
+
+        let user_modes = ModeTable::user_modes();
+        let changes: Vec<_> = mode(b":oper!u@h MODE oper +os +cC")
+            .changes(&user_modes).collect();
+        assert_eq!(changes, vec![
+            (Sign::Plus, 'o', None),
+            (Sign::Plus, 's', Some(&b"+cC"[..])),
+        ]);
+        assert!(user_modes.prefix_modes().is_empty());
+
+        assert!(ModeTable::from_isupport("b,k,l", "(ov)@+").is_err());
+        assert!(ModeTable::from_isupport("b,k,l,imnt", "(ov)@").is_err());
+    }
+}
@@ -0,0 +1,340 @@
+//! Numeric replies.
+//!
+//! `Numeric` names the well-known reply codes.  The most common replies
+//! also have typed views exposing their fields, so callers don't need to
+//! count arguments by hand; `NumericReply` covers the rest.
+
+use std::borrow::{Borrow, ToOwned};
+use std::{mem, ops};
+
+use ::{IrcMsg, IrcMsgBuf};
+use ::parse::ArgumentIter;
+use ::parse_helpers;
+use super::super::{FromIrcMsg, MessageError};
+
+macro_rules! numerics {
+    ($($variant:ident = $code:expr, $name:expr;)*) => {
+        /// Well-known numeric replies.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub enum Numeric {
+            $($variant,)*
+        }
+
+        impl Numeric {
+            pub fn from_code(code: u16) -> Option<Numeric> {
+                match code {
+                    $($code => Some(Numeric::$variant),)*
+                    _ => None,
+                }
+            }
+
+            pub fn code(&self) -> u16 {
+                match *self {
+                    $(Numeric::$variant => $code,)*
+                }
+            }
+
+            /// The conventional name, such as `RPL_WELCOME`.
+            pub fn name(&self) -> &'static str {
+                match *self {
+                    $(Numeric::$variant => $name,)*
+                }
+            }
+        }
+    }
+}
+
+numerics! {
+    RplWelcome = 1, "RPL_WELCOME";
+    RplYourhost = 2, "RPL_YOURHOST";
+    RplCreated = 3, "RPL_CREATED";
+    RplMyinfo = 4, "RPL_MYINFO";
+    RplIsupport = 5, "RPL_ISUPPORT";
+    RplSnomask = 8, "RPL_SNOMASK";
+    RplUmodeis = 221, "RPL_UMODEIS";
+    RplAway = 301, "RPL_AWAY";
+    RplUnaway = 305, "RPL_UNAWAY";
+    RplNowaway = 306, "RPL_NOWAWAY";
+    RplWhoisuser = 311, "RPL_WHOISUSER";
+    RplWhoisserver = 312, "RPL_WHOISSERVER";
+    RplWhoisoperator = 313, "RPL_WHOISOPERATOR";
+    RplEndofwho = 315, "RPL_ENDOFWHO";
+    RplWhoisidle = 317, "RPL_WHOISIDLE";
+    RplEndofwhois = 318, "RPL_ENDOFWHOIS";
+    RplWhoischannels = 319, "RPL_WHOISCHANNELS";
+    RplListstart = 321, "RPL_LISTSTART";
+    RplList = 322, "RPL_LIST";
+    RplListend = 323, "RPL_LISTEND";
+    RplChannelmodeis = 324, "RPL_CHANNELMODEIS";
+    RplCreationtime = 329, "RPL_CREATIONTIME";
+    RplWhoisaccount = 330, "RPL_WHOISACCOUNT";
+    RplNotopic = 331, "RPL_NOTOPIC";
+    RplTopic = 332, "RPL_TOPIC";
+    RplTopicwhotime = 333, "RPL_TOPICWHOTIME";
+    RplInviting = 341, "RPL_INVITING";
+    RplWhoreply = 352, "RPL_WHOREPLY";
+    RplNamreply = 353, "RPL_NAMREPLY";
+    RplEndofnames = 366, "RPL_ENDOFNAMES";
+    RplBanlist = 367, "RPL_BANLIST";
+    RplEndofbanlist = 368, "RPL_ENDOFBANLIST";
+    RplMotd = 372, "RPL_MOTD";
+    RplMotdstart = 375, "RPL_MOTDSTART";
+    RplEndofmotd = 376, "RPL_ENDOFMOTD";
+    RplYoureoper = 381, "RPL_YOUREOPER";
+    RplHosthidden = 396, "RPL_HOSTHIDDEN";
+    ErrNosuchnick = 401, "ERR_NOSUCHNICK";
+    ErrNosuchserver = 402, "ERR_NOSUCHSERVER";
+    ErrNosuchchannel = 403, "ERR_NOSUCHCHANNEL";
+    ErrCannotsendtochan = 404, "ERR_CANNOTSENDTOCHAN";
+    ErrToomanychannels = 405, "ERR_TOOMANYCHANNELS";
+    ErrUnknowncommand = 421, "ERR_UNKNOWNCOMMAND";
+    ErrNomotd = 422, "ERR_NOMOTD";
+    ErrNonicknamegiven = 431, "ERR_NONICKNAMEGIVEN";
+    ErrErroneusnickname = 432, "ERR_ERRONEUSNICKNAME";
+    ErrNicknameinuse = 433, "ERR_NICKNAMEINUSE";
+    ErrNickcollision = 436, "ERR_NICKCOLLISION";
+    ErrUnavailresource = 437, "ERR_UNAVAILRESOURCE";
+    ErrUsernotinchannel = 441, "ERR_USERNOTINCHANNEL";
+    ErrNotonchannel = 442, "ERR_NOTONCHANNEL";
+    ErrUseronchannel = 443, "ERR_USERONCHANNEL";
+    ErrNotregistered = 451, "ERR_NOTREGISTERED";
+    ErrNeedmoreparams = 461, "ERR_NEEDMOREPARAMS";
+    ErrAlreadyregistered = 462, "ERR_ALREADYREGISTERED";
+    ErrPasswdmismatch = 464, "ERR_PASSWDMISMATCH";
+    ErrYourebannedcreep = 465, "ERR_YOUREBANNEDCREEP";
+    ErrChannelisfull = 471, "ERR_CHANNELISFULL";
+    ErrInviteonlychan = 473, "ERR_INVITEONLYCHAN";
+    ErrBannedfromchan = 474, "ERR_BANNEDFROMCHAN";
+    ErrBadchannelkey = 475, "ERR_BADCHANNELKEY";
+    ErrNoprivileges = 481, "ERR_NOPRIVILEGES";
+    ErrChanoprivsneeded = 482, "ERR_CHANOPRIVSNEEDED";
+    ErrRestricted = 484, "ERR_RESTRICTED";
+    RplLoggedin = 900, "RPL_LOGGEDIN";
+    RplLoggedout = 901, "RPL_LOGGEDOUT";
+    ErrNicklocked = 902, "ERR_NICKLOCKED";
+    RplSaslsuccess = 903, "RPL_SASLSUCCESS";
+    ErrSaslfail = 904, "ERR_SASLFAIL";
+    ErrSasltoolong = 905, "ERR_SASLTOOLONG";
+    ErrSaslaborted = 906, "ERR_SASLABORTED";
+    ErrSaslalready = 907, "ERR_SASLALREADY";
+    RplSaslmechs = 908, "RPL_SASLMECHS";
+}
+
+impl Numeric {
+    /// The numeric `msg` carries, if it is a well-known one.
+    pub fn from_msg(msg: &IrcMsg) -> Option<Numeric> {
+        numeric_code(msg).and_then(Numeric::from_code)
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.name().starts_with("ERR_")
+    }
+}
+
+/// The code of a three-digit numeric command.
+pub fn numeric_code(msg: &IrcMsg) -> Option<u16> {
+    let command = msg.get_command();
+    if command.len() != 3 || !command.bytes().all(|b| b'0' <= b && b <= b'9') {
+        return None;
+    }
+    command.parse().ok()
+}
+
+fn validate_numeric(msg: &IrcMsg, numeric: Numeric, min_args: usize) -> Result<(), MessageError> {
+    if numeric_code(msg) != Some(numeric.code()) {
+        return Err(MessageError::WrongCommand);
+    }
+    if msg.args().count() < min_args {
+        return Err(MessageError::MissingArgument);
+    }
+    Ok(())
+}
+
+/// The `n`th argument, which validation guarantees is present.
+fn nth_arg(msg: &IrcMsg, n: usize) -> &[u8] {
+    msg.args().nth(n).unwrap()
+}
+
+
+impl_irc_msg_subtype!(NumericReply);
+impl_irc_msg_subtype_buf!(NumericReplyBuf, NumericReply);
+
+/// Any three-digit reply, known or not.  Every numeric is addressed to
+/// the client it is sent to, so there is always at least one argument.
+impl NumericReply {
+    pub fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        if numeric_code(msg).is_none() {
+            return Err(MessageError::WrongCommand);
+        }
+        let (_command, rest) = parse_helpers::split_command(after_prefix(msg));
+        if rest.len() == 0 {
+            return Err(MessageError::MissingArgument);
+        }
+        Ok(())
+    }
+
+    pub fn get_code(&self) -> u16 {
+        numeric_code(self).unwrap()
+    }
+
+    /// The code as a `Numeric`, if it is a well-known one.
+    pub fn get_numeric(&self) -> Option<Numeric> {
+        Numeric::from_code(self.get_code())
+    }
+
+    /// Our nick, or `*` before registration.
+    pub fn get_target(&self) -> &[u8] {
+        let (_command, rest) = parse_helpers::split_command(after_prefix(self));
+        let (target, _rest) = parse_helpers::split_arg(rest);
+        target
+    }
+
+    /// The arguments after the target.
+    pub fn params(&self) -> ArgumentIter {
+        let mut args = self.args();
+        args.next();
+        args
+    }
+}
+
+fn after_prefix(msg: &IrcMsg) -> &[u8] {
+    let (_prefix, rest) = parse_helpers::split_prefix(msg.as_bytes());
+    rest
+}
+
+
+impl_irc_msg_subtype!(RplWelcome);
+impl_irc_msg_subtype_buf!(RplWelcomeBuf, RplWelcome);
+
+impl RplWelcome {
+    pub fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        validate_numeric(msg, Numeric::RplWelcome, 1)
+    }
+
+    /// The nick we registered with.
+    pub fn get_nick(&self) -> &[u8] {
+        nth_arg(self, 0)
+    }
+
+    pub fn get_text(&self) -> Option<&[u8]> {
+        self.args().nth(1)
+    }
+}
+
+
+impl_irc_msg_subtype!(RplTopic);
+impl_irc_msg_subtype_buf!(RplTopicBuf, RplTopic);
+
+impl RplTopic {
+    pub fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        validate_numeric(msg, Numeric::RplTopic, 3)
+    }
+
+    pub fn get_channel(&self) -> &[u8] {
+        nth_arg(self, 1)
+    }
+
+    pub fn get_topic(&self) -> &[u8] {
+        nth_arg(self, 2)
+    }
+}
+
+
+impl_irc_msg_subtype!(RplNamreply);
+impl_irc_msg_subtype_buf!(RplNamreplyBuf, RplNamreply);
+
+impl RplNamreply {
+    pub fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        try!(validate_numeric(msg, Numeric::RplNamreply, 4));
+        match nth_arg(msg, 1) {
+            b"=" | b"*" | b"@" => Ok(()),
+            _ => Err(MessageError::BadArgument),
+        }
+    }
+
+    /// `=` for a public channel, `*` for a private one and `@` for a
+    /// secret one.
+    pub fn get_visibility(&self) -> u8 {
+        nth_arg(self, 1)[0]
+    }
+
+    pub fn get_channel(&self) -> &[u8] {
+        nth_arg(self, 2)
+    }
+
+    /// The listed members, with their prefix symbols such as `@`.  Use
+    /// `ModeTable::prefix_mode` to interpret those.
+    pub fn get_nicks(&self) -> Vec<&[u8]> {
+        nth_arg(self, 3).split(|&b| b == b' ')
+            .filter(|nick| !nick.is_empty())
+            .collect()
+    }
+}
+
+
+impl_irc_msg_subtype!(ErrNicknameinuse);
+impl_irc_msg_subtype_buf!(ErrNicknameinuseBuf, ErrNicknameinuse);
+
+impl ErrNicknameinuse {
+    pub fn validate(msg: &IrcMsg) -> Result<(), MessageError> {
+        validate_numeric(msg, Numeric::ErrNicknameinuse, 2)
+    }
+
+    /// The nick we asked for, which someone else has.
+    pub fn get_nick(&self) -> &[u8] {
+        nth_arg(self, 1)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use ::IrcMsg;
+    use super::{Numeric, NumericReply, RplNamreply, RplTopic, ErrNicknameinuse};
+
+    #[test]
+    fn numeric_codes() {
+        let msg = IrcMsg::new(b":srv 433 * bot :Nickname is already in use").unwrap();
+        let numeric = Numeric::from_msg(msg).unwrap();
+        assert_eq!(numeric, Numeric::ErrNicknameinuse);
+        assert_eq!(numeric.name(), "ERR_NICKNAMEINUSE");
+        assert!(numeric.is_error());
+        assert_eq!(Numeric::from_code(1).unwrap().code(), 1);
+        assert_eq!(Numeric::from_msg(IrcMsg::new(b":srv PRIVMSG #c :333").unwrap()), None);
+
+        assert_eq!(msg.as_tymsg::<&ErrNicknameinuse>().unwrap().get_nick(), b"bot");
+    }
+
+    #[test]
+    fn any_numeric() {
+        let msg = IrcMsg::new(b":srv 042 bot ABCDEFGHI :your unique ID").unwrap();
+        let reply = msg.as_tymsg::<&NumericReply>().unwrap();
+        assert_eq!(reply.get_code(), 42);
+        assert_eq!(reply.get_numeric(), None);
+        assert_eq!(reply.get_target(), b"bot");
+        assert_eq!(reply.params().collect::<Vec<_>>(),
+            vec![&b"ABCDEFGHI"[..], &b"your unique ID"[..]]);
+
+        let msg = IrcMsg::new(b":srv 001 bot :Welcome").unwrap();
+        assert_eq!(msg.as_tymsg::<&NumericReply>().unwrap().get_numeric(), Some(Numeric::RplWelcome));
+        assert!(IrcMsg::new(b":srv 001").unwrap().as_tymsg::<&NumericReply>().is_err());
+        assert!(IrcMsg::new(b":srv NOTICE * :hi").unwrap().as_tymsg::<&NumericReply>().is_err());
+    }
+
+    #[test]
+    fn typed_numerics() {
+        let msg = IrcMsg::new(b":srv 353 me @ #secret :@alice +bob carol").unwrap();
+        let names = msg.as_tymsg::<&RplNamreply>().unwrap();
+        assert_eq!(names.get_visibility(), b'@');
+        assert_eq!(names.get_channel(), b"#secret");
+        assert_eq!(names.get_nicks(), vec![&b"@alice"[..], b"+bob", b"carol"]);
+
+        let msg = IrcMsg::new(b":srv 332 me #rust :Rust discussion").unwrap();
+        let topic = msg.as_tymsg::<&RplTopic>().unwrap();
+        assert_eq!(topic.get_channel(), b"#rust");
+        assert_eq!(topic.get_topic(), b"Rust discussion");
+
+        assert!(msg.as_tymsg::<&RplNamreply>().is_err());
+        assert!(IrcMsg::new(b":srv 332 me #rust").unwrap().as_tymsg::<&RplTopic>().is_err());
+    }
+}
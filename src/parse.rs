@@ -2,12 +2,21 @@ use std::error::Error;
 
 use std::mem;
 use std::ops;
-use std::borrow::{Borrow, BorrowMut, ToOwned};
+use std::borrow::{Borrow, BorrowMut, Cow, ToOwned};
 
 use ::slice::Slice;
 use ::parse_helpers;
 use ::legacy::IrcMsg as IrcMsgLegacy;
 use ::mtype2::FromIrcMsg;
+use ::tags::{self, ServerTime};
+
+/// The maximum length of a message on the wire, including the trailing
+/// CR-LF but excluding any message tags.  See RFC 1459, section 2.3.
+pub const MAX_MESSAGE_LEN: usize = 512;
+
+/// The maximum length of the message tags section, including the leading
+/// `@` and the trailing space.  See the IRCv3 message-tags specification.
+pub const MAX_TAGS_LEN: usize = 8191;
 
 #[derive(Clone, Debug)]
 pub struct IrcMsgBuf {
@@ -64,6 +73,10 @@ impl ToOwned for IrcMsg {
 }
 
 impl IrcMsgBuf {
+    /// Parse the first line of `buf`.  Anything from the first LF (or
+    /// CR-LF) on is dropped; everything before it is kept byte for byte,
+    /// so `as_bytes()` re-renders the accepted line exactly, extra spaces
+    /// included.  Only the accessors normalize.
     pub fn new(mut buf: Vec<u8>) -> Result<IrcMsgBuf, ParseError> {
         let msg_len = try!(IrcMsg::new(&buf)).as_bytes().len();
         buf.truncate(msg_len);
@@ -87,11 +100,13 @@ impl IrcMsgBuf {
     }
 
     pub fn into_legacy(self) -> IrcMsgLegacy {
-        IrcMsgLegacy::new(self.inner).unwrap()
+        IrcMsgLegacy::new_lossless(self.inner).unwrap()
     }
 }
 
 impl IrcMsg {
+    /// Borrow the first line of `buf` as a message, without copying.  See
+    /// `IrcMsgBuf::new` for what is kept.
     pub fn new(buf: &[u8]) -> Result<&IrcMsg, ParseError>  {
         let buf = parse_helpers::first_line(buf);
         try!(IrcMsg::validate_buffer(&buf));
@@ -137,10 +152,25 @@ impl IrcMsg {
         mem::transmute(s)
     }
 
+    /// The message exactly as it was accepted, without the line ending.
     pub fn as_bytes(&self) -> &[u8] {
         &self.inner
     }
 
+    /// The number of bytes this message occupies on the wire, including
+    /// the trailing CR-LF.
+    pub fn wire_len(&self) -> usize {
+        self.inner.len() + 2
+    }
+
+    /// The number of bytes which may still be added to this message before
+    /// it exceeds `MAX_MESSAGE_LEN`.  Zero if the message is already too long.
+    /// Message tags have a budget of their own and are not counted.
+    pub fn remaining_budget(&self) -> usize {
+        let (_, untagged) = parse_helpers::split_tags(&self.inner);
+        MAX_MESSAGE_LEN.saturating_sub(untagged.len() + 2)
+    }
+
     /// Mutably borrow the underlying storage.  This is private because it
     /// does not maintain the IrcMsg invariant.
     fn as_u8_slice_mut(&mut self) -> &mut [u8] {
@@ -171,10 +201,56 @@ impl IrcMsg {
         FromIrcMsg::from_irc_msg(self)
     }
 
+    /// The message tags as `(key, raw value)` pairs, in message order.
+    /// Values are still escaped, and are `None` if the tag has no `=`.
     pub fn tags(&self) -> TagIter {
-        let _buffer = &self.inner[..];
-        unimplemented!();
-        TagIter { arg_body: _buffer }
+        let (tags, _) = parse_helpers::split_tags(&self.inner);
+        TagIter { tag_body: tags }
+    }
+
+    /// The escaped value of the last tag named `key`.  A tag without a
+    /// value gives `Some(b"")`.
+    pub fn get_tag_raw(&self, key: &str) -> Option<&[u8]> {
+        let mut found = None;
+        for (tag_key, value) in self.tags() {
+            if tag_key == key.as_bytes() {
+                found = Some(value.unwrap_or(b""));
+            }
+        }
+        found
+    }
+
+    /// The unescaped value of the last tag named `key`.
+    pub fn get_tag(&self, key: &str) -> Option<Cow<[u8]>> {
+        self.get_tag_raw(key).map(tags::unescape_value)
+    }
+
+    fn get_tag_str(&self, key: &str) -> Option<Cow<str>> {
+        match self.get_tag(key) {
+            Some(Cow::Borrowed(value)) => ::std::str::from_utf8(value).ok().map(Cow::Borrowed),
+            Some(Cow::Owned(value)) => String::from_utf8(value).ok().map(Cow::Owned),
+            None => None,
+        }
+    }
+
+    /// The `server-time` tag.  `None` if it is missing or malformed.
+    pub fn server_time(&self) -> Option<ServerTime> {
+        self.get_tag(tags::SERVER_TIME).and_then(|value| ServerTime::parse(&value).ok())
+    }
+
+    /// The account the sender is logged in to, from the `account` tag.
+    pub fn account(&self) -> Option<Cow<str>> {
+        self.get_tag_str(tags::ACCOUNT)
+    }
+
+    /// The `msgid` tag.
+    pub fn msgid(&self) -> Option<Cow<str>> {
+        self.get_tag_str(tags::MSGID)
+    }
+
+    /// The reference tag of the batch this message belongs to.
+    pub fn batch(&self) -> Option<Cow<str>> {
+        self.get_tag_str(tags::BATCH)
     }
 
     pub fn args(&self) -> ArgumentIter {
@@ -183,6 +259,18 @@ impl IrcMsg {
         let (_, buffer) = parse_helpers::split_command(buffer);
         ArgumentIter { arg_body: buffer }
     }
+
+    /// Like `args`, but also says whether each argument was written as a
+    /// trailing (`:`-prefixed) parameter.  Only the last argument can be.
+    pub fn args_with_meta(&self) -> ArgumentMetaIter {
+        ArgumentMetaIter { inner: self.args() }
+    }
+
+    /// True if the last argument was written as a trailing parameter, as
+    /// in `PRIVMSG #c :hi` but not `PRIVMSG #c hi`.
+    pub fn has_trailing(&self) -> bool {
+        self.args_with_meta().any(|(_, trailing)| trailing)
+    }
 }
 
 impl IrcMsgPrefix {
@@ -198,8 +286,183 @@ impl IrcMsgPrefix {
     }
 }
 
+/// Builds an `IrcMsgBuf` piece by piece.  Each piece is checked as it is
+/// added and the first bad one is reported by `build`, so calls can be
+/// chained freely.
+///
+/// ```
+/// use irc::IrcMsgBuilder;
+///
+/// let msg = IrcMsgBuilder::new()
+///     .tag("+example.com/reply", Some("abc 123"))
+///     .command("PRIVMSG")
+///     .arg("#rust")
+///     .trailing("hello, world")
+///     .build()
+///     .unwrap();
+/// assert_eq!(msg.as_bytes(), &b"@+example.com/reply=abc\\s123 PRIVMSG #rust :hello, world"[..]);
+/// ```
+#[derive(Clone, Debug)]
+pub struct IrcMsgBuilder {
+    tags: Vec<u8>,
+    prefix: Option<Vec<u8>>,
+    command: Option<Vec<u8>>,
+    args: Vec<Vec<u8>>,
+    trailing: Option<Vec<u8>>,
+    error: Option<ParseError>,
+}
+
+impl IrcMsgBuilder {
+    pub fn new() -> IrcMsgBuilder {
+        IrcMsgBuilder {
+            tags: Vec::new(),
+            prefix: None,
+            command: None,
+            args: Vec::new(),
+            trailing: None,
+            error: None,
+        }
+    }
+
+    fn check<F>(mut self, piece: &[u8], what: &str, is_valid: F) -> IrcMsgBuilder
+        where F: FnOnce(&[u8]) -> bool
+    {
+        if self.error.is_none() {
+            if let Some(&byte) = piece.iter().find(|&&b| b == 0 || b == b'\r' || b == b'\n') {
+                self.error = Some(ParseError::unexpected_byte(byte, what));
+            } else if !is_valid(piece) {
+                self.error = Some(ParseError::invalid(what, piece));
+            }
+        }
+        self
+    }
+
+    /// Add a message tag.  The value is escaped as needed.
+    pub fn tag<K>(self, key: K, value: Option<&str>) -> IrcMsgBuilder
+        where K: AsRef<[u8]>
+    {
+        let key = key.as_ref();
+        let mut builder = self.check(key, "tag key", tags::is_valid_key);
+        if builder.error.is_none() {
+            builder.tags.push(if builder.tags.is_empty() { b'@' } else { b';' });
+            builder.tags.extend(key);
+            if let Some(value) = value {
+                builder.tags.push(b'=');
+                builder.tags.extend(tags::escape_value(value.as_bytes()).iter());
+            }
+        }
+        builder
+    }
+
+    /// Set the prefix, without its leading `:`.
+    pub fn prefix<P>(self, prefix: P) -> IrcMsgBuilder
+        where P: AsRef<[u8]>
+    {
+        let prefix = prefix.as_ref();
+        let mut builder = self.check(prefix, "prefix", |p| {
+            p.len() > 0 && parse_helpers::is_valid_prefix(p)
+        });
+        builder.prefix = Some(prefix.to_vec());
+        builder
+    }
+
+    pub fn command<C>(self, command: C) -> IrcMsgBuilder
+        where C: AsRef<[u8]>
+    {
+        let command = command.as_ref();
+        let mut builder = self.check(command, "command", |c| {
+            parse_helpers::is_middle_arg(c) && c[0] != b'@'
+                && parse_helpers::is_valid_command(c)
+        });
+        builder.command = Some(command.to_vec());
+        builder
+    }
+
+    /// Add a middle argument: non-empty, without spaces and not starting
+    /// with `:`.  Must come before `trailing`.
+    pub fn arg<A>(self, arg: A) -> IrcMsgBuilder
+        where A: AsRef<[u8]>
+    {
+        let arg = arg.as_ref();
+        let has_trailing = self.trailing.is_some();
+        let mut builder = self.check(arg, "argument", |a| {
+            !has_trailing && parse_helpers::is_middle_arg(a)
+        });
+        builder.args.push(arg.to_vec());
+        builder
+    }
+
+    /// Set the trailing argument, which may contain spaces or be empty.
+    pub fn trailing<T>(self, trailing: T) -> IrcMsgBuilder
+        where T: AsRef<[u8]>
+    {
+        let trailing = trailing.as_ref();
+        let mut builder = self.check(trailing, "trailing argument", |_| true);
+        builder.trailing = Some(trailing.to_vec());
+        builder
+    }
+
+    pub fn build(self) -> Result<IrcMsgBuf, ParseError> {
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+        let command = match self.command {
+            Some(command) => command,
+            None => return Err(ParseError::new(ParseErrorKind::Truncated, Vec::new())),
+        };
+
+        let mut out = self.tags;
+        if !out.is_empty() {
+            out.push(b' ');
+        }
+        if let Some(prefix) = self.prefix {
+            out.push(b':');
+            out.extend(prefix);
+            out.push(b' ');
+        }
+        out.extend(command);
+        for arg in self.args.iter() {
+            out.push(b' ');
+            out.extend(arg);
+        }
+        if let Some(trailing) = self.trailing {
+            out.extend(b" :");
+            out.extend(trailing);
+        }
+        IrcMsgBuf::new(out)
+    }
+}
+
 pub struct TagIter<'a> {
-    arg_body: &'a [u8],
+    tag_body: &'a [u8],
+}
+
+impl<'a> Iterator for TagIter<'a> {
+    /// The key, and the still-escaped value if there was an `=`
+    type Item = (&'a [u8], Option<&'a [u8]>);
+
+    fn next(&mut self) -> Option<(&'a [u8], Option<&'a [u8]>)> {
+        loop {
+            if self.tag_body.len() == 0 {
+                return None;
+            }
+            let tag = match parse_helpers::find_character(self.tag_body, b';', 0) {
+                Some(idx) => {
+                    let tag = &self.tag_body[..idx];
+                    self.tag_body = &self.tag_body[idx+1..];
+                    tag
+                },
+                None => mem::replace(&mut self.tag_body, &[]),
+            };
+            if tag.len() == 0 {
+                continue;
+            }
+            return Some(match parse_helpers::find_character(tag, b'=', 0) {
+                Some(idx) => (&tag[..idx], Some(&tag[idx+1..])),
+                None => (tag, None),
+            });
+        }
+    }
 }
 
 pub struct ArgumentIter<'a> {
@@ -220,12 +483,28 @@ impl<'a> Iterator for ArgumentIter<'a> {
     }
 }
 
+pub struct ArgumentMetaIter<'a> {
+    inner: ArgumentIter<'a>,
+}
+
+impl<'a> Iterator for ArgumentMetaIter<'a> {
+    /// The argument, and whether it was a trailing parameter
+    type Item = (&'a [u8], bool);
+
+    fn next(&mut self) -> Option<(&'a [u8], bool)> {
+        let trailing = self.inner.arg_body.first() == Some(&b':');
+        self.inner.next().map(|arg| (arg, trailing))
+    }
+}
+
 #[derive(Clone)]
 struct IrcParser(IrcParserState);
 
 #[derive(Copy, Clone, Debug)]
 enum IrcParserState {
     Initial,
+    Tags,
+    TagsEnd,
     Prefix,
     CommandStart,
     Command,
@@ -250,9 +529,17 @@ impl IrcParser {
 
         match (self.0, byte) {
             (Initial, b' ') => Ok(IrcParser(Initial)),
+            (Initial, b'@') => Ok(IrcParser(Tags)),
             (Initial, b':') => Ok(IrcParser(Prefix)),
             (Initial, _byte) => Ok(IrcParser(Command)),
 
+            (Tags, b' ') => Ok(IrcParser(TagsEnd)),
+            (Tags, _byte) => Ok(IrcParser(Tags)),
+
+            (TagsEnd, b' ') => Ok(IrcParser(TagsEnd)),
+            (TagsEnd, b':') => Ok(IrcParser(Prefix)),
+            (TagsEnd, _byte) => Ok(IrcParser(Command)),
+
             (Prefix, b' ') => Ok(IrcParser(CommandStart)),
             (Prefix, byte) if is_valid_prefix_byte(byte) => Ok(IrcParser(Prefix)),
             (Prefix, _byte) => {
@@ -285,10 +572,13 @@ impl IrcParser {
         let truncated = Err(ParseError::new(ParseErrorKind::Truncated, Vec::new()));
         match self.0 {
             Initial => truncated,
+            Tags => truncated,
+            TagsEnd => truncated,
             Prefix => truncated,
             CommandStart => truncated,
-            Command => truncated,
-            ArgStart => truncated,
+            // Commands such as a bare QUIT have no arguments at all.
+            Command => Ok(()),
+            ArgStart => Ok(()),
             Arg => Ok(()),
             ArgEnd => Ok(()),
             RestArg => Ok(())
@@ -331,6 +621,14 @@ impl ParseError {
         }
     }
 
+    fn invalid(phase: &str, piece: &[u8]) -> ParseError {
+        ParseError {
+            kind: ParseErrorKind::UnexpectedByte,
+            message: piece.to_vec(),
+            error_msg: format!("Invalid {}", phase),
+        }
+    }
+
     fn replace_message(&self, buf: &[u8]) -> ParseError {
         ParseError {
             kind: self.kind.clone(),
@@ -368,7 +666,8 @@ impl Error for ParseError {
 
 #[cfg(test)]
 mod tests {
-    use super::IrcMsg;
+    use std::borrow::ToOwned;
+    use super::{IrcMsg, IrcMsgBuf, IrcMsgBuilder, MAX_MESSAGE_LEN};
     use ::mtype2::server::{Ping, Pong, Privmsg};
 
     #[test]
@@ -396,6 +695,150 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_borrowed_parsing_does_not_allocate() {
+        use ::testinfra::alloc::count_allocations;
+
+        let line: &[u8] = b"@time=2020-01-01T00:00:00.000Z;msgid=abc :n!u@h PRIVMSG #rust :hello there\r\n";
+        let allocations = count_allocations(|| {
+            let msg = IrcMsg::new(line).unwrap();
+            assert_eq!(msg.get_command(), "PRIVMSG");
+            assert_eq!(msg.get_prefix().unwrap().as_bytes(), b"n!u@h");
+            assert_eq!(msg.args().count(), 2);
+            assert_eq!(msg.tags().count(), 2);
+            assert_eq!(msg.get_tag_raw("msgid"), Some(&b"abc"[..]));
+            assert!(msg.msgid().is_some());
+
+            let privmsg = msg.as_tymsg::<&Privmsg>().unwrap();
+            assert_eq!(privmsg.source_nick(), "n");
+            assert_eq!(privmsg.get_body_raw(), b"hello there");
+        });
+        assert_eq!(allocations, 0);
+
+        // Make sure the counter is counting.
+        let msg = IrcMsg::new(line).unwrap();
+        assert!(count_allocations(|| msg.to_owned()) > 0);
+    }
+
+    #[test]
+    fn test_wire_len() {
+        let msg = IrcMsg::new(b"PING :foo\r\n").unwrap();
+        assert_eq!(msg.wire_len(), 11);
+        assert_eq!(msg.remaining_budget(), MAX_MESSAGE_LEN - 11);
+
+        let long = vec![b'a'; MAX_MESSAGE_LEN];
+        let mut buf = b"PRIVMSG #foo :".to_vec();
+        buf.extend(long.iter());
+        assert_eq!(IrcMsg::new(&buf).unwrap().remaining_budget(), 0);
+    }
+
+    #[test]
+    fn test_exact_round_trip() {
+        let lines: &[&[u8]] = &[
+            b":n!u@h PRIVMSG #c :hi",
+            b":n!u@h  PRIVMSG   #c   :  spaced  out  ",
+            b"PING :",
+            b":services.esper.net MODE #foo-bar +o foobar  ",
+            b":gravel.mozilla.org 432  #momo :Erroneous Nickname",
+            b":cool\tguy foo bar baz",
+            b":n!u@h PRIVMSG #c :\x02bold\x02 \x0304red",
+        ];
+        for &line in lines.iter() {
+            assert_eq!(IrcMsg::new(line).unwrap().as_bytes(), line);
+            let owned = IrcMsgBuf::new(line.to_vec()).unwrap();
+            assert_eq!(owned.as_bytes(), line);
+            assert_eq!(owned.to_owned().as_bytes(), line);
+            assert_eq!(IrcMsgBuf::from_legacy(owned.into_legacy()).as_bytes(), line);
+
+            for ending in [&b"\r\n"[..], b"\n", b"\r\nPING :next\r\n"].iter() {
+                let mut buf = line.to_vec();
+                buf.extend(ending.iter());
+                assert_eq!(IrcMsgBuf::new(buf).unwrap().as_bytes(), line);
+            }
+        }
+    }
+
+    #[test]
+    fn test_args_with_meta() {
+        let msg = IrcMsg::new(b":n!u@h PRIVMSG #c :hi there").unwrap();
+        let args: Vec<_> = msg.args_with_meta().collect();
+        assert_eq!(args, vec![(&b"#c"[..], false), (&b"hi there"[..], true)]);
+        assert!(msg.has_trailing());
+
+        let msg = IrcMsg::new(b":n!u@h PRIVMSG #c hi").unwrap();
+        assert!(!msg.has_trailing());
+        assert_eq!(msg.args().collect::<Vec<_>>(),
+            IrcMsg::new(b":n!u@h PRIVMSG #c :hi").unwrap().args().collect::<Vec<_>>());
+
+        let msg = IrcMsg::new(b"TOPIC #c :").unwrap();
+        assert_eq!(msg.args_with_meta().last(), Some((&b""[..], true)));
+    }
+
+    #[test]
+    fn test_tags() {
+        let line: &[u8] = b"@time=2011-10-19T16:40:51.620Z;account=bob;msgid=a\\sb;+example.com/x;batch=yXNAbvnRHTRBv \
+            :n!u@h PRIVMSG #c :hi";
+        let msg = IrcMsg::new(line).unwrap();
+
+        assert_eq!(msg.get_prefix().unwrap().as_bytes(), b"n!u@h");
+        assert_eq!(msg.get_command(), "PRIVMSG");
+        assert_eq!(msg.args().collect::<Vec<_>>(), vec![&b"#c"[..], b"hi"]);
+        assert!(msg.as_tymsg::<&Privmsg>().is_ok());
+
+        assert_eq!(msg.tags().count(), 5);
+        assert_eq!(msg.get_tag_raw("msgid"), Some(&b"a\\sb"[..]));
+        assert_eq!(msg.get_tag("+example.com/x").as_ref().map(|v| &v[..]), Some(&b""[..]));
+        assert!(msg.get_tag("missing").is_none());
+
+        assert_eq!(msg.server_time().unwrap().unix_millis(), 1319042451620);
+        assert_eq!(msg.account().as_ref().map(|v| &v[..]), Some("bob"));
+        assert_eq!(msg.msgid().as_ref().map(|v| &v[..]), Some("a b"));
+        assert_eq!(msg.batch().as_ref().map(|v| &v[..]), Some("yXNAbvnRHTRBv"));
+
+        let legacy = IrcMsgBuf::new(line.to_vec()).unwrap().into_legacy();
+        assert_eq!(legacy.get_prefix_str(), "n!u@h");
+        assert_eq!(legacy.get_command(), "PRIVMSG");
+
+        assert!(IrcMsg::new(b"@a=b").is_err());
+        assert!(IrcMsg::new(b"@a=b :n!u@h").is_err());
+    }
+
+    #[test]
+    fn test_builder() {
+        let msg = IrcMsgBuilder::new()
+            .tag("msgid", Some("a;b"))
+            .tag("+draft/typing", None)
+            .prefix("n!u@h")
+            .command("PRIVMSG")
+            .arg("#c")
+            .trailing("")
+            .build()
+            .unwrap();
+        assert_eq!(msg.as_bytes(), &b"@msgid=a\\:b;+draft/typing :n!u@h PRIVMSG #c :"[..]);
+        assert_eq!(msg.msgid().as_ref().map(|v| &v[..]), Some("a;b"));
+        assert_eq!(msg.args().collect::<Vec<_>>(), vec![&b"#c"[..], b""]);
+
+        let msg = IrcMsgBuilder::new().command("MODE").arg("#c").arg("+o").arg("n").build();
+        assert_eq!(msg.unwrap().as_bytes(), b"MODE #c +o n");
+
+        let msg = IrcMsgBuilder::new().command("QUIT").build();
+        assert_eq!(msg.unwrap().as_bytes(), b"QUIT");
+
+        let bad = [
+            IrcMsgBuilder::new().command("PRIVMSG").arg("#c").trailing("a\r\nQUIT"),
+            IrcMsgBuilder::new().command("PRIVMSG").arg("#c d").trailing("x"),
+            IrcMsgBuilder::new().command("PRIVMSG").arg(":c").trailing("x"),
+            IrcMsgBuilder::new().command("PRIVMSG").trailing("x").arg("#c"),
+            IrcMsgBuilder::new().prefix("").command("PING").arg("x"),
+            IrcMsgBuilder::new().command("").arg("x"),
+            IrcMsgBuilder::new().tag("a b", None).command("PING").arg("x"),
+            IrcMsgBuilder::new().arg("x"),
+        ];
+        for builder in bad.iter() {
+            assert!(builder.clone().build().is_err(), "{:?}", builder);
+        }
+    }
+
     #[test]
     fn test_many_modes2() {
         let buf: &[u8] = b":InfinityB!q@d0-0-0-0.abhsia.telus.net MODE # +vvvvvvvvvvvvvvvvvvvv a b c d e f g h i j k l m n o p q r s t";
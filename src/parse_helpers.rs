@@ -39,8 +39,25 @@ pub fn consume_whitespace(input: &[u8]) -> &[u8] {
     output
 }
 
+/// Split off the message tags section, without its leading `@`.  The tags
+/// are empty if the message has none.
+pub fn split_tags(input: &[u8]) -> (&[u8], &[u8]) {
+    if input.first() == Some(&b'@') {
+        let end_idx = find_character(input, b' ', 0);
+        match end_idx {
+            Some(idx) => (&input[1..idx], consume_whitespace(&input[idx+1..])),
+            None => (&input[1..], &[]),
+        }
+    } else {
+        (&[], input)
+    }
+}
+
+/// Split off the prefix, including its leading `:`.  Message tags before
+/// the prefix are skipped; use `split_tags` to get at them.
 pub fn split_prefix(input: &[u8]) -> (&[u8], &[u8]) {
-    if input[0] == b':' {
+    let (_, input) = split_tags(input);
+    if input.first() == Some(&b':') {
         let end_idx = find_character(input, b' ', 0);
         match end_idx {
             Some(idx) => (&input[..idx], consume_whitespace(&input[idx+1..])),
@@ -63,9 +80,9 @@ pub fn parse_prefix(input: &[u8]) -> Result<(&[u8], &[u8], &[u8]), ()> {
     let nick_end = try!(find_character(input, b'!', nick_start).ok_or(()));
 
     let user_start = nick_end + 1;
-    let user_end = try!(find_character(input, b'@', user_start).ok_or(()));;
+    let user_end = try!(find_character(input, b'@', user_start).ok_or(()));
 
-    if is_valid_user(&input[user_start..user_end]) {
+    if !is_valid_user(&input[user_start..user_end]) {
         return Err(());
     }
 
@@ -124,6 +141,23 @@ pub fn is_valid_user(user: &[u8]) -> bool {
     return true;
 }
 
+/// True if `buf` contains a byte which may never appear inside a single
+/// IRC message: NUL, CR or LF.
+pub fn has_forbidden_byte(buf: &[u8]) -> bool {
+    for &byte in buf.iter() {
+        match byte {
+            b'\0' | b'\r' | b'\n' => return true,
+            _ => (),
+        }
+    }
+    false
+}
+
+/// A non-empty argument that may appear before the trailing argument.
+pub fn is_middle_arg(arg: &[u8]) -> bool {
+    arg.len() > 0 && arg[0] != b':' && !arg.contains(&b' ')
+}
+
 pub fn is_valid_prefix_byte(byte: u8) -> bool {
     is_non_white(byte)
 }
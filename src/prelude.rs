@@ -0,0 +1,14 @@
+//! The types and traits most programs need, in one import.
+//!
+//! ```
+//! use irc::prelude::*;
+//!
+//! let msg = IrcMsg::new(b":n!u@h PRIVMSG #rust :hello").unwrap();
+//! let privmsg = msg.as_tymsg::<&server::Privmsg>().unwrap();
+//! assert!(privmsg.get_target().eq_ignore_irc_case(b"#RUST"));
+//! ```
+
+pub use ::{IrcMsg, IrcMsgBuf, IrcMsgBuilder, ParseError, FromIrcMsg, MessageError};
+pub use ::{client, server};
+pub use ::{CaseMapping, IrcAsciiExt, OwnedIrcAsciiExt};
+pub use ::legacy::{IrcEvent, IrcSession, State};
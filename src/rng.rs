@@ -0,0 +1,101 @@
+//! Injectable randomness.
+//!
+//! Components needing random labels, tokens or jitter take an `Rng`
+//! rather than reaching for a global generator, so tests can substitute
+//! a deterministic one.  None of this is suitable for cryptography.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// Characters used by `gen_token`; safe in tags, parameters and nicks.
+const TOKEN_CHARS: &'static [u8] =
+    b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+
+pub trait Rng {
+    fn next_u64(&mut self) -> u64;
+
+    /// A value in `low..high`.  Panics if the range is empty.
+    fn gen_range(&mut self, low: u64, high: u64) -> u64 {
+        assert!(low < high, "empty range");
+        low + self.next_u64() % (high - low)
+    }
+
+    /// An alphanumeric token, for labels and the like.
+    fn gen_token(&mut self, len: usize) -> String {
+        (0..len)
+            .map(|_| TOKEN_CHARS[self.gen_range(0, TOKEN_CHARS.len() as u64) as usize] as char)
+            .collect()
+    }
+
+    /// `base`, randomly lengthened by up to `spread`.  Used to keep
+    /// reconnecting clients from arriving in lockstep.
+    fn jitter(&mut self, base: Duration, spread: Duration) -> Duration {
+        let spread_ms = spread.as_secs() * 1000 + (spread.subsec_nanos() / 1_000_000) as u64;
+        if spread_ms == 0 {
+            return base;
+        }
+        base + Duration::from_millis(self.gen_range(0, spread_ms + 1))
+    }
+}
+
+/// A small, fast xorshift generator.
+#[derive(Clone, Debug)]
+pub struct XorShiftRng {
+    state: u64,
+}
+
+impl XorShiftRng {
+    /// A generator seeded differently on each call.
+    pub fn new() -> XorShiftRng {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(0);
+        XorShiftRng::from_seed(hasher.finish())
+    }
+
+    /// A generator which always produces the same sequence for `seed`.
+    pub fn from_seed(seed: u64) -> XorShiftRng {
+        // The state must never be zero.
+        XorShiftRng { state: if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed } }
+    }
+}
+
+impl Rng for XorShiftRng {
+    fn next_u64(&mut self) -> u64 {
+        // xorshift64*
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use ::testinfra::rng::SequenceRng;
+    use super::{Rng, XorShiftRng};
+
+    #[test]
+    fn seeded_rng_is_reproducible() {
+        let mut a = XorShiftRng::from_seed(42);
+        let mut b = XorShiftRng::from_seed(42);
+        assert_eq!(a.gen_token(12), b.gen_token(12));
+        assert!(a.gen_token(12).bytes().all(|b| b.is_ascii_alphanumeric()));
+        assert!(XorShiftRng::from_seed(0).next_u64() != 0);
+    }
+
+    #[test]
+    fn rng_helpers() {
+        let mut rng = SequenceRng::new(vec![0, 1, 61, 7]);
+        assert_eq!(rng.gen_token(3), "ab9");
+        assert_eq!(rng.gen_range(5, 10), 7);
+
+        let mut rng = SequenceRng::new(vec![250]);
+        assert_eq!(rng.jitter(Duration::from_secs(10), Duration::from_secs(1)),
+            Duration::from_millis(10250));
+        assert_eq!(rng.jitter(Duration::from_secs(10), Duration::from_secs(0)),
+            Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,256 @@
+//! IRCv3 message tags: value escaping and the well-known tags.
+//!
+//! Raw tag values are available from `IrcMsg::tags`; these helpers turn
+//! them into something usable.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The tag carrying the time a message was processed by the server.
+pub const SERVER_TIME: &'static str = "time";
+
+/// The tag carrying the sender's services account.
+pub const ACCOUNT: &'static str = "account";
+
+/// The tag carrying a server-assigned unique message ID.
+pub const MSGID: &'static str = "msgid";
+
+/// The tag naming the batch a message belongs to.
+pub const BATCH: &'static str = "batch";
+
+/// Undo the escaping of a tag value.  Unknown escapes stand for the
+/// escaped character itself and a lone trailing backslash is dropped, as
+/// the specification asks.  Borrows when there is nothing to unescape.
+pub fn unescape_value(raw: &[u8]) -> Cow<[u8]> {
+    if !raw.contains(&b'\\') {
+        return Cow::Borrowed(raw);
+    }
+
+    let mut out = Vec::with_capacity(raw.len());
+    let mut iter = raw.iter();
+    while let Some(&byte) = iter.next() {
+        if byte != b'\\' {
+            out.push(byte);
+            continue;
+        }
+        match iter.next() {
+            Some(&b':') => out.push(b';'),
+            Some(&b's') => out.push(b' '),
+            Some(&b'r') => out.push(b'\r'),
+            Some(&b'n') => out.push(b'\n'),
+            Some(&other) => out.push(other),
+            None => (),
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// Escape a tag value for the wire; the inverse of `unescape_value`.
+pub fn escape_value(value: &[u8]) -> Cow<[u8]> {
+    let needs_escape = value.iter().any(|&b| match b {
+        b';' | b' ' | b'\\' | b'\r' | b'\n' => true,
+        _ => false,
+    });
+    if !needs_escape {
+        return Cow::Borrowed(value);
+    }
+
+    let mut out = Vec::with_capacity(value.len() + 8);
+    for &byte in value.iter() {
+        match byte {
+            b';' => out.extend(b"\\:"),
+            b' ' => out.extend(b"\\s"),
+            b'\\' => out.extend(b"\\\\"),
+            b'\r' => out.extend(b"\\r"),
+            b'\n' => out.extend(b"\\n"),
+            other => out.push(other),
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// Whether `key` is a well-formed tag key: an optional `+` client-only
+/// marker, an optional vendor ending in `/`, then letters, digits and
+/// hyphens.
+pub fn is_valid_key(key: &[u8]) -> bool {
+    let key = if key.first() == Some(&b'+') { &key[1..] } else { key };
+    let name = match key.iter().rposition(|&b| b == b'/') {
+        Some(idx) => {
+            let vendor = &key[..idx];
+            if vendor.is_empty() || !vendor.iter().all(|&b| is_key_byte(b) || b == b'.') {
+                return false;
+            }
+            &key[idx+1..]
+        },
+        None => key,
+    };
+    !name.is_empty() && name.iter().all(|&b| is_key_byte(b))
+}
+
+fn is_key_byte(byte: u8) -> bool {
+    match byte {
+        b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' | b'-' => true,
+        _ => false,
+    }
+}
+
+/// A `server-time` timestamp, with millisecond precision.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ServerTime {
+    unix_millis: u64,
+}
+
+impl ServerTime {
+    pub fn from_unix_millis(unix_millis: u64) -> ServerTime {
+        ServerTime { unix_millis: unix_millis }
+    }
+
+    /// Parse a tag value such as `2011-10-19T16:40:51.620Z`.  The
+    /// fractional seconds may be omitted or given to any precision.
+    pub fn parse(value: &[u8]) -> Result<ServerTime, ()> {
+        fn number(value: &[u8]) -> Result<u64, ()> {
+            if value.is_empty() {
+                return Err(());
+            }
+            let mut acc: u64 = 0;
+            for &byte in value.iter() {
+                if byte < b'0' || b'9' < byte {
+                    return Err(());
+                }
+                acc = try!(acc.checked_mul(10)
+                    .and_then(|acc| acc.checked_add((byte - b'0') as u64))
+                    .ok_or(()));
+            }
+            Ok(acc)
+        }
+
+        if value.len() < 20 || value[value.len() - 1] != b'Z' {
+            return Err(());
+        }
+        let separators = [(4, b'-'), (7, b'-'), (10, b'T'), (13, b':'), (16, b':')];
+        for &(idx, sep) in separators.iter() {
+            if value[idx] != sep {
+                return Err(());
+            }
+        }
+
+        let year = try!(number(&value[0..4]));
+        let month = try!(number(&value[5..7]));
+        let day = try!(number(&value[8..10]));
+        let hour = try!(number(&value[11..13]));
+        let minute = try!(number(&value[14..16]));
+        let second = try!(number(&value[17..19]));
+        if year < 1970 || month < 1 || 12 < month || day < 1 || 31 < day
+            || 23 < hour || 59 < minute || 60 < second {
+            return Err(());
+        }
+
+        let mut millis = 0;
+        let rest = &value[19..value.len() - 1];
+        if !rest.is_empty() {
+            if rest[0] != b'.' {
+                return Err(());
+            }
+            let fraction = &rest[1..];
+            // Only the first three digits count, however many there are.
+            if fraction.is_empty() || !fraction.iter().all(|b| b.is_ascii_digit()) {
+                return Err(());
+            }
+            for idx in 0..3 {
+                let digit = fraction.get(idx).map(|&b| (b - b'0') as u64).unwrap_or(0);
+                millis = millis * 10 + digit;
+            }
+        }
+
+        let days = days_from_civil(year, month, day);
+        let secs = ((days * 24 + hour) * 60 + minute) * 60 + second;
+        Ok(ServerTime { unix_millis: secs * 1000 + millis })
+    }
+
+    pub fn unix_millis(&self) -> u64 {
+        self.unix_millis
+    }
+
+    pub fn to_system_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.unix_millis)
+    }
+}
+
+impl fmt::Display for ServerTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.unix_millis / 1000;
+        let (year, month, day) = civil_from_days(secs / 86400);
+        let secs_of_day = secs % 86400;
+        write!(f, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year, month, day,
+            secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60,
+            self.unix_millis % 1000)
+    }
+}
+
+// Days since 1970-01-01 in the proleptic Gregorian calendar, after
+// Howard Hinnant's `days_from_civil`.  Only defined from 1970 on.
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape_value, is_valid_key, unescape_value, ServerTime};
+
+    #[test]
+    fn test_unescape_value() {
+        assert_eq!(&unescape_value(b"plain")[..], b"plain");
+        assert_eq!(&unescape_value(b"a\\sb\\:c\\\\d\\r\\n")[..], b"a b;c\\d\r\n");
+        assert_eq!(&unescape_value(b"\\b")[..], b"b");
+        assert_eq!(&unescape_value(b"end\\")[..], b"end");
+    }
+
+    #[test]
+    fn test_escape_value() {
+        let raw: &[u8] = b"a b;c\\d\r\n";
+        assert_eq!(&escape_value(raw)[..], b"a\\sb\\:c\\\\d\\r\\n");
+        assert_eq!(&unescape_value(&escape_value(raw))[..], raw);
+        assert!(is_valid_key(b"+example.com/foo-bar"));
+        assert!(is_valid_key(b"msgid"));
+        assert!(!is_valid_key(b""));
+        assert!(!is_valid_key(b"example.com/"));
+        assert!(!is_valid_key(b"a=b"));
+    }
+
+    #[test]
+    fn test_server_time() {
+        let time = ServerTime::parse(b"2011-10-19T16:40:51.620Z").unwrap();
+        assert_eq!(time.unix_millis(), 1319042451620);
+        assert_eq!(time.to_string(), "2011-10-19T16:40:51.620Z");
+
+        assert_eq!(ServerTime::parse(b"2000-02-29T00:00:00Z").unwrap().unix_millis(),
+            951782400000);
+        assert_eq!(ServerTime::parse(b"1970-01-01T00:00:00.5Z").unwrap().unix_millis(), 500);
+        assert!(ServerTime::parse(b"2011-10-19 16:40:51.620Z").is_err());
+        assert!(ServerTime::parse(b"2011-10-19T16:40:51.Z").is_err());
+        let precise = ServerTime::parse(b"2011-10-19T16:40:51.620999999999999999999Z").unwrap();
+        assert_eq!(precise, time);
+        assert!(ServerTime::parse(b"2011-13-19T16:40:51Z").is_err());
+    }
+}
@@ -0,0 +1,200 @@
+//! Runs the parser and friends against test cases in the format of the
+//! ircdocs parser-tests corpus, vendored under `testdata/parser-tests`.
+//!
+//! Each file is handled by a `Suite`, which checks one case at a time, so
+//! a bad case is reported on its own instead of aborting the whole run.
+
+use std::borrow::Cow;
+use std::fmt;
+
+use serde_yaml::{self, Value};
+
+use ::{IrcMsgBuf, mask_matches};
+use ::tags::unescape_value;
+use ::legacy::IrcMsgPrefix;
+
+pub trait Suite {
+    /// Name of the suite, for failure reports.
+    fn name(&self) -> &'static str;
+
+    /// A short identifier for `case`, for failure reports.
+    fn describe(&self, case: &Value) -> String;
+
+    fn check(&self, case: &Value) -> Result<(), String>;
+}
+
+#[derive(Debug)]
+pub struct Failure {
+    pub suite: &'static str,
+    pub case: String,
+    pub reason: String,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {:?}: {}", self.suite, self.case, self.reason)
+    }
+}
+
+/// Run every case under the top-level `tests` key of `data`.
+pub fn run<S: Suite>(suite: &S, data: &str) -> Vec<Failure> {
+    let doc: Value = serde_yaml::from_str(data).expect("bad YAML");
+    let cases = doc.get("tests").and_then(Value::as_sequence).expect("no tests");
+
+    let mut failures = Vec::new();
+    for case in cases.iter() {
+        if let Err(reason) = suite.check(case) {
+            failures.push(Failure {
+                suite: suite.name(),
+                case: suite.describe(case),
+                reason: reason,
+            });
+        }
+    }
+    failures
+}
+
+fn get_str<'a>(value: &'a Value, key: &str) -> Option<&'a str> {
+    value.get(key).and_then(Value::as_str)
+}
+
+fn get_strs<'a>(value: &'a Value, key: &str) -> Vec<&'a str> {
+    match value.get(key).and_then(Value::as_sequence) {
+        Some(seq) => seq.iter().map(|v| v.as_str().expect("non-string")).collect(),
+        None => Vec::new(),
+    }
+}
+
+fn check_eq<T: fmt::Debug + PartialEq>(what: &str, expected: T, got: T) -> Result<(), String> {
+    if expected == got {
+        Ok(())
+    } else {
+        Err(format!("{}: expected {:?}, got {:?}", what, expected, got))
+    }
+}
+
+/// `msg-split.yaml`: splitting a line into tags, source, verb and params.
+pub struct MsgSplit;
+
+impl Suite for MsgSplit {
+    fn name(&self) -> &'static str {
+        "msg-split"
+    }
+
+    fn describe(&self, case: &Value) -> String {
+        get_str(case, "input").unwrap_or("").to_string()
+    }
+
+    fn check(&self, case: &Value) -> Result<(), String> {
+        let input = get_str(case, "input").expect("no input");
+        let atoms = case.get("atoms").expect("no atoms");
+
+        let msg = try!(IrcMsgBuf::new(input.as_bytes().to_vec())
+            .map_err(|err| format!("rejected: {:?}", err.kind)));
+
+        let mut expected_tags: Vec<(Vec<u8>, Option<Vec<u8>>)> = Vec::new();
+        if let Some(tags) = atoms.get("tags").and_then(Value::as_mapping) {
+            for (key, value) in tags.iter() {
+                let key = key.as_str().expect("non-string tag key");
+                let value = value.as_str().map(|v| v.as_bytes().to_vec());
+                expected_tags.push((key.as_bytes().to_vec(), value));
+            }
+        }
+        let tags: Vec<(Vec<u8>, Option<Vec<u8>>)> = msg.tags()
+            .map(|(key, value)| (key.to_vec(), value.map(|v| unescape_value(v).into_owned())))
+            .collect();
+        try!(check_eq("tags", expected_tags, tags));
+
+        let source = msg.get_prefix().map(|p| p.as_bytes());
+        try!(check_eq("source", get_str(atoms, "source").map(str::as_bytes), source));
+        try!(check_eq("verb", get_str(atoms, "verb").unwrap_or(""), msg.get_command()));
+
+        let expected: Vec<&[u8]> = get_strs(atoms, "params").into_iter()
+            .map(str::as_bytes).collect();
+        let params: Vec<&[u8]> = msg.args().collect();
+        check_eq("params", expected, params)
+    }
+}
+
+/// `userhost-split.yaml`: splitting a source into nick, user and host.
+pub struct UserhostSplit;
+
+impl Suite for UserhostSplit {
+    fn name(&self) -> &'static str {
+        "userhost-split"
+    }
+
+    fn describe(&self, case: &Value) -> String {
+        get_str(case, "source").unwrap_or("").to_string()
+    }
+
+    fn check(&self, case: &Value) -> Result<(), String> {
+        let source = get_str(case, "source").expect("no source");
+        let atoms = case.get("atoms").expect("no atoms");
+
+        let prefix = IrcMsgPrefix::new(Cow::Borrowed(source));
+        let host = Some(prefix.hostname()).and_then(|h| if h.is_empty() { None } else { Some(h) });
+        try!(check_eq("nick", get_str(atoms, "nick"), prefix.nick()));
+        try!(check_eq("user", get_str(atoms, "user"), prefix.username()));
+        check_eq("host", get_str(atoms, "host"), host)
+    }
+}
+
+/// `mask-match.yaml`: wildcard mask matching.
+pub struct MaskMatch;
+
+impl Suite for MaskMatch {
+    fn name(&self) -> &'static str {
+        "mask-match"
+    }
+
+    fn describe(&self, case: &Value) -> String {
+        get_str(case, "mask").unwrap_or("").to_string()
+    }
+
+    fn check(&self, case: &Value) -> Result<(), String> {
+        let mask = get_str(case, "mask").expect("no mask");
+        for target in get_strs(case, "matchers").into_iter() {
+            if !mask_matches(mask.as_bytes(), target.as_bytes()) {
+                return Err(format!("should match {:?}", target));
+            }
+        }
+        for target in get_strs(case, "fails").into_iter() {
+            if mask_matches(mask.as_bytes(), target.as_bytes()) {
+                return Err(format!("should not match {:?}", target));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn parser_tests_corpus() {
+    let mut failures = Vec::new();
+    failures.extend(run(&MsgSplit,
+        include_str!("../../testdata/parser-tests/msg-split.yaml")));
+    failures.extend(run(&UserhostSplit,
+        include_str!("../../testdata/parser-tests/userhost-split.yaml")));
+    failures.extend(run(&MaskMatch,
+        include_str!("../../testdata/parser-tests/mask-match.yaml")));
+
+    // Known gaps.  Remove entries as they are fixed; a case that starts
+    // passing fails this test too, so the list can't go stale.
+    let known: &[(&str, &str)] = &[
+        // runs of trailing spaces yield an empty param
+        ("msg-split", ":services.esper.net MODE #foo-bar +o foobar  "),
+        // IrcMsgPrefix only finds a nick when there is a `!`
+        ("userhost-split", "coolguy"),
+        ("userhost-split", "coolguy@127.0.0.1"),
+        ("userhost-split", "coolguy!ag"),
+    ];
+
+    let unexpected: Vec<_> = failures.iter()
+        .filter(|failure| !known.contains(&(failure.suite, &failure.case[..])))
+        .map(|failure| failure.to_string())
+        .collect();
+    assert!(unexpected.is_empty(), "unexpected failures: {:#?}", unexpected);
+    let failures: Vec<_> = failures.iter().map(|failure| failure.to_string()).collect();
+    assert!(failures.len() == known.len(),
+        "known failures now pass; update the list.  Still failing: {:#?}", failures);
+}
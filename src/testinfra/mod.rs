@@ -1 +1,4 @@
-pub mod transcript;
\ No newline at end of file
+pub mod alloc;
+pub mod transcript;
+pub mod conformance;
+pub mod rng;
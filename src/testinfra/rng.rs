@@ -0,0 +1,23 @@
+use ::rng::Rng;
+
+/// An `Rng` which cycles through fixed values, for tests.
+#[derive(Clone, Debug)]
+pub struct SequenceRng {
+    values: Vec<u64>,
+    next: usize,
+}
+
+impl SequenceRng {
+    pub fn new(values: Vec<u64>) -> SequenceRng {
+        assert!(!values.is_empty());
+        SequenceRng { values: values, next: 0 }
+    }
+}
+
+impl Rng for SequenceRng {
+    fn next_u64(&mut self) -> u64 {
+        let value = self.values[self.next];
+        self.next = (self.next + 1) % self.values.len();
+        value
+    }
+}
@@ -0,0 +1,172 @@
+//! Checking messages against the limits servers enforce.
+//!
+//! Parsing is deliberately lenient about length, so that over-long lines
+//! from a misbehaving server can still be read.  Before sending, check a
+//! message with `IrcMsg::validate` or build it with
+//! `IrcMsgBuf::new_validated`; typed messages such as `PrivmsgBuf`
+//! dereference to `IrcMsg` and can be checked the same way.
+
+use std::error::Error;
+use std::fmt;
+use std::str;
+
+use ::parse_helpers;
+use ::parse::{IrcMsg, IrcMsgBuf, ParseError, MAX_MESSAGE_LEN, MAX_TAGS_LEN};
+
+/// The limits `IrcMsg::validate` checks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationOptions {
+    /// The longest a message may be on the wire, including the CR-LF but
+    /// not the tags section
+    pub max_line_len: usize,
+    /// The longest the tags section may be, including its `@` and space
+    pub max_tags_len: usize,
+    /// The most arguments a message may have
+    pub max_args: usize,
+    /// Reject messages which aren't valid UTF-8
+    pub require_utf8: bool,
+}
+
+impl ValidationOptions {
+    /// The RFC 1459 limits of 512 bytes and 15 arguments, with the IRCv3
+    /// tags budget.
+    pub fn new() -> ValidationOptions {
+        ValidationOptions {
+            max_line_len: MAX_MESSAGE_LEN,
+            max_tags_len: MAX_TAGS_LEN,
+            max_args: 15,
+            require_utf8: false,
+        }
+    }
+
+    pub fn max_line_len(mut self, max_line_len: usize) -> ValidationOptions {
+        self.max_line_len = max_line_len;
+        self
+    }
+
+    pub fn max_tags_len(mut self, max_tags_len: usize) -> ValidationOptions {
+        self.max_tags_len = max_tags_len;
+        self
+    }
+
+    pub fn max_args(mut self, max_args: usize) -> ValidationOptions {
+        self.max_args = max_args;
+        self
+    }
+
+    pub fn require_utf8(mut self, require_utf8: bool) -> ValidationOptions {
+        self.require_utf8 = require_utf8;
+        self
+    }
+}
+
+impl Default for ValidationOptions {
+    fn default() -> ValidationOptions {
+        ValidationOptions::new()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ValidationError {
+    /// The message didn't parse at all
+    Parse(ParseError),
+    LineTooLong { len: usize, max: usize },
+    TagsTooLong { len: usize, max: usize },
+    TooManyArguments { count: usize, max: usize },
+    NotUtf8,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ValidationError::Parse(ref err) => write!(f, "{}", err),
+            ValidationError::LineTooLong { len, max } =>
+                write!(f, "message is {} bytes, over the limit of {}", len, max),
+            ValidationError::TagsTooLong { len, max } =>
+                write!(f, "tags are {} bytes, over the limit of {}", len, max),
+            ValidationError::TooManyArguments { count, max } =>
+                write!(f, "message has {} arguments, over the limit of {}", count, max),
+            ValidationError::NotUtf8 => write!(f, "message is not valid UTF-8"),
+        }
+    }
+}
+
+impl Error for ValidationError {
+    fn description(&self) -> &str {
+        match *self {
+            ValidationError::Parse(_) => "parse error",
+            ValidationError::LineTooLong { .. } => "message too long",
+            ValidationError::TagsTooLong { .. } => "tags too long",
+            ValidationError::TooManyArguments { .. } => "too many arguments",
+            ValidationError::NotUtf8 => "message not UTF-8",
+        }
+    }
+}
+
+impl IrcMsg {
+    /// Check this message against `options`.
+    pub fn validate(&self, options: &ValidationOptions) -> Result<(), ValidationError> {
+        let bytes = self.as_bytes();
+        let (_, untagged) = parse_helpers::split_tags(bytes);
+
+        let tags_len = bytes.len() - untagged.len();
+        if options.max_tags_len < tags_len {
+            return Err(ValidationError::TagsTooLong { len: tags_len, max: options.max_tags_len });
+        }
+        let line_len = untagged.len() + 2;
+        if options.max_line_len < line_len {
+            return Err(ValidationError::LineTooLong { len: line_len, max: options.max_line_len });
+        }
+        let count = self.args().count();
+        if options.max_args < count {
+            return Err(ValidationError::TooManyArguments { count: count, max: options.max_args });
+        }
+        if options.require_utf8 && str::from_utf8(bytes).is_err() {
+            return Err(ValidationError::NotUtf8);
+        }
+        Ok(())
+    }
+}
+
+impl IrcMsgBuf {
+    /// Like `new`, but the message must also pass `options`.
+    pub fn new_validated(buf: Vec<u8>, options: &ValidationOptions) -> Result<IrcMsgBuf, ValidationError> {
+        let msg = try!(IrcMsgBuf::new(buf).map_err(ValidationError::Parse));
+        try!(msg.validate(options));
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::client::PrivmsgBuf;
+    use ::parse::{IrcMsgBuf, MAX_MESSAGE_LEN};
+    use super::{ValidationError, ValidationOptions};
+
+    #[test]
+    fn validate_limits() {
+        let options = ValidationOptions::new();
+        let privmsg = PrivmsgBuf::new("#rust", vec![b'a'; MAX_MESSAGE_LEN]).unwrap();
+        assert_eq!(privmsg.validate(&options), Err(ValidationError::LineTooLong {
+            len: MAX_MESSAGE_LEN + 17,
+            max: MAX_MESSAGE_LEN,
+        }));
+        assert!(privmsg.validate(&options.clone().max_line_len(1024)).is_ok());
+
+        let tagged = b"@a=b PING :x".to_vec();
+        assert!(IrcMsgBuf::new_validated(tagged.clone(), &options).is_ok());
+        assert_eq!(
+            IrcMsgBuf::new_validated(tagged, &options.clone().max_tags_len(4)).unwrap_err(),
+            ValidationError::TagsTooLong { len: 5, max: 4 });
+
+        let many = b"CMD 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16".to_vec();
+        assert_eq!(IrcMsgBuf::new_validated(many, &options).unwrap_err(),
+            ValidationError::TooManyArguments { count: 16, max: 15 });
+
+        let latin1 = b"PRIVMSG #rust :caf\xe9".to_vec();
+        assert!(IrcMsgBuf::new_validated(latin1.clone(), &options).is_ok());
+        assert_eq!(
+            IrcMsgBuf::new_validated(latin1, &options.require_utf8(true)).unwrap_err(),
+            ValidationError::NotUtf8);
+    }
+}